@@ -20,6 +20,7 @@ pgt:
 .pml4t:  equ 0x1000
 .pdpt:   equ 0x2000
 .pdt:    equ 0x3000
+.pdpt_high: equ 0x4000
 align 512
 
 disable_paging:
@@ -49,6 +50,19 @@ setup_paging:
     or eax, 0b11
     mov [pgt.pdpt], eax
 
+    ; The kernel is linked in the upper half, so we also map the same 1GB at
+    ; the physmap and at the kernel virtual base. The physmap uses the same
+    ; pdpt as the identity map.
+    mov eax, pgt.pdpt
+    or eax, 0b11
+    mov [pgt.pml4t + ((config.physmap_start >> 39) & 0x1ff) * 8], eax
+    mov eax, pgt.pdpt_high
+    or eax, 0b11
+    mov [pgt.pml4t + ((config.kernel_virt_base >> 39) & 0x1ff) * 8], eax
+    mov eax, pgt.pdt
+    or eax, 0b11
+    mov [pgt.pdpt_high + ((config.kernel_virt_base >> 30) & 0x1ff) * 8], eax
+
     ; Configure all 512 pages.
     mov ecx, 0
 .loop:
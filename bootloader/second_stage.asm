@@ -81,9 +81,12 @@ long_mode_entry:
     call load_sample_elf
 
     ; Set the stack pointer to the ending address of the kernel stack section.
-    mov rsp, config.kernel_stack_end
+    ; The kernel runs in the upper half, so we use the upper half address.
+    mov rsp, config.kernel_virt_base + config.kernel_stack_end
 
-    jmp ENTRY_POINT
+    ; The entry point is too far to jump to directly.
+    mov rax, ENTRY_POINT
+    jmp rax
 
 load_kernel:
     ; Print payload_start
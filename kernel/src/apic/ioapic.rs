@@ -0,0 +1,121 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![cfg(not(test))]
+
+//! I/O APIC module. The I/O APIC receives the interrupts of the devices and
+//! sends them to the local APICs of the processors. It replaces the legacy
+//! PIC, which is disabled.
+
+use core::ptr;
+
+use ::paging::{self, phy_to_virt};
+use ::port::outb;
+use ::sync::Spinlock;
+
+/// The physical address of the registers of the I/O APIC. This is the
+/// default one, which is used by Qemu.
+const IOAPIC_BASE: usize = 0xfec0_0000;
+
+/// The offsets of the registers used to access the other registers. The
+/// index of a register is written to IOREGSEL, and then its value can be
+/// accessed through IOWIN.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+/// The indices of the registers of the I/O APIC.
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+/// The bit of a redirection entry that masks the interrupt.
+const MASKED: u32 = 1 << 16;
+
+/// The I/O ports of the legacy PICs.
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+/// The vectors that the legacy PICs are moved to.
+const PIC1_VECTOR: u8 = 0x20;
+const PIC2_VECTOR: u8 = 0x28;
+
+/// The registers are accessed in two steps, so only one processor can use
+/// them at a time.
+static LOCK: Spinlock<()> = Spinlock::new(());
+
+unsafe fn read(register: u32) -> u32 {
+    let base = phy_to_virt(IOAPIC_BASE);
+    ptr::write_volatile((base + IOREGSEL) as *mut u32, register);
+    ptr::read_volatile((base + IOWIN) as *const u32)
+}
+
+unsafe fn write(register: u32, value: u32) {
+    let base = phy_to_virt(IOAPIC_BASE);
+    ptr::write_volatile((base + IOREGSEL) as *mut u32, register);
+    ptr::write_volatile((base + IOWIN) as *mut u32, value);
+}
+
+/// Disable the legacy PICs. By default, they send their interrupts to the
+/// vectors of the exceptions, so they are moved to other vectors first, in
+/// case an interrupt arrives before they are masked.
+fn disable_pic() {
+    unsafe {
+        // Start the initialization sequence, which expects 3 more words.
+        outb(PIC1_COMMAND, 0x11);
+        outb(PIC2_COMMAND, 0x11);
+        outb(PIC1_DATA, PIC1_VECTOR);
+        outb(PIC2_DATA, PIC2_VECTOR);
+        // The second PIC is connected to IRQ 2 of the first one.
+        outb(PIC1_DATA, 1 << 2);
+        outb(PIC2_DATA, 2);
+        // 8086 mode.
+        outb(PIC1_DATA, 0x01);
+        outb(PIC2_DATA, 0x01);
+        // Mask all the interrupts.
+        outb(PIC1_DATA, 0xff);
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+/// Send the interrupts of the ISA IRQ `irq` to the processor whose APIC ID
+/// is `id` with vector `vector`.
+pub fn route(irq: u8, vector: u8, id: usize) {
+    // The ISA IRQs are connected to the inputs of the I/O APIC with the
+    // same numbers, except the ones overridden by the ACPI tables, which
+    // Qemu only does for the timer.
+    let register = IOREDTBL + 2 * u32::from(irq);
+    let _lock = LOCK.lock();
+    unsafe {
+        write(register + 1, (id as u32) << 24);
+        // Fixed delivery mode, physical destination, active high, and edge
+        // triggered, which is what the ISA devices use.
+        write(register, u32::from(vector));
+    }
+}
+
+/// Initialization function for I/O APIC module. All the interrupts are
+/// masked until they are routed.
+pub fn init() {
+    paging::map_device(IOAPIC_BASE);
+    disable_pic();
+    let _lock = LOCK.lock();
+    unsafe {
+        let count = (read(IOAPICVER) >> 16 & 0xff) + 1;
+        for i in 0..count {
+            write(IOREDTBL + 2 * i, MASKED);
+        }
+    }
+}
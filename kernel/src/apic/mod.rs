@@ -0,0 +1,157 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Local APIC module. This module talks to the local APIC of the processor
+//! to send and acknowledge interrupts between processors. The interrupts
+//! of the devices come from the I/O APIC in [ioapic](ioapic).
+
+pub mod ioapic;
+
+#[cfg(not(test))]
+use core::ptr;
+#[cfg(not(test))]
+use ::paging::{self, phy_to_virt};
+
+/// The physical address of the registers of the local APIC.
+#[cfg(not(test))]
+const APIC_BASE: usize = 0xfee0_0000;
+
+/// The offsets of the registers of the local APIC.
+#[cfg(not(test))]
+const EOI: usize = 0xb0;
+#[cfg(not(test))]
+const SPURIOUS_VECTOR: usize = 0xf0;
+#[cfg(not(test))]
+const ICR_LOW: usize = 0x300;
+#[cfg(not(test))]
+const ICR_HIGH: usize = 0x310;
+
+/// The interrupt vector used by the local APIC for spurious interrupts.
+#[cfg(not(test))]
+pub const SPURIOUS_INTERRUPT: u8 = 0xff;
+
+/// The maximum number of processors. The APIC ID of every processor must be
+/// less than this.
+pub const MAX_CPUS: usize = 64;
+
+#[cfg(not(test))]
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile((phy_to_virt(APIC_BASE) + register) as *const u32)
+}
+
+#[cfg(not(test))]
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile((phy_to_virt(APIC_BASE) + register) as *mut u32,
+                        value);
+}
+
+/// Return the APIC ID of the processor that is running this code.
+#[cfg(not(test))]
+pub fn id() -> usize {
+    let ebx: u32;
+    // CPUID clobbers rbx, which LLVM may use internally, so we save it to
+    // rsi first.
+    unsafe {
+        asm!("mov %rbx, %rsi
+              cpuid
+              xchg %rsi, %rbx"
+              : "={esi}"(ebx)
+              : "{eax}"(1)
+              : "ecx", "edx"
+              : "volatile");
+    }
+    let id = (ebx >> 24) as usize;
+    assert!(id < MAX_CPUS);
+    id
+}
+
+/// Return the APIC ID of the processor. There is only one processor in
+/// test.
+#[cfg(test)]
+pub fn id() -> usize {
+    0
+}
+
+/// Acknowledge the interrupt that is being handled.
+#[cfg(not(test))]
+pub fn eoi() {
+    unsafe {
+        write(EOI, 0);
+    }
+}
+
+/// Send the interrupt `vector` to the processor whose APIC ID is `id`.
+#[cfg(not(test))]
+pub fn send_ipi(id: usize, vector: u8) {
+    unsafe {
+        write(ICR_HIGH, (id as u32) << 24);
+        // Fixed delivery mode, physical destination.
+        write(ICR_LOW, u32::from(vector));
+        // Wait until the interrupt is delivered.
+        while read(ICR_LOW) & (1 << 12) != 0 {}
+    }
+}
+
+/// Send the interrupt command `command` to all the processors except the
+/// one that is running this code.
+#[cfg(not(test))]
+fn broadcast(command: u32) {
+    unsafe {
+        write(ICR_HIGH, 0);
+        // The all-excluding-self destination shorthand.
+        write(ICR_LOW, (0b11 << 18) | command);
+        while read(ICR_LOW) & (1 << 12) != 0 {}
+    }
+}
+
+/// Send an INIT interrupt to all the other processors, which resets them
+/// and makes them wait for a startup interrupt.
+#[cfg(not(test))]
+pub fn broadcast_init() {
+    // INIT delivery mode, level assert.
+    broadcast((1 << 14) | (0b101 << 8));
+}
+
+/// Send a startup interrupt to all the other processors, which makes them
+/// start executing in real mode at physical address `addr`. The address
+/// must be page aligned and below 1MB.
+#[cfg(not(test))]
+pub fn broadcast_startup(addr: usize) {
+    paging::assert_align(addr);
+    assert!(addr < 0x10_0000);
+    // Startup delivery mode, level assert. The vector is the page number of
+    // the address.
+    broadcast((1 << 14) | (0b110 << 8) | (addr >> 12) as u32);
+}
+
+/// Enable the local APIC of the processor that is running this code.
+#[cfg(not(test))]
+pub fn enable() {
+    unsafe {
+        // The local APIC is software-disabled after reset, so it can't
+        // receive any interrupt from other processors until we set bit 8.
+        write(SPURIOUS_VECTOR, (1 << 8) | u32::from(SPURIOUS_INTERRUPT));
+    }
+}
+
+/// Initialization function for local APIC module. This must be called after
+/// the paging module is initialized.
+#[cfg(not(test))]
+pub fn init() {
+    paging::map_device(APIC_BASE);
+    enable();
+    ioapic::init();
+}
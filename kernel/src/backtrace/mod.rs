@@ -0,0 +1,214 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Stack backtraces. The kernel is compiled with frame pointers, so the
+//! return addresses of the callers can be found by following the frame
+//! pointers on the stack.
+
+mod symbols;
+
+use core::fmt;
+#[cfg(not(test))]
+use ::config::{KERNEL_STACK_END, KERNEL_VIRT_BASE};
+#[cfg(not(test))]
+use ::percpu;
+use self::symbols::Demangle;
+
+/// The number of return addresses recorded in a [Site](Site).
+const SITE_DEPTH: usize = 8;
+/// The maximum number of return addresses printed by [print](print).
+#[cfg(not(test))]
+const MAX_DEPTH: usize = 32;
+
+/// Return the frame pointer of the function that calls this function.
+#[cfg(not(test))]
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let frame: usize;
+    unsafe {
+        asm!("mov %rbp, $0" : "=r"(frame) ::: "volatile");
+    }
+    frame
+}
+
+/// Fill `addrs` with the return addresses found by following the frame
+/// pointers from `frame`, which must be in the current stack. Return the
+/// number of the addresses found.
+#[cfg(not(test))]
+#[inline(always)]
+fn walk(mut frame: usize, addrs: &mut [usize]) -> usize {
+    let rsp: usize;
+    unsafe {
+        asm!("mov %rsp, $0" : "=r"(rsp) ::: "volatile");
+    }
+    // The per-CPU data is not set up yet early in the boot.
+    let stack_end = match percpu::try_current() {
+        Some(percpu) => percpu.stack_end,
+        None => KERNEL_VIRT_BASE + KERNEL_STACK_END,
+    };
+    let mut low = rsp;
+    let mut depth = 0;
+    for addr in addrs.iter_mut() {
+        // Each frame starts with the frame pointer of its caller, which is
+        // followed by the return address. The frames of the callers are
+        // always higher in the stack.
+        if frame < low || frame + 16 > stack_end || frame & 7 != 0 {
+            break;
+        }
+        unsafe {
+            *addr = *((frame + 8) as *const usize);
+            low = frame + 16;
+            frame = *(frame as *const usize);
+        }
+        depth += 1;
+    }
+    depth
+}
+
+/// Fill `addrs` with the return addresses of the callers of this function,
+/// starting from the innermost one. Return the number of the addresses
+/// found.
+#[cfg(not(test))]
+#[inline(never)]
+pub fn return_addresses(addrs: &mut [usize]) -> usize {
+    walk(frame_pointer(), addrs)
+}
+
+// In test, we don't know how the host lays out the stack.
+#[cfg(test)]
+pub fn return_addresses(_addrs: &mut [usize]) -> usize {
+    0
+}
+
+/// An address in the kernel code, which is displayed with the name of the
+/// function that contains it.
+#[derive(Copy, Clone)]
+struct Code {
+    addr: usize,
+    // The address that is looked up in the symbol table.
+    lookup: usize,
+}
+
+impl Code {
+    /// Return the [Code](Code) of the instruction at `addr`.
+    #[cfg_attr(test, allow(dead_code))]
+    fn at(addr: usize) -> Code {
+        Code { addr, lookup: addr }
+    }
+
+    /// Return the [Code](Code) of the return address `addr`. The call may
+    /// be the last instruction of the caller, so the caller is found from
+    /// the address right before it.
+    fn returning_to(addr: usize) -> Code {
+        Code { addr, lookup: addr.wrapping_sub(1) }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.addr)?;
+        if let Some(symbol) = symbols::lookup(symbols::table(), self.lookup) {
+            let offset = symbol.offset + self.addr.wrapping_sub(self.lookup);
+            write!(f, " <{}+{:#x}>", Demangle(symbol.name), offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the backtrace of the code at `rip`, whose frame pointer is
+/// `frame`, on the screen. This is used for the exceptions, where the
+/// interrupted code is not a caller of the handler.
+#[cfg(not(test))]
+pub fn print_from(rip: usize, frame: usize) {
+    let mut addrs = [0; MAX_DEPTH];
+    let depth = walk(frame, &mut addrs);
+    println!("Backtrace:");
+    println!("  {}", Code::at(rip));
+    for addr in addrs[..depth].iter() {
+        println!("  {}", Code::returning_to(*addr));
+    }
+}
+
+/// Print the return addresses of the callers on the screen.
+#[cfg(not(test))]
+pub fn print() {
+    let mut addrs = [0; MAX_DEPTH];
+    let depth = return_addresses(&mut addrs);
+    println!("Backtrace:");
+    for addr in addrs[..depth].iter() {
+        println!("  {}", Code::returning_to(*addr));
+    }
+}
+
+/// The return addresses of the functions that called a function, starting
+/// from the innermost one. The unknown addresses are 0.
+#[derive(Copy, Clone, Default)]
+pub struct Site([usize; SITE_DEPTH]);
+
+impl Site {
+    /// Return the site of the function that calls this function.
+    #[inline(always)]
+    pub fn here() -> Site {
+        let mut site = [0; SITE_DEPTH];
+        return_addresses(&mut site);
+        Site(site)
+    }
+
+    /// Return the site of the caller of the function whose frame pointer
+    /// is `frame`, which must be in the current stack. The functions called
+    /// by the caller are not in the site.
+    #[cfg(not(test))]
+    #[inline(always)]
+    pub fn from_frame(frame: usize) -> Site {
+        let mut site = [0; SITE_DEPTH];
+        walk(frame, &mut site);
+        Site(site)
+    }
+
+    #[cfg(test)]
+    pub fn from_frame(_frame: usize) -> Site {
+        Site::default()
+    }
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0[0] == 0 {
+            return write!(f, "<unknown>");
+        }
+        for (index, addr) in self.0.iter().take_while(|&&a| a != 0)
+            .enumerate()
+        {
+            if index > 0 {
+                write!(f, " <- ")?;
+            }
+            write!(f, "{}", Code::returning_to(*addr))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_site() {
+        let site = Site([0x10, 0x20, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(format!("{}", site), "0x10 <- 0x20");
+        assert_eq!(format!("{}", Site::default()), "<unknown>");
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Kernel symbols. After the kernel is linked, the build writes the
+//! function symbols of the kernel ELF to the `.symbols` section of the
+//! image. Each line of the table is an address in hexadecimal and a mangled
+//! name separated by a space, and the lines are sorted by address.
+
+use core::char;
+use core::fmt;
+use core::str;
+#[cfg(not(test))]
+use core::slice;
+
+/// The function that contains an address.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Symbol<'a> {
+    /// The mangled name of the function.
+    pub name: &'a str,
+    /// The offset of the address from the start of the function.
+    pub offset: usize,
+}
+
+/// Return the symbol table embedded in the kernel image.
+#[cfg(not(test))]
+pub fn table() -> &'static [u8] {
+    extern "C" {
+        static symbols_start: u8;
+        static symbols_end: u8;
+    }
+    let table = unsafe {
+        let start = &symbols_start as *const u8;
+        let len = &symbols_end as *const u8 as usize - start as usize;
+        slice::from_raw_parts(start, len)
+    };
+    // The rest of the section is filled with zeroes.
+    let len = table.iter().position(|&b| b == 0)
+        .unwrap_or_else(|| table.len());
+    &table[..len]
+}
+
+/// There is no symbol table in test.
+#[cfg(test)]
+pub fn table() -> &'static [u8] {
+    &[]
+}
+
+/// Parse a line of the symbol table into the address and the name.
+fn parse_line(line: &[u8]) -> Option<(usize, &str)> {
+    let space = line.iter().position(|&b| b == b' ')?;
+    let addr = str::from_utf8(&line[..space]).ok()?;
+    let addr = usize::from_str_radix(addr, 16).ok()?;
+    let name = str::from_utf8(&line[space + 1..]).ok()?;
+    Some((addr, name))
+}
+
+/// Find the function that contains `addr` in the symbol table `table`.
+/// The sizes of the functions are not recorded, so the function is the
+/// last one that starts at or before `addr`.
+pub fn lookup(table: &[u8], addr: usize) -> Option<Symbol> {
+    let mut symbol = None;
+    for line in table.split(|&b| b == b'\n') {
+        match parse_line(line) {
+            Some((start, _)) if start > addr => break,
+            Some((start, name)) => symbol = Some(Symbol {
+                name,
+                offset: addr - start,
+            }),
+            None => (),
+        }
+    }
+    symbol
+}
+
+/// Return the character that `escape` stands for in a mangled name.
+fn unescape(escape: &str) -> Option<char> {
+    match escape {
+        "SP" => Some('@'),
+        "BP" => Some('*'),
+        "RF" => Some('&'),
+        "LT" => Some('<'),
+        "GT" => Some('>'),
+        "LP" => Some('('),
+        "RP" => Some(')'),
+        "C" => Some(','),
+        _ if escape.starts_with('u') => {
+            u32::from_str_radix(&escape[1..], 16).ok()
+                .and_then(char::from_u32)
+        },
+        _ => None,
+    }
+}
+
+/// Split the first length-prefixed component of a mangled name from the
+/// rest of it.
+fn split_component(name: &str) -> Option<(&str, &str)> {
+    let digits = name.find(|c: char| !c.is_ascii_digit())?;
+    let len: usize = name[..digits].parse().ok()?;
+    let rest = &name[digits..];
+    if len > rest.len() || !rest.is_char_boundary(len) {
+        return None;
+    }
+    Some((&rest[..len], &rest[len..]))
+}
+
+/// Return true if `component` is the hash at the end of a mangled name.
+fn is_hash(component: &str) -> bool {
+    component.len() == 17 && component.starts_with('h')
+        && component[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Write the component of a mangled name with its escapes replaced.
+fn write_component(f: &mut fmt::Formatter, mut component: &str)
+    -> fmt::Result
+{
+    // An underscore is added in front of a component that starts with an
+    // escape.
+    if component.starts_with("_$") {
+        component = &component[1..];
+    }
+    while !component.is_empty() {
+        if component.starts_with("..") {
+            f.write_str("::")?;
+            component = &component[2..];
+            continue;
+        }
+        if component.starts_with('$') {
+            if let Some(end) = component[1..].find('$') {
+                if let Some(c) = unescape(&component[1..=end]) {
+                    write!(f, "{}", c)?;
+                    component = &component[end + 2..];
+                    continue;
+                }
+            }
+        }
+        // Write everything up to the next escape or the next dot.
+        let end = component[1..].find(|c| c == '$' || c == '.')
+            .map(|end| end + 1)
+            .unwrap_or_else(|| component.len());
+        f.write_str(&component[..end])?;
+        component = &component[end..];
+    }
+    Ok(())
+}
+
+/// A symbol name that is demangled when it's displayed. Only the legacy
+/// mangling of Rust is understood, and the other names are displayed as
+/// they are.
+pub struct Demangle<'a>(pub &'a str);
+
+impl<'a> Demangle<'a> {
+    /// Return the components of the name, or None if the name is not
+    /// mangled.
+    fn components(&self) -> Option<&'a str> {
+        // LLVM adds a suffix to the names of some local functions.
+        let name = match self.0.find(".llvm.") {
+            Some(end) => &self.0[..end],
+            None => self.0,
+        };
+        if !name.starts_with("_ZN") || !name.ends_with('E') {
+            return None;
+        }
+        let components = &name[3..name.len() - 1];
+        let mut rest = components;
+        while !rest.is_empty() {
+            rest = split_component(rest)?.1;
+        }
+        Some(components)
+    }
+}
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = match self.components() {
+            Some(components) => components,
+            None => return f.write_str(self.0),
+        };
+        let mut first = true;
+        while let Some((component, next)) = split_component(rest) {
+            if next.is_empty() && is_hash(component) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            write_component(f, component)?;
+            first = false;
+            rest = next;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &[u8] = b"0000000000001000 _start\n\
+                           0000000000001040 memcpy\n\
+                           00000000000010a0 rust_begin_unwind\n";
+
+    #[test]
+    fn lookup_address() {
+        assert_eq!(lookup(TABLE, 0xfff), None);
+        assert_eq!(lookup(TABLE, 0x1000), Some(Symbol {
+            name: "_start",
+            offset: 0,
+        }));
+        assert_eq!(lookup(TABLE, 0x1050), Some(Symbol {
+            name: "memcpy",
+            offset: 0x10,
+        }));
+        assert_eq!(lookup(TABLE, 0x2000), Some(Symbol {
+            name: "rust_begin_unwind",
+            offset: 0xf60,
+        }));
+        assert_eq!(lookup(b"", 0x1000), None);
+    }
+
+    #[test]
+    fn demangle() {
+        let demangle = |name| format!("{}", Demangle(name));
+        assert_eq!(demangle("_ZN6kernel5panic17h0123456789abcdefE"),
+                   "kernel::panic");
+        assert_eq!(demangle("_ZN6kernel2vm5fault18page_fault_handler\
+                             17h0123456789abcdefE"),
+                   "kernel::vm::fault::page_fault_handler");
+        assert_eq!(demangle("_ZN50_$LT$$RF$mut$u20$W$u20$as$u20$core..fmt..\
+                             Write$GT$10write_char17h0349ed6a09ad53e6E"),
+                   "<&mut W as core::fmt::Write>::write_char");
+        assert_eq!(demangle("_ZN4core3ptr18real_drop_in_place\
+                             17hd5122946103c1b8cE"),
+                   "core::ptr::real_drop_in_place");
+        assert_eq!(demangle("_ZN6kernel9interrupt9exception12divide_error\
+                             17h2334c591b43475c8E.llvm.320382760187549449"),
+                   "kernel::interrupt::exception::divide_error");
+        // The names that are not mangled by Rust are left as they are.
+        assert_eq!(demangle("memcpy"), "memcpy");
+        assert_eq!(demangle("_ZN6kernel99panicE"), "_ZN6kernel99panicE");
+    }
+}
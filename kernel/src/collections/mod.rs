@@ -17,11 +17,18 @@
 //! Collections module. This module contains all the collections that are
 //! used throughout the kernel.
 
+mod ring_buffer;
 mod static_intvlist;
+// The kernel doesn't use these collections anymore, but they are kept for
+// the code that can't allocate memory from the heap.
+#[cfg_attr(not(test), allow(dead_code))]
 mod static_list;
+#[cfg_attr(not(test), allow(dead_code))]
 mod static_map;
+#[cfg_attr(not(test), allow(dead_code))]
 mod static_stack;
 
+pub use self::ring_buffer::*;
 pub use self::static_intvlist::*;
 pub use self::static_list::*;
 pub use self::static_map::*;
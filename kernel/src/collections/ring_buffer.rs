@@ -0,0 +1,135 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+/// The static size of the ring buffer.
+#[cfg(not(test))]
+const RING_SIZE: usize = 0x0000_1000;
+#[cfg(test)]
+const RING_SIZE: usize = 6;
+
+/// A first-in first-out queue of bytes with a fixed capacity. It doesn't
+/// allocate memory, so it can be used in statics and by interrupt handlers.
+pub struct RingBuffer {
+    // The buffer to store all bytes in the queue.
+    buf: [u8; RING_SIZE],
+    // The index of the oldest byte.
+    head: usize,
+    // The number of bytes in the queue.
+    len: usize,
+}
+
+impl RingBuffer {
+    /// Create an empty [RingBuffer](self::RingBuffer).
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            buf: [0; RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Return the number of bytes in the queue.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return true if there is no byte in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return true if no more byte can be pushed.
+    pub fn is_full(&self) -> bool {
+        self.len == RING_SIZE
+    }
+
+    /// Push a byte at the end of the queue.
+    pub fn push(&mut self, byte: u8) -> Result<(), ()> {
+        // If the queue is already full, return error.
+        if self.is_full() {
+            return Err(());
+        }
+        self.buf[(self.head + self.len) % RING_SIZE] = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Pop the oldest byte from the queue.
+    pub fn pop(&mut self) -> Result<u8, ()> {
+        if self.is_empty() {
+            return Err(());
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % RING_SIZE;
+        self.len -= 1;
+        Ok(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn return_bytes_in_order() {
+        let mut ring = RingBuffer::new();
+        assert!(ring.push(10).is_ok());
+        assert!(ring.push(20).is_ok());
+        assert!(ring.push(30).is_ok());
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.pop().unwrap(), 10);
+        assert_eq!(ring.pop().unwrap(), 20);
+        assert_eq!(ring.pop().unwrap(), 30);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut ring = RingBuffer::new();
+        for round in 0..5 {
+            for i in 0..4 {
+                assert!(ring.push(round * 4 + i).is_ok());
+            }
+            for i in 0..4 {
+                assert_eq!(ring.pop().unwrap(), round * 4 + i);
+            }
+        }
+        assert_eq!(ring.len(), 0);
+    }
+
+    #[test]
+    fn push_when_full() {
+        let mut ring = RingBuffer::new();
+        for i in 0..6 {
+            assert!(ring.push(i).is_ok());
+        }
+        assert!(ring.is_full());
+        // This one should return error, because the queue is full already.
+        assert!(ring.push(6).is_err());
+        assert_eq!(ring.pop().unwrap(), 0);
+        assert!(ring.push(6).is_ok());
+    }
+
+    #[test]
+    fn pop_when_empty() {
+        let mut ring = RingBuffer::new();
+        assert!(ring.pop().is_err());
+        assert!(ring.push(1).is_ok());
+        assert_eq!(ring.pop().unwrap(), 1);
+        assert!(ring.pop().is_err());
+    }
+}
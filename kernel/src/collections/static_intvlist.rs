@@ -45,6 +45,16 @@ impl Interval {
         }
     }
 
+    /// Return the start of the interval.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Return the length of the interval.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
     /// Create [Interval](Interval) from a slice. For example, `0x1-0x3`.
     pub fn from(bytes: &[u8]) -> Result<Interval, ()> {
         let mut start = None;
@@ -222,6 +232,39 @@ impl StaticIntvlist {
         true
     }
 
+    /// Return a list of the ranges that are covered by this list, but not
+    /// covered by the other list.
+    pub fn difference(&self, other: &StaticIntvlist)
+        -> Result<StaticIntvlist, Error>
+    {
+        let mut result = StaticIntvlist::new();
+        let mut others = *other;
+        others.sort();
+
+        for item in self.iter() {
+            // This is the start of the range of item that is not cut yet.
+            let mut start = item.start;
+            let end = item.start + item.length;
+            for cut in others.iter() {
+                let cut_end = cut.start + cut.length;
+                if cut_end <= start || cut.start >= end {
+                    continue;
+                }
+                if cut.start > start {
+                    result.push(Interval::new(start, cut.start - start))?;
+                }
+                start = cut_end;
+                if start >= end {
+                    break;
+                }
+            }
+            if start < end {
+                result.push(Interval::new(start, end - start))?;
+            }
+        }
+        Ok(result)
+    }
+
     /// Sort all intervals in the list.
     pub fn sort(&mut self) {
         self.list[..self.len].sort_by(|a, b| a.unwrap().cmp(&b.unwrap()));
@@ -432,4 +475,21 @@ Some(Interval { start: 3, length: 1 }), Some(Interval { start: 5, length: 1 \
 
         assert!(!list1.is_covered_by(&list2));
     }
+
+    #[test]
+    fn correct_difference() {
+        let list1 = StaticIntvlist::from(b"0x0-0x10,0x20-0x30").unwrap();
+        let list2 = StaticIntvlist::from(b"0x28-0x40,0x4-0x8").unwrap();
+        let expected = StaticIntvlist::from(b"0x0-0x4,0x8-0x10,0x20-0x28")
+            .unwrap();
+        assert_eq!(list1.difference(&list2).unwrap(), expected);
+    }
+
+    #[test]
+    fn difference_cut_everything() {
+        let list1 = StaticIntvlist::from(b"0x4-0x8,0x10-0x20").unwrap();
+        let list2 = StaticIntvlist::from(b"0x0-0x8,0x10-0x18,0x18-0x20")
+            .unwrap();
+        assert_eq!(list1.difference(&list2).unwrap().len(), 0);
+    }
 }
@@ -16,93 +16,80 @@
 #![allow(dead_code)]
 
 //! Debugging module. This module is used for debugging purpose only.
-//! You can print any string on the screen using this module.
+//! You can print any string on the screen and the serial port using this
+//! module.
 
-use core::fmt;
+use core::fmt::{self, Write};
+use ::serial;
+use ::sync::IrqSpinlock;
+use ::vga::Screen;
 
-/// The maximum number of columns on the screen.
-const NUM_COLUMNS: usize = 80;
-/// The maximum number of lines on the screen.
-const NUM_LINES: usize = 25;
+pub use ::vga::Color;
 
-/// The structure containing details of the screen.
-struct Screen {
-    x: usize,
-    y: usize,
+/// The console sends everything printed to all the output devices.
+struct Console {
+    screen: Screen,
 }
 
-#[derive(Copy, Clone)]
-pub enum Color {
-    Black, Blue, Green, Cyan, Red, Purple, Brown, Gray,
-    DarkGray, LightBlue, LightGreen, LightCyan, LightRed, LightPurple,
-    Yellow, White
-}
-
-const DEFAULT_COLOR: Color = Color::LightCyan;
-static mut FOREGROUND_COLOR: Color = DEFAULT_COLOR;
-
-static mut SCREEN: Screen = Screen {
-    x: 0,
-    y: 0,
-};
-
-impl Screen {
-    /// Print one character.
-    fn putc(&mut self, byte: u8) {
-        if byte == b'\n' {
-            self.y += 1;
-            self.x = 0;
-            if self.y == NUM_LINES {
-                self.y = 0;
-            }
-            return;
-        }
-        unsafe {
-            let position = self.y * NUM_COLUMNS + self.x;
-            let vga_buffer = 0xb8000 as *mut u8;
-            let color_byte: u8 = FOREGROUND_COLOR as u8;
-
-            *vga_buffer.offset(position as isize * 2) = byte;
-            *vga_buffer.offset(position as isize * 2 + 1) = color_byte;
-
-            self.x += 1;
-            if self.x == NUM_COLUMNS {
-                self.x = 0;
-                self.y += 1;
-            }
-            if self.y == NUM_LINES {
-                self.y = 0;
+/// The console is used by interrupt handlers too, so the interrupts are
+/// disabled while it's locked.
+static CONSOLE: IrqSpinlock<Console> = IrqSpinlock::new(Console {
+    screen: Screen::new(),
+});
+
+impl Console {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.screen.write_bytes(bytes);
+        // Terminals expect a carriage return before each line feed.
+        for (i, line) in bytes.split(|byte| *byte == b'\n').enumerate() {
+            if i > 0 {
+                serial::write(b"\r\n");
             }
+            serial::write(line);
         }
     }
 }
 
-impl fmt::Write for Screen {
+impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        for c in s.bytes() {
-            self.putc(c);
-        }
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
 
 pub fn write(args: fmt::Arguments) -> Result<(), fmt::Error> {
-    unsafe {
-        fmt::write(&mut SCREEN, args)?;
-    }
-    Ok(())
+    fmt::write(&mut *CONSOLE.lock(), args)
+}
+
+/// Print `bytes`, which may not be valid UTF-8.
+pub fn write_bytes(bytes: &[u8]) {
+    CONSOLE.lock().write_bytes(bytes);
 }
 
+/// Set the color of the text printed after this. The escape sequence is
+/// understood by both the screen and the terminal on the other side of the
+/// serial port.
 pub fn set_color(color: Color) {
-    unsafe {
-        FOREGROUND_COLOR = color;
-    }
+    write!(CONSOLE.lock(), "\x1b[{}m", color.ansi_code()).unwrap();
 }
 
+/// Set the color of the text back to the default one.
 pub fn reset_color() {
-    unsafe {
-        FOREGROUND_COLOR = DEFAULT_COLOR;
-    }
+    CONSOLE.lock().write_str("\x1b[0m").unwrap();
+}
+
+/// Wait until everything printed is sent to all the output devices. This
+/// is used before the kernel stops.
+pub fn flush() {
+    let _console = CONSOLE.lock();
+    serial::flush();
+}
+
+/// Release the locks of the console, which may be held by the code that
+/// panics, so that the panic message can be printed.
+pub unsafe fn force_unlock() {
+    CONSOLE.force_unlock();
+    serial::force_unlock();
 }
 
 #[macro_export]
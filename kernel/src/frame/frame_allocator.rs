@@ -0,0 +1,242 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+use alloc::collections::btree_map::BTreeMap;
+use core::ptr;
+use ::collections::StaticIntvlist;
+use ::config::PAGE_SIZE;
+use ::paging::phy_to_virt;
+
+/// A structure that allocates physical frames. Frames are carved from the
+/// free memory in order. Freed frames are kept in a list threaded through
+/// the frames themselves, so the allocator doesn't need any memory to keep
+/// track of them.
+pub struct FrameAllocator {
+    // The free memory that the frames are carved from.
+    intervals: StaticIntvlist,
+    // The index of the interval in `intervals` that we are carving from.
+    index: usize,
+    // The next frame in the current interval that has never been allocated.
+    next_frame: usize,
+    // The physical address of the first freed frame. Each freed frame stores
+    // the physical address of the next one in its first eight bytes.
+    free_list: Option<usize>,
+    // The number of references to each frame that is shared by more than
+    // one mapping. Other allocated frames have exactly one reference.
+    shared: BTreeMap<usize, usize>,
+    // True if an allocation has failed since the last call to
+    // `take_exhausted`.
+    exhausted: bool,
+}
+
+impl FrameAllocator {
+    /// Create a [FrameAllocator](FrameAllocator) that allocates frames from
+    /// `intervals`. The frames must be accessible through the physmap.
+    pub fn new(intervals: StaticIntvlist) -> FrameAllocator {
+        let next_frame = intervals.iter().next()
+            .map_or(0, |interval| round_up(interval.start()));
+        FrameAllocator {
+            intervals,
+            index: 0,
+            next_frame,
+            free_list: None,
+            shared: BTreeMap::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Allocate a frame. Return the physical address of the frame.
+    pub fn alloc(&mut self) -> Result<usize, ()> {
+        if let Some(frame) = self.free_list {
+            self.free_list = unsafe {
+                match *(phy_to_virt(frame) as *const usize) {
+                    0 => None,
+                    next => Some(next),
+                }
+            };
+            return Ok(frame);
+        }
+
+        // Find an interval that still has a whole frame left.
+        while let Some(interval) = self.intervals.iter().nth(self.index) {
+            let end = interval.start() + interval.length();
+            if self.next_frame + PAGE_SIZE <= end {
+                let frame = self.next_frame;
+                self.next_frame += PAGE_SIZE;
+                return Ok(frame);
+            }
+            self.index += 1;
+            if let Some(next) = self.intervals.iter().nth(self.index) {
+                self.next_frame = round_up(next.start());
+            }
+        }
+        self.exhausted = true;
+        Err(())
+    }
+
+    /// Return true if an allocation has failed since the last call.
+    pub fn take_exhausted(&mut self) -> bool {
+        let exhausted = self.exhausted;
+        self.exhausted = false;
+        exhausted
+    }
+
+    /// Allocate a frame filled with zeroes.
+    pub fn alloc_zeroed(&mut self) -> Result<usize, ()> {
+        let frame = self.alloc()?;
+        unsafe {
+            ptr::write_bytes(phy_to_virt(frame) as *mut u8, 0, PAGE_SIZE);
+        }
+        Ok(frame)
+    }
+
+    /// Add a reference to the allocated frame `frame`, so that it is not
+    /// freed until [free](FrameAllocator::free) is called one more time.
+    pub fn share(&mut self, frame: usize) {
+        *self.shared.entry(frame).or_insert(1) += 1;
+    }
+
+    /// Return the number of references to the allocated frame `frame`.
+    pub fn refcount(&self, frame: usize) -> usize {
+        self.shared.get(&frame).cloned().unwrap_or(1)
+    }
+
+    /// Drop a reference to the allocated frame `frame`. The frame is freed
+    /// when there is no reference left.
+    pub fn free(&mut self, frame: usize) {
+        if let Some(count) = self.shared.get_mut(&frame) {
+            *count -= 1;
+            if *count > 1 {
+                return;
+            }
+        }
+        if self.shared.get(&frame) == Some(&1) {
+            self.shared.remove(&frame);
+            return;
+        }
+
+        // Zero means that there is no next frame. Frame 0 is never
+        // allocated because it's in the low memory.
+        unsafe {
+            *(phy_to_virt(frame) as *mut usize) = self.free_list.unwrap_or(0);
+        }
+        self.free_list = Some(frame);
+    }
+}
+
+/// Round the address up to the next frame boundary.
+fn round_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Create a [FrameAllocator](FrameAllocator) with `count` frames allocated
+/// from the host, since we can't use the physical memory in test.
+#[cfg(test)]
+pub fn new_for_test(count: usize) -> FrameAllocator {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use ::collections::Interval;
+
+    #[repr(align(4096))]
+    struct Frame([u8; PAGE_SIZE]);
+
+    let frames: Vec<Frame> = (0..count).map(|_| Frame([0xff; PAGE_SIZE]))
+        .collect();
+    let frames = Box::leak(frames.into_boxed_slice());
+    let mut intervals = StaticIntvlist::new();
+    intervals.push(Interval::new(frames.as_ptr() as usize,
+                                 count * PAGE_SIZE)).unwrap();
+    FrameAllocator::new(intervals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::collections::Interval;
+
+    #[test]
+    fn allocate_all_frames() {
+        let mut allocator = new_for_test(3);
+        let frame1 = allocator.alloc().unwrap();
+        let frame2 = allocator.alloc().unwrap();
+        let frame3 = allocator.alloc().unwrap();
+        assert_eq!(frame2, frame1 + PAGE_SIZE);
+        assert_eq!(frame3, frame2 + PAGE_SIZE);
+        assert!(allocator.alloc().is_err());
+    }
+
+    #[test]
+    fn allocate_from_many_intervals() {
+        let mut intervals = StaticIntvlist::new();
+        // We never touch the memory of the frames unless they are freed.
+        intervals.push(Interval::new(0x1800, 0x1000)).unwrap();
+        intervals.push(Interval::new(0x5000, 0x2800)).unwrap();
+        let mut allocator = FrameAllocator::new(intervals);
+        assert_eq!(allocator.alloc().unwrap(), 0x5000);
+        assert_eq!(allocator.alloc().unwrap(), 0x6000);
+        assert!(allocator.alloc().is_err());
+    }
+
+    #[test]
+    fn reuse_freed_frames() {
+        let mut allocator = new_for_test(2);
+        let frame1 = allocator.alloc().unwrap();
+        let frame2 = allocator.alloc().unwrap();
+        allocator.free(frame1);
+        allocator.free(frame2);
+        assert_eq!(allocator.alloc().unwrap(), frame2);
+        assert_eq!(allocator.alloc().unwrap(), frame1);
+        assert!(allocator.alloc().is_err());
+    }
+
+    #[test]
+    fn allocate_zeroed_frame() {
+        let mut allocator = new_for_test(1);
+        let frame = allocator.alloc_zeroed().unwrap();
+        let bytes = unsafe {
+            ::core::slice::from_raw_parts(frame as *const u8, PAGE_SIZE)
+        };
+        assert!(bytes.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn free_shared_frame() {
+        let mut allocator = new_for_test(1);
+        let frame = allocator.alloc().unwrap();
+        assert_eq!(allocator.refcount(frame), 1);
+        allocator.share(frame);
+        allocator.share(frame);
+        assert_eq!(allocator.refcount(frame), 3);
+        allocator.free(frame);
+        allocator.free(frame);
+        assert_eq!(allocator.refcount(frame), 1);
+        assert!(allocator.alloc().is_err());
+        allocator.free(frame);
+        assert_eq!(allocator.alloc().unwrap(), frame);
+    }
+
+    #[test]
+    fn remember_exhaustion() {
+        let mut allocator = new_for_test(1);
+        let frame = allocator.alloc().unwrap();
+        assert!(!allocator.take_exhausted());
+        assert!(allocator.alloc().is_err());
+        allocator.free(frame);
+        assert!(allocator.alloc().is_ok());
+        assert!(allocator.take_exhausted());
+        assert!(!allocator.take_exhausted());
+    }
+}
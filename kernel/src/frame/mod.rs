@@ -0,0 +1,88 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Frame allocation module. This module manages physical frames that are not
+//! used by the kernel itself, e.g. frames for user memory.
+
+mod frame_allocator;
+
+pub use self::frame_allocator::FrameAllocator;
+#[cfg(test)]
+pub use self::frame_allocator::new_for_test;
+
+#[cfg(not(test))]
+use ::collections::StaticIntvlist;
+#[cfg(not(test))]
+use ::config::{LOW_MEMORY, USED_KERNEL_MEMORY};
+#[cfg(not(test))]
+use ::layout::MemoryLayout;
+#[cfg(not(test))]
+use ::kalloc;
+#[cfg(not(test))]
+use ::sync::{Once, Spinlock};
+
+#[cfg(not(test))]
+static ALLOCATOR: Once<Spinlock<FrameAllocator>> = Once::new();
+
+/// Return the frame allocator of the system, which must be locked before
+/// it's used.
+#[cfg(not(test))]
+pub fn allocator() -> &'static Spinlock<FrameAllocator> {
+    // We have to make sure that we already initialized the frame module
+    // before allocating any frame.
+    ALLOCATOR.get().unwrap()
+}
+
+/// Call `f` with the frame allocator locked. If `f` fails after running out
+/// of frames, the unused frames of the kernel heap are taken back and `f`
+/// is called again, so it must be safe to call it twice.
+#[cfg(not(test))]
+pub fn with_allocator<F, T, E>(mut f: F) -> Result<T, E>
+    where F: FnMut(&mut FrameAllocator) -> Result<T, E>
+{
+    // The heap may need to grow while the frame allocator is locked.
+    kalloc::refill_spares();
+    let (result, exhausted) = {
+        let mut frames = allocator().lock();
+        frames.take_exhausted();
+        let result = f(&mut frames);
+        let exhausted = result.is_err() && frames.take_exhausted();
+        (result, exhausted)
+    };
+    // The frame allocator must not be locked while the heap is unmapping
+    // its pages, because the other processors may need to take it before
+    // they can answer the shootdown.
+    if exhausted && kalloc::reclaim() > 0 {
+        f(&mut allocator().lock())
+    } else {
+        result
+    }
+}
+
+/// Initialization function for the frame allocation module. This must be
+/// called after the physmap is set up because the allocator accesses the
+/// frames through it.
+#[cfg(not(test))]
+pub fn init() {
+    let free_memory_list = MemoryLayout::new().as_free_interval_list();
+    let used_memory_list = StaticIntvlist::from(USED_KERNEL_MEMORY).unwrap();
+    let low_memory_list = StaticIntvlist::from(LOW_MEMORY).unwrap();
+    let intervals = free_memory_list
+        .difference(&used_memory_list).unwrap()
+        .difference(&low_memory_list).unwrap();
+
+    ALLOCATOR.call_once(|| Spinlock::new(FrameAllocator::new(intervals)));
+}
@@ -0,0 +1,662 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A stub of the GDB remote serial protocol, so that GDB can debug the
+//! kernel through COM2 on any machine, not only in Qemu. The stub is only
+//! built in when Kelner is configured with `--enable-gdb-stub`.
+//!
+//! The stub runs when a processor hits a breakpoint or finishes a single
+//! step. It stops the other processors with an interrupt and talks to GDB
+//! until GDB resumes the kernel. There are no kernel threads yet, so GDB
+//! sees each processor as a thread whose ID is its APIC ID plus one.
+
+mod packet;
+mod registers;
+
+#[cfg(not(test))]
+use core::fmt::Write;
+#[cfg(not(test))]
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+#[cfg(not(test))]
+use ::apic::{self, MAX_CPUS};
+#[cfg(not(test))]
+use ::config::GDB_STUB;
+#[cfg(not(test))]
+use ::interrupt::{exception, InterruptStackFrame};
+#[cfg(not(test))]
+use ::paging::{self, phy_to_virt, PageSize};
+#[cfg(not(test))]
+use ::pit;
+#[cfg(not(test))]
+use ::process::Signal;
+#[cfg(not(test))]
+use ::serial::{Uart, COM2};
+#[cfg(not(test))]
+use ::smp;
+#[cfg(not(test))]
+use ::sync::Spinlock;
+#[cfg(not(test))]
+use self::packet::{decode_hex, parse_hex, Channel, Packet, PACKET_SIZE};
+#[cfg(not(test))]
+use self::registers::Registers;
+
+/// The interrupt vector used to stop the other processors.
+#[cfg(not(test))]
+pub const STOP_INTERRUPT: u8 = 0xfc;
+/// The vectors of the debug exception and the breakpoint exception.
+#[cfg(not(test))]
+const DEBUG: u64 = 1;
+#[cfg(not(test))]
+const BREAKPOINT: u64 = 3;
+
+/// The trap flag of rflags, which makes the processor raise a debug
+/// exception after it executes an instruction.
+#[cfg(not(test))]
+const TF: u64 = 1 << 8;
+/// The opcode of int3, which raises a breakpoint exception.
+#[cfg(not(test))]
+const INT3: u8 = 0xcc;
+/// The maximum number of breakpoints inserted at a time.
+#[cfg(not(test))]
+const MAX_BREAKPOINTS: usize = 32;
+/// The number of milliseconds to wait for the other processors to stop.
+#[cfg(not(test))]
+const STOP_TIMEOUT: usize = 100;
+
+// The entries of the debug exception, the breakpoint exception and the
+// interrupt that stops the processors. They save all the registers, so that
+// GDB can read and change them. The processor aligns the stack to 16 bytes
+// and pushes 5 quadwords before jumping here, so the stack is aligned again
+// after we push 17 quadwords.
+#[cfg(not(test))]
+global_asm!("
+    .macro gdb_entry name, vector
+    .global \\name
+    \\name:
+        push $0
+        push $\\vector
+        push %r15
+        push %r14
+        push %r13
+        push %r12
+        push %r11
+        push %r10
+        push %r9
+        push %r8
+        push %rbp
+        push %rdi
+        push %rsi
+        push %rdx
+        push %rcx
+        push %rbx
+        push %rax
+        mov %rsp, %rdi
+        cld
+        call gdb_trap
+        pop %rax
+        pop %rbx
+        pop %rcx
+        pop %rdx
+        pop %rsi
+        pop %rdi
+        pop %rbp
+        pop %r8
+        pop %r9
+        pop %r10
+        pop %r11
+        pop %r12
+        pop %r13
+        pop %r14
+        pop %r15
+        add $16, %rsp
+        iretq
+    .endm
+
+    gdb_entry gdb_debug_entry, 1
+    gdb_entry gdb_breakpoint_entry, 3
+    gdb_entry gdb_stop_entry, 0xfc
+");
+
+#[cfg(not(test))]
+extern "C" {
+    pub fn gdb_debug_entry();
+    pub fn gdb_breakpoint_entry();
+    pub fn gdb_stop_entry();
+}
+
+/// A breakpoint inserted by GDB, together with the byte that int3
+/// replaces.
+#[cfg(not(test))]
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: usize,
+    saved: u8,
+}
+
+/// The state of the stub, which is kept between the stops.
+#[cfg(not(test))]
+struct State {
+    // True if there is a UART at COM2.
+    present: bool,
+    // True if GDB is attached, so it waits for a stop reply when the kernel
+    // stops.
+    attached: bool,
+    // True if GDB wants the stub to move rip back to the breakpoint and
+    // report it with `swbreak`, instead of doing it itself.
+    swbreak: bool,
+    // True if the last stop is at a breakpoint inserted by GDB.
+    at_breakpoint: bool,
+    // True if a processor is single-stepping for GDB.
+    stepping: bool,
+    // The thread whose registers are read and written by GDB, or 0 for the
+    // thread that stops.
+    thread: usize,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+#[cfg(not(test))]
+struct Stub {
+    state: State,
+    input: Packet,
+    output: Packet,
+}
+
+/// The stub is locked by the processor that talks to GDB.
+#[cfg(not(test))]
+static STUB: Spinlock<Stub> = Spinlock::new(Stub {
+    state: State {
+        present: false,
+        attached: false,
+        swbreak: false,
+        at_breakpoint: false,
+        stepping: false,
+        thread: 0,
+        breakpoints: [None; MAX_BREAKPOINTS],
+    },
+    input: Packet::new(),
+    output: Packet::new(),
+});
+
+/// The addresses of the registers of the stopped processors, indexed by
+/// APIC ID. The running processors have 0.
+#[cfg(not(test))]
+static STOPPED: Spinlock<[usize; MAX_CPUS]> = Spinlock::new([0; MAX_CPUS]);
+
+/// The number of the times that the kernel stops and resumes. It's odd
+/// while a processor is talking to GDB.
+#[cfg(not(test))]
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(not(test))]
+impl Channel for Uart {
+    fn read(&mut self) -> u8 {
+        Uart::read(self)
+    }
+
+    fn write(&mut self, byte: u8) {
+        Uart::write(self, byte)
+    }
+}
+
+/// Return the address in the physmap of the byte at virtual address
+/// `addr`, using the page tables loaded on this processor, or None if the
+/// address is not mapped.
+#[cfg(not(test))]
+fn translate(addr: usize) -> Option<usize> {
+    // The address must be canonical.
+    if addr >> 47 != 0 && addr >> 47 != (1 << 17) - 1 {
+        return None;
+    }
+    let cr3: usize;
+    unsafe {
+        asm!("mov %cr3, $0" : "=r"(cr3) ::: "volatile");
+    }
+    let mut table = paging::entry_address(cr3 as u64);
+    for (level, &index) in paging::parse_addr(addr).iter().enumerate() {
+        let entry = unsafe {
+            *((phy_to_virt(table) + index * 8) as *const u64)
+        };
+        // The present bit.
+        if entry & 1 == 0 {
+            return None;
+        }
+        // The entries above the page tables may map large pages.
+        if level == 3 || (level > 0 && entry & (1 << 7) != 0) {
+            let size = PageSize::at_level(level).size();
+            let base = paging::entry_address(entry) & !(size - 1);
+            return Some(phy_to_virt(base + (addr & (size - 1))));
+        }
+        table = paging::entry_address(entry);
+    }
+    None
+}
+
+/// Return the byte at virtual address `addr`, if it's mapped.
+#[cfg(not(test))]
+fn read_byte(addr: usize) -> Option<u8> {
+    translate(addr).map(|addr| unsafe { *(addr as *const u8) })
+}
+
+/// Replace the byte at virtual address `addr` with `byte`. The byte is
+/// written through the physmap, so the code can be changed too.
+#[cfg(not(test))]
+fn write_byte(addr: usize, byte: u8) -> Result<(), ()> {
+    let addr = translate(addr).ok_or(())?;
+    unsafe {
+        *(addr as *mut u8) = byte;
+    }
+    Ok(())
+}
+
+/// Wait until the processor that talks to GDB resumes the kernel, if there
+/// is one. GDB can read and change `registers` in the meantime.
+#[cfg(not(test))]
+fn park(registers: &mut Registers) {
+    let generation = GENERATION.load(Ordering::SeqCst);
+    if generation % 2 == 0 {
+        return;
+    }
+    let id = apic::id();
+    STOPPED.lock()[id] = registers as *mut Registers as usize;
+    while GENERATION.load(Ordering::SeqCst) == generation {
+        spin_loop_hint();
+    }
+    STOPPED.lock()[id] = 0;
+}
+
+/// Return a bitmap of the APIC IDs of the stopped processors.
+#[cfg(not(test))]
+fn stopped() -> usize {
+    let stopped = STOPPED.lock();
+    (0..MAX_CPUS).filter(|&id| stopped[id] != 0)
+        .fold(0, |bitmap, id| bitmap | 1 << id)
+}
+
+/// Stop all the processors except this one.
+#[cfg(not(test))]
+fn stop_others() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    let others = smp::online() & !(1 << apic::id());
+    for id in 0..MAX_CPUS {
+        if others & (1 << id) != 0 {
+            apic::send_ipi(id, STOP_INTERRUPT);
+        }
+    }
+    // A processor may not stop while its interrupts are disabled, so we
+    // don't wait for it forever.
+    for _ in 0..STOP_TIMEOUT {
+        if stopped() == others {
+            break;
+        }
+        pit::delay(1000);
+    }
+}
+
+/// Resume the processors stopped by [stop_others](stop_others).
+#[cfg(not(test))]
+fn resume_others() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Parse the ID of a thread in a packet. Both -1, which means all the
+/// threads, and 0, which means any thread, are returned as 0.
+#[cfg(not(test))]
+fn parse_thread(hex: &[u8]) -> Result<usize, ()> {
+    if hex == b"-1" {
+        return Ok(0);
+    }
+    parse_hex(hex)
+}
+
+/// Parse the address and the length in `addr,length`.
+#[cfg(not(test))]
+fn parse_range(args: &[u8]) -> Result<(usize, usize), ()> {
+    let comma = args.iter().position(|&b| b == b',').ok_or(())?;
+    Ok((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+#[cfg(not(test))]
+impl State {
+    /// Return the registers of the thread `thread`, where `current` holds
+    /// the registers of the thread that stops.
+    fn registers<'a>(&self, thread: usize, current: &'a mut Registers)
+        -> Result<&'a mut Registers, ()>
+    {
+        if thread == 0 || thread == apic::id() + 1 {
+            return Ok(current);
+        }
+        match STOPPED.lock().get(thread - 1) {
+            Some(&addr) if addr != 0 => {
+                Ok(unsafe { &mut *(addr as *mut Registers) })
+            },
+            _ => Err(()),
+        }
+    }
+
+    fn find_breakpoint(&self, addr: usize) -> Option<usize> {
+        self.breakpoints.iter().position(|breakpoint| match *breakpoint {
+            Some(breakpoint) => breakpoint.addr == addr,
+            None => false,
+        })
+    }
+
+    fn insert_breakpoint(&mut self, addr: usize) -> Result<(), ()> {
+        if self.find_breakpoint(addr).is_some() {
+            return Ok(());
+        }
+        let index = self.breakpoints.iter().position(Option::is_none)
+            .ok_or(())?;
+        let saved = read_byte(addr).ok_or(())?;
+        write_byte(addr, INT3)?;
+        self.breakpoints[index] = Some(Breakpoint { addr, saved });
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: usize) -> Result<(), ()> {
+        let index = self.find_breakpoint(addr).ok_or(())?;
+        let breakpoint = self.breakpoints[index].take().unwrap();
+        write_byte(breakpoint.addr, breakpoint.saved)
+    }
+
+    /// Write the reply of `?`, which tells GDB why the kernel stops.
+    fn stop_reply(&self, output: &mut Packet) -> Result<(), ()> {
+        write!(output, "T05thread:{:x};", apic::id() + 1).map_err(|_| ())?;
+        if self.swbreak && self.at_breakpoint {
+            write!(output, "swbreak:;").map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Handle a query packet, whose data is `query`.
+    fn query(&mut self, query: &[u8], output: &mut Packet)
+        -> Result<(), ()>
+    {
+        if query.starts_with(b"qSupported") {
+            self.swbreak = query.windows(8).any(|w| w == b"swbreak+");
+            write!(output, "PacketSize={:x};swbreak+", PACKET_SIZE)
+                .map_err(|_| ())?;
+        } else if query == b"qAttached" {
+            output.push(b'1')?;
+        } else if query == b"qC" {
+            write!(output, "QC{:x}", apic::id() + 1).map_err(|_| ())?;
+        } else if query == b"qfThreadInfo" {
+            let threads = stopped() | 1 << apic::id();
+            output.push(b'm')?;
+            for id in (0..MAX_CPUS).filter(|&id| threads & (1 << id) != 0) {
+                if output.as_bytes().len() > 1 {
+                    output.push(b',')?;
+                }
+                write!(output, "{:x}", id + 1).map_err(|_| ())?;
+            }
+        } else if query == b"qsThreadInfo" {
+            output.push(b'l')?;
+        } else if query.starts_with(b"qThreadExtraInfo,") {
+            let id = match parse_thread(&query[17..])? {
+                0 => apic::id(),
+                thread => thread - 1,
+            };
+            // The description of the thread is sent in hexadecimal.
+            output.push_hex(b"CPU ")?;
+            if id >= 10 {
+                output.push_hex(&[b'0' + (id / 10) as u8])?;
+            }
+            output.push_hex(&[b'0' + (id % 10) as u8])?;
+        }
+        Ok(())
+    }
+
+    /// Handle the packet `packet` from GDB and write the reply to `output`.
+    /// `registers` holds the registers of the thread that stops. Return
+    /// true if the kernel must be resumed.
+    fn command(&mut self, packet: &[u8], output: &mut Packet,
+               registers: &mut Registers) -> Result<bool, ()>
+    {
+        let (&kind, args) = packet.split_first().ok_or(())?;
+        match kind {
+            b'?' => self.stop_reply(output)?,
+            b'g' => self.registers(self.thread, registers)?
+                .read_all(output)?,
+            b'G' => {
+                self.registers(self.thread, registers)?.write_all(args)?;
+                write!(output, "OK").map_err(|_| ())?;
+            },
+            b'p' => {
+                let n = parse_hex(args)?;
+                self.registers(self.thread, registers)?.read(n, output)?;
+            },
+            b'P' => {
+                let equal = args.iter().position(|&b| b == b'=').ok_or(())?;
+                let n = parse_hex(&args[..equal])?;
+                self.registers(self.thread, registers)?
+                    .write(n, &args[equal + 1..])?;
+                write!(output, "OK").map_err(|_| ())?;
+            },
+            b'm' => {
+                let (addr, len) = parse_range(args)?;
+                // The memory is read until the first byte that is not
+                // mapped.
+                for addr in (addr..).take(len.min(PACKET_SIZE / 2)) {
+                    match read_byte(addr) {
+                        Some(byte) => output.push_hex(&[byte])?,
+                        None => break,
+                    }
+                }
+                if output.as_bytes().is_empty() {
+                    return Err(());
+                }
+            },
+            b'M' => {
+                let colon = args.iter().position(|&b| b == b':').ok_or(())?;
+                let (addr, len) = parse_range(&args[..colon])?;
+                let data = &args[colon + 1..];
+                if data.len() != len * 2 {
+                    return Err(());
+                }
+                for (addr, hex) in (addr..).zip(data.chunks(2)) {
+                    let mut byte = [0];
+                    decode_hex(hex, &mut byte)?;
+                    write_byte(addr, byte[0])?;
+                }
+                write!(output, "OK").map_err(|_| ())?;
+            },
+            // Only the software breakpoints are supported, and the other
+            // kinds get an empty reply.
+            b'Z' | b'z' if args.starts_with(b"0,") => {
+                let (addr, _) = parse_range(&args[2..])?;
+                if kind == b'Z' {
+                    self.insert_breakpoint(addr)?;
+                } else {
+                    self.remove_breakpoint(addr)?;
+                }
+                write!(output, "OK").map_err(|_| ())?;
+            },
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    registers.rip = parse_hex(args)? as u64;
+                }
+                if kind == b's' {
+                    registers.rflags |= TF;
+                    self.stepping = true;
+                }
+                self.attached = true;
+                return Ok(true);
+            },
+            b'D' => {
+                self.attached = false;
+                write!(output, "OK").map_err(|_| ())?;
+                return Ok(true);
+            },
+            // The kernel can't be killed, so it just continues.
+            b'k' => {
+                self.attached = false;
+                return Ok(true);
+            },
+            b'H' if !args.is_empty() => {
+                let thread = parse_thread(&args[1..])?;
+                self.registers(thread, registers)?;
+                if args[0] == b'g' {
+                    self.thread = thread;
+                }
+                write!(output, "OK").map_err(|_| ())?;
+            },
+            b'T' => {
+                self.registers(parse_thread(args)?, registers)?;
+                write!(output, "OK").map_err(|_| ())?;
+            },
+            b'q' => self.query(packet, output)?,
+            _ => (),
+        }
+        Ok(false)
+    }
+}
+
+/// Talk to GDB until it resumes the kernel. `registers` holds the
+/// registers of the code that stops.
+#[cfg(not(test))]
+fn talk(stub: &mut Stub, registers: &mut Registers) {
+    let Stub { ref mut state, ref mut input, ref mut output } = *stub;
+    let mut port = Uart::new(COM2);
+    state.thread = 0;
+    // GDB asks for the reason with `?` when it attaches.
+    if state.attached {
+        output.clear();
+        state.stop_reply(output).unwrap();
+        output.send(&mut port);
+    }
+    loop {
+        input.receive(&mut port);
+        output.clear();
+        let resume = match state.command(input.as_bytes(), output,
+                                         registers) {
+            Ok(resume) => resume,
+            Err(_) => {
+                output.clear();
+                write!(output, "E01").unwrap();
+                false
+            },
+        };
+        if !resume || !output.as_bytes().is_empty() {
+            output.send(&mut port);
+        }
+        if resume {
+            return;
+        }
+    }
+}
+
+/// Handle the debug or breakpoint exception of the code whose registers
+/// are in `registers`, without the stub.
+#[cfg(not(test))]
+fn fallback(registers: &Registers) {
+    let frame = InterruptStackFrame {
+        rip: registers.rip,
+        cs: registers.cs,
+        rflags: registers.rflags,
+        rsp: registers.rsp,
+        ss: registers.ss,
+    };
+    let name = if registers.vector == DEBUG {
+        "Debug exception"
+    } else {
+        "Breakpoint"
+    };
+    exception::handle(&frame, Some(Signal::Trap), registers.rbp as usize,
+                      format_args!("{}", name));
+}
+
+/// The function called by the entries of the stub with the registers of
+/// the interrupted code.
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn gdb_trap(registers: &mut Registers) {
+    if registers.vector == u64::from(STOP_INTERRUPT) {
+        apic::eoi();
+        park(registers);
+        return;
+    }
+    let mut stub = loop {
+        if let Some(stub) = STUB.try_lock() {
+            break stub;
+        }
+        // Another processor is talking to GDB, so we wait like the stopped
+        // processors and try again.
+        park(registers);
+        // GDB may remove the breakpoint in the meantime, in which case the
+        // replaced instruction is executed again.
+        if registers.vector == BREAKPOINT
+            && read_byte(registers.rip as usize - 1) != Some(INT3)
+        {
+            registers.rip -= 1;
+            return;
+        }
+    };
+    let ours = {
+        let state = &stub.state;
+        let user = registers.cs & 3 == 3;
+        state.present && match registers.vector {
+            DEBUG => state.stepping,
+            // The user programs may have their own breakpoints.
+            _ => !user || state.find_breakpoint(registers.rip as usize - 1)
+                .is_some(),
+        }
+    };
+    if !ours {
+        drop(stub);
+        fallback(registers);
+        return;
+    }
+
+    {
+        let state = &mut stub.state;
+        state.stepping = false;
+        registers.rflags &= !TF;
+        state.at_breakpoint = registers.vector == BREAKPOINT
+            && state.find_breakpoint(registers.rip as usize - 1).is_some();
+        if state.at_breakpoint && state.swbreak {
+            registers.rip -= 1;
+        }
+    }
+    stop_others();
+    talk(&mut stub, registers);
+    resume_others();
+}
+
+/// Stop the kernel and give the control to GDB, if the stub is enabled.
+#[cfg(not(test))]
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe {
+        asm!("int3" :::: "volatile");
+    }
+}
+
+/// Initialization function for gdb module. If the stub is enabled, this
+/// waits until GDB attaches through COM2. This must be called after the
+/// interrupt module is initialized.
+#[cfg(not(test))]
+pub fn init() {
+    if !GDB_STUB {
+        return;
+    }
+    if !Uart::new(COM2).init() {
+        warn!("The GDB stub is disabled, because there is no COM2.");
+        return;
+    }
+    STUB.lock().state.present = true;
+    info!("Waiting for GDB on COM2...");
+    breakpoint();
+}
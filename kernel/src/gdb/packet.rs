@@ -0,0 +1,268 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The packets of the GDB remote serial protocol. A packet is sent as
+//! `$<data>#<checksum>`, where the checksum is the sum of the bytes of the
+//! data modulo 256 in two hexadecimal digits. The receiver acknowledges
+//! each packet with `+`, or asks for it again with `-`.
+
+use core::fmt;
+
+/// The maximum size of the data of a packet. It must fit all the registers
+/// in hexadecimal.
+pub const PACKET_SIZE: usize = 0x400;
+
+/// A byte stream connected to GDB.
+pub trait Channel {
+    /// Wait for the next byte from GDB.
+    fn read(&mut self) -> u8;
+    /// Send `byte` to GDB.
+    fn write(&mut self, byte: u8);
+}
+
+/// Return the hexadecimal digit of the lowest 4 bits of `value`.
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[usize::from(value & 0xf)]
+}
+
+/// Return the value of the hexadecimal digit `digit`.
+fn from_hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a hexadecimal number, like the addresses and the lengths in the
+/// packets.
+pub fn parse_hex(hex: &[u8]) -> Result<usize, ()> {
+    if hex.is_empty() || hex.len() > 16 {
+        return Err(());
+    }
+    hex.iter().try_fold(0, |value, &digit| {
+        from_hex_digit(digit).map(|d| value << 4 | usize::from(d)).ok_or(())
+    })
+}
+
+/// Decode the pairs of hexadecimal digits in `hex` into the bytes of `out`.
+/// Return the number of the bytes decoded.
+pub fn decode_hex(hex: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+    if hex.len() % 2 != 0 || hex.len() / 2 > out.len() {
+        return Err(());
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.chunks(2)) {
+        let high = from_hex_digit(pair[0]).ok_or(())?;
+        let low = from_hex_digit(pair[1]).ok_or(())?;
+        *byte = high << 4 | low;
+    }
+    Ok(hex.len() / 2)
+}
+
+/// Return the checksum of the data of a packet.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// The data of a packet.
+pub struct Packet {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    /// Create a new empty [Packet](Packet).
+    pub const fn new() -> Packet {
+        Packet {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Append `byte` to the packet. Return an error if the packet is full.
+    pub fn push(&mut self, byte: u8) -> Result<(), ()> {
+        if self.len == PACKET_SIZE {
+            return Err(());
+        }
+        self.data[self.len] = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Append `bytes` in hexadecimal, two digits for each byte.
+    pub fn push_hex(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        for &byte in bytes {
+            self.push(hex_digit(byte >> 4))?;
+            self.push(hex_digit(byte))?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next packet from GDB and replace the data of this
+    /// packet with it. The packets with a wrong checksum, or that are too
+    /// long, are asked again.
+    pub fn receive<C: Channel>(&mut self, channel: &mut C) {
+        loop {
+            // Skip everything before the start of the packet, like the
+            // acknowledgements.
+            while channel.read() != b'$' {}
+            self.clear();
+            let mut fits = true;
+            loop {
+                let byte = channel.read();
+                if byte == b'#' {
+                    break;
+                }
+                fits &= self.push(byte).is_ok();
+            }
+            let high = from_hex_digit(channel.read());
+            let low = from_hex_digit(channel.read());
+            let valid = match (high, low) {
+                (Some(high), Some(low)) => {
+                    high << 4 | low == checksum(self.as_bytes())
+                },
+                _ => false,
+            };
+            if fits && valid {
+                channel.write(b'+');
+                return;
+            }
+            channel.write(b'-');
+        }
+    }
+
+    /// Send the packet to GDB, again and again until it's acknowledged.
+    pub fn send<C: Channel>(&self, channel: &mut C) {
+        let sum = checksum(self.as_bytes());
+        loop {
+            channel.write(b'$');
+            for &byte in self.as_bytes() {
+                channel.write(byte);
+            }
+            channel.write(b'#');
+            channel.write(hex_digit(sum >> 4));
+            channel.write(hex_digit(sum));
+            loop {
+                match channel.read() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.push(byte).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    struct TestChannel {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl TestChannel {
+        fn new(input: &[u8]) -> TestChannel {
+            TestChannel {
+                input: input.iter().cloned().collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Channel for TestChannel {
+        fn read(&mut self) -> u8 {
+            self.input.pop_front().expect("there is no input left")
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_hex(b"ffff8000001234aB"), Ok(0xffff_8000_0012_34ab));
+        assert_eq!(parse_hex(b"0"), Ok(0));
+        assert!(parse_hex(b"").is_err());
+        assert!(parse_hex(b"12g").is_err());
+        assert!(parse_hex(b"10000000000000000").is_err());
+
+        let mut out = [0; 4];
+        assert_eq!(decode_hex(b"00fF7a", &mut out), Ok(3));
+        assert_eq!(out, [0x00, 0xff, 0x7a, 0]);
+        assert!(decode_hex(b"123", &mut out).is_err());
+        assert!(decode_hex(b"0102030405", &mut out).is_err());
+        assert!(decode_hex(b"zz", &mut out).is_err());
+    }
+
+    #[test]
+    fn build_packet() {
+        let mut packet = Packet::new();
+        write!(packet, "T{:02x}", 5).unwrap();
+        packet.push_hex(&[0x12, 0xab]).unwrap();
+        assert_eq!(packet.as_bytes(), b"T0512ab");
+        packet.clear();
+        for _ in 0..PACKET_SIZE {
+            packet.push(b'0').unwrap();
+        }
+        assert!(packet.push(b'0').is_err());
+        assert!(write!(packet, "0").is_err());
+    }
+
+    #[test]
+    fn receive_packet() {
+        // The acknowledgement of the previous packet comes first, and the
+        // first copy of the packet has a wrong checksum.
+        let mut channel = TestChannel::new(b"+$m1000,4#00$m1000,4#8e");
+        let mut packet = Packet::new();
+        packet.receive(&mut channel);
+        assert_eq!(packet.as_bytes(), b"m1000,4");
+        assert_eq!(channel.output, b"-+");
+        assert!(channel.input.is_empty());
+    }
+
+    #[test]
+    fn send_packet() {
+        let mut channel = TestChannel::new(b"-+");
+        let mut packet = Packet::new();
+        write!(packet, "OK").unwrap();
+        packet.send(&mut channel);
+        assert_eq!(channel.output, b"$OK#9a$OK#9a");
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The registers of the code stopped by the GDB stub, in the order that GDB
+//! numbers them on x86_64.
+
+use super::packet::{decode_hex, Packet};
+
+/// The number of the registers sent in a `g` packet: the 16 general
+/// purpose registers, rip, rflags and the 6 segment registers.
+const NUM_REGISTERS: usize = 24;
+
+/// The registers of the interrupted code, which are saved on the stack by
+/// the entries of the stub. The order must be the same as in the assembly.
+#[derive(Clone, Debug, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// The interrupt vector of the entry.
+    pub vector: u64,
+    /// The error code of the exception, which is always 0 for the entries
+    /// of the stub.
+    pub error_code: u64,
+    // The interrupt stack frame pushed by the processor.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Registers {
+    /// Return a mutable reference to the register numbered `n` by GDB and
+    /// its size in bytes. The data segment registers are not saved, so they
+    /// are None.
+    fn register(&mut self, n: usize) -> Option<(&mut u64, usize)> {
+        let register = match n {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            18 => &mut self.cs,
+            19 => &mut self.ss,
+            _ => return None,
+        };
+        Some((register, if n < 17 { 8 } else { 4 }))
+    }
+
+    /// Append the register numbered `n` by GDB to `packet` in hexadecimal.
+    pub fn read(&mut self, n: usize, packet: &mut Packet) -> Result<(), ()> {
+        match self.register(n) {
+            Some((register, size)) => {
+                packet.push_hex(&register.to_le_bytes()[..size])
+            },
+            // GDB shows the registers whose digits are all 'x' as
+            // unavailable.
+            None if n < NUM_REGISTERS => {
+                for _ in 0..8 {
+                    packet.push(b'x')?;
+                }
+                Ok(())
+            },
+            None => Err(()),
+        }
+    }
+
+    /// Set the register numbered `n` by GDB to the value in `hex`. The
+    /// segment registers can't be changed.
+    pub fn write(&mut self, n: usize, hex: &[u8]) -> Result<(), ()> {
+        let mut bytes = [0; 8];
+        let len = decode_hex(hex, &mut bytes)?;
+        match self.register(n) {
+            Some((register, size)) => {
+                if len != size {
+                    return Err(());
+                }
+                if n < 18 {
+                    *register = u64::from_le_bytes(bytes);
+                }
+                Ok(())
+            },
+            None if n < NUM_REGISTERS && len == 4 => Ok(()),
+            None => Err(()),
+        }
+    }
+
+    /// Append all the registers to `packet`, like the reply of a `g`
+    /// packet.
+    pub fn read_all(&mut self, packet: &mut Packet) -> Result<(), ()> {
+        for n in 0..NUM_REGISTERS {
+            self.read(n, packet)?;
+        }
+        Ok(())
+    }
+
+    /// Set all the registers to the values in `hex`, like the data of a `G`
+    /// packet.
+    pub fn write_all(&mut self, mut hex: &[u8]) -> Result<(), ()> {
+        for n in 0..NUM_REGISTERS {
+            let size = if n < 17 { 8 } else { 4 };
+            if hex.len() < size * 2 {
+                return Err(());
+            }
+            // The unavailable registers are sent back as they are.
+            if hex[0] != b'x' {
+                self.write(n, &hex[..size * 2])?;
+            }
+            hex = &hex[size * 2..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_registers() {
+        let mut registers = Registers {
+            rax: 0x1122_3344_5566_7788,
+            rsp: 0x10,
+            rip: 0xffff_8000_0010_0000,
+            rflags: 0x202,
+            cs: 0x8,
+            ..Registers::default()
+        };
+        let mut packet = Packet::new();
+        registers.read(0, &mut packet).unwrap();
+        assert_eq!(packet.as_bytes(), b"8877665544332211");
+        packet.clear();
+        registers.read(17, &mut packet).unwrap();
+        registers.read(18, &mut packet).unwrap();
+        registers.read(20, &mut packet).unwrap();
+        assert_eq!(packet.as_bytes(), b"0202000008000000xxxxxxxx");
+        assert!(registers.read(NUM_REGISTERS, &mut packet).is_err());
+
+        packet.clear();
+        registers.read_all(&mut packet).unwrap();
+        let hex = packet.as_bytes();
+        assert_eq!(hex.len(), 17 * 16 + 7 * 8);
+        assert_eq!(&hex[7 * 16..8 * 16], b"1000000000000000");
+        assert_eq!(&hex[16 * 16..17 * 16], b"000010000080ffff");
+    }
+
+    #[test]
+    fn write_registers() {
+        let mut registers = Registers {
+            cs: 0x8,
+            ..Registers::default()
+        };
+        registers.write(15, b"0100000000000000").unwrap();
+        assert_eq!(registers.r15, 1);
+        registers.write(17, b"46000000").unwrap();
+        assert_eq!(registers.rflags, 0x46);
+        // The segment registers are not changed.
+        registers.write(18, b"33000000").unwrap();
+        registers.write(21, b"33000000").unwrap();
+        assert_eq!(registers.cs, 0x8);
+        assert!(registers.write(0, b"01").is_err());
+        assert!(registers.write(NUM_REGISTERS, b"00000000").is_err());
+
+        let mut packet = Packet::new();
+        registers.rax = 0x1234;
+        registers.read_all(&mut packet).unwrap();
+        let mut other = Registers::default();
+        other.write_all(packet.as_bytes()).unwrap();
+        assert_eq!(other.rax, 0x1234);
+        assert_eq!(other.r15, 1);
+        assert_eq!(other.rflags, 0x46);
+        assert!(other.write_all(&packet.as_bytes()[..16]).is_err());
+    }
+}
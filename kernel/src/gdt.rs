@@ -0,0 +1,163 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Global descriptor tables. Every processor has its own GDT, because the
+//! TSS that holds the stack used when the processor enters the kernel is
+//! different on each of them.
+
+use core::mem;
+
+/// The segment selectors of the kernel, which are the same as the ones set
+/// up by the bootloader. The user segments come after them.
+#[cfg_attr(test, allow(dead_code))]
+pub const KERNEL_CODE: u16 = 1 << 3;
+#[cfg_attr(test, allow(dead_code))]
+pub const KERNEL_DATA: u16 = 2 << 3;
+#[cfg_attr(test, allow(dead_code))]
+const TSS: u16 = 5 << 3;
+
+/// The index of the stack in the interrupt stack table that the double
+/// fault handler runs on. The indices start from 1.
+#[cfg_attr(test, allow(dead_code))]
+pub const DOUBLE_FAULT_IST: usize = 1;
+
+/// The descriptors of the code and data segments. Only the access bits
+/// matter in long mode.
+const KERNEL_CODE_DESCRIPTOR: u64 = 0x00af_9a00_0000_ffff;
+const KERNEL_DATA_DESCRIPTOR: u64 = 0x00cf_9200_0000_ffff;
+const USER_DATA_DESCRIPTOR: u64 = 0x00cf_f200_0000_ffff;
+const USER_CODE_DESCRIPTOR: u64 = 0x00af_fa00_0000_ffff;
+
+/// The task state segment. In long mode, it only holds the stacks that the
+/// processor switches to on interrupts.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C, packed)]
+pub struct Tss {
+    reserved0: u32,
+    /// The stacks used when the privilege level changes to 0, 1 and 2.
+    pub rsp: [u64; 3],
+    reserved1: u64,
+    /// The interrupt stack table.
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+/// The global descriptor table of a processor, together with its TSS.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Gdt {
+    entries: [u64; 7],
+    tss: Tss,
+}
+
+/// Return the two entries of the descriptor of a TSS at address `base`.
+fn tss_descriptor(base: usize) -> [u64; 2] {
+    let base = base as u64;
+    let limit = (mem::size_of::<Tss>() - 1) as u64;
+    let low = (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        // Present, available 64-bit TSS.
+        | 0x89 << 40
+        | (limit >> 16 & 0xf) << 48
+        | (base >> 24 & 0xff) << 56;
+    [low, base >> 32]
+}
+
+impl Gdt {
+    /// Create a new [Gdt](Gdt) whose TSS has `stack_end` as the stack used
+    /// when entering the kernel from the user space, and `ist_end` as the
+    /// stack of the double fault handler.
+    pub fn new(stack_end: usize, ist_end: usize) -> Gdt {
+        let mut tss = Tss::default();
+        tss.rsp[0] = stack_end as u64;
+        tss.ist[DOUBLE_FAULT_IST - 1] = ist_end as u64;
+        // There is no I/O permission bitmap.
+        tss.iomap_base = mem::size_of::<Tss>() as u16;
+        Gdt {
+            entries: [
+                0,
+                KERNEL_CODE_DESCRIPTOR,
+                KERNEL_DATA_DESCRIPTOR,
+                USER_DATA_DESCRIPTOR,
+                USER_CODE_DESCRIPTOR,
+                // The TSS descriptor is filled when the GDT is loaded, because
+                // the address of the TSS is not known yet.
+                0,
+                0,
+            ],
+            tss,
+        }
+    }
+
+    /// Load this GDT and its TSS on the processor. The selectors of the data
+    /// segments are reloaded and FS and GS are cleared, so the GS base must
+    /// be set after this.
+    #[cfg(not(test))]
+    pub unsafe fn load(&'static mut self) {
+        let descriptor = tss_descriptor(&self.tss as *const Tss as usize);
+        self.entries[TSS as usize >> 3..].copy_from_slice(&descriptor);
+        let base = self.entries.as_ptr() as u64;
+        let limit = mem::size_of_val(&self.entries) as u16 - 1;
+        // CS can only be reloaded by a far return.
+        asm!("sub $$16, %rsp
+              mov %dx, (%rsp)
+              mov %rcx, 2(%rsp)
+              lgdt (%rsp)
+              add $$16, %rsp
+              pushq $1
+              lea 1f(%rip), %rax
+              pushq %rax
+              lretq
+              1:
+              mov $2, %ax
+              mov %ax, %ds
+              mov %ax, %es
+              mov %ax, %ss
+              xor %eax, %eax
+              mov %ax, %fs
+              mov %ax, %gs
+              mov $3, %ax
+              ltr %ax"
+              :: "{dx}"(limit), "{rcx}"(base), "i"(KERNEL_CODE),
+                 "i"(KERNEL_DATA), "i"(TSS)
+              : "rax", "memory"
+              : "volatile");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tss_size() {
+        assert_eq!(mem::size_of::<Tss>(), 104);
+        let gdt = Gdt::new(0x1000, 0x2000);
+        let rsp0 = gdt.tss.rsp[0];
+        assert_eq!(rsp0, 0x1000);
+        let ist = gdt.tss.ist;
+        assert_eq!(ist[DOUBLE_FAULT_IST - 1], 0x2000);
+    }
+
+    #[test]
+    fn encode_tss_descriptor() {
+        let descriptor = tss_descriptor(0xffff_8000_1234_5678);
+        assert_eq!(descriptor[0], 0x1200_8934_5678_0067);
+        assert_eq!(descriptor[1], 0xffff_8000);
+    }
+}
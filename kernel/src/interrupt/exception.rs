@@ -0,0 +1,145 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![cfg(not(test))]
+
+//! CPU exceptions. An exception in the kernel is a bug, so the registers
+//! and the backtrace of the code that causes it are printed and the kernel
+//! stops. An exception in the user mode sends a signal to the process.
+
+use core::fmt;
+
+use ::backtrace;
+use ::debug;
+use ::process::{self, Signal};
+use ::gdt::DOUBLE_FAULT_IST;
+use super::{ist_entry, kernel_entry, user_entry, InterruptStackFrame};
+
+/// Define the handler of an exception. The handler sends `$signal` to the
+/// process if the exception is caused by the user mode, or stops the
+/// kernel if `$signal` is None.
+macro_rules! exception_handler {
+    ($handler:ident, $name:expr, $signal:expr) => {
+        extern "x86-interrupt" fn $handler(frame: &mut InterruptStackFrame) {
+            handle(frame, $signal, interrupted_frame(),
+                   format_args!("{}", $name));
+        }
+    };
+    ($handler:ident, $name:expr, $signal:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(frame: &mut InterruptStackFrame,
+                                           error_code: u64) {
+            handle(frame, $signal, interrupted_frame(),
+                   format_args!("{}, error code {:#x}", $name, error_code));
+        }
+    };
+}
+
+exception_handler!(divide_error, "Divide error", Some(Signal::Fpe));
+exception_handler!(debug, "Debug exception", Some(Signal::Trap));
+exception_handler!(breakpoint, "Breakpoint", Some(Signal::Trap));
+exception_handler!(overflow, "Overflow", Some(Signal::Segv));
+exception_handler!(bound_range, "Bound range exceeded", Some(Signal::Segv));
+exception_handler!(invalid_opcode, "Invalid opcode", Some(Signal::Ill));
+exception_handler!(device_not_available, "Device not available",
+                   Some(Signal::Fpe));
+exception_handler!(double_fault, "Double fault", None, error_code);
+exception_handler!(invalid_tss, "Invalid TSS", None, error_code);
+exception_handler!(segment_not_present, "Segment not present",
+                   Some(Signal::Segv), error_code);
+exception_handler!(stack_fault, "Stack fault", Some(Signal::Segv),
+                   error_code);
+exception_handler!(general_protection, "General protection fault",
+                   Some(Signal::Segv), error_code);
+exception_handler!(x87_error, "x87 floating-point error",
+                   Some(Signal::Fpe));
+exception_handler!(alignment_check, "Alignment check", Some(Signal::Segv),
+                   error_code);
+exception_handler!(machine_check, "Machine check", None);
+exception_handler!(simd_error, "SIMD floating-point error",
+                   Some(Signal::Fpe));
+
+/// Return the frame pointer of the code interrupted by the handler that
+/// calls this function. The handler saves it at the start of its frame.
+#[inline(always)]
+pub fn interrupted_frame() -> usize {
+    unsafe { *(backtrace::frame_pointer() as *const usize) }
+}
+
+/// Handle the exception described by `description`, which is caused by the
+/// code in `frame`, whose frame pointer is `rbp`.
+pub fn handle(frame: &InterruptStackFrame, signal: Option<Signal>,
+              rbp: usize, description: fmt::Arguments)
+{
+    match signal {
+        Some(signal) if frame.cs & 3 == 3 => {
+            process::current()
+                .expect("exception in the user mode without any process")
+                .send_signal(signal);
+            process::deliver_signals();
+        },
+        _ => fatal(frame, rbp, description),
+    }
+}
+
+/// Print the exception described by `description`, which is caused by the
+/// kernel code in `frame`, whose frame pointer is `rbp`, together with its
+/// backtrace and stop the kernel.
+#[allow(clippy::empty_loop)]
+pub fn fatal(frame: &InterruptStackFrame, rbp: usize,
+             description: fmt::Arguments) -> !
+{
+    unsafe {
+        debug::force_unlock();
+    }
+    debug::set_color(debug::Color::LightRed);
+    println!("Kelner paniked!");
+    println!("{}", description);
+    println!("rip = {:#x}, rsp = {:#x}, rflags = {:#x}, cs = {:#x}, \
+              ss = {:#x}", frame.rip, frame.rsp, frame.rflags, frame.cs,
+             frame.ss);
+    backtrace::print_from(frame.rip as usize, rbp);
+    debug::reset_color();
+    debug::flush();
+    loop {}
+}
+
+/// Set the handlers of the exceptions in `idt`, except the page fault,
+/// which is handled by the vm module.
+pub fn install(idt: &mut [u128]) {
+    let handlers = [
+        (0, divide_error as usize),
+        (1, debug as usize),
+        (4, overflow as usize),
+        (5, bound_range as usize),
+        (6, invalid_opcode as usize),
+        (7, device_not_available as usize),
+        (10, invalid_tss as usize),
+        (11, segment_not_present as usize),
+        (12, stack_fault as usize),
+        (13, general_protection as usize),
+        (16, x87_error as usize),
+        (17, alignment_check as usize),
+        (18, machine_check as usize),
+        (19, simd_error as usize),
+    ];
+    for &(vector, handler) in handlers.iter() {
+        idt[vector] = kernel_entry(handler);
+    }
+    // A double fault is often caused by a kernel stack overflow, so its
+    // handler has its own stack.
+    idt[8] = ist_entry(double_fault as usize, DOUBLE_FAULT_IST);
+    // The user programs can use int3 to stop themselves.
+    idt[3] = user_entry(breakpoint as usize);
+}
@@ -30,6 +30,7 @@ macro_rules! idt_entry {
                   result = set_bits(result, 64, 96, $value >> 32);
                 },
                 "selector"    => result = set_bits(result, 16, 32, $value),
+                "ist"         => result = set_bits(result, 32, 35, $value),
                 "d"           => result = set_bits(result, 43, 44, $value),
                 "dpl"         => result = set_bits(result, 45, 47, $value),
                 "p"           => result = set_bits(result, 47, 48, $value),
@@ -18,28 +18,212 @@
 
 #[macro_use]
 mod macros;
+pub mod exception;
 
 #[cfg(not(test))]
-use ::syscall::interrupt_handler;
+use ::apic::SPURIOUS_INTERRUPT;
+#[cfg(not(test))]
+use ::config::GDB_STUB;
+#[cfg(not(test))]
+use ::gdb::{gdb_breakpoint_entry, gdb_debug_entry, gdb_stop_entry,
+            STOP_INTERRUPT};
+#[cfg(not(test))]
+use ::keyboard::{keyboard_handler, KEYBOARD_INTERRUPT};
+#[cfg(not(test))]
+use ::paging::tlb::{shootdown_handler, SHOOTDOWN_INTERRUPT};
+#[cfg(not(test))]
+use ::percpu;
+#[cfg(not(test))]
+use ::serial::{serial_handler, SERIAL_INTERRUPT};
+#[cfg(not(test))]
+use ::sync::Once;
+#[cfg(not(test))]
+use ::syscall::syscall_entry;
 #[cfg(not(test))]
 use ::util::set_bits;
+#[cfg(not(test))]
+use ::vm::page_fault_handler;
+
+/// The stack frame pushed by the processor when there is an interrupt.
+#[cfg_attr(test, allow(dead_code))]
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// The interrupt descriptor table shared by all the processors. It's
+/// built once by the boot processor.
+#[cfg(not(test))]
+static IDT: Once<[u128; 0x100]> = Once::new();
+
+/// The interrupt handler for spurious interrupts from the local APIC.
+#[cfg(not(test))]
+extern "x86-interrupt" fn spurious_handler(_frame: &mut InterruptStackFrame) {
+    let _context = enter();
+}
+
+/// Disable the interrupts on the processor that is running this code.
+/// Return true if they were enabled.
+#[cfg(not(test))]
+pub fn disable() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq
+              pop $0
+              cli"
+              : "=r"(rflags)
+              :: "memory"
+              : "volatile");
+    }
+    rflags & (1 << 9) != 0
+}
+
+/// Return true if the interrupts are enabled on the processor that is
+/// running this code.
+#[cfg(not(test))]
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq
+              pop $0"
+              : "=r"(rflags)
+              ::: "volatile");
+    }
+    rflags & (1 << 9) != 0
+}
+
+/// Enable the interrupts on the processor that is running this code.
+#[cfg(not(test))]
+pub fn enable() {
+    unsafe {
+        asm!("sti" :::: "volatile");
+    }
+}
 
+/// There are no interrupts in test.
+#[cfg(test)]
+pub fn disable() -> bool {
+    false
+}
+
+#[cfg(test)]
+pub fn enable() {}
+
+/// A guard that marks the processor as running an interrupt handler until
+/// it's dropped. The handlers of exceptions and system calls run on behalf
+/// of the code that causes them, so they don't take it.
+#[cfg(not(test))]
+pub struct InterruptContext(());
+
+/// Mark the processor as running an interrupt handler.
+#[cfg(not(test))]
+pub fn enter() -> InterruptContext {
+    if let Some(percpu) = percpu::try_current() {
+        percpu.interrupt_depth.set(percpu.interrupt_depth.get() + 1);
+    }
+    InterruptContext(())
+}
+
+#[cfg(not(test))]
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        if let Some(percpu) = percpu::try_current() {
+            percpu.interrupt_depth.set(percpu.interrupt_depth.get() - 1);
+        }
+    }
+}
+
+/// Return true if the processor is running an interrupt handler.
+#[cfg(not(test))]
+pub fn in_interrupt() -> bool {
+    match percpu::try_current() {
+        Some(percpu) => percpu.interrupt_depth.get() > 0,
+        None => false,
+    }
+}
+
+/// Return the entry of the IDT for the interrupt handler `handler`, which
+/// can only be triggered by the kernel and the devices.
+#[cfg(not(test))]
+fn kernel_entry(handler: usize) -> u128 {
+    idt_entry! {
+      .offset = handler as u128,
+      .selector = 1<<3,
+      .d = 1, .dpl = 0, .p = 1
+    }
+}
+
+/// Return the entry of the IDT for the interrupt handler `handler`, which
+/// runs on the stack `ist` of the interrupt stack table in the TSS, even if
+/// the kernel stack is broken.
+#[cfg(not(test))]
+fn ist_entry(handler: usize, ist: usize) -> u128 {
+    idt_entry! {
+      .offset = handler as u128,
+      .selector = 1<<3,
+      .ist = ist as u128,
+      .d = 1, .dpl = 0, .p = 1
+    }
+}
+
+/// Return the entry of the IDT for the interrupt handler `handler`, which
+/// can also be triggered by the user programs with the int instruction.
 #[cfg(not(test))]
-static mut IDT: [u128; 0x100] = [0; 0x100];
+fn user_entry(handler: usize) -> u128 {
+    idt_entry! {
+      .offset = handler as u128,
+      .selector = 1<<3,
+      .d = 1, .dpl = 3, .p = 1
+    }
+}
 
 /// Initialization function for interrupt module.
 #[cfg(not(test))]
 pub fn init() {
     // By the time I wrote this code, I'm not sure why I set .d to be 1.
-    unsafe {
+    IDT.call_once(|| {
+        let mut idt = [0; 0x100];
+        exception::install(&mut idt);
+        // The interrupt handler for page faults.
+        idt[14] = kernel_entry(page_fault_handler as usize);
+        // The interrupt handler for TLB shootdowns from other processors.
+        idt[SHOOTDOWN_INTERRUPT as usize] =
+            kernel_entry(shootdown_handler as usize);
+        // The interrupt handler for the keyboard.
+        idt[KEYBOARD_INTERRUPT as usize] =
+            kernel_entry(keyboard_handler as usize);
+        // The interrupt handler for the serial port.
+        idt[SERIAL_INTERRUPT as usize] = kernel_entry(serial_handler as usize);
+        // The local APIC doesn't expect an EOI for spurious interrupts.
+        idt[SPURIOUS_INTERRUPT as usize] =
+            kernel_entry(spurious_handler as usize);
+        // The GDB stub takes over the debug and breakpoint exceptions.
+        if GDB_STUB {
+            idt[1] = kernel_entry(gdb_debug_entry as usize);
+            idt[3] = user_entry(gdb_breakpoint_entry as usize);
+            idt[STOP_INTERRUPT as usize] =
+                kernel_entry(gdb_stop_entry as usize);
+        }
         // The interrupt handler for system calls.
-        IDT[0x80] = idt_entry! {
-          .offset = (interrupt_handler as usize) as u128,
-          .selector = 1<<3,
-          .d = 1, .dpl = 3, .p = 1
-        };
-        let base = IDT.as_ptr() as u64;
-        let limit = 16 * IDT.len() as u16;
+        idt[0x80] = user_entry(syscall_entry as usize);
+        idt
+    });
+    load();
+}
+
+/// Load the IDT on the processor that is running this code. The other
+/// processors share the same IDT with the boot processor.
+#[cfg(not(test))]
+pub fn load() {
+    let idt = IDT.get().expect("the IDT is not built yet");
+    let base = idt.as_ptr() as u64;
+    let limit = 16 * idt.len() as u16;
+    unsafe {
         asm!("sub $$80, %rsp
               mov %ax, (%rsp)
               mov %rbx, 2(%rsp)
@@ -18,231 +18,1056 @@
 //! to allocate kernel memory.
 
 use core::alloc::{AllocErr, Layout};
-use core::{ptr, mem};
+use core::ops::Range;
+use core::{cmp, fmt, mem, ptr};
 use ::util::lg;
-use ::collections::{
-    StaticMap,
-    StaticStack,
-    StaticList,
-    StaticListRef,
-};
-#[cfg(not(test))]
-use ::config::{
-    KERNEL_HEAP_START,
-    KERNEL_HEAP_END,
-};
-
-/// The maximum slab size.
-#[cfg(not(test))]
-const SLAB_SIZE: usize = 0x1000;
-// Log of SLAB_SIZE + 1.
-#[cfg(not(test))]
-const NUM_CACHES: usize = 12 + 1;
-
-/// The value entry in the mapping of allocated addresses.
-struct MapEntry {
-    // The bit length of the allocation size. Maximum is NUM_CACHES-1.
+use ::backtrace::Site;
+use super::debug::{self, LiveObject, LiveObjects, Quarantine};
+
+/// The size of a slab. The caches take memory from the heap slab by slab.
+pub const SLAB_SIZE: usize = 0x1000;
+/// The size of the smallest object, which must be able to hold the address
+/// of the next free object.
+const MIN_OBJECT_SIZE: usize = 8;
+/// The size of the largest object in the caches. Larger objects take runs
+/// of whole slabs instead.
+const MAX_OBJECT_SIZE: usize = SLAB_SIZE / 4;
+/// The number of caches, one for each power of two from MIN_OBJECT_SIZE to
+/// MAX_OBJECT_SIZE.
+const NUM_CACHES: usize = 8;
+/// The value in the header of every slab used by a cache. This is used to
+/// catch the pointers that are not allocated by the caches.
+const SLAB_MAGIC: usize = 0x51ab_51ab;
+
+/// The header at the start of every slab used by a cache. The objects are
+/// in the same slab after it, so the header of an object can be found by
+/// rounding its address down to SLAB_SIZE.
+#[repr(C)]
+struct SlabHeader {
+    // This is always SLAB_MAGIC.
+    magic: usize,
+    // The index of the cache that this slab belongs to.
     cache_index: usize,
-    // The reference to an entry in the cache.
-    cache_entry: StaticListRef<CacheEntry>,
+    // The address of the first free object. Each free object holds the
+    // address of the next one, and the last one holds 0.
+    free: usize,
+    // The objects from this address to the end of the slab have never been
+    // allocated, so they are not in the list of free objects yet.
+    fresh: usize,
+    // The number of allocated objects.
+    used: usize,
+    // Whether the slab was filled with zeroes when the cache took it.
+    zeroed: bool,
+    // The links in the list of the slabs that have free objects.
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+}
+
+impl SlabHeader {
+    /// Return the size of the objects in the slab.
+    fn object_size(&self) -> usize {
+        MIN_OBJECT_SIZE << self.cache_index
+    }
+
+    /// Return the address of the first object in the slab.
+    fn first_object(&self) -> usize {
+        let size = self.object_size();
+        let header = mem::size_of::<SlabHeader>();
+        self as *const _ as usize + (header + size - 1) / size * size
+    }
+
+    /// Return true if there is no free object left.
+    fn is_full(&self) -> bool {
+        let end = self as *const _ as usize + SLAB_SIZE;
+        self.free == 0 && self.fresh + self.object_size() > end
+    }
 }
 
-/// This is a cache entry in the cache.
-struct CacheEntry {
-    // This is the physical address of the memory that actually stores user
-    // data. Since this is an allocation module for the kernel memory and we
-    // have an identity mapping between virtual addresses and physical
-    // addresses, this address will always be the same as the virtual address.
-    phy_addr: usize,
+/// The header at the start of every run of free slabs.
+struct FreeRun {
+    // The number of bytes in the run.
+    length: usize,
+    // The next run, which is at a higher address.
+    next: *mut FreeRun,
 }
 
-/// This is a cache for keeping free objects and allocated objects. There will
-/// be one cache for one allocation size.
+/// This is a cache for objects of one size.
+#[derive(Copy, Clone)]
 struct Cache {
-    // Free entries in the cache.
-    free_entries: StaticStack<CacheEntry>,
-    // Allocated entries in the cache.
-    allocated_entries: StaticList<CacheEntry>,
+    // The first slab in the list of the slabs that have free objects. The
+    // full slabs are not in the list.
+    slabs: *mut SlabHeader,
+}
+
+impl Cache {
+    /// Add `slab` to the front of the list.
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.slabs;
+        if !self.slabs.is_null() {
+            (*self.slabs).prev = slab;
+        }
+        self.slabs = slab;
+    }
+
+    /// Remove `slab` from the list.
+    unsafe fn unlink(&mut self, slab: *mut SlabHeader) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            self.slabs = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
 }
 
+/// The statistics of the objects of one size.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClassStats {
+    /// The number of slabs used by the objects.
+    pub slabs: usize,
+    /// The number of objects that are allocated and not freed yet.
+    pub objects: usize,
+    /// The largest number of objects allocated at the same time.
+    pub peak_objects: usize,
+    /// The number of allocations that fail.
+    pub failures: usize,
+    /// The number of bytes that are allocated but not asked for because
+    /// the sizes are rounded up.
+    pub wasted: usize,
+}
+
+impl ClassStats {
+    /// Count an object that wastes `wasted` bytes.
+    fn add_object(&mut self, wasted: usize) {
+        self.objects += 1;
+        self.peak_objects = cmp::max(self.peak_objects, self.objects);
+        self.wasted += wasted;
+    }
+
+    /// Stop counting an object that wastes `wasted` bytes.
+    fn remove_object(&mut self, wasted: usize) {
+        // The layout used to free an object may be wrong, so we don't want
+        // to overflow here.
+        self.objects = self.objects.saturating_sub(1);
+        self.wasted = self.wasted.saturating_sub(wasted);
+    }
+
+    /// Write the statistics as a row of the table written by
+    /// [Stats](Stats).
+    fn fmt_row(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, " {:>6} {:>8} {:>8} {:>8} {:>8}", self.slabs,
+                 self.objects, self.peak_objects, self.failures, self.wasted)
+    }
+}
+
+/// The statistics of the heap.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats {
+    /// The statistics of each cache, from the one of the smallest objects.
+    pub caches: [ClassStats; NUM_CACHES],
+    /// The statistics of the objects that take runs of slabs.
+    pub large: ClassStats,
+    /// The number of bytes in the heap that are not used by any slab.
+    pub free_space: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>6} {:>6} {:>8} {:>8} {:>8} {:>8}", "size", "slabs",
+                 "objects", "peak", "failed", "wasted")?;
+        for (index, stats) in self.caches.iter().enumerate() {
+            write!(f, "{:>6}", MIN_OBJECT_SIZE << index)?;
+            stats.fmt_row(f)?;
+        }
+        write!(f, "{:>6}", "large")?;
+        self.large.fmt_row(f)?;
+        write!(f, "{} bytes free", self.free_space)
+    }
+}
+
+/// A point in time that is used to find the objects allocated after it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Snapshot(u64);
+
 /// This structure will contains everything the kernel needs to know for
 /// kernel memory allocation.
 pub struct AllocContext {
-    // This is a mapping between the address and the cache entry.
-    addr_map: StaticMap<usize, MapEntry>,
     // List of caches.
     caches: [Cache; NUM_CACHES],
-    // The next slab address that we can use. Note that this variable will
-    // only increase because currently we assume that we can allocate slabs
-    // but we cannot deallocate slab. If we want to deallocate slabs, we can
-    // improve it later.
+    // The first run of slabs that are freed, which can be used again. The
+    // runs are sorted by address and adjacent runs are always merged.
+    free_runs: *mut FreeRun,
+    // The total length of the free runs, so that the free space is known
+    // without walking them.
+    free_run_space: usize,
+    // The next slab address that we can use. This only decreases when the
+    // run of slabs just below it is freed.
     next_slab_addr: usize,
+    // The end of the heap. The heap can be extended later.
+    heap_end: usize,
+    // The memory from this address to the end of the heap has never been
+    // used, so it is still filled with zeroes.
+    clean_addr: usize,
+    // The statistics of the objects, except the free space.
+    stats: Stats,
+    // The freed objects that are not used again yet, and the objects that
+    // are not freed yet. These are None unless the heap debugging is
+    // enabled.
+    quarantine: Option<Quarantine>,
+    live: Option<LiveObjects>,
+    // The frame pointer of the function that calls the allocator, whose
+    // caller is recorded as the site of the objects. The frames inside the
+    // allocator are not interesting.
+    caller: usize,
 }
 
-/// Since currently we assume that there is only one core that can use this
-/// allocation module, we can safely let this implement Sync.
-unsafe impl Sync for AllocContext {}
+/// The context is only used while it's locked, so it can be moved between
+/// the processors even though it has raw pointers.
+unsafe impl Send for AllocContext {}
 
 impl AllocContext {
-    /// Create an empty [AllocContext](AllocContext).
-    pub fn new() -> AllocContext {
-        let caches = unsafe {
-            let mut array: [Cache; NUM_CACHES] = mem::uninitialized();
-            for elem in &mut array {
-                ptr::write(elem, Cache {
-                    free_entries: StaticStack::new(),
-                    allocated_entries: StaticList::new(),
-                });
-            }
-            array
-        };
+    /// Create an [AllocContext](AllocContext) whose heap is the memory from
+    /// `start` to `end`, which must be aligned to SLAB_SIZE.
+    pub fn new(start: usize, end: usize) -> AllocContext {
+        assert!(start & (SLAB_SIZE - 1) == 0 && end & (SLAB_SIZE - 1) == 0);
+        // Clear the heap once, so that the slabs which are used for the
+        // first time don't need to be cleared by alloc_zeroed.
+        unsafe {
+            ptr::write_bytes(start as *mut u8, 0, end - start);
+        }
         AllocContext {
-            addr_map: StaticMap::new(),
-            caches,
-            next_slab_addr: KERNEL_HEAP_START,
+            caches: [Cache { slabs: ptr::null_mut() }; NUM_CACHES],
+            free_runs: ptr::null_mut(),
+            free_run_space: 0,
+            next_slab_addr: start,
+            heap_end: end,
+            clean_addr: start,
+            stats: Stats::default(),
+            quarantine: None,
+            live: None,
+            caller: 0,
         }
     }
 
-    /// Allocate a kernel memory using a given layout.
-    pub fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        // The size of memory that we want to allocate.
-        let size = layout.align();
-        let sizelg = lg(size).unwrap();
-        // If the allocation size is too large, return error.
-        if size > SLAB_SIZE {
-            return Err(AllocErr);
+    /// Record the allocations and the deallocations from now on as made by
+    /// the caller of the function whose frame pointer is `frame`.
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn set_caller(&mut self, frame: usize) {
+        self.caller = frame;
+    }
+
+    /// Enable the heap debugging. This must be called before any object is
+    /// allocated.
+    pub fn enable_debug(&mut self) {
+        self.quarantine = Some(Quarantine::new());
+        self.live = Some(LiveObjects::new());
+    }
+
+    /// Return the statistics of the heap.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            free_space: self.free_space(),
+            ..self.stats
+        }
+    }
+
+    /// Count an allocation using `layout` that fails.
+    pub fn record_failure(&mut self, layout: Layout) {
+        match AllocContext::cache_index(layout) {
+            Some(index) => self.stats.caches[index].failures += 1,
+            None => self.stats.large.failures += 1,
+        }
+    }
+
+    /// Return the current point in time, or None if the heap debugging is
+    /// disabled because the objects are not tracked.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.live.as_ref().map(|live| Snapshot(live.next_serial()))
+    }
+
+    /// Call `f` for every object that is allocated after `snapshot` and is
+    /// not freed yet, from the newest one.
+    pub fn for_each_leak<F>(&self, snapshot: Snapshot, f: F)
+        where F: FnMut(&LiveObject)
+    {
+        if let Some(ref live) = self.live {
+            live.for_each_since(snapshot.0, f);
+        }
+    }
+
+    /// Add `length` bytes of memory filled with zeroes at address `start`
+    /// to the heap. If the memory is not right after the end of the heap,
+    /// the heap moves to it and the rest of the old heap becomes a free run.
+    pub fn extend(&mut self, start: usize, length: usize) {
+        if start != self.heap_end {
+            let (addr, end) = (self.next_slab_addr, self.heap_end);
+            self.next_slab_addr = start;
+            self.clean_addr = start;
+            if addr < end {
+                self.free_run(addr, end - addr);
+            }
         }
+        self.heap_end = start + length;
+    }
 
-        let free_stack = &mut self.caches[sizelg].free_entries;
-        let allocated_list = &mut self.caches[sizelg].allocated_entries;
+    /// Return the range at the end of the heap that is not used yet.
+    pub fn unused(&self) -> Range<usize> {
+        self.next_slab_addr..self.heap_end
+    }
 
-        // If we are going beyond the heap section, return error.
-        if free_stack.len() == 0
-            && self.next_slab_addr >= KERNEL_HEAP_END {
+    /// Cut the heap at address `end`, which must be in the unused range.
+    pub fn shrink(&mut self, end: usize) {
+        assert!(self.next_slab_addr <= end && end <= self.heap_end);
+        self.heap_end = end;
+        // The memory added at `end` later is filled with zeroes again.
+        self.clean_addr = cmp::min(self.clean_addr, end);
+    }
+
+    /// Return the number of bytes in the heap that are not used by any
+    /// slab.
+    pub fn free_space(&self) -> usize {
+        self.heap_end - self.next_slab_addr + self.free_run_space
+    }
+
+    /// Give the slabs that have no allocated object back to the heap.
+    /// Return the number of slabs given back.
+    pub fn release_empty_slabs(&mut self) -> usize {
+        self.drain_quarantine(0);
+        let mut released = 0;
+        for index in 0..NUM_CACHES {
+            let mut slab = self.caches[index].slabs;
+            while !slab.is_null() {
+                let next = unsafe { (*slab).next };
+                if unsafe { (*slab).used } == 0 {
+                    self.release_slab(slab);
+                    released += 1;
+                }
+                slab = next;
+            }
+        }
+        released
+    }
+
+    /// Remove the empty slab `slab` from its cache and give it back to the
+    /// heap.
+    fn release_slab(&mut self, slab: *mut SlabHeader) {
+        unsafe {
+            self.caches[(*slab).cache_index].unlink(slab);
+            self.stats.caches[(*slab).cache_index].slabs -= 1;
+            // The pointers to the slab are not valid anymore.
+            (*slab).magic = 0;
+        }
+        self.free_run(slab as usize, SLAB_SIZE);
+    }
+
+    /// Take `length` bytes of slabs from a free run or from the unused part
+    /// of the heap. Return the address of the slabs and whether they are
+    /// known to be filled with zeroes.
+    fn take_slabs(&mut self, length: usize) -> Result<(usize, bool), AllocErr>
+    {
+        match self.take_free_run(length, None) {
+            Some(addr) => Ok((addr, false)),
+            None => self.take_new_slabs(length),
+        }
+    }
+
+    /// Take `length` bytes from the first free run that is large enough. If
+    /// `start` is given, only the run starting at `start` can be used.
+    fn take_free_run(&mut self, length: usize, start: Option<usize>)
+        -> Option<usize>
+    {
+        unsafe {
+            let mut link: *mut *mut FreeRun = &mut self.free_runs;
+            while !(*link).is_null() {
+                let run = *link;
+                let addr = run as usize;
+                if (*run).length >= length
+                    && start.map_or(true, |start| start == addr)
+                {
+                    self.free_run_space -= length;
+                    if (*run).length == length {
+                        *link = (*run).next;
+                    } else {
+                        let rest = (addr + length) as *mut FreeRun;
+                        ptr::write(rest, FreeRun {
+                            length: (*run).length - length,
+                            next: (*run).next,
+                        });
+                        *link = rest;
+                    }
+                    return Some(addr);
+                }
+                link = &mut (*run).next;
+            }
+        }
+        None
+    }
+
+    /// Take `length` bytes of slabs after the used part of the heap. Return
+    /// the address of the slabs and whether they are known to be filled
+    /// with zeroes.
+    fn take_new_slabs(&mut self, length: usize)
+        -> Result<(usize, bool), AllocErr>
+    {
+        if self.heap_end - self.next_slab_addr < length {
             return Err(AllocErr);
         }
+        let addr = self.next_slab_addr;
+        let zeroed = addr >= self.clean_addr;
+        self.next_slab_addr += length;
+        self.clean_addr = cmp::max(self.clean_addr, self.next_slab_addr);
+        Ok((addr, zeroed))
+    }
 
-        // If the cache has no free entry, allocate a new slab and push
-        // all new free entries into the stack.
-        if free_stack.len() == 0 {
+    /// Give back the run of slabs starting at `addr` with `length` bytes.
+    fn free_run(&mut self, addr: usize, length: usize) {
+        self.free_run_space += length;
+        unsafe {
+            // Find the runs right before and after the run.
+            let mut link: *mut *mut FreeRun = &mut self.free_runs;
+            let mut prev_link: *mut *mut FreeRun = ptr::null_mut();
+            while !(*link).is_null() && (*link as usize) < addr {
+                prev_link = link;
+                link = &mut (**link).next;
+            }
 
-            // Add SLAB_SIZE/size entries to the free stack.
-            for i in 0..SLAB_SIZE/size {
-                // The address of ith entry of the slab.
-                let phy_addr = i * size + self.next_slab_addr;
+            // Merge the run with the free runs next to it.
+            let (mut start, mut length) = (addr, length);
+            let mut next = *link;
+            if !next.is_null() && next as usize == addr + length {
+                length += (*next).length;
+                next = (*next).next;
+            }
+            if !prev_link.is_null() {
+                let prev = *prev_link;
+                if prev as usize + (*prev).length == addr {
+                    start = prev as usize;
+                    length += (*prev).length;
+                    link = prev_link;
+                }
+            }
 
-                free_stack.push(CacheEntry { phy_addr }).unwrap();
+            if start + length == self.next_slab_addr {
+                // The merged run becomes a part of the unused range.
+                self.free_run_space -= length;
+                self.next_slab_addr = start;
+                *link = next;
+                return;
             }
+            let run = start as *mut FreeRun;
+            ptr::write(run, FreeRun { length, next });
+            *link = run;
+        }
+    }
 
-            // Move next_slab_addr to the next available slab.
-            self.next_slab_addr += SLAB_SIZE;
+    /// Return the index of the cache that is used for the given layout, or
+    /// None if it needs a run of slabs.
+    fn cache_index(layout: Layout) -> Option<usize> {
+        // Every object in a cache is aligned to its size, so the size of
+        // memory that we want to allocate must be at least the alignment.
+        let size = cmp::max(layout.size(), layout.align());
+        let size = cmp::max(size, MIN_OBJECT_SIZE).next_power_of_two();
+        if size > MAX_OBJECT_SIZE {
+            None
+        } else {
+            Some(lg(size).unwrap() - lg(MIN_OBJECT_SIZE).unwrap())
         }
+    }
 
-        // Get a free entry from the stack.
-        let entry = free_stack.pop().unwrap();
-        let list_ref = allocated_list.push(entry).unwrap();
+    /// Return the number of slabs in the run that is used for an object of
+    /// `size` bytes.
+    fn run_slabs(size: usize) -> usize {
+        cmp::max((size + SLAB_SIZE - 1) / SLAB_SIZE, 1)
+    }
+
+    /// Return the header of the slab that contains the object at `addr`,
+    /// which must be allocated by a cache.
+    fn slab_of(addr: usize) -> Result<*mut SlabHeader, ()> {
+        let slab = (addr & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        unsafe {
+            let header = &*slab;
+            if header.magic != SLAB_MAGIC || addr < header.first_object()
+                || addr >= header.fresh
+                || (addr - header.first_object()) % header.object_size() != 0
+            {
+                return Err(());
+            }
+        }
+        Ok(slab)
+    }
 
-        let addr = allocated_list.get(&list_ref).phy_addr;
-        // Add an entry to the map.
-        self.addr_map.insert(addr, MapEntry {
-            cache_index: sizelg,
-            cache_entry: list_ref,
-        }).unwrap();
+    /// Allocate a kernel memory using a given layout.
+    pub fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        self.alloc_object(layout).map(|(addr, _)| addr as *mut _)
+    }
 
+    /// Allocate a kernel memory filled with zeroes using a given layout.
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn alloc_zeroed(&mut self, layout: Layout)
+        -> Result<*mut u8, AllocErr>
+    {
+        let (addr, zeroed) = self.alloc_object(layout)?;
+        if !zeroed {
+            unsafe {
+                ptr::write_bytes(addr as *mut u8, 0, layout.size());
+            }
+        }
         Ok(addr as *mut _)
     }
 
-    /// Deallocate a kernel memory using a ptr and layout.
-    pub fn dealloc(&mut self, ptr: *mut u8, _: Layout) -> Result<(), ()> {
-        let addr = ptr as usize;
-        let map_entry = self.addr_map.remove(addr)?;
+    /// Allocate a kernel memory using a given layout. Return the address
+    /// and whether the memory is known to be filled with zeroes.
+    fn alloc_object(&mut self, layout: Layout)
+        -> Result<(usize, bool), AllocErr>
+    {
+        if self.quarantine.is_none() {
+            return self.take_object(layout);
+        }
+        let outer = debug::outer_layout(layout).ok_or(AllocErr)?;
+        let mut result = self.take_object(outer);
+        // The memory in the quarantine can be used before the heap grows.
+        if result.is_err() && self.drain_quarantine(0) {
+            result = self.take_object(outer);
+        }
+        let (addr, zeroed) = result?;
+        let object = unsafe {
+            let site = Site::from_frame(self.caller);
+            let object = debug::arm(addr, layout, site);
+            self.live.as_mut().unwrap().insert(object, layout);
+            object
+        };
+        Ok((object, zeroed))
+    }
 
-        // Create new variables to make the code shorter.
-        let cache_index = map_entry.cache_index;
-        let cache_entry_ref = map_entry.cache_entry;
+    /// Allocate a kernel memory using a given layout without the heap
+    /// debugging.
+    fn take_object(&mut self, layout: Layout)
+        -> Result<(usize, bool), AllocErr>
+    {
+        let index = match AllocContext::cache_index(layout) {
+            Some(index) => index,
+            None => return self.alloc_large(layout),
+        };
 
-        let free_stack = &mut self.caches[cache_index].free_entries;
-        let allocated_list = &mut self.caches[cache_index].allocated_entries;
+        // If the cache has no free object, take a new slab.
+        if self.caches[index].slabs.is_null() {
+            let (addr, zeroed) = self.take_slabs(SLAB_SIZE)?;
+            let slab = addr as *mut SlabHeader;
+            unsafe {
+                ptr::write(slab, SlabHeader {
+                    magic: SLAB_MAGIC,
+                    cache_index: index,
+                    free: 0,
+                    fresh: 0,
+                    used: 0,
+                    zeroed,
+                    prev: ptr::null_mut(),
+                    next: ptr::null_mut(),
+                });
+                (*slab).fresh = (*slab).first_object();
+                self.caches[index].push(slab);
+            }
+            self.stats.caches[index].slabs += 1;
+        }
 
-        // Move the cache entry from the allocated list to the free stack.
-        let cache_entry = allocated_list.remove(cache_entry_ref);
-        free_stack.push(cache_entry).unwrap();
+        let slab_ptr = self.caches[index].slabs;
+        let slab = unsafe { &mut *slab_ptr };
+        // Take a free object, or a fresh one if there is none.
+        let (addr, zeroed) = if slab.free != 0 {
+            let addr = slab.free;
+            slab.free = unsafe { *(addr as *const usize) };
+            (addr, false)
+        } else {
+            let addr = slab.fresh;
+            slab.fresh += slab.object_size();
+            (addr, slab.zeroed)
+        };
+        slab.used += 1;
+        self.stats.caches[index].add_object(slab.object_size() - layout.size());
+        if slab.is_full() {
+            unsafe {
+                self.caches[index].unlink(slab_ptr);
+            }
+        }
+        Ok((addr, zeroed))
+    }
 
+    /// Allocate a run of contiguous slabs that is large enough for the
+    /// given layout.
+    fn alloc_large(&mut self, layout: Layout)
+        -> Result<(usize, bool), AllocErr>
+    {
+        // Runs are only aligned to SLAB_SIZE.
+        if layout.align() > SLAB_SIZE {
+            return Err(AllocErr);
+        }
+        let length = AllocContext::run_slabs(layout.size()) * SLAB_SIZE;
+        let result = self.take_slabs(length)?;
+        self.stats.large.slabs += length / SLAB_SIZE;
+        self.stats.large.add_object(length - layout.size());
+        Ok(result)
+    }
+
+    /// Deallocate a kernel memory using a ptr and layout. If the heap
+    /// debugging is enabled, the memory goes to the quarantine first, and
+    /// this panics instead of returning an error.
+    pub fn dealloc(&mut self, ptr: *mut u8, layout: Layout) -> Result<(), ()> {
+        if self.quarantine.is_none() {
+            return self.free_object(ptr as usize, layout);
+        }
+        let object = ptr as usize;
+        let outer = debug::outer_layout(layout);
+        let addr = object.wrapping_sub(debug::offset(layout));
+        if outer.map_or(true, |outer| !AllocContext::is_object(addr, outer))
+        {
+            panic!("free of {:#x}, which is not allocated by the heap",
+                   object);
+        }
+        unsafe {
+            let site = Site::from_frame(self.caller);
+            debug::disarm(object, layout, site);
+            self.live.as_mut().unwrap().remove(object, layout);
+            self.quarantine.as_mut().unwrap().push(object, layout);
+        }
+        self.drain_quarantine(debug::QUARANTINE_SIZE);
         Ok(())
     }
-}
 
-#[cfg(test)] const KERNEL_HEAP_START: usize = ::config::KERNEL_HEAP_START;
-#[cfg(test)] const KERNEL_HEAP_END: usize = KERNEL_HEAP_START + 8;
-#[cfg(test)] const SLAB_SIZE: usize = 4;
-#[cfg(test)] const NUM_CACHES: usize = 2 + 1;
+    /// Return true if `addr` can be the address of an object allocated
+    /// using `layout`.
+    fn is_object(addr: usize, layout: Layout) -> bool {
+        let slab_aligned = addr & (SLAB_SIZE - 1) == 0;
+        match AllocContext::cache_index(layout) {
+            Some(index) => !slab_aligned && match AllocContext::slab_of(addr) {
+                Ok(slab) => unsafe { (*slab).cache_index == index },
+                Err(()) => false,
+            },
+            None => slab_aligned && addr != 0,
+        }
+    }
+
+    /// Really free the objects in the quarantine, from the oldest one,
+    /// until it holds at most `bytes` bytes. Return true if any object is
+    /// freed.
+    fn drain_quarantine(&mut self, bytes: usize) -> bool {
+        let mut drained = false;
+        loop {
+            let object = match self.quarantine {
+                Some(ref mut quarantine) if quarantine.bytes() > bytes => {
+                    unsafe { quarantine.pop() }
+                },
+                _ => None,
+            };
+            let (addr, layout) = match object {
+                Some(object) => object,
+                None => return drained,
+            };
+            self.free_object(addr, layout)
+                .expect("the heap is corrupted");
+            drained = true;
+        }
+    }
+
+    /// Deallocate a kernel memory using an address and layout without the
+    /// heap debugging.
+    fn free_object(&mut self, addr: usize, layout: Layout) -> Result<(), ()> {
+        // Only runs of slabs start at the beginning of a slab because every
+        // slab used by a cache starts with a header.
+        if addr & (SLAB_SIZE - 1) == 0 {
+            if addr == 0 || AllocContext::cache_index(layout).is_some() {
+                return Err(());
+            }
+            let slabs = AllocContext::run_slabs(layout.size());
+            self.free_run(addr, slabs * SLAB_SIZE);
+            self.stats.large.slabs -= slabs;
+            self.stats.large.remove_object(slabs * SLAB_SIZE - layout.size());
+            return Ok(());
+        }
+
+        let slab_ptr = AllocContext::slab_of(addr)?;
+        let slab = unsafe { &mut *slab_ptr };
+        self.stats.caches[slab.cache_index]
+            .remove_object(slab.object_size().saturating_sub(layout.size()));
+        let was_full = slab.is_full();
+        unsafe {
+            *(addr as *mut usize) = slab.free;
+        }
+        slab.free = addr;
+        slab.used -= 1;
+
+        let release = {
+            let cache = &mut self.caches[slab.cache_index];
+            if was_full {
+                unsafe {
+                    cache.push(slab_ptr);
+                }
+            }
+            // Keep at most one empty slab in the cache, so that allocating
+            // and deallocating an object repeatedly doesn't take and give
+            // back the slab every time.
+            slab.used == 0 && (cache.slabs != slab_ptr || !slab.next.is_null())
+        };
+        if release {
+            self.release_slab(slab_ptr);
+        }
+        Ok(())
+    }
+
+    /// Resize the memory at `ptr`, which is allocated using `layout`, to
+    /// `new_size` bytes without moving it. Return an error if it can't be
+    /// done, in which case the memory is untouched.
+    pub fn resize(&mut self, ptr: *mut u8, layout: Layout, new_size: usize)
+        -> Result<(), ()>
+    {
+        // The objects are always moved when the heap debugging is enabled,
+        // so that the redzones are set up again.
+        if self.quarantine.is_some() {
+            return Err(());
+        }
+        let addr = ptr as usize;
+        let new_layout = Layout::from_size_align(new_size, layout.align())
+            .map_err(|_| ())?;
+        // The object stays in place if it still needs the same cache.
+        if let Some(index) = AllocContext::cache_index(layout) {
+            return match AllocContext::cache_index(new_layout) {
+                Some(new_index) if new_index == index => {
+                    let stats = &mut self.stats.caches[index];
+                    stats.wasted += layout.size();
+                    stats.wasted -= new_size;
+                    Ok(())
+                },
+                _ => Err(()),
+            };
+        }
+        if AllocContext::cache_index(new_layout).is_some() {
+            return Err(());
+        }
+
+        let length = AllocContext::run_slabs(layout.size()) * SLAB_SIZE;
+        let new_length = AllocContext::run_slabs(new_size) * SLAB_SIZE;
+        if new_length < length {
+            // Give back the slabs at the end of the run.
+            self.free_run(addr + new_length, length - new_length);
+        } else if new_length > length {
+            // Take the slabs right after the run, if they are free.
+            let end = addr + length;
+            let needed = new_length - length;
+            if self.take_free_run(needed, Some(end)).is_none() {
+                if end != self.next_slab_addr {
+                    return Err(());
+                }
+                self.take_new_slabs(needed).map_err(|_| ())?;
+            }
+        }
+        let stats = &mut self.stats.large;
+        stats.slabs += new_length / SLAB_SIZE;
+        stats.slabs -= length / SLAB_SIZE;
+        stats.wasted += new_length - new_size;
+        stats.wasted -= length - layout.size();
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::slice;
+    use test::Bencher;
     use super::*;
 
+    #[repr(align(4096))]
+    struct Slab([u8; SLAB_SIZE]);
+
+    /// Return the address of `slabs` slabs of memory that is never freed.
+    fn memory(slabs: usize) -> usize {
+        let memory: Vec<Slab> = (0..slabs).map(|_| Slab([0xff; SLAB_SIZE]))
+            .collect();
+        Box::leak(memory.into_boxed_slice()).as_ptr() as usize
+    }
+
+    /// Create an [AllocContext](AllocContext) whose heap has `slabs` slabs.
+    /// Return the context and the start of the heap.
+    fn heap(slabs: usize) -> (AllocContext, usize) {
+        let start = memory(slabs);
+        (AllocContext::new(start, start + slabs * SLAB_SIZE), start)
+    }
+
+    /// Create an [AllocContext](AllocContext) like [heap](heap) with the
+    /// heap debugging enabled.
+    fn debug_heap(slabs: usize) -> (AllocContext, usize) {
+        let (mut context, start) = heap(slabs);
+        context.enable_debug();
+        (context, start)
+    }
+
+    /// Return the start and the length of every free run.
+    fn free_runs(context: &AllocContext) -> Vec<(usize, usize)> {
+        let mut runs = Vec::new();
+        let mut run = context.free_runs;
+        while !run.is_null() {
+            unsafe {
+                runs.push((run as usize, (*run).length));
+                run = (*run).next;
+            }
+        }
+        runs
+    }
+
     #[test]
     fn allocate_slab_size() {
-        let mut context = AllocContext::new();
-        let layout = Layout::from_size_align(4, 4).unwrap();
+        let (mut context, start) = heap(1);
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
 
-        assert!(context.alloc(layout).is_ok());
+        assert_eq!(context.alloc(layout).unwrap() as usize, start);
     }
 
     #[test]
     fn allocate_beyond_slab_size() {
-        let mut context = AllocContext::new();
-        let layout = Layout::from_size_align(8, 8).unwrap();
+        let (mut context, _) = heap(4);
+        let layout = Layout::from_size_align(2 * SLAB_SIZE, 2 * SLAB_SIZE)
+            .unwrap();
 
+        // The alignment is larger than a slab.
         assert_eq!(context.alloc(layout).unwrap_err(), AllocErr);
     }
 
     #[test]
     fn allocate_beyond_heap() {
-        let mut context = AllocContext::new();
+        let (mut context, _) = heap(1);
         let layout = Layout::from_size_align(2, 2).unwrap();
 
-        assert!(context.alloc(layout).is_ok());
-        assert!(context.alloc(layout).is_ok());
-        assert!(context.alloc(layout).is_ok());
-        assert!(context.alloc(layout).is_ok());
+        // The header is at the start of the slab.
+        let header = mem::size_of::<SlabHeader>();
+        for _ in 0..(SLAB_SIZE - header) / 8 {
+            assert!(context.alloc(layout).is_ok());
+        }
         assert_eq!(context.alloc(layout).unwrap_err(), AllocErr);
     }
 
     #[test]
     fn allocate_slab_size_beyond_heap() {
-        let mut context = AllocContext::new();
-        let layout = Layout::from_size_align(4, 4).unwrap();
+        let (mut context, _) = heap(2);
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
 
         assert!(context.alloc(layout).is_ok());
         assert!(context.alloc(layout).is_ok());
         assert_eq!(context.alloc(layout).unwrap_err(), AllocErr);
     }
 
+    #[test]
+    fn allocate_size_larger_than_align() {
+        let (mut context, _) = heap(1);
+        let layout = Layout::from_size_align(100, 1).unwrap();
+
+        // The size is rounded up to 128 bytes.
+        let a1 = context.alloc(layout).unwrap() as usize;
+        let a2 = context.alloc(layout).unwrap() as usize;
+        assert_eq!(a1 % 128, 0);
+        assert_eq!(a2 % 128, 0);
+        assert_ne!(a1, a2);
+    }
+
+    #[test]
+    fn allocate_large() {
+        let (mut context, start) = heap(4);
+        let layout = Layout::from_size_align(3 * SLAB_SIZE + 1, 8).unwrap();
+
+        // It takes the whole heap.
+        let addr = context.alloc(layout).unwrap();
+        assert_eq!(addr as usize, start);
+        let small = Layout::from_size_align(1, 1).unwrap();
+        assert_eq!(context.alloc(small).unwrap_err(), AllocErr);
+        assert_eq!(context.alloc(layout).unwrap_err(), AllocErr);
+
+        // The slabs can be used again after they are freed.
+        assert!(context.dealloc(addr, layout).is_ok());
+        assert!(context.alloc(small).is_ok());
+        assert_eq!(context.alloc(layout).unwrap_err(), AllocErr);
+    }
+
+    #[test]
+    fn reuse_free_runs() {
+        let (mut context, _) = heap(4);
+        let layout = Layout::from_size_align(2 * SLAB_SIZE, 1).unwrap();
+        let a1 = context.alloc(layout).unwrap();
+        let a2 = context.alloc(layout).unwrap();
+        assert!(context.dealloc(a1, layout).is_ok());
+        assert_eq!(free_runs(&context), vec![(a1 as usize, 2 * SLAB_SIZE)]);
+
+        // The freed run is used again.
+        let other = Layout::from_size_align(SLAB_SIZE + 1, 1).unwrap();
+        assert_eq!(context.alloc(other).unwrap(), a1);
+        assert!(context.alloc(other).is_err());
+        assert!(context.dealloc(a2, layout).is_ok());
+        assert!(context.alloc(other).is_ok());
+    }
+
+    #[test]
+    fn merge_free_runs() {
+        let (mut context, start) = heap(4);
+        context.next_slab_addr = start + 4 * SLAB_SIZE;
+
+        context.free_run(start + 2 * SLAB_SIZE, SLAB_SIZE);
+        context.free_run(start, SLAB_SIZE);
+        assert_eq!(free_runs(&context), vec![(start, SLAB_SIZE),
+                                             (start + 2 * SLAB_SIZE,
+                                              SLAB_SIZE)]);
+        assert_eq!(context.free_space(), 2 * SLAB_SIZE);
+        // The runs around it are merged.
+        context.free_run(start + SLAB_SIZE, SLAB_SIZE);
+        assert_eq!(free_runs(&context), vec![(start, 3 * SLAB_SIZE)]);
+        assert_eq!(context.free_space(), 3 * SLAB_SIZE);
+        // The merged run is given back to the heap.
+        context.free_run(start + 3 * SLAB_SIZE, SLAB_SIZE);
+        assert_eq!(free_runs(&context), vec![]);
+        assert_eq!(context.next_slab_addr, start);
+        assert_eq!(context.free_space(), 4 * SLAB_SIZE);
+    }
+
+    #[test]
+    fn resize_small_in_place() {
+        let (mut context, _) = heap(1);
+        let layout = Layout::from_size_align(100, 1).unwrap();
+        let addr = context.alloc(layout).unwrap();
+
+        // The object can be resized within its size class only.
+        assert!(context.resize(addr, layout, 128).is_ok());
+        assert!(context.resize(addr, layout, 64).is_err());
+        assert!(context.resize(addr, layout, 129).is_err());
+    }
+
+    #[test]
+    fn resize_large_in_place() {
+        let (mut context, start) = heap(4);
+        let layout = Layout::from_size_align(2 * SLAB_SIZE, 1).unwrap();
+        let addr = context.alloc(layout).unwrap();
+
+        // Grow into the rest of the heap.
+        assert!(context.resize(addr, layout, 3 * SLAB_SIZE).is_ok());
+        assert_eq!(context.next_slab_addr, start + 3 * SLAB_SIZE);
+        // Shrink and give back the slab at the end.
+        let layout = Layout::from_size_align(3 * SLAB_SIZE, 1).unwrap();
+        assert!(context.resize(addr, layout, 2 * SLAB_SIZE).is_ok());
+        assert_eq!(context.next_slab_addr, start + 2 * SLAB_SIZE);
+        let layout = Layout::from_size_align(2 * SLAB_SIZE, 1).unwrap();
+        assert!(context.resize(addr, layout, 4 * SLAB_SIZE + 1).is_err());
+        assert!(context.resize(addr, layout, 100).is_err());
+    }
+
+    #[test]
+    fn resize_into_free_run() {
+        let (mut context, start) = heap(5);
+        let layout = Layout::from_size_align(2 * SLAB_SIZE, 1).unwrap();
+        let addr = context.alloc(layout).unwrap();
+        let next = context.alloc(layout).unwrap();
+        let slab = Layout::from_size_align(SLAB_SIZE, 1).unwrap();
+        assert!(context.alloc(slab).is_ok());
+
+        // Free the run after it.
+        assert!(context.dealloc(next, layout).is_ok());
+        assert!(context.resize(addr, layout, 3 * SLAB_SIZE).is_ok());
+        assert_eq!(free_runs(&context),
+                   vec![(start + 3 * SLAB_SIZE, SLAB_SIZE)]);
+        let layout = Layout::from_size_align(3 * SLAB_SIZE, 1).unwrap();
+        assert!(context.resize(addr, layout, 4 * SLAB_SIZE).is_ok());
+        assert_eq!(free_runs(&context), vec![]);
+        let layout = Layout::from_size_align(4 * SLAB_SIZE, 1).unwrap();
+        assert!(context.resize(addr, layout, 4 * SLAB_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn zeroed_fresh_memory() {
+        let (mut context, _) = heap(4);
+        let layout = Layout::from_size_align(2, 2).unwrap();
+
+        // Objects in a new slab are known to be zero until they are freed.
+        let (addr, zeroed) = context.alloc_object(layout).unwrap();
+        assert!(zeroed);
+        assert!(context.dealloc(addr as *mut _, layout).is_ok());
+        assert_eq!(context.alloc_object(layout).unwrap(), (addr, false));
+        let (_, zeroed) = context.alloc_object(layout).unwrap();
+        assert!(zeroed);
+
+        // A freed run is not zero, even if it is given back to the heap.
+        let large = Layout::from_size_align(SLAB_SIZE + 1, 1).unwrap();
+        let (addr, zeroed) = context.alloc_object(large).unwrap();
+        assert!(zeroed);
+        assert!(context.dealloc(addr as *mut _, large).is_ok());
+        let (_, zeroed) = context.alloc_object(large).unwrap();
+        assert!(!zeroed);
+    }
+
+    #[test]
+    fn extend_heap() {
+        let (mut context, start) = heap(2);
+        let layout = Layout::from_size_align(SLAB_SIZE, 1).unwrap();
+        assert!(context.alloc(layout).is_ok());
+
+        // The rest of the old heap becomes a free run when the heap moves.
+        let other = memory(2);
+        context.extend(other, SLAB_SIZE);
+        assert_eq!(context.free_space(), 2 * SLAB_SIZE);
+        assert_eq!(context.alloc(layout).unwrap() as usize,
+                   start + SLAB_SIZE);
+        assert_eq!(context.alloc_object(layout).unwrap(), (other, true));
+        assert!(context.alloc(layout).is_err());
+
+        context.extend(other + SLAB_SIZE, SLAB_SIZE);
+        assert_eq!(context.alloc(layout).unwrap() as usize,
+                   other + SLAB_SIZE);
+    }
+
+    #[test]
+    fn shrink_heap() {
+        let (mut context, start) = heap(2);
+        let layout = Layout::from_size_align(SLAB_SIZE, 1).unwrap();
+        let addr = context.alloc(layout).unwrap();
+        assert_eq!(context.unused(), start + SLAB_SIZE..start + 2 * SLAB_SIZE);
+
+        context.shrink(start + SLAB_SIZE);
+        assert_eq!(context.free_space(), 0);
+        assert!(context.alloc(layout).is_err());
+
+        // The freed memory is not zero, but the memory added again is.
+        assert!(context.dealloc(addr, layout).is_ok());
+        context.extend(start + SLAB_SIZE, SLAB_SIZE);
+        assert_eq!(context.alloc_object(layout).unwrap(), (start, false));
+        assert_eq!(context.alloc_object(layout).unwrap(),
+                   (start + SLAB_SIZE, true));
+    }
+
     #[test]
     fn allocate_different_sizes() {
-        let mut context = AllocContext::new();
-        let layout1 = Layout::from_size_align(2, 2).unwrap();
-        let layout2 = Layout::from_size_align(2, 4).unwrap();
-        let layout3 = Layout::from_size_align(1, 1).unwrap();
+        let (mut context, _) = heap(3);
+        let layout1 = Layout::from_size_align(8, 8).unwrap();
+        let layout2 = Layout::from_size_align(8, 16).unwrap();
+        let layout3 = Layout::from_size_align(32, 1).unwrap();
 
         assert!(context.alloc(layout1).is_ok());
         assert!(context.alloc(layout2).is_ok());
-        // The last one should fail because it will need three slabs for
-        // three different sizes.
-        assert_eq!(context.alloc(layout3).unwrap_err(), AllocErr);
+        assert!(context.alloc(layout3).is_ok());
+        // The last one should fail because the three different sizes
+        // already take three slabs.
+        let layout4 = Layout::from_size_align(64, 1).unwrap();
+        assert_eq!(context.alloc(layout4).unwrap_err(), AllocErr);
     }
 
     #[test]
     fn different_valid_allocated_addresses() {
-        let mut context = AllocContext::new();
+        let (mut context, start) = heap(1);
         let layout = Layout::from_size_align(2, 2).unwrap();
         let mut output: [*mut u8; 4] = [ptr::null_mut(); 4];
         let mut expected: [*mut u8; 4] = [ptr::null_mut(); 4];
 
         for i in 0..4 {
             output[i] = context.alloc(layout).unwrap();
-            expected[i] = (KERNEL_HEAP_START + i * 2) as *mut _;
+            let header = mem::size_of::<SlabHeader>();
+            expected[i] = (start + header + i * 8) as *mut _;
         }
 
         output.sort();
@@ -253,16 +1078,32 @@ mod tests {
     #[test]
     #[should_panic]
     fn dealloc_non_allocated_address() {
-        let mut context = AllocContext::new();
+        let (mut context, start) = heap(1);
         let layout = Layout::from_size_align(1, 1).unwrap();
 
         // This should return error.
-        context.dealloc(KERNEL_HEAP_START as *mut _, layout).unwrap();
+        context.dealloc(start as *mut _, layout).unwrap();
+    }
+
+    #[test]
+    fn dealloc_invalid_addresses() {
+        let (mut context, _) = heap(2);
+        let layout = Layout::from_size_align(16, 16).unwrap();
+        let addr = context.alloc(layout).unwrap() as usize;
+        let large = Layout::from_size_align(SLAB_SIZE, 1).unwrap();
+        let run = context.alloc(large).unwrap() as usize;
+
+        // The middle of an object, an object that is never allocated and
+        // the middle of a run.
+        assert!(context.dealloc((addr + 8) as *mut _, layout).is_err());
+        assert!(context.dealloc((addr + 16) as *mut _, layout).is_err());
+        assert!(context.dealloc((run + 16) as *mut _, layout).is_err());
+        assert!(context.dealloc(addr as *mut _, layout).is_ok());
     }
 
     #[test]
     fn dealloc_alternate_with_alloc() {
-        let mut context = AllocContext::new();
+        let (mut context, _) = heap(1);
         let layout = Layout::from_size_align(2, 2).unwrap();
 
         let a1 = context.alloc(layout).unwrap();
@@ -278,4 +1119,273 @@ mod tests {
         assert!(context.dealloc(a2, layout).is_ok());
         assert!(context.dealloc(a4, layout).is_ok());
     }
+
+    #[test]
+    fn keep_one_empty_slab() {
+        let (mut context, _) = heap(4);
+        let layout = Layout::from_size_align(1024, 1).unwrap();
+
+        // There are three objects in each slab.
+        let objects: Vec<_> = (0..6).map(|_| context.alloc(layout).unwrap())
+            .collect();
+        assert_eq!(context.free_space(), 2 * SLAB_SIZE);
+        for object in objects {
+            assert!(context.dealloc(object, layout).is_ok());
+        }
+        assert_eq!(context.free_space(), 3 * SLAB_SIZE);
+
+        assert_eq!(context.release_empty_slabs(), 1);
+        assert_eq!(context.free_space(), 4 * SLAB_SIZE);
+    }
+
+    #[test]
+    fn many_live_objects() {
+        let (mut context, start) = heap(16);
+        let layout = Layout::from_size_align(8, 8).unwrap();
+
+        let mut objects: Vec<_> = (0..5000)
+            .map(|_| context.alloc(layout).unwrap())
+            .collect();
+        objects.sort();
+        objects.dedup();
+        assert_eq!(objects.len(), 5000);
+        for object in objects {
+            assert!(context.dealloc(object, layout).is_ok());
+        }
+        context.release_empty_slabs();
+        assert_eq!(context.next_slab_addr, start);
+    }
+
+    #[test]
+    fn debug_quarantine() {
+        let (mut context, _) = debug_heap(1);
+        let layout = Layout::from_size_align(8, 8).unwrap();
+
+        // The freed object is not used again while there is other memory.
+        let a1 = context.alloc(layout).unwrap();
+        assert!(context.dealloc(a1, layout).is_ok());
+        let mut objects = 0;
+        while context.alloc(layout).unwrap() != a1 {
+            objects += 1;
+        }
+        // It's used again only when there is nothing else left.
+        assert!(objects > 1);
+        assert_eq!(context.alloc(layout).unwrap_err(), AllocErr);
+    }
+
+    #[test]
+    fn debug_large_objects() {
+        let (mut context, _) = debug_heap(4);
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+
+        let addr = context.alloc(layout).unwrap();
+        assert_eq!(addr as usize % SLAB_SIZE, 0);
+        // The object is never resized in place.
+        assert!(context.resize(addr, layout, SLAB_SIZE + 1).is_err());
+        assert!(context.dealloc(addr, layout).is_ok());
+        assert_eq!(context.release_empty_slabs(), 0);
+        assert_eq!(context.free_space(), 4 * SLAB_SIZE);
+    }
+
+    #[test]
+    fn debug_zeroed_objects() {
+        let (mut context, _) = debug_heap(2);
+        let layout = Layout::from_size_align(100, 4).unwrap();
+
+        let addr = context.alloc_zeroed(layout).unwrap();
+        let bytes = unsafe { slice::from_raw_parts(addr, 100) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn debug_double_free() {
+        let (mut context, _) = debug_heap(1);
+        let layout = Layout::from_size_align(8, 8).unwrap();
+
+        let addr = context.alloc(layout).unwrap();
+        let _ = context.dealloc(addr, layout);
+        let _ = context.dealloc(addr, layout);
+    }
+
+    #[test]
+    #[should_panic(expected = "not allocated by the heap")]
+    fn debug_invalid_free() {
+        let (mut context, _) = debug_heap(1);
+        let layout = Layout::from_size_align(8, 8).unwrap();
+
+        let addr = context.alloc(layout).unwrap() as usize;
+        let _ = context.dealloc((addr + 8) as *mut _, layout);
+    }
+
+    #[test]
+    #[should_panic(expected = "out-of-bounds write")]
+    fn debug_out_of_bounds_write() {
+        let (mut context, _) = debug_heap(1);
+        let layout = Layout::from_size_align(10, 2).unwrap();
+
+        let addr = context.alloc(layout).unwrap();
+        unsafe {
+            *addr.offset(10) = 1;
+        }
+        let _ = context.dealloc(addr, layout);
+    }
+
+    #[test]
+    #[should_panic(expected = "write after free")]
+    fn debug_write_after_free() {
+        let (mut context, _) = debug_heap(1);
+        let layout = Layout::from_size_align(10, 2).unwrap();
+
+        let addr = context.alloc(layout).unwrap();
+        let _ = context.dealloc(addr, layout);
+        unsafe {
+            *addr = 1;
+        }
+        context.release_empty_slabs();
+    }
+
+    #[test]
+    fn count_objects() {
+        let (mut context, _) = heap(4);
+        let layout = Layout::from_size_align(100, 4).unwrap();
+        let large = Layout::from_size_align(SLAB_SIZE + 1, 8).unwrap();
+
+        let a1 = context.alloc(layout).unwrap();
+        let a2 = context.alloc(layout).unwrap();
+        let a3 = context.alloc(large).unwrap();
+        assert!(context.dealloc(a1, layout).is_ok());
+        let stats = context.stats();
+        assert_eq!(stats.caches[4], ClassStats {
+            slabs: 1,
+            objects: 1,
+            peak_objects: 2,
+            failures: 0,
+            wasted: 28,
+        });
+        assert_eq!(stats.large, ClassStats {
+            slabs: 2,
+            objects: 1,
+            peak_objects: 1,
+            failures: 0,
+            wasted: SLAB_SIZE - 1,
+        });
+        assert_eq!(stats.free_space, SLAB_SIZE);
+
+        // Resizing changes the wasted bytes.
+        assert!(context.resize(a2, layout, 128).is_ok());
+        assert!(context.resize(a3, large, 1).is_err());
+        assert_eq!(context.stats().caches[4].wasted, 0);
+        assert!(context.resize(a3, large, 3 * SLAB_SIZE).is_ok());
+        assert_eq!(context.stats().large.slabs, 3);
+        assert_eq!(context.stats().large.wasted, 0);
+
+        let layout = Layout::from_size_align(128, 4).unwrap();
+        assert!(context.dealloc(a2, layout).is_ok());
+        context.release_empty_slabs();
+        assert_eq!(context.stats().caches[4].slabs, 0);
+        assert_eq!(context.stats().caches[4].objects, 0);
+    }
+
+    #[test]
+    fn count_failures() {
+        let (mut context, _) = heap(1);
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let large = Layout::from_size_align(SLAB_SIZE + 1, 8).unwrap();
+
+        context.record_failure(layout);
+        context.record_failure(large);
+        context.record_failure(large);
+        let stats = context.stats();
+        assert_eq!(stats.caches[0].failures, 1);
+        assert_eq!(stats.large.failures, 2);
+    }
+
+    #[test]
+    fn display_stats() {
+        let (mut context, _) = heap(2);
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        assert!(context.alloc(layout).is_ok());
+
+        let text = format!("{}", context.stats());
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), NUM_CACHES + 3);
+        assert_eq!(lines[0],
+                   "  size  slabs  objects     peak   failed   wasted");
+        assert_eq!(lines[1],
+                   "     8      1        1        1        0        0");
+        assert_eq!(lines[NUM_CACHES + 2], "4096 bytes free");
+    }
+
+    #[test]
+    fn find_leaks() {
+        let (mut context, _) = debug_heap(4);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+
+        let a1 = context.alloc(layout).unwrap();
+        let snapshot = context.snapshot().unwrap();
+        let a2 = context.alloc(layout).unwrap();
+        let a3 = context.alloc(layout).unwrap();
+        let a4 = context.alloc(layout).unwrap();
+        assert!(context.dealloc(a3, layout).is_ok());
+
+        let mut leaks = Vec::new();
+        context.for_each_leak(snapshot, |object| {
+            assert_eq!(format!("{}", object.site), "<unknown>");
+            leaks.push((object.addr as *mut u8, object.size));
+        });
+        assert_eq!(leaks, vec![(a4, 24), (a2, 24)]);
+        assert!(context.dealloc(a1, layout).is_ok());
+
+        // The objects are not tracked without the heap debugging.
+        assert!(heap(1).0.snapshot().is_none());
+    }
+
+    #[bench]
+    fn bench_small_objects(b: &mut Bencher) {
+        let (mut context, _) = heap(16);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let mut objects = Vec::with_capacity(1000);
+        b.iter(|| {
+            for _ in 0..1000 {
+                objects.push(context.alloc(layout).unwrap());
+            }
+            for object in objects.drain(..) {
+                context.dealloc(object, layout).unwrap();
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_mixed_objects(b: &mut Bencher) {
+        let (mut context, _) = heap(128);
+        let layouts: Vec<_> = (0..8)
+            .map(|i| Layout::from_size_align(MIN_OBJECT_SIZE << i, 8).unwrap())
+            .collect();
+        let mut objects = Vec::with_capacity(1000);
+        b.iter(|| {
+            for i in 0..1000 {
+                let layout = layouts[i % layouts.len()];
+                objects.push((context.alloc(layout).unwrap(), layout));
+            }
+            for (object, layout) in objects.drain(..) {
+                context.dealloc(object, layout).unwrap();
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_large_objects(b: &mut Bencher) {
+        let (mut context, _) = heap(64);
+        let layout = Layout::from_size_align(3 * SLAB_SIZE, 8).unwrap();
+        let mut objects = Vec::with_capacity(16);
+        b.iter(|| {
+            for _ in 0..16 {
+                objects.push(context.alloc(layout).unwrap());
+            }
+            for object in objects.drain(..) {
+                context.dealloc(object, layout).unwrap();
+            }
+        });
+    }
 }
@@ -15,27 +15,100 @@
 // along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
 #![cfg(not(test))]
 
-use core::alloc::{GlobalAlloc, Layout};
-use core::ptr;
-use ::kalloc::CONTEXT;
+use core::alloc::{AllocErr, GlobalAlloc, Layout};
+use core::{cmp, ptr};
+use ::backtrace::frame_pointer;
+use ::kalloc::alloc_context::AllocContext;
+use ::kalloc::{CONTEXT, grow, keep_reserve};
+
+/// Allocate a kernel memory for `layout` using `alloc` for the caller of
+/// the function whose frame pointer is `caller`. If there is not enough
+/// memory in the heap, grow the heap and try again.
+unsafe fn alloc_with<F>(layout: Layout, caller: usize, alloc: F) -> *mut u8
+    where F: Fn(&mut AllocContext) -> Result<*mut u8, AllocErr>
+{
+    let alloc = |context: &mut AllocContext| {
+        context.set_caller(caller);
+        alloc(context)
+    };
+    // We have to make sure that we already initialized the alloc module
+    // before allocating any memory. The context must not be locked while
+    // the heap is growing.
+    let (mut result, mut free_space) = {
+        let mut context = CONTEXT.lock();
+        let context = context.as_mut().unwrap();
+        (alloc(context), context.free_space())
+    };
+    if result.is_err() {
+        // Even if the heap can't grow as much as we want, some memory may
+        // be added, so we try again anyway.
+        let _ = grow(layout.size());
+        let mut context = CONTEXT.lock();
+        let context = context.as_mut().unwrap();
+        result = alloc(context);
+        if result.is_err() {
+            context.record_failure(layout);
+        }
+        free_space = context.free_space();
+    }
+    keep_reserve(free_space);
+    result.unwrap_or(ptr::null_mut())
+}
+
+/// Deallocate a kernel memory using a ptr and layout for the caller of the
+/// function whose frame pointer is `caller`.
+unsafe fn dealloc_from(ptr: *mut u8, layout: Layout, caller: usize) {
+    let mut context = CONTEXT.lock();
+    let context = context.as_mut().unwrap();
+    context.set_caller(caller);
+    // If the memory is not already allocated, just silently return from
+    // the function. We don't want to panic because this is a usual
+    // situation that will happen so often. The heap panics by itself if the
+    // heap debugging is enabled.
+    let _ = context.dealloc(ptr, layout);
+}
 
 /// Empty structure to used in Rust's `global_allocator` feature.
 pub struct Allocator;
 
+// The functions are inlined into the ones that Rust calls to allocate the
+// memory, so that the allocations are recorded as made by their callers
+// instead of somewhere inside the allocator.
 unsafe impl GlobalAlloc for Allocator {
+    #[inline(always)]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // We have to make sure that we already initialized the alloc module
-        // before allocating any memory.
-        match CONTEXT.as_mut().unwrap().alloc(layout) {
-            Ok(addr) => addr,
-            Err(_) => ptr::null_mut(),
-        }
+        alloc_with(layout, frame_pointer(), |context| context.alloc(layout))
     }
-    #[allow(unused_must_use)]
+    #[inline(always)]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // If the memory is not already allocated, just silently return
-        // from the function. We don't want to panic because this is a usual
-        // situation that will happen so often.
-        CONTEXT.as_mut().unwrap().dealloc(ptr, layout);
+        dealloc_from(ptr, layout, frame_pointer());
+    }
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        alloc_with(layout, frame_pointer(),
+                   |context| context.alloc_zeroed(layout))
+    }
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize)
+        -> *mut u8
+    {
+        let caller = frame_pointer();
+        // Try to resize the memory in place first, so that we don't need to
+        // copy it.
+        let resized = CONTEXT.lock().as_mut().unwrap()
+            .resize(ptr, layout, new_size);
+        if resized.is_ok() {
+            return ptr;
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size,
+                                                           layout.align());
+        let new_ptr = alloc_with(new_layout, caller,
+                                 |context| context.alloc(new_layout));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr,
+                                     cmp::min(layout.size(), new_size));
+            dealloc_from(ptr, layout, caller);
+        }
+        new_ptr
     }
 }
@@ -0,0 +1,391 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Heap debugging. Every object is put between two redzones filled with a
+//! known pattern, and a freed object is poisoned and kept in a quarantine
+//! for a while before its memory is used again. The patterns are checked
+//! when the object is freed and when it leaves the quarantine, so that
+//! out-of-bounds writes and writes after free are caught. The objects that
+//! are not freed yet are also tracked, so that leaks can be found.
+
+use core::alloc::Layout;
+use core::{cmp, mem, ptr};
+use ::backtrace::Site;
+
+/// The number of bytes in each redzone. The redzone before an object may be
+/// longer because of the alignment.
+const REDZONE_SIZE: usize = 16;
+/// The byte that fills the redzones.
+const REDZONE_BYTE: u8 = 0xbb;
+/// The byte that fills the freed objects.
+const POISON_BYTE: u8 = 0x6b;
+/// The value in the header of an allocated object.
+const LIVE_MAGIC: usize = 0xa11c_a11c;
+/// The value in the header of a freed object.
+const FREED_MAGIC: usize = 0xf4ee_f4ee;
+/// The number of bytes that the quarantine can hold before the oldest
+/// objects in it are really freed.
+pub const QUARANTINE_SIZE: usize = 0x4_0000;
+
+/// The header at the start of the memory of every object.
+#[repr(C)]
+struct Header {
+    // The next object in the quarantine. When the object leaves the
+    // quarantine, this is overwritten by the list of free objects.
+    next: *mut Header,
+    // The links in the list of live objects.
+    prev_live: *mut Header,
+    next_live: *mut Header,
+    serial: u64,
+    magic: usize,
+    // The layout used to allocate the object.
+    size: usize,
+    align: usize,
+    alloc_site: Site,
+    free_site: Site,
+}
+
+/// Return the offset of an object with the given layout from the start of
+/// its memory.
+pub fn offset(layout: Layout) -> usize {
+    let front = mem::size_of::<Header>() + REDZONE_SIZE;
+    (front + layout.align() - 1) & !(layout.align() - 1)
+}
+
+/// Return the layout of the memory that holds an object with the given
+/// layout, its header, and its redzones.
+pub fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = offset(layout).checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    let align = cmp::max(layout.align(), mem::align_of::<Header>());
+    Layout::from_size_align(size, align).ok()
+}
+
+/// Return true if all the `length` bytes at `addr` are `byte`.
+unsafe fn filled(addr: usize, length: usize, byte: u8) -> bool {
+    (addr..addr + length).all(|addr| *(addr as *const u8) == byte)
+}
+
+/// Write the header and the redzones of an object with the given layout
+/// to the memory at `addr`, which is allocated by `site`. Return the
+/// address of the object.
+pub unsafe fn arm(addr: usize, layout: Layout, site: Site) -> usize {
+    let object = addr + offset(layout);
+    ptr::write(addr as *mut Header, Header {
+        next: ptr::null_mut(),
+        prev_live: ptr::null_mut(),
+        next_live: ptr::null_mut(),
+        serial: 0,
+        magic: LIVE_MAGIC,
+        size: layout.size(),
+        align: layout.align(),
+        alloc_site: site,
+        free_site: Site::default(),
+    });
+    let redzone = addr + mem::size_of::<Header>();
+    ptr::write_bytes(redzone as *mut u8, REDZONE_BYTE, object - redzone);
+    ptr::write_bytes((object + layout.size()) as *mut u8, REDZONE_BYTE,
+                     REDZONE_SIZE);
+    object
+}
+
+/// Check the header and the redzones of the object at `object`, which is
+/// freed by `site` using `layout`, and poison it. Panic if the object is
+/// not allocated or its redzones are overwritten.
+pub unsafe fn disarm(object: usize, layout: Layout, site: Site) {
+    let addr = object - offset(layout);
+    let header = &mut *(addr as *mut Header);
+    match header.magic {
+        LIVE_MAGIC => {},
+        FREED_MAGIC => panic!("double free of {:#x} allocated at {} and \
+                               freed at {}", object, header.alloc_site,
+                              header.free_site),
+        _ => panic!("free of {:#x}, which is not allocated", object),
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!("free of {:#x} with a wrong layout, allocated at {}", object,
+               header.alloc_site);
+    }
+    let redzone = addr + mem::size_of::<Header>();
+    if !filled(redzone, object - redzone, REDZONE_BYTE)
+        || !filled(object + layout.size(), REDZONE_SIZE, REDZONE_BYTE)
+    {
+        panic!("out-of-bounds write to {:#x} allocated at {}", object,
+               header.alloc_site);
+    }
+    header.magic = FREED_MAGIC;
+    header.free_site = site;
+    ptr::write_bytes(object as *mut u8, POISON_BYTE, layout.size());
+}
+
+/// The freed objects that are not used again yet, from the oldest one.
+pub struct Quarantine {
+    head: *mut Header,
+    tail: *mut Header,
+    // The number of bytes of memory taken by the objects.
+    bytes: usize,
+}
+
+impl Quarantine {
+    pub const fn new() -> Quarantine {
+        Quarantine {
+            head: ptr::null_mut(),
+            tail: ptr::null_mut(),
+            bytes: 0,
+        }
+    }
+
+    /// Return the number of bytes of memory taken by the objects.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Add the object at `object` with the given layout, which is already
+    /// disarmed, to the quarantine.
+    pub unsafe fn push(&mut self, object: usize, layout: Layout) {
+        let header = (object - offset(layout)) as *mut Header;
+        (*header).next = ptr::null_mut();
+        if self.tail.is_null() {
+            self.head = header;
+        } else {
+            (*self.tail).next = header;
+        }
+        self.tail = header;
+        self.bytes += outer_layout(layout).unwrap().size();
+    }
+
+    /// Remove the oldest object from the quarantine. Return the address
+    /// and the layout of its memory. Panic if the object is written after
+    /// it's freed.
+    pub unsafe fn pop(&mut self) -> Option<(usize, Layout)> {
+        if self.head.is_null() {
+            return None;
+        }
+        let header = &*self.head;
+        self.head = header.next;
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+
+        let layout = Layout::from_size_align_unchecked(header.size,
+                                                       header.align);
+        let addr = header as *const _ as usize;
+        let object = addr + offset(layout);
+        if !filled(object, layout.size(), POISON_BYTE) {
+            panic!("write after free to {:#x} allocated at {} and freed \
+                    at {}", object, header.alloc_site, header.free_site);
+        }
+        let outer = outer_layout(layout).unwrap();
+        self.bytes -= outer.size();
+        Some((addr, outer))
+    }
+}
+
+/// An object that is allocated and not freed yet.
+pub struct LiveObject {
+    pub addr: usize,
+    pub size: usize,
+    /// The number of objects allocated before it.
+    pub serial: u64,
+    pub site: Site,
+}
+
+/// The objects that are allocated and not freed yet, from the newest one.
+pub struct LiveObjects {
+    head: *mut Header,
+    next_serial: u64,
+}
+
+impl LiveObjects {
+    pub const fn new() -> LiveObjects {
+        LiveObjects {
+            head: ptr::null_mut(),
+            next_serial: 0,
+        }
+    }
+
+    /// Return the serial number of the next object.
+    pub fn next_serial(&self) -> u64 {
+        self.next_serial
+    }
+
+    /// Add the object at `object` with the given layout, which is just
+    /// armed, to the list.
+    pub unsafe fn insert(&mut self, object: usize, layout: Layout) {
+        let header = (object - offset(layout)) as *mut Header;
+        (*header).serial = self.next_serial;
+        self.next_serial += 1;
+        (*header).prev_live = ptr::null_mut();
+        (*header).next_live = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev_live = header;
+        }
+        self.head = header;
+    }
+
+    /// Remove the object at `object` with the given layout from the list.
+    pub unsafe fn remove(&mut self, object: usize, layout: Layout) {
+        let header = (object - offset(layout)) as *mut Header;
+        let (prev, next) = ((*header).prev_live, (*header).next_live);
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next_live = next;
+        }
+        if !next.is_null() {
+            (*next).prev_live = prev;
+        }
+    }
+
+    /// Call `f` for every object whose serial number is at least `serial`,
+    /// from the newest one.
+    pub fn for_each_since<F>(&self, serial: u64, mut f: F)
+        where F: FnMut(&LiveObject)
+    {
+        let mut header = self.head;
+        while !header.is_null() {
+            let header_ref = unsafe { &*header };
+            // The list is sorted by the serial numbers.
+            if header_ref.serial < serial {
+                break;
+            }
+            let layout = unsafe {
+                Layout::from_size_align_unchecked(header_ref.size,
+                                                  header_ref.align)
+            };
+            f(&LiveObject {
+                addr: header as usize + offset(layout),
+                size: header_ref.size,
+                serial: header_ref.serial,
+                site: header_ref.alloc_site,
+            });
+            header = header_ref.next_live;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    /// Return the address of a buffer that fits an object with the given
+    /// layout.
+    fn buffer(layout: Layout) -> usize {
+        let outer = outer_layout(layout).unwrap();
+        let buffer = vec![0_u64; outer.size() / 8 + 1];
+        let addr = buffer.as_ptr() as usize;
+        assert_eq!(addr % outer.align(), 0);
+        ::core::mem::forget(buffer);
+        addr
+    }
+
+    #[test]
+    fn object_layout() {
+        let layout = Layout::from_size_align(10, 2).unwrap();
+        let outer = outer_layout(layout).unwrap();
+        assert!(offset(layout) >= mem::size_of::<Header>() + REDZONE_SIZE);
+        assert_eq!(outer.size(), offset(layout) + 10 + REDZONE_SIZE);
+        assert_eq!(outer.align(), 8);
+
+        let layout = Layout::from_size_align(10, 256).unwrap();
+        assert_eq!(offset(layout) % 256, 0);
+        assert_eq!(outer_layout(layout).unwrap().align(), 256);
+        let layout = Layout::from_size_align(usize::max_value() - 8, 8)
+            .unwrap();
+        assert!(outer_layout(layout).is_none());
+    }
+
+    #[test]
+    fn arm_and_disarm() {
+        let layout = Layout::from_size_align(10, 8).unwrap();
+        let addr = buffer(layout);
+        let object = unsafe { arm(addr, layout, Site::here()) };
+        assert_eq!(object, addr + offset(layout));
+        unsafe {
+            assert!(filled(object + 10, REDZONE_SIZE, REDZONE_BYTE));
+            disarm(object, layout, Site::here());
+            assert!(filled(object, 10, POISON_BYTE));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "out-of-bounds write")]
+    fn write_past_the_end() {
+        let layout = Layout::from_size_align(10, 8).unwrap();
+        unsafe {
+            let object = arm(buffer(layout), layout, Site::here());
+            *((object + 10) as *mut u8) = 0;
+            disarm(object, layout, Site::here());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong layout")]
+    fn free_with_wrong_layout() {
+        let layout = Layout::from_size_align(10, 8).unwrap();
+        unsafe {
+            let object = arm(buffer(layout), layout, Site::here());
+            disarm(object, Layout::from_size_align(9, 8).unwrap(),
+                   Site::here());
+        }
+    }
+
+    #[test]
+    fn quarantine_order() {
+        let layout = Layout::from_size_align(10, 8).unwrap();
+        let outer = outer_layout(layout).unwrap();
+        let mut quarantine = Quarantine::new();
+        let addrs: Vec<usize> = (0..3).map(|_| buffer(layout)).collect();
+        for &addr in addrs.iter() {
+            unsafe {
+                let object = arm(addr, layout, Site::here());
+                disarm(object, layout, Site::here());
+                quarantine.push(object, layout);
+            }
+        }
+        assert_eq!(quarantine.bytes(), 3 * outer.size());
+        for &addr in addrs.iter() {
+            assert_eq!(unsafe { quarantine.pop() }, Some((addr, outer)));
+        }
+        assert_eq!(unsafe { quarantine.pop() }, None);
+        assert_eq!(quarantine.bytes(), 0);
+    }
+
+    #[test]
+    fn live_objects_since() {
+        let layout = Layout::from_size_align(10, 8).unwrap();
+        let mut live = LiveObjects::new();
+        let objects: Vec<usize> = (0..4)
+            .map(|_| unsafe {
+                let object = arm(buffer(layout), layout, Site::here());
+                live.insert(object, layout);
+                object
+            })
+            .collect();
+        unsafe {
+            live.remove(objects[2], layout);
+        }
+        assert_eq!(live.next_serial(), 4);
+
+        let mut found = Vec::new();
+        live.for_each_since(1, |object| {
+            assert_eq!(object.size, 10);
+            found.push((object.addr, object.serial));
+        });
+        assert_eq!(found, vec![(objects[3], 3), (objects[1], 1)]);
+    }
+}
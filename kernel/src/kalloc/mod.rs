@@ -20,20 +20,319 @@
 mod alloc_context;
 #[cfg(not(test))]
 mod allocator;
+mod debug;
 
 #[cfg(not(test))]
 pub use self::allocator::Allocator;
+#[cfg(not(test))]
+pub use self::alloc_context::Snapshot;
 
+#[cfg(not(test))]
+use core::cmp;
+#[cfg(not(test))]
+use core::sync::atomic::spin_loop_hint;
 #[cfg(not(test))]
 use self::alloc_context::*;
+#[cfg(not(test))]
+use ::config::{
+    KERNEL_HEAP_START,
+    KERNEL_HEAP_END,
+    KERNEL_HEAP_VIRT_START,
+    KERNEL_HEAP_VIRT_END,
+    KALLOC_DEBUG,
+    PAGE_SIZE,
+    PHYSMAP_START,
+};
+#[cfg(not(test))]
+use ::sync::{IrqSpinlock, OwnedSpinlock, OwnedSpinlockGuard, SpinlockGuard};
+#[cfg(not(test))]
+use ::frame::{self, FrameAllocator};
+#[cfg(not(test))]
+use ::paging::{self, phy_to_virt, tlb};
+
+/// The number of bytes that are kept free in the heap, so that the
+/// allocations made while the heap is growing, e.g. for page tables, don't
+/// need to grow the heap again.
+#[cfg(not(test))]
+const RESERVE: usize = 8 * PAGE_SIZE;
+/// The minimum number of bytes by which the heap grows.
+#[cfg(not(test))]
+const GROW_SIZE: usize = 16 * PAGE_SIZE;
+/// The number of frames kept aside for the heap, so that it can grow while
+/// the frame allocator is held by the caller, e.g. a system call that
+/// allocates memory while it's mapping user pages.
+#[cfg(not(test))]
+const NUM_SPARES: usize = 2 * GROW_SIZE / PAGE_SIZE;
+
+/// The heap is used by interrupt handlers too, so the interrupts are
+/// disabled while it's locked.
+#[cfg(not(test))]
+static CONTEXT: IrqSpinlock<Option<AllocContext>> = IrqSpinlock::new(None);
+
+/// The state of the growth of the heap. The lock is held while the heap is
+/// growing or shrinking. The frame allocator is never waited for while it's
+/// held, but the paging context of the kernel may be.
+#[cfg(not(test))]
+struct Growth {
+    // True if the heap can take frames from the frame allocator.
+    growable: bool,
+    // The end of the part of the range from KERNEL_HEAP_VIRT_START that is
+    // mapped.
+    mapped_end: usize,
+    // The zeroed frames kept aside. Only the first `num_spares` are valid.
+    spares: [usize; NUM_SPARES],
+    num_spares: usize,
+}
+
+#[cfg(not(test))]
+impl Growth {
+    /// Take the frames from `frames` until there are
+    /// [NUM_SPARES](NUM_SPARES) spare frames.
+    fn refill(&mut self, frames: &mut FrameAllocator) {
+        while self.num_spares < NUM_SPARES {
+            match frames.alloc_zeroed() {
+                Ok(frame) => self.spares[self.num_spares] = frame,
+                Err(_) => break,
+            }
+            self.num_spares += 1;
+        }
+    }
+
+    /// Take a frame from `frames`, or a spare frame if `frames` is held by
+    /// someone else.
+    fn alloc(&mut self, frames: &mut Option<SpinlockGuard<FrameAllocator>>)
+        -> Result<usize, ()>
+    {
+        if let Some(ref mut frames) = *frames {
+            return frames.alloc_zeroed();
+        }
+        if self.num_spares == 0 {
+            return Err(());
+        }
+        self.num_spares -= 1;
+        Ok(self.spares[self.num_spares])
+    }
+
+    /// Give back `frame` taken by [alloc](Growth::alloc) with the same
+    /// `frames`.
+    fn free(&mut self, frames: &mut Option<SpinlockGuard<FrameAllocator>>,
+            frame: usize)
+    {
+        match *frames {
+            Some(ref mut frames) => frames.free(frame),
+            // The frame is still zeroed.
+            None => {
+                self.spares[self.num_spares] = frame;
+                self.num_spares += 1;
+            },
+        }
+    }
+}
 
 #[cfg(not(test))]
-static mut CONTEXT: Option<AllocContext> = None;
+static GROWTH: OwnedSpinlock<Growth> = OwnedSpinlock::new(Growth {
+    growable: false,
+    mapped_end: KERNEL_HEAP_VIRT_START,
+    spares: [0; NUM_SPARES],
+    num_spares: 0,
+});
 
 /// Initialization function for the entire kernel memory allocation module.
 #[cfg(not(test))]
 pub fn init() {
-    unsafe {
-        CONTEXT = Some(AllocContext::new());
+    // The heap is accessed through the physmap until it grows.
+    let mut context = AllocContext::new(PHYSMAP_START + KERNEL_HEAP_START,
+                                        PHYSMAP_START + KERNEL_HEAP_END);
+    if KALLOC_DEBUG {
+        context.enable_debug();
+    }
+    *CONTEXT.lock() = Some(context);
+}
+
+/// Let the heap grow by mapping frames from the frame allocator. This must
+/// be called after the paging and frame modules are initialized, and before
+/// any user paging context is created because the user contexts share only
+/// the root entries of the kernel that already exist.
+#[cfg(not(test))]
+pub fn init_growth() {
+    {
+        let mut frames = frame::allocator().lock();
+        let mut growth = lock_growth();
+        growth.growable = true;
+        growth.refill(&mut frames);
+    }
+    // The first pages are never given back, so the root entry of the range
+    // always exists.
+    grow(GROW_SIZE).expect("cannot map the kernel heap");
+}
+
+/// Wait until [GROWTH](GROWTH) is released, and take it. The holder may be
+/// shrinking the heap and waiting for this processor to flush its TLB, so
+/// the shootdowns are handled while waiting.
+#[cfg(not(test))]
+fn lock_growth() -> OwnedSpinlockGuard<'static, Growth> {
+    loop {
+        if let Some(growth) = GROWTH.try_lock() {
+            return growth;
+        }
+        tlb::handle_pending();
+        spin_loop_hint();
+    }
+}
+
+/// Map at least `length` bytes of new memory at the end of the heap.
+#[cfg(not(test))]
+fn grow(length: usize) -> Result<(), ()> {
+    // Mapping the pages may allocate page tables from the heap, and so may
+    // an interrupt handler, which would call this function again while this
+    // processor is holding the lock or the paging context of the kernel.
+    // The reserve is used then.
+    if GROWTH.is_held_here() || paging::holds_kernel_context() {
+        return Err(());
+    }
+    let mut growth = lock_growth();
+    if !growth.growable {
+        return Err(());
+    }
+    // The frame allocator may be held by the caller, which is allocating
+    // memory while it's mapping user pages, or by another processor that is
+    // waiting for the lock, and then the spare frames are used.
+    let mut frames = frame::allocator().try_lock();
+    let start = growth.mapped_end;
+    let length = cmp::max(length + PAGE_SIZE - 1, GROW_SIZE) & !(PAGE_SIZE-1);
+    let end = cmp::min(start.saturating_add(length), KERNEL_HEAP_VIRT_END);
+
+    // We must not hold the context here.
+    while growth.mapped_end < end {
+        let frame = match growth.alloc(&mut frames) {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+        if paging::map_kernel(growth.mapped_end, frame).is_err() {
+            growth.free(&mut frames, frame);
+            break;
+        }
+        growth.mapped_end += PAGE_SIZE;
+    }
+    if let Some(ref mut frames) = frames {
+        growth.refill(frames);
+    }
+    let mapped = growth.mapped_end - start;
+    if mapped > 0 {
+        CONTEXT.lock().as_mut().unwrap().extend(start, mapped);
+    }
+    if mapped == length {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Grow the heap if `free_space`, the number of free bytes left, is not
+/// enough.
+#[cfg(not(test))]
+fn keep_reserve(free_space: usize) {
+    if free_space < RESERVE {
+        let _ = grow(GROW_SIZE);
+    }
+}
+
+/// Take the spare frames that the heap has used since the last call from
+/// the frame allocator again. This must be called before the frame
+/// allocator is held for a long time.
+#[cfg(not(test))]
+pub fn refill_spares() {
+    let mut frames = frame::allocator().lock();
+    let mut growth = lock_growth();
+    if growth.growable && growth.num_spares < NUM_SPARES {
+        growth.refill(&mut frames);
+    }
+}
+
+/// Give the empty slabs back to the heap, and the unused memory at the end
+/// of the heap back to the frame allocator, except the reserve. Return the
+/// number of frames given back.
+#[cfg(not(test))]
+pub fn reclaim() -> usize {
+    if GROWTH.is_held_here() {
+        return 0;
     }
+    let mut growth = lock_growth();
+    if !growth.growable {
+        return 0;
+    }
+    let (start, end) = {
+        let mut context = CONTEXT.lock();
+        let context = context.as_mut().unwrap();
+        context.release_empty_slabs();
+        let unused = context.unused();
+        // Only the memory after KERNEL_HEAP_VIRT_START is taken from the
+        // frame allocator.
+        if unused.end <= KERNEL_HEAP_VIRT_START {
+            return 0;
+        }
+        let start = cmp::max(unused.start + RESERVE,
+                             KERNEL_HEAP_VIRT_START + GROW_SIZE);
+        let start = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if start >= unused.end {
+            return 0;
+        }
+        context.shrink(start);
+        (start, unused.end)
+    };
+
+    // Unmapping the pages waits for the other processors to flush their
+    // TLBs, which they can't do while they are waiting for the heap with the
+    // interrupts disabled, so the context must not be locked here.
+    // The frames are linked through their first words, since the frame
+    // allocator must not be locked while the lock is held. A frame at
+    // physical address 0 is never in the heap.
+    let mut released = 0;
+    for page in (start..end).step_by(PAGE_SIZE) {
+        let frame = paging::unmap_kernel(page).unwrap();
+        unsafe {
+            *(phy_to_virt(frame) as *mut usize) = released;
+        }
+        released = frame;
+    }
+    growth.mapped_end = start;
+    drop(growth);
+
+    let mut frames = frame::allocator().lock();
+    while released != 0 {
+        let next = unsafe { *(phy_to_virt(released) as *const usize) };
+        frames.free(released);
+        released = next;
+    }
+    (end - start) / PAGE_SIZE
+}
+
+/// Print the statistics of the heap on the screen.
+#[cfg(not(test))]
+pub fn dump_stats() {
+    let stats = CONTEXT.lock().as_ref().unwrap().stats();
+    println!("{}", stats);
+}
+
+/// Return the current point in time, which can be passed to
+/// [report_leaks](report_leaks) later. Return None if the heap debugging is
+/// disabled.
+#[allow(dead_code)]
+#[cfg(not(test))]
+pub fn snapshot() -> Option<Snapshot> {
+    CONTEXT.lock().as_ref().unwrap().snapshot()
+}
+
+/// Print the objects that are allocated after `snapshot` and are not freed
+/// yet on the screen. Return the number of the objects.
+#[allow(dead_code)]
+#[cfg(not(test))]
+pub fn report_leaks(snapshot: Snapshot) -> usize {
+    let mut leaks = 0;
+    CONTEXT.lock().as_ref().unwrap().for_each_leak(snapshot, |object| {
+        println!("leak of {} bytes at {:#x} allocated at {}", object.size,
+                 object.addr, object.site);
+        leaks += 1;
+    });
+    leaks
 }
@@ -0,0 +1,157 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Keymaps, which give the characters of the printable keys. A new layout
+//! only needs a new [Keymap](Keymap) in [KEYMAPS](KEYMAPS).
+
+use super::scancode::KeyCode;
+
+/// The set 1 make codes of the printable keys in each row of the keyboard,
+/// from the left. The key between the quote and Enter is the backslash on
+/// the US keyboards, and the key between Left Shift and Z only exists on
+/// the international ones.
+const ROWS: [&[u8]; 4] = [
+    &[0x29, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+      0x0d],
+    &[0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b],
+    &[0x1e, 0x1f, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x2b],
+    &[0x56, 0x2c, 0x2d, 0x2e, 0x2f, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35],
+];
+
+/// A keyboard layout. Each row has a character for each key in the same
+/// row of [ROWS](ROWS).
+#[derive(Debug)]
+pub struct Keymap {
+    /// The name used to choose the keymap.
+    pub name: &'static str,
+    /// The characters of the keys without Shift.
+    pub normal: [&'static str; 4],
+    /// The characters of the keys with Shift.
+    pub shift: [&'static str; 4],
+    /// The characters of the keys with AltGr, which is the right Alt key.
+    /// Only some keys have them.
+    pub altgr: &'static [(KeyCode, char)],
+}
+
+/// The US layout.
+pub static US: Keymap = Keymap {
+    name: "us",
+    normal: ["`1234567890-=", "qwertyuiop[]", "asdfghjkl;'\\",
+             "\\zxcvbnm,./"],
+    shift: ["~!@#$%^&*()_+", "QWERTYUIOP{}", "ASDFGHJKL:\"|",
+            "|ZXCVBNM<>?"],
+    altgr: &[],
+};
+
+/// The UK layout.
+pub static UK: Keymap = Keymap {
+    name: "uk",
+    normal: ["`1234567890-=", "qwertyuiop[]", "asdfghjkl;'#",
+             "\\zxcvbnm,./"],
+    shift: ["¬!\"£$%^&*()_+", "QWERTYUIOP{}", "ASDFGHJKL:@~",
+            "|ZXCVBNM<>?"],
+    altgr: &[(KeyCode(0x29), '¦'), (KeyCode(0x05), '€')],
+};
+
+/// The German layout. The dead keys are treated as normal keys.
+pub static DE: Keymap = Keymap {
+    name: "de",
+    normal: ["^1234567890ß´", "qwertzuiopü+", "asdfghjklöä#",
+             "<yxcvbnm,.-"],
+    shift: ["°!\"§$%&/()=?`", "QWERTZUIOPÜ*", "ASDFGHJKLÖÄ'",
+            ">YXCVBNM;:_"],
+    altgr: &[(KeyCode(0x03), '²'), (KeyCode(0x04), '³'),
+             (KeyCode(0x08), '{'), (KeyCode(0x09), '['),
+             (KeyCode(0x0a), ']'), (KeyCode(0x0b), '}'),
+             (KeyCode(0x0c), '\\'), (KeyCode(0x10), '@'),
+             (KeyCode(0x12), '€'), (KeyCode(0x1b), '~'),
+             (KeyCode(0x56), '|'), (KeyCode(0x32), 'µ')],
+};
+
+/// All the keymaps that can be chosen.
+pub static KEYMAPS: [&Keymap; 3] = [&US, &UK, &DE];
+
+impl Keymap {
+    /// Return the keymap whose name is `name`, ignoring the case.
+    pub fn find(name: &str) -> Result<&'static Keymap, ()> {
+        KEYMAPS.iter()
+            .find(|keymap| keymap.name.eq_ignore_ascii_case(name))
+            .cloned()
+            .ok_or(())
+    }
+
+    /// Return the character of `key` with Shift if `shift` is true, or
+    /// None if the key is not printable.
+    pub fn get(&self, key: KeyCode, shift: bool) -> Option<char> {
+        if key == KeyCode::SPACE {
+            return Some(' ');
+        }
+        let rows = if shift { &self.shift } else { &self.normal };
+        for (row, codes) in rows.iter().zip(ROWS.iter()) {
+            if let Some(column) = codes.iter().position(|&code| code == key.0)
+            {
+                return row.chars().nth(column);
+            }
+        }
+        None
+    }
+
+    /// Return the character of `key` with AltGr, if it has one.
+    pub fn get_altgr(&self, key: KeyCode) -> Option<char> {
+        self.altgr.iter()
+            .find(|&&(code, _)| code == key)
+            .map(|&(_, c)| c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_have_all_keys() {
+        for keymap in KEYMAPS.iter() {
+            for (i, codes) in ROWS.iter().enumerate() {
+                assert_eq!(keymap.normal[i].chars().count(), codes.len());
+                assert_eq!(keymap.shift[i].chars().count(), codes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn translate_keys() {
+        assert_eq!(US.get(KeyCode(0x10), false), Some('q'));
+        assert_eq!(US.get(KeyCode(0x03), true), Some('@'));
+        assert_eq!(US.get(KeyCode::SPACE, true), Some(' '));
+        assert_eq!(US.get(KeyCode::ENTER, false), None);
+        assert_eq!(US.get_altgr(KeyCode(0x10)), None);
+        assert_eq!(UK.get(KeyCode(0x03), true), Some('"'));
+        assert_eq!(UK.get(KeyCode(0x04), true), Some('£'));
+        assert_eq!(UK.get(KeyCode(0x2b), true), Some('~'));
+        assert_eq!(DE.get(KeyCode(0x15), false), Some('z'));
+        assert_eq!(DE.get(KeyCode(0x2c), true), Some('Y'));
+        assert_eq!(DE.get(KeyCode(0x27), true), Some('Ö'));
+        assert_eq!(DE.get_altgr(KeyCode(0x10)), Some('@'));
+        assert_eq!(DE.get_altgr(KeyCode(0x11)), None);
+    }
+
+    #[test]
+    fn find_keymap() {
+        assert_eq!(Keymap::find("de").unwrap().name, "de");
+        assert_eq!(Keymap::find("UK").unwrap().name, "uk");
+        assert!(Keymap::find("fr").is_err());
+    }
+}
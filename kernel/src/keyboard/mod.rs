@@ -0,0 +1,578 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! PS/2 keyboard module. This module decodes the scancodes from the
+//! keyboard behind the PS/2 controller, tracks the modifier keys, and sends
+//! the typed characters to the console [tty](::tty).
+//!
+//! The keys that don't have characters, like the cursor keys, send the
+//! same escape sequences as the VT100 terminals, and the characters typed
+//! with Alt are sent after an escape.
+
+mod keymap;
+mod scancode;
+
+#[cfg(not(test))]
+use core::sync::atomic::spin_loop_hint;
+
+#[cfg(not(test))]
+use ::apic::{self, ioapic};
+#[cfg(not(test))]
+use ::config::KEYMAP;
+#[cfg(not(test))]
+use ::interrupt::{self, InterruptStackFrame};
+#[cfg(not(test))]
+use ::port::{inb, outb};
+#[cfg(not(test))]
+use ::sync::IrqSpinlock;
+#[cfg(not(test))]
+use ::tty;
+use self::keymap::Keymap;
+#[cfg(not(test))]
+use self::keymap::US;
+use self::scancode::{Decoder, KeyCode, KeyEvent, ScancodeSet};
+
+/// The maximum number of bytes that a key sends.
+const INPUT_SIZE: usize = 8;
+
+/// The characters of the keypad keys when Num Lock is on.
+const KEYPAD: &str = "789-456+1230.";
+
+/// The I/O ports of the PS/2 controller.
+#[cfg(not(test))]
+const DATA: u16 = 0x60;
+#[cfg(not(test))]
+const STATUS: u16 = 0x64;
+#[cfg(not(test))]
+const COMMAND: u16 = 0x64;
+
+/// The bits in the status register.
+#[cfg(not(test))]
+const STATUS_OUTPUT_FULL: u8 = 1;
+#[cfg(not(test))]
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// The commands of the controller.
+#[cfg(not(test))]
+const READ_CONFIG: u8 = 0x20;
+#[cfg(not(test))]
+const WRITE_CONFIG: u8 = 0x60;
+#[cfg(not(test))]
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+#[cfg(not(test))]
+const DISABLE_FIRST_PORT: u8 = 0xad;
+#[cfg(not(test))]
+const ENABLE_FIRST_PORT: u8 = 0xae;
+
+/// The bits in the configuration byte of the controller.
+#[cfg(not(test))]
+const CONFIG_FIRST_INTERRUPT: u8 = 1;
+#[cfg(not(test))]
+const CONFIG_SECOND_INTERRUPT: u8 = 1 << 1;
+#[cfg(not(test))]
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// The command of the keyboard that sets the LEDs.
+const SET_LEDS: u8 = 0xed;
+
+/// The replies of the keyboard to the commands.
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+/// The bits of the LEDs in the argument of [SET_LEDS](SET_LEDS).
+const LED_SCROLL_LOCK: u8 = 1;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// The number of times to poll the status register before giving up. Each
+/// read of an I/O port takes about a microsecond.
+#[cfg(not(test))]
+const POLL_COUNT: usize = 10_000;
+
+/// The ISA IRQ of the keyboard.
+#[cfg(not(test))]
+const KEYBOARD_IRQ: u8 = 1;
+
+/// The interrupt vector of the keyboard.
+#[cfg(not(test))]
+pub const KEYBOARD_INTERRUPT: u8 = 0x21;
+
+/// The modifier keys that are held and the lock keys that are on.
+#[derive(Debug)]
+struct Modifiers {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    altgr: bool,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+}
+
+/// The progress of updating the LEDs. The command and its argument are
+/// sent one at a time, each after the keyboard acknowledges the previous
+/// byte.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum LedUpdate {
+    Idle,
+    // [SET_LEDS](SET_LEDS) is sent.
+    Command,
+    // The argument is sent.
+    Argument(u8),
+}
+
+/// The state of the keyboard, which turns the bytes from the keyboard into
+/// the bytes for the tty.
+#[derive(Debug)]
+struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+    keymap: &'static Keymap,
+    led_update: LedUpdate,
+    // The LEDs that the keyboard shows.
+    leds_shown: u8,
+}
+
+impl Keyboard {
+    /// Create a [Keyboard](Keyboard) that decodes the scancode set `set`
+    /// with the layout `keymap`.
+    const fn new(set: ScancodeSet, keymap: &'static Keymap) -> Keyboard {
+        Keyboard {
+            decoder: Decoder::new(set),
+            modifiers: Modifiers {
+                left_shift: false,
+                right_shift: false,
+                left_ctrl: false,
+                right_ctrl: false,
+                alt: false,
+                altgr: false,
+                caps_lock: false,
+                num_lock: false,
+                scroll_lock: false,
+            },
+            keymap,
+            led_update: LedUpdate::Idle,
+            leds_shown: 0,
+        }
+    }
+
+    /// Return the argument of [SET_LEDS](SET_LEDS) that shows the lock
+    /// keys that are on.
+    fn leds(&self) -> u8 {
+        let modifiers = &self.modifiers;
+        let mut leds = 0;
+        if modifiers.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if modifiers.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if modifiers.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        leds
+    }
+
+    /// Handle the next byte from the keyboard, and put the bytes typed by it
+    /// in `input`. Return the number of the typed bytes. If a byte must be
+    /// sent to the keyboard next, it's put in `reply`.
+    fn feed(&mut self, byte: u8, input: &mut [u8; INPUT_SIZE],
+            reply: &mut Option<u8>) -> usize
+    {
+        if self.led_update != LedUpdate::Idle && (byte == ACK ||
+                                                 byte == RESEND) {
+            *reply = self.answer(byte);
+            return 0;
+        }
+        let len = match self.decoder.feed(byte) {
+            Some(event) => self.handle(event, input),
+            None => 0,
+        };
+        if self.led_update == LedUpdate::Idle && self.leds() != self.leds_shown
+        {
+            self.led_update = LedUpdate::Command;
+            *reply = Some(SET_LEDS);
+        }
+        len
+    }
+
+    /// Continue updating the LEDs after the keyboard replies `byte` to the
+    /// last byte sent. Return the byte to send next, if any.
+    fn answer(&mut self, byte: u8) -> Option<u8> {
+        match (self.led_update, byte) {
+            (LedUpdate::Idle, _) => None,
+            (LedUpdate::Command, RESEND) => Some(SET_LEDS),
+            (LedUpdate::Argument(leds), RESEND) => Some(leds),
+            (LedUpdate::Command, _) => {
+                let leds = self.leds();
+                self.led_update = LedUpdate::Argument(leds);
+                Some(leds)
+            },
+            (LedUpdate::Argument(leds), _) => {
+                self.leds_shown = leds;
+                // A lock key may be pressed again in the meantime.
+                if self.leds() != leds {
+                    self.led_update = LedUpdate::Command;
+                    Some(SET_LEDS)
+                } else {
+                    self.led_update = LedUpdate::Idle;
+                    None
+                }
+            },
+        }
+    }
+
+    /// Update the modifiers with `event`, and put the bytes typed by it in
+    /// `input`. Return the number of the typed bytes.
+    fn handle(&mut self, event: KeyEvent, input: &mut [u8; INPUT_SIZE])
+        -> usize
+    {
+        let pressed = event.pressed;
+        {
+            let modifiers = &mut self.modifiers;
+            match event.key {
+                KeyCode::LEFT_SHIFT => modifiers.left_shift = pressed,
+                KeyCode::RIGHT_SHIFT => modifiers.right_shift = pressed,
+                KeyCode::LEFT_CTRL => modifiers.left_ctrl = pressed,
+                KeyCode::RIGHT_CTRL => modifiers.right_ctrl = pressed,
+                KeyCode::LEFT_ALT => modifiers.alt = pressed,
+                KeyCode::RIGHT_ALT => modifiers.altgr = pressed,
+                // The lock keys are toggled when they are pressed, and
+                // they repeat while they are held.
+                KeyCode::CAPS_LOCK if pressed => {
+                    modifiers.caps_lock = !modifiers.caps_lock;
+                },
+                KeyCode::NUM_LOCK if pressed => {
+                    modifiers.num_lock = !modifiers.num_lock;
+                },
+                KeyCode::SCROLL_LOCK if pressed => {
+                    modifiers.scroll_lock = !modifiers.scroll_lock;
+                },
+                _ => {},
+            }
+        }
+        if !pressed {
+            return 0;
+        }
+        self.type_key(event.key, input)
+    }
+
+    /// Put the bytes typed by pressing `key` in `input`. Return the number
+    /// of the typed bytes.
+    fn type_key(&self, key: KeyCode, input: &mut [u8; INPUT_SIZE]) -> usize {
+        let modifiers = &self.modifiers;
+        let mut key = key;
+        if key.is_keypad() {
+            let c = KEYPAD.as_bytes()[(key.0 - KeyCode::KEYPAD_START.0)
+                                      as usize];
+            if modifiers.num_lock || c == b'-' || c == b'+' {
+                input[0] = c;
+                return 1;
+            }
+            // Without Num Lock, the keypad keys are the cursor keys.
+            key = KeyCode(key.0 | 0x80);
+        }
+        let sequence: &[u8] = match key {
+            KeyCode::ESCAPE => b"\x1b",
+            KeyCode::BACKSPACE => b"\x7f",
+            KeyCode::TAB => b"\t",
+            KeyCode::ENTER | KeyCode::KEYPAD_ENTER => b"\n",
+            KeyCode::KEYPAD_STAR => b"*",
+            KeyCode::KEYPAD_SLASH => b"/",
+            KeyCode::UP => b"\x1b[A",
+            KeyCode::DOWN => b"\x1b[B",
+            KeyCode::RIGHT => b"\x1b[C",
+            KeyCode::LEFT => b"\x1b[D",
+            KeyCode::HOME => b"\x1b[H",
+            KeyCode::END => b"\x1b[F",
+            KeyCode::INSERT => b"\x1b[2~",
+            KeyCode::DELETE => b"\x1b[3~",
+            KeyCode::PAGE_UP => b"\x1b[5~",
+            KeyCode::PAGE_DOWN => b"\x1b[6~",
+            _ => b"",
+        };
+        if !sequence.is_empty() {
+            input[..sequence.len()].copy_from_slice(sequence);
+            return sequence.len();
+        }
+
+        // The right Alt key is the left one on the layouts without AltGr.
+        let altgr = if modifiers.altgr {
+            self.keymap.get_altgr(key)
+        } else {
+            None
+        };
+        let alt = modifiers.alt || modifiers.altgr && altgr.is_none();
+        let c = match altgr {
+            Some(c) => c,
+            None => {
+                // Caps Lock works like Shift, but only for the letters.
+                let letter = self.keymap.get(key, false)
+                    .map_or(false, char::is_alphabetic);
+                let shift = modifiers.left_shift || modifiers.right_shift;
+                match self.keymap.get(key, shift != (modifiers.caps_lock &&
+                                                     letter)) {
+                    Some(c) => c,
+                    None => return 0,
+                }
+            },
+        };
+
+        let mut len = 0;
+        if alt {
+            input[0] = 0x1b;
+            len += 1;
+        }
+        let ctrl = modifiers.left_ctrl || modifiers.right_ctrl;
+        match c {
+            // Ctrl turns the characters from @ to _ into the control
+            // characters, and the letters work in both cases.
+            '@'..='_' | 'a'..='z' if ctrl => input[len] = c as u8 & 0x1f,
+            '?' if ctrl => input[len] = 0x7f,
+            _ => return len + c.encode_utf8(&mut input[len..]).len(),
+        }
+        len + 1
+    }
+}
+
+/// The state of the keyboard. The scancode set and the keymap are chosen by
+/// [init](init).
+#[cfg(not(test))]
+static KEYBOARD: IrqSpinlock<Keyboard> =
+    IrqSpinlock::new(Keyboard::new(ScancodeSet::Set1, &US));
+
+/// Wait until the controller can take another byte.
+#[cfg(not(test))]
+fn wait_input() -> Result<(), ()> {
+    for _ in 0..POLL_COUNT {
+        if unsafe { inb(STATUS) } & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        spin_loop_hint();
+    }
+    Err(())
+}
+
+/// Wait until the controller has a byte for us.
+#[cfg(not(test))]
+fn wait_output() -> Result<(), ()> {
+    for _ in 0..POLL_COUNT {
+        if unsafe { inb(STATUS) } & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+        spin_loop_hint();
+    }
+    Err(())
+}
+
+/// Send the command `command` to the controller.
+#[cfg(not(test))]
+fn command(command: u8) -> Result<(), ()> {
+    wait_input()?;
+    unsafe {
+        outb(COMMAND, command);
+    }
+    Ok(())
+}
+
+/// Send `byte` to the keyboard, or to the controller after a command that
+/// takes an argument.
+#[cfg(not(test))]
+fn write(byte: u8) -> Result<(), ()> {
+    wait_input()?;
+    unsafe {
+        outb(DATA, byte);
+    }
+    Ok(())
+}
+
+/// Read the reply of the controller to a command.
+#[cfg(not(test))]
+fn read() -> Result<u8, ()> {
+    wait_output()?;
+    Ok(unsafe { inb(DATA) })
+}
+
+/// The interrupt handler of the keyboard.
+#[cfg(not(test))]
+pub extern "x86-interrupt" fn keyboard_handler(
+    _frame: &mut InterruptStackFrame)
+{
+    let _context = interrupt::enter();
+    let mut input = [0; INPUT_SIZE];
+    let len = {
+        let mut keyboard = KEYBOARD.lock();
+        let byte = unsafe { inb(DATA) };
+        let mut reply = None;
+        let len = keyboard.feed(byte, &mut input, &mut reply);
+        if let Some(reply) = reply {
+            let _ = write(reply);
+        }
+        len
+    };
+    tty::input(&input[..len]);
+    apic::eoi();
+}
+
+/// Program the controller to send the keyboard interrupts, and return the
+/// scancode set that the keyboard uses.
+#[cfg(not(test))]
+fn init_controller() -> Result<ScancodeSet, ()> {
+    // The ports are disabled while the configuration is changed, so that
+    // the keyboard doesn't fill the output buffer in the middle of it.
+    command(DISABLE_FIRST_PORT)?;
+    command(DISABLE_SECOND_PORT)?;
+    unsafe {
+        while inb(STATUS) & STATUS_OUTPUT_FULL != 0 {
+            inb(DATA);
+        }
+    }
+    command(READ_CONFIG)?;
+    let config = read()?;
+    let config = config & !CONFIG_SECOND_INTERRUPT | CONFIG_FIRST_INTERRUPT;
+    command(WRITE_CONFIG)?;
+    write(config)?;
+    command(ENABLE_FIRST_PORT)?;
+    if config & CONFIG_TRANSLATION != 0 {
+        Ok(ScancodeSet::Set1)
+    } else {
+        Ok(ScancodeSet::Set2)
+    }
+}
+
+/// Initialization function for keyboard module. This must be called after
+/// the interrupt and local APIC modules are initialized.
+#[cfg(not(test))]
+pub fn init() {
+    let keymap = Keymap::find(KEYMAP).unwrap_or_else(|_| {
+        warn!("unknown keymap \"{}\", using \"{}\"", KEYMAP, US.name);
+        &US
+    });
+    let set = match init_controller() {
+        Ok(set) => set,
+        Err(_) => {
+            warn!("no PS/2 controller");
+            return;
+        },
+    };
+    *KEYBOARD.lock() = Keyboard::new(set, keymap);
+    ioapic::route(KEYBOARD_IRQ, KEYBOARD_INTERRUPT, apic::id());
+    info!("PS/2 keyboard with scancode set {} and keymap \"{}\".",
+          if set == ScancodeSet::Set1 { 1 } else { 2 }, keymap.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use super::keymap::{DE, US};
+
+    /// Type the keys in `events`, where the negative ones are released,
+    /// and return the typed bytes.
+    fn type_keys(keyboard: &mut Keyboard, events: &[i16]) -> Vec<u8> {
+        let mut typed = Vec::new();
+        for &event in events {
+            let event = KeyEvent {
+                key: KeyCode(event.abs() as u8),
+                pressed: event > 0,
+            };
+            let mut input = [0; INPUT_SIZE];
+            let len = keyboard.handle(event, &mut input);
+            typed.extend_from_slice(&input[..len]);
+        }
+        typed
+    }
+
+    #[test]
+    fn shift_and_caps_lock() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, &US);
+        // a, Shift+a, Shift+1, Caps Lock, a, 1, Shift+a.
+        let typed = type_keys(&mut keyboard, &[
+            0x1e, -0x1e, 0x2a, 0x1e, 0x02, -0x2a, 0x3a, -0x3a, 0x1e, 0x02,
+            0x36, 0x1e,
+        ]);
+        assert_eq!(typed, b"aA!A1a");
+        assert_eq!(keyboard.leds(), LED_CAPS_LOCK);
+    }
+
+    #[test]
+    fn ctrl_and_alt() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, &US);
+        // Ctrl+c, Ctrl+[, Alt+x, and AltGr+x without AltGr characters.
+        let typed = type_keys(&mut keyboard, &[
+            0x1d, 0x2e, 0x1a, -0x1d, 0x38, 0x2d, -0x38, 0xb8, 0x2d,
+        ]);
+        assert_eq!(typed, b"\x03\x1b\x1bx\x1bx");
+    }
+
+    #[test]
+    fn altgr_and_unicode() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, &DE);
+        // AltGr+q, AltGr+e, Shift+;, and Caps Lock with ä.
+        let typed = type_keys(&mut keyboard, &[
+            0xb8, 0x10, 0x12, -0xb8, 0x2a, 0x27, -0x2a, 0x3a, 0x28,
+        ]);
+        assert_eq!(typed, "@€ÖÄ".as_bytes());
+    }
+
+    #[test]
+    fn special_keys() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set2, &US);
+        let typed = type_keys(&mut keyboard, &[
+            0x1c, 0x0e, 0xc8, 0xd3, 0x9c, 0x47, 0x4a,
+        ]);
+        assert_eq!(typed, b"\n\x7f\x1b[A\x1b[3~\n\x1b[H-");
+        // Num Lock turns the keypad into digits.
+        let typed = type_keys(&mut keyboard, &[0x45, -0x45, 0x47, 0x53]);
+        assert_eq!(typed, b"7.");
+        assert_eq!(keyboard.leds(), LED_NUM_LOCK);
+        // The bytes go through the decoder first.
+        let mut input = [0; INPUT_SIZE];
+        let mut reply = None;
+        assert_eq!(keyboard.feed(0xe0, &mut input, &mut reply), 0);
+        assert_eq!(keyboard.feed(0x75, &mut input, &mut reply), 3);
+        assert_eq!(&input[..3], b"\x1b[A");
+    }
+
+    #[test]
+    fn update_leds() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, &US);
+        let mut input = [0; INPUT_SIZE];
+        let mut feed = |keyboard: &mut Keyboard, byte| {
+            let mut reply = None;
+            keyboard.feed(byte, &mut input, &mut reply);
+            reply
+        };
+        // Caps Lock sends the command, and the argument after the ACK.
+        assert_eq!(feed(&mut keyboard, 0x3a), Some(SET_LEDS));
+        assert_eq!(feed(&mut keyboard, 0xba), None);
+        assert_eq!(feed(&mut keyboard, RESEND), Some(SET_LEDS));
+        assert_eq!(feed(&mut keyboard, ACK), Some(LED_CAPS_LOCK));
+        // Num Lock is pressed before the argument is acknowledged.
+        assert_eq!(feed(&mut keyboard, 0x45), None);
+        assert_eq!(feed(&mut keyboard, ACK), Some(SET_LEDS));
+        assert_eq!(feed(&mut keyboard, ACK),
+                   Some(LED_CAPS_LOCK | LED_NUM_LOCK));
+        assert_eq!(feed(&mut keyboard, ACK), None);
+        assert_eq!(keyboard.led_update, LedUpdate::Idle);
+        assert_eq!(keyboard.leds_shown, LED_CAPS_LOCK | LED_NUM_LOCK);
+        // The ACK is a key when no command is sent.
+        assert_eq!(feed(&mut keyboard, ACK), None);
+        assert_eq!(feed(&mut keyboard, 0x1e), None);
+    }
+}
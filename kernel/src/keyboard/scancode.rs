@@ -0,0 +1,250 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Scancode decoders. The keyboard sends scancode set 2, which the PS/2
+//! controller usually translates to set 1, so both of them are decoded
+//! into the same [KeyCode](KeyCode)s.
+
+/// The prefix of the scancodes of the extended keys.
+const EXTENDED: u8 = 0xe0;
+/// The prefix of the scancode of the Pause key, which has no release code.
+const PAUSE: u8 = 0xe1;
+/// The prefix of the release codes in set 2.
+const RELEASE: u8 = 0xf0;
+/// The bit that marks the release codes in set 1.
+const RELEASE_BIT: u8 = 0x80;
+
+/// The number of bytes after [PAUSE](PAUSE) in the make code of the Pause
+/// key, not counting the [RELEASE](RELEASE) prefixes.
+const PAUSE_LEN: u8 = 5;
+
+/// The set 1 make codes of the keys in set 2, without the prefixes. The
+/// Windows keys and the Menu key only exist with the extended prefix.
+static SET2_TO_SET1: [u8; 0x84] = [
+    0x00, 0x43, 0x00, 0x3f, 0x3d, 0x3b, 0x3c, 0x58, // 0x00
+    0x00, 0x44, 0x42, 0x40, 0x3e, 0x0f, 0x29, 0x00, // 0x08
+    0x00, 0x38, 0x2a, 0x00, 0x1d, 0x10, 0x02, 0x00, // 0x10
+    0x00, 0x00, 0x2c, 0x1f, 0x1e, 0x11, 0x03, 0x5b, // 0x18
+    0x00, 0x2e, 0x2d, 0x20, 0x12, 0x05, 0x04, 0x5c, // 0x20
+    0x00, 0x39, 0x2f, 0x21, 0x14, 0x13, 0x06, 0x5d, // 0x28
+    0x00, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x00, // 0x30
+    0x00, 0x00, 0x32, 0x24, 0x16, 0x08, 0x09, 0x00, // 0x38
+    0x00, 0x33, 0x25, 0x17, 0x18, 0x0b, 0x0a, 0x00, // 0x40
+    0x00, 0x34, 0x35, 0x26, 0x27, 0x19, 0x0c, 0x00, // 0x48
+    0x00, 0x00, 0x28, 0x00, 0x1a, 0x0d, 0x00, 0x00, // 0x50
+    0x3a, 0x36, 0x1c, 0x1b, 0x00, 0x2b, 0x00, 0x00, // 0x58
+    0x00, 0x56, 0x00, 0x00, 0x00, 0x00, 0x0e, 0x00, // 0x60
+    0x00, 0x4f, 0x00, 0x4b, 0x47, 0x00, 0x00, 0x00, // 0x68
+    0x52, 0x53, 0x50, 0x4c, 0x4d, 0x48, 0x01, 0x45, // 0x70
+    0x57, 0x4e, 0x51, 0x4a, 0x37, 0x49, 0x46, 0x00, // 0x78
+    0x00, 0x00, 0x00, 0x41,                         // 0x80
+];
+
+/// A key on the keyboard, which is identified by its make code in set 1.
+/// The extended keys also have bit 7 set. The printable keys are named
+/// after the US layout.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeyCode(pub u8);
+
+impl KeyCode {
+    pub const ESCAPE: KeyCode = KeyCode(0x01);
+    pub const BACKSPACE: KeyCode = KeyCode(0x0e);
+    pub const TAB: KeyCode = KeyCode(0x0f);
+    pub const ENTER: KeyCode = KeyCode(0x1c);
+    pub const LEFT_CTRL: KeyCode = KeyCode(0x1d);
+    pub const LEFT_SHIFT: KeyCode = KeyCode(0x2a);
+    pub const RIGHT_SHIFT: KeyCode = KeyCode(0x36);
+    pub const KEYPAD_STAR: KeyCode = KeyCode(0x37);
+    pub const LEFT_ALT: KeyCode = KeyCode(0x38);
+    pub const SPACE: KeyCode = KeyCode(0x39);
+    pub const CAPS_LOCK: KeyCode = KeyCode(0x3a);
+    pub const NUM_LOCK: KeyCode = KeyCode(0x45);
+    pub const SCROLL_LOCK: KeyCode = KeyCode(0x46);
+    /// The first key of the keypad, which is 7. The keys up to the period
+    /// follow in rows.
+    pub const KEYPAD_START: KeyCode = KeyCode(0x47);
+    pub const KEYPAD_END: KeyCode = KeyCode(0x53);
+    pub const KEYPAD_ENTER: KeyCode = KeyCode(0x9c);
+    pub const RIGHT_CTRL: KeyCode = KeyCode(0x9d);
+    pub const KEYPAD_SLASH: KeyCode = KeyCode(0xb5);
+    pub const RIGHT_ALT: KeyCode = KeyCode(0xb8);
+    pub const HOME: KeyCode = KeyCode(0xc7);
+    pub const UP: KeyCode = KeyCode(0xc8);
+    pub const PAGE_UP: KeyCode = KeyCode(0xc9);
+    pub const LEFT: KeyCode = KeyCode(0xcb);
+    pub const RIGHT: KeyCode = KeyCode(0xcd);
+    pub const END: KeyCode = KeyCode(0xcf);
+    pub const DOWN: KeyCode = KeyCode(0xd0);
+    pub const PAGE_DOWN: KeyCode = KeyCode(0xd1);
+    pub const INSERT: KeyCode = KeyCode(0xd2);
+    pub const DELETE: KeyCode = KeyCode(0xd3);
+
+    /// Return true if the key is on the keypad. The keys shared with the
+    /// cursor keys are only on the keypad without the extended prefix.
+    pub fn is_keypad(self) -> bool {
+        self.0 >= KeyCode::KEYPAD_START.0 && self.0 <= KeyCode::KEYPAD_END.0
+    }
+}
+
+/// A key that is pressed or released.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+}
+
+/// The scancode sets understood by [Decoder](Decoder).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// A decoder that turns the bytes from the keyboard into
+/// [KeyEvent](KeyEvent)s. A scancode can span more than one byte, so the
+/// decoder keeps the prefixes it has seen.
+#[derive(Debug)]
+pub struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    // The number of bytes of the Pause key that are still to be skipped.
+    pause: u8,
+}
+
+impl Decoder {
+    /// Create a [Decoder](Decoder) for the scancode set `set`.
+    pub const fn new(set: ScancodeSet) -> Decoder {
+        Decoder {
+            set,
+            extended: false,
+            release: false,
+            pause: 0,
+        }
+    }
+
+    /// Decode the next byte from the keyboard. Return the event if the byte
+    /// completes a scancode of a known key.
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.pause > 0 {
+            // The Pause key can't be told apart from its own release, so
+            // it's ignored as a whole.
+            if !(self.set == ScancodeSet::Set2 && byte == RELEASE) {
+                self.pause -= 1;
+            }
+            return None;
+        }
+        match byte {
+            EXTENDED => {
+                self.extended = true;
+                return None;
+            },
+            PAUSE => {
+                self.pause = PAUSE_LEN;
+                return None;
+            },
+            RELEASE if self.set == ScancodeSet::Set2 => {
+                self.release = true;
+                return None;
+            },
+            _ => {},
+        }
+        let extended = self.extended;
+        let (code, pressed) = match self.set {
+            ScancodeSet::Set1 => {
+                (byte & !RELEASE_BIT, byte & RELEASE_BIT == 0)
+            },
+            ScancodeSet::Set2 => {
+                let code = SET2_TO_SET1.get(byte as usize).cloned();
+                (code.unwrap_or(0), !self.release)
+            },
+        };
+        self.extended = false;
+        self.release = false;
+        // The keyboard sends fake Shift codes around some extended keys,
+        // like Print Screen, for the old systems that don't know them.
+        if code == 0 || extended && (code == KeyCode::LEFT_SHIFT.0 ||
+                                     code == KeyCode::RIGHT_SHIFT.0) {
+            return None;
+        }
+        let key = if extended { code | 0x80 } else { code };
+        Some(KeyEvent { key: KeyCode(key), pressed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn decode(set: ScancodeSet, bytes: &[u8]) -> Vec<KeyEvent> {
+        let mut decoder = Decoder::new(set);
+        bytes.iter().filter_map(|&byte| decoder.feed(byte)).collect()
+    }
+
+    fn press(key: KeyCode) -> KeyEvent {
+        KeyEvent { key, pressed: true }
+    }
+
+    fn release(key: KeyCode) -> KeyEvent {
+        KeyEvent { key, pressed: false }
+    }
+
+    #[test]
+    fn decode_set1() {
+        let events = decode(ScancodeSet::Set1, &[0x2a, 0x1e, 0x9e, 0xaa]);
+        assert_eq!(events, [press(KeyCode::LEFT_SHIFT), press(KeyCode(0x1e)),
+                            release(KeyCode(0x1e)),
+                            release(KeyCode::LEFT_SHIFT)]);
+        let events = decode(ScancodeSet::Set1, &[0xe0, 0x48, 0xe0, 0xc8]);
+        assert_eq!(events, [press(KeyCode::UP), release(KeyCode::UP)]);
+    }
+
+    #[test]
+    fn decode_set2() {
+        let events = decode(ScancodeSet::Set2,
+                            &[0x12, 0x1c, 0xf0, 0x1c, 0xf0, 0x12]);
+        assert_eq!(events, [press(KeyCode::LEFT_SHIFT), press(KeyCode(0x1e)),
+                            release(KeyCode(0x1e)),
+                            release(KeyCode::LEFT_SHIFT)]);
+        let events = decode(ScancodeSet::Set2,
+                            &[0xe0, 0x75, 0xe0, 0xf0, 0x75, 0xe0, 0x11]);
+        assert_eq!(events, [press(KeyCode::UP), release(KeyCode::UP),
+                            press(KeyCode::RIGHT_ALT)]);
+        // The keypad keys are the cursor keys without the prefix.
+        let events = decode(ScancodeSet::Set2, &[0x75, 0xe0, 0x4a, 0x83]);
+        assert_eq!(events, [press(KeyCode(0x48)), press(KeyCode::KEYPAD_SLASH),
+                            press(KeyCode(0x41))]);
+    }
+
+    #[test]
+    fn skip_fake_keys() {
+        // Print Screen, and then Pause followed by A.
+        let set1 = [0xe0, 0x2a, 0xe0, 0x37, 0xe0, 0xb7, 0xe0, 0xaa,
+                    0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0x1e];
+        let events = decode(ScancodeSet::Set1, &set1);
+        assert_eq!(events, [press(KeyCode(0xb7)), release(KeyCode(0xb7)),
+                            press(KeyCode(0x1e))]);
+        let set2 = [0xe0, 0x12, 0xe0, 0x7c, 0xe0, 0xf0, 0x7c, 0xe0, 0xf0,
+                    0x12, 0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77,
+                    0x1c];
+        let events = decode(ScancodeSet::Set2, &set2);
+        assert_eq!(events, [press(KeyCode(0xb7)), release(KeyCode(0xb7)),
+                            press(KeyCode(0x1e))]);
+        // Unknown scancodes and the replies of the keyboard are ignored.
+        assert!(decode(ScancodeSet::Set2, &[0xfa, 0x00, 0x0f]).is_empty());
+    }
+}
@@ -17,13 +17,15 @@
 use core::slice;
 use core::fmt;
 use ::collections::{Interval, StaticIntvlist};
+#[cfg(not(test))]
+use ::paging::phy_to_virt;
 
 const LIST_SIZE: usize = 0x100;
 
 // In our convention, the bootloader will store the memory map
 // at 0x508 and the number of entries in the map at 0x500.
 #[cfg(not(test))]
-const LAYOUT_BUFFER: *const u64 = 0x500 as *const u64;
+const LAYOUT_BUFFER: *const u64 = phy_to_virt(0x500) as *const u64;
 
 /// Memory entry type returned from the BIOS.
 #[derive(Copy, Clone, Debug)]
@@ -86,7 +88,7 @@ impl MemoryLayout {
     }
 
     /// Create [MemoryLayout](MemoryLayout) from data starting at
-    /// address 0x500.
+    /// physical address 0x500.
     pub fn new() -> MemoryLayout {
         unsafe {
             let len = *LAYOUT_BUFFER as usize;
@@ -17,6 +17,11 @@
 
 //! A loader used to load ELF files.
 
+use core::cmp;
+use ::config::PAGE_SIZE;
+use ::process::File;
+use ::vm::{AddressSpace, Backing, Protection, Vma, VmaFlags};
+
 macro_rules! assert_and_shift_bytes {
     {$bytes:expr, $expected:expr} => {{
         if $bytes.len() < $expected.len() {
@@ -36,10 +41,11 @@ macro_rules! extract_and_shift_bytes {
         if $bytes.len() < $len {
             return Err(());
         }
+        // The fields of ELF are in little-endian.
         let mut result: u128 = 0;
-        for i in 0..$len {
-            result *= 8;
-            result += u128::from($bytes[i]);
+        for i in (0..$len).rev() {
+            result <<= 8;
+            result |= u128::from($bytes[i]);
         }
         $bytes = &$bytes[$len..];
         result
@@ -106,31 +112,149 @@ fn parse_file_header(input_bytes: &[u8]) -> Result<FileHeader, ()> {
     })
 }
 
-fn load_segment(input_bytes: &[u8]) -> Result<(), ()> {
-    let mut bytes = input_bytes;
-    // We support only PT_LOAD.
-    assert_and_shift_bytes!(bytes, [1, 0, 0, 0]);
+/// The type of a program header entry for a loadable segment.
+const PT_LOAD: u32 = 1;
 
-    // TODO: We will ignore the flags for now and implement it later.
-    bytes = &bytes[4..];
+/// The permission flags of a segment.
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
 
-    let _offset = extract_and_shift_bytes!(bytes, 8) as u64;
-    let _address = extract_and_shift_bytes!(bytes, 8) as u64;
+/// The information of the executable that is loaded to an address space.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Image {
+    /// The address of the entry point.
+    pub entry: usize,
+    /// The end of the highest loadable segment.
+    pub end: usize,
+}
+
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    mem_size: u64,
+}
+
+fn parse_program_header(input_bytes: &[u8]) -> Result<ProgramHeader, ()> {
+    let mut bytes = input_bytes;
+    let kind = extract_and_shift_bytes!(bytes, 4) as u32;
+    let flags = extract_and_shift_bytes!(bytes, 4) as u32;
+    let offset = extract_and_shift_bytes!(bytes, 8) as u64;
+    let address = extract_and_shift_bytes!(bytes, 8) as u64;
 
     // Ignore suggested physical address.
     bytes = &bytes[8..];
 
-    let _file_size = extract_and_shift_bytes!(bytes, 8) as u64;
-    let _mem_size = extract_and_shift_bytes!(bytes, 8) as u64;
-
-    // Ignore alignment.
+    let file_size = extract_and_shift_bytes!(bytes, 8) as u64;
     #[allow(unused_assignments)]
-    bytes = &bytes[8..];
+    let mem_size = extract_and_shift_bytes!(bytes, 8) as u64;
+
+    Ok(ProgramHeader {
+        kind,
+        flags,
+        offset,
+        address,
+        file_size,
+        mem_size,
+    })
+}
+
+/// Return the memory area for the loadable segment `header` of `file`, or
+/// None if the segment is empty. The pages are loaded when they are
+/// touched.
+fn segment_vma(file: File, header: &ProgramHeader)
+    -> Result<Option<Vma>, ()>
+{
+    let address = header.address as usize;
+    let file_end = header.offset.checked_add(header.file_size)
+        .ok_or(())? as usize;
+    let end = address.checked_add(header.mem_size as usize).ok_or(())?;
+    // The address and the offset must be the same modulo the page size, so
+    // the segment can be mapped page by page.
+    let page_offset = address & (PAGE_SIZE - 1);
+    if header.file_size > header.mem_size || file_end > file.data.len() ||
+        header.offset as usize & (PAGE_SIZE - 1) != page_offset
+    {
+        return Err(());
+    }
+
+    let start = address - page_offset;
+    let end = end.checked_add(PAGE_SIZE - 1).ok_or(())? & !(PAGE_SIZE - 1);
+    if start == end {
+        return Ok(None);
+    }
+    Ok(Some(Vma {
+        start,
+        end,
+        prot: Protection {
+            read: header.flags & PF_R != 0,
+            write: header.flags & PF_W != 0,
+            exec: header.flags & PF_X != 0,
+        },
+        flags: VmaFlags::default(),
+        // The data after the end of the segment in the file is cut, so the
+        // rest of the segment is filled with zeroes.
+        backing: Backing::File {
+            name: file.name,
+            data: &file.data[..file_end],
+            offset: header.offset as usize - page_offset,
+        },
+    }))
+}
 
+/// Add the memory area `vma` of a segment to `space`. `last` is the area of
+/// the previous segment, which is not added yet because its last page may
+/// be shared with `vma`. The area of `vma` that is not added yet is put in
+/// `last` afterwards.
+fn load_segment(vma: Vma, last: &mut Option<Vma>, space: &mut AddressSpace)
+    -> Result<(), ()>
+{
+    let mut vma = vma;
+    if let Some(mut prev) = last.take() {
+        if vma.start < prev.end {
+            // The segments are sorted by their addresses and don't overlap,
+            // so only one page can be shared.
+            if vma.start != prev.end - PAGE_SIZE {
+                return Err(());
+            }
+            // The content of the shared page comes from the later segment,
+            // as in Linux, but the page can be accessed as both segments.
+            let prot = Protection {
+                read: prev.prot.read || vma.prot.read,
+                write: prev.prot.write || vma.prot.write,
+                exec: prev.prot.exec || vma.prot.exec,
+            };
+            prev.end = vma.start;
+            if prev.start < prev.end {
+                space.insert_vma(prev)?;
+            }
+            space.insert_vma(Vma {
+                start: vma.start,
+                end: vma.start + PAGE_SIZE,
+                prot,
+                flags: vma.flags,
+                backing: vma.backing.clone(),
+            })?;
+            vma.start += PAGE_SIZE;
+            vma.backing = vma.backing.advance(PAGE_SIZE);
+        } else {
+            space.insert_vma(prev)?;
+        }
+    }
+    if vma.start < vma.end {
+        *last = Some(vma);
+    }
     Ok(())
 }
 
-pub fn load_elf(bytes: &[u8]) -> Result<(), ()> {
+/// Load the executable `file` to `space`. The program break of `space` is
+/// set to the end of the highest loadable segment. Only the tests call this
+/// until the init process is started.
+pub fn load_elf(file: File, space: &mut AddressSpace) -> Result<Image, ()> {
+    let bytes = file.data;
     let file_header = parse_file_header(bytes)?;
 
     // The only supported program header entry size is 0x38 bytes because
@@ -141,18 +265,173 @@ pub fn load_elf(bytes: &[u8]) -> Result<(), ()> {
 
     // If the blob is not big enough to find a program header table, return
     // an error.
-    let phsize = file_header.phentsize * file_header.phnum;
-    if (bytes.len() as u64) < file_header.ph_offset + u64::from(phsize) {
+    let phsize = usize::from(file_header.phentsize) *
+        usize::from(file_header.phnum);
+    let ph_end = (file_header.ph_offset as usize).checked_add(phsize)
+        .ok_or(())?;
+    if bytes.len() < ph_end {
         return Err(());
     }
 
-    for i in 0..file_header.phnum {
+    let mut image_end = 0;
+    let mut last = None;
+    for i in 0..usize::from(file_header.phnum) {
         let ph_table = &bytes[(file_header.ph_offset as usize)..];
-        let phent_start = (i * file_header.phentsize) as usize;
-        let phent_end = ((i+1) * file_header.phentsize) as usize;
-        let phent = &ph_table[phent_start..phent_end];
-        let _ = load_segment(phent);
+        let phent_start = i * usize::from(file_header.phentsize);
+        let phent_end = (i+1) * usize::from(file_header.phentsize);
+        let header = parse_program_header(&ph_table[phent_start..phent_end])?;
+        if header.kind != PT_LOAD {
+            continue;
+        }
+        if let Some(vma) = segment_vma(file, &header)? {
+            load_segment(vma, &mut last, space)?;
+        }
+        image_end = cmp::max(image_end,
+                             (header.address + header.mem_size) as usize);
     }
+    if let Some(vma) = last {
+        space.insert_vma(vma)?;
+    }
+    space.init_brk(image_end);
 
-    Ok(())
+    Ok(Image {
+        entry: file_header.entry as usize,
+        end: image_end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use super::*;
+    use ::paging::PagingContext;
+
+    fn put(bytes: &mut Vec<u8>, offset: usize, value: u64, len: usize) {
+        for i in 0..len {
+            bytes[offset + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    /// Create an ELF file whose program headers are `headers`. Each header
+    /// is (type, flags, offset, address, file size, memory size).
+    fn elf(headers: &[(u32, u32, u64, u64, u64, u64)], len: usize) -> File {
+        let mut bytes = vec![0; len];
+        bytes[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        put(&mut bytes, 16, 2, 2);
+        put(&mut bytes, 18, 0x3e, 2);
+        put(&mut bytes, 20, 1, 4);
+        put(&mut bytes, 24, 0x40_1000, 8);
+        put(&mut bytes, 32, 64, 8);
+        put(&mut bytes, 52, 64, 2);
+        put(&mut bytes, 54, 0x38, 2);
+        put(&mut bytes, 56, headers.len() as u64, 2);
+        for (i, header) in headers.iter().enumerate() {
+            let base = 64 + i * 0x38;
+            put(&mut bytes, base, u64::from(header.0), 4);
+            put(&mut bytes, base + 4, u64::from(header.1), 4);
+            put(&mut bytes, base + 8, header.2, 8);
+            put(&mut bytes, base + 16, header.3, 8);
+            put(&mut bytes, base + 32, header.4, 8);
+            put(&mut bytes, base + 40, header.5, 8);
+        }
+        File {
+            name: "/bin/init",
+            data: Box::leak(bytes.into_boxed_slice()),
+        }
+    }
+
+    #[test]
+    fn load_segments() {
+        let file = elf(&[
+            (PT_LOAD, PF_R | PF_X, 0x0, 0x40_0000, 0x1800, 0x1800),
+            // PT_NOTE is ignored.
+            (4, PF_R, 0x0, 0x50_0000, 0x10, 0x10),
+            (PT_LOAD, PF_R | PF_W, 0x2100, 0x40_3100, 0x100, 0x2000),
+        ], 0x2200);
+        let mut space = AddressSpace::new(PagingContext::new());
+        let image = load_elf(file, &mut space).unwrap();
+        assert_eq!(image, Image {
+            entry: 0x40_1000,
+            end: 0x40_5100,
+        });
+        assert_eq!(space.brk(), 0x40_6000);
+
+        let text = space.vmas().lookup(0x40_0000).unwrap();
+        assert_eq!((text.start, text.end), (0x40_0000, 0x40_2000));
+        assert!(text.prot.exec && !text.prot.write);
+        let data = space.vmas().lookup(0x40_3000).unwrap();
+        assert_eq!((data.start, data.end), (0x40_3000, 0x40_6000));
+        assert!(!data.prot.exec && data.prot.write);
+        match data.backing {
+            Backing::File { data, offset, .. } => {
+                assert_eq!(data.len(), 0x2200);
+                assert_eq!(offset, 0x2000);
+            },
+            _ => panic!("the segment should be file-backed"),
+        }
+        assert!(space.vmas().lookup(0x50_0000).is_none());
+    }
+
+    #[test]
+    fn load_invalid_segments() {
+        let mut space = AddressSpace::new(PagingContext::new());
+        // The segment is beyond the end of the file.
+        let file = elf(&[(PT_LOAD, PF_R, 0x0, 0x40_0000, 0x1000, 0x1000)],
+                       0x100);
+        assert!(load_elf(file, &mut space).is_err());
+        // The address and the offset are not the same modulo the page size.
+        let file = elf(&[(PT_LOAD, PF_R, 0x10, 0x40_0000, 0x10, 0x10)],
+                       0x100);
+        assert!(load_elf(file, &mut space).is_err());
+        // The file size is larger than the memory size.
+        let file = elf(&[(PT_LOAD, PF_R, 0x0, 0x40_0000, 0x20, 0x10)],
+                       0x100);
+        assert!(load_elf(file, &mut space).is_err());
+        // The segments overlap by more than a page.
+        let file = elf(&[
+            (PT_LOAD, PF_R, 0x0, 0x40_0000, 0x100, 0x2000),
+            (PT_LOAD, PF_R, 0x0, 0x40_0000, 0x100, 0x100),
+        ], 0x100);
+        assert!(load_elf(file, &mut space).is_err());
+    }
+
+    #[test]
+    fn load_segments_sharing_page() {
+        let file = elf(&[
+            (PT_LOAD, PF_R | PF_X, 0x0, 0x40_0000, 0x1800, 0x1800),
+            (PT_LOAD, PF_R | PF_W, 0x1800, 0x40_1800, 0x1000, 0x1000),
+            (PT_LOAD, PF_R, 0x2800, 0x40_2800, 0x100, 0x100),
+        ], 0x2900);
+        let mut space = AddressSpace::new(PagingContext::new());
+        load_elf(file, &mut space).unwrap();
+
+        let vmas: Vec<_> = space.vmas().iter()
+            .map(|vma| (vma.start, vma.end, vma.prot.write, vma.prot.exec))
+            .collect();
+        assert_eq!(vmas, [
+            (0x40_0000, 0x40_1000, false, true),
+            (0x40_1000, 0x40_2000, true, true),
+            (0x40_2000, 0x40_3000, true, false),
+        ]);
+        // The shared pages are loaded from the later segments.
+        match space.vmas().lookup(0x40_2000).unwrap().backing {
+            Backing::File { data, offset, .. } => {
+                assert_eq!(data.len(), 0x2900);
+                assert_eq!(offset, 0x2000);
+            },
+            _ => panic!("the segment should be file-backed"),
+        }
+    }
+
+    #[test]
+    fn program_headers_out_of_file() {
+        let mut file = elf(&[], 0x100);
+        let mut bytes = file.data.to_vec();
+        put(&mut bytes, 32, !0 - 0x10, 8);
+        put(&mut bytes, 56, 1, 2);
+        file.data = Box::leak(bytes.into_boxed_slice());
+        let mut space = AddressSpace::new(PagingContext::new());
+        assert!(load_elf(file, &mut space).is_err());
+    }
 }
@@ -15,6 +15,7 @@
 // along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
 
 //! Loader module. This module includes all the loaders for each format
-//! to be used to create processes.
+//! to be used to create processes. There is no init program to load yet,
+//! so the loaders are only used by the tests for now.
 
 pub mod elf;
@@ -0,0 +1,194 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The buffer that keeps the latest log messages, like the one read by
+//! `dmesg` in Linux.
+
+use core::fmt;
+
+/// The static size of the buffer.
+#[cfg(not(test))]
+pub const BUFFER_SIZE: usize = 0x0001_0000;
+#[cfg(test)]
+pub const BUFFER_SIZE: usize = 64;
+
+/// A ring buffer of text. When it's full, the oldest lines are dropped to
+/// make room for the new text.
+pub struct LogBuffer {
+    buf: [u8; BUFFER_SIZE],
+    // The index of the oldest byte.
+    head: usize,
+    // The number of bytes in the buffer.
+    len: usize,
+}
+
+impl LogBuffer {
+    /// Create an empty [LogBuffer](LogBuffer).
+    pub const fn new() -> LogBuffer {
+        LogBuffer {
+            buf: [0; BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Return the number of bytes in the buffer.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Drop the oldest line, so that a partial line is not left at the
+    /// beginning of the buffer unless the line is longer than the buffer.
+    fn drop_line(&mut self) {
+        while self.len > 0 {
+            let byte = self.buf[self.head];
+            self.head = (self.head + 1) % BUFFER_SIZE;
+            self.len -= 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+    }
+
+    /// Append `bytes` to the buffer.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == BUFFER_SIZE {
+                self.drop_line();
+            }
+            self.buf[(self.head + self.len) % BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Return the content of the buffer in two slices. The first one has the
+    /// older bytes.
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        if self.head + self.len <= BUFFER_SIZE {
+            (&self.buf[self.head..self.head + self.len], &[])
+        } else {
+            let end = self.head + self.len - BUFFER_SIZE;
+            (&self.buf[self.head..], &self.buf[..end])
+        }
+    }
+
+    /// Copy the latest bytes to `out` until either of them runs out. Return
+    /// the number of bytes copied.
+    pub fn read_last(&self, out: &mut [u8]) -> usize {
+        let count = if out.len() < self.len { out.len() } else { self.len };
+        let skip = self.len - count;
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.buf[(self.head + skip + i) % BUFFER_SIZE];
+        }
+        count
+    }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn content(buffer: &LogBuffer) -> Vec<u8> {
+        let (first, second) = buffer.as_slices();
+        let mut content = first.to_vec();
+        content.extend_from_slice(second);
+        content
+    }
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for part in parts.iter() {
+            result.extend_from_slice(part);
+        }
+        result
+    }
+
+    /// Return a line of 16 bytes that starts with `first`.
+    fn line(first: u8) -> Vec<u8> {
+        let mut line = Vec::new();
+        line.resize(15, first);
+        line.push(b'\n');
+        line
+    }
+
+    #[test]
+    fn keep_all_lines() {
+        let mut buffer = LogBuffer::new();
+        buffer.write(b"one\n");
+        buffer.write(b"two\n");
+        assert_eq!(buffer.len(), 8);
+        assert_eq!(content(&buffer), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn drop_oldest_lines() {
+        let mut buffer = LogBuffer::new();
+        for &first in b"abc".iter() {
+            buffer.write(&line(first));
+        }
+        buffer.write(b"xyz\n");
+        buffer.write(&line(b'd'));
+        let expected = concat(&[&line(b'b'), &line(b'c'), b"xyz\n",
+                                &line(b'd')]);
+        assert_eq!(content(&buffer), expected);
+        {
+            let (first, second) = buffer.as_slices();
+            assert!(!first.is_empty() && !second.is_empty());
+        }
+
+        // The whole line is dropped, even if only one byte is needed.
+        buffer.write(b"0123456789ab\n");
+        let expected = concat(&[&line(b'c'), b"xyz\n", &line(b'd'),
+                                b"0123456789ab\n"]);
+        assert_eq!(content(&buffer), expected);
+    }
+
+    #[test]
+    fn drop_long_line() {
+        let mut buffer = LogBuffer::new();
+        let long: Vec<u8> = (0..70).map(|i| b'a' + i % 26).collect();
+        buffer.write(&long);
+        buffer.write(b"\nk");
+        // Only the end of a line longer than the buffer can be kept.
+        assert_eq!(content(&buffer), concat(&[&long[64..], b"\nk"]));
+    }
+
+    #[test]
+    fn read_latest_bytes() {
+        let mut buffer = LogBuffer::new();
+        for &first in b"abcde".iter() {
+            buffer.write(&line(first));
+        }
+        let mut out = [0; 128];
+        let count = buffer.read_last(&mut out);
+        // The first line is already dropped.
+        let expected = concat(&[&line(b'b'), &line(b'c'), &line(b'd'),
+                                &line(b'e')]);
+        assert_eq!(&out[..count], &expected[..]);
+        let mut out = [0; 5];
+        assert_eq!(buffer.read_last(&mut out), 5);
+        assert_eq!(&out, &line(b'e')[11..]);
+    }
+}
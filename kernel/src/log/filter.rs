@@ -0,0 +1,228 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Log levels and the filter that decides which messages are logged.
+
+use core::str;
+
+/// The maximum number of modules that can have their own levels.
+const MAX_RULES: usize = 16;
+/// The maximum length of the name of a module in a rule.
+const MAX_NAME_LEN: usize = 32;
+
+/// The levels of the log messages, from the most important one.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Return the level whose name is `name`, ignoring the case.
+    fn from_name(name: &str) -> Result<Level, ()> {
+        let levels = [Level::Error, Level::Warn, Level::Info, Level::Debug,
+                      Level::Trace];
+        for &level in levels.iter() {
+            if level.name().eq_ignore_ascii_case(name) {
+                return Ok(level);
+            }
+        }
+        Err(())
+    }
+
+    /// Return the name of the level, which is shown in the messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// The maximum level of a module. The filter may be built from a string
+/// that doesn't live forever, so the name is copied.
+#[derive(Copy, Clone, Debug)]
+struct Rule {
+    name: [u8; MAX_NAME_LEN],
+    len: usize,
+    level: Level,
+}
+
+impl Rule {
+    /// Create a [Rule](Rule) for the module `name`. Return an error if the
+    /// name is too long.
+    fn new(name: &str, level: Level) -> Result<Rule, ()> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(());
+        }
+        let mut rule = Rule {
+            name: [0; MAX_NAME_LEN],
+            len: name.len(),
+            level,
+        };
+        rule.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(rule)
+    }
+
+    fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.len]).unwrap()
+    }
+}
+
+/// A filter that allows the messages up to a maximum level. Each module can
+/// have its own maximum level, which also applies to its submodules.
+#[derive(Debug)]
+pub struct Filter {
+    // The maximum level of the modules without rules.
+    default: Level,
+    // The modules that have their own levels.
+    rules: [Rule; MAX_RULES],
+    num_rules: usize,
+}
+
+impl Filter {
+    /// Create a [Filter](Filter) that allows the messages up to `default`
+    /// from all the modules.
+    pub const fn new(default: Level) -> Filter {
+        Filter {
+            default,
+            rules: [Rule {
+                name: [0; MAX_NAME_LEN],
+                len: 0,
+                level: Level::Error,
+            }; MAX_RULES],
+            num_rules: 0,
+        }
+    }
+
+    /// Parse a filter like `warn,smp=debug,kalloc::debug=trace`, which
+    /// allows the warnings from all the modules, the debug messages from
+    /// smp, and all the messages from kalloc::debug. The module names don't
+    /// include the name of the crate.
+    pub fn parse(spec: &str) -> Result<Filter, ()> {
+        let mut filter = Filter::new(Level::Info);
+        for rule in spec.split(',').map(str::trim) {
+            if rule.is_empty() {
+                continue;
+            }
+            let mut parts = rule.splitn(2, '=');
+            let first = parts.next().unwrap().trim();
+            match parts.next() {
+                None => filter.default = Level::from_name(first)?,
+                Some(level) => {
+                    if first.is_empty() || filter.num_rules == MAX_RULES {
+                        return Err(());
+                    }
+                    let level = Level::from_name(level.trim())?;
+                    filter.rules[filter.num_rules] = Rule::new(first, level)?;
+                    filter.num_rules += 1;
+                },
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Return true if the messages of level `level` from the module
+    /// `module` are allowed. The rule of the innermost module that contains
+    /// `module` is used.
+    pub fn allows(&self, module: &str, level: Level) -> bool {
+        let mut max = self.default;
+        let mut longest = 0;
+        for rule in self.rules[..self.num_rules].iter() {
+            let name = rule.name();
+            let contains = module == name || module.starts_with(name) &&
+                module[name.len()..].starts_with("::");
+            if contains && name.len() >= longest {
+                max = rule.level;
+                longest = name.len();
+            }
+        }
+        level <= max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    #[test]
+    fn parse_level() {
+        assert_eq!(Level::from_name("error"), Ok(Level::Error));
+        assert_eq!(Level::from_name("WARN"), Ok(Level::Warn));
+        assert_eq!(Level::from_name("Trace"), Ok(Level::Trace));
+        assert!(Level::from_name("verbose").is_err());
+        assert!(Level::Error < Level::Warn);
+        assert!(Level::Debug < Level::Trace);
+    }
+
+    #[test]
+    fn default_level() {
+        let filter = Filter::parse("").unwrap();
+        assert!(filter.allows("smp", Level::Info));
+        assert!(!filter.allows("smp", Level::Debug));
+        let filter = Filter::parse("warn").unwrap();
+        assert!(filter.allows("smp", Level::Error));
+        assert!(!filter.allows("smp", Level::Info));
+    }
+
+    #[test]
+    fn module_levels() {
+        let spec = " error, kalloc=debug,kalloc::debug = trace,smp=warn,";
+        let filter = Filter::parse(spec).unwrap();
+        assert!(filter.allows("kalloc", Level::Debug));
+        assert!(!filter.allows("kalloc", Level::Trace));
+        assert!(filter.allows("kalloc::allocator", Level::Debug));
+        assert!(filter.allows("kalloc::debug", Level::Trace));
+        assert!(filter.allows("kalloc::debug::site", Level::Trace));
+        assert!(filter.allows("smp::trampoline", Level::Warn));
+        assert!(!filter.allows("smp::trampoline", Level::Info));
+        // A module is not a submodule of another one with the same prefix.
+        assert!(!filter.allows("kallocator", Level::Warn));
+        assert!(!filter.allows("paging", Level::Warn));
+    }
+
+    #[test]
+    fn outlive_spec() {
+        let spec = String::from("warn,smp=debug");
+        let filter = Filter::parse(&spec).unwrap();
+        drop(spec);
+        assert!(filter.allows("smp", Level::Debug));
+        assert!(!filter.allows("paging", Level::Info));
+    }
+
+    #[test]
+    fn invalid_filter() {
+        assert!(Filter::parse("loud").is_err());
+        assert!(Filter::parse("smp=loud").is_err());
+        assert!(Filter::parse("=info").is_err());
+        let long = "a_module_name_longer_than_32_bytes=info";
+        assert!(Filter::parse(long).is_err());
+        let rules = "a=info,b=info,c=info,d=info,e=info,f=info,g=info,h=info,\
+                     i=info,j=info,k=info,l=info,m=info,n=info,o=info,p=info";
+        assert!(Filter::parse(rules).is_ok());
+        let rules = "a=info,b=info,c=info,d=info,e=info,f=info,g=info,h=info,\
+                     i=info,j=info,k=info,l=info,m=info,n=info,o=info,p=info,\
+                     q=info";
+        assert!(Filter::parse(rules).is_err());
+    }
+}
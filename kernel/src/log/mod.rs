@@ -0,0 +1,270 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Logging module. The kernel logs its messages with the macros in this
+//! module, like [info](info) and [warn](warn), instead of printing them.
+//!
+//! Every message has a level and comes from a module. The filter decides
+//! which messages are logged. It's built from the configuration at boot,
+//! and can be replaced at runtime with [set_filter](set_filter). The logged
+//! messages are kept in a buffer, which can be read later by the user
+//! programs, and printed on the console. The messages logged before the
+//! console is ready are printed when it becomes ready.
+
+mod buffer;
+mod filter;
+
+use core::cmp;
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+#[cfg(not(test))]
+use ::config::LOG_FILTER;
+#[cfg(not(test))]
+use ::debug;
+use ::sync::IrqSpinlock;
+use ::time;
+use self::buffer::LogBuffer;
+use self::filter::Filter;
+
+pub use self::buffer::BUFFER_SIZE;
+pub use self::filter::Level;
+
+/// The maximum length of a log message. The longer ones are truncated.
+const LINE_SIZE: usize = 0x200;
+
+/// The state of the logger.
+struct Logger {
+    buffer: LogBuffer,
+    filter: Filter,
+    // True if the messages are printed on the console too.
+    #[cfg_attr(test, allow(dead_code))]
+    console: bool,
+}
+
+/// The messages may be logged by interrupt handlers too, so the interrupts
+/// are disabled while the logger is locked. The filter allows the messages
+/// up to [Info](Level::Info) until the configured filter is parsed.
+static LOGGER: IrqSpinlock<Logger> = IrqSpinlock::new(Logger {
+    buffer: LogBuffer::new(),
+    filter: Filter::new(Level::Info),
+    console: false,
+});
+
+/// A log message with its details. It's displayed as one line.
+struct Record<'a> {
+    time: Duration,
+    level: Level,
+    module: &'a str,
+    args: fmt::Arguments<'a>,
+}
+
+impl<'a> fmt::Display for Record<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[{:5}.{:06}] {:<5} {}: {}", self.time.as_secs(),
+                 self.time.subsec_micros(), self.level.name(), self.module,
+                 self.args)
+    }
+}
+
+/// A log message formatted on the stack, so that it can be formatted
+/// before the logger is locked.
+struct Line {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Line {
+        Line {
+            buf: [0; LINE_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Return the formatted message. A truncated message still ends with a
+    /// line feed.
+    fn as_bytes(&mut self) -> &[u8] {
+        if self.len == LINE_SIZE {
+            self.buf[LINE_SIZE - 1] = b'\n';
+        }
+        &self.buf[..self.len]
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        let len = cmp::min(s.len(), LINE_SIZE - self.len);
+        self.buf[self.len..self.len + len]
+            .copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Return the name of the module whose path is `path` without the name of
+/// the crate.
+fn module_name(path: &str) -> &str {
+    match path.find("::") {
+        Some(index) => &path[index + 2..],
+        None => path,
+    }
+}
+
+/// Log the message `args` of level `level` from the module whose path is
+/// `path`. This is called by the macros.
+pub fn log(level: Level, path: &'static str, args: fmt::Arguments) {
+    let module = module_name(path);
+    if !LOGGER.lock().filter.allows(module, level) {
+        return;
+    }
+    // The message is formatted without the lock, so that the interrupts are
+    // not disabled for the whole formatting, which runs arbitrary code.
+    let mut line = Line::new();
+    write!(line, "{}", Record {
+        time: time::now(),
+        level,
+        module,
+        args,
+    }).unwrap();
+    let line = line.as_bytes();
+    let console = {
+        let mut logger = LOGGER.lock();
+        logger.buffer.write(line);
+        logger.console
+    };
+    // The console is slow, so the line is printed after the logger is
+    // unlocked. The messages from different processors may be printed in a
+    // different order than they are in the buffer.
+    if console {
+        print(line);
+    }
+}
+
+/// Print `line` on the console.
+#[cfg(not(test))]
+fn print(line: &[u8]) {
+    debug::write_bytes(line);
+}
+
+/// There is no console in test.
+#[cfg(test)]
+fn print(_line: &[u8]) {}
+
+/// Replace the filter with the one parsed from `spec`, like
+/// `warn,smp=debug`. If `spec` is invalid, the filter is not changed and an
+/// error is returned.
+pub fn set_filter(spec: &str) -> Result<(), ()> {
+    let filter = Filter::parse(spec)?;
+    LOGGER.lock().filter = filter;
+    Ok(())
+}
+
+/// Copy the latest logged messages to `out` until either of them runs out.
+/// Return the number of bytes copied.
+pub fn read_last(out: &mut [u8]) -> usize {
+    LOGGER.lock().buffer.read_last(out)
+}
+
+/// Initialization function for logging module. The filter is built from the
+/// configuration and the messages are printed on the console after this.
+/// This must be called after the console is ready.
+#[cfg(not(test))]
+pub fn init() {
+    let valid = set_filter(LOG_FILTER).is_ok();
+    {
+        let mut logger = LOGGER.lock();
+        logger.console = true;
+        // Print the messages logged before the console is ready.
+        let (first, second) = logger.buffer.as_slices();
+        debug::write_bytes(first);
+        debug::write_bytes(second);
+    }
+    if !valid {
+        log(Level::Warn, module_path!(),
+            format_args!("invalid log filter \"{}\"", LOG_FILTER));
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::log::log($level, module_path!(), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => (log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => (log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => (log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => (log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => (log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_crate_name() {
+        assert_eq!(module_name("kernel"), "kernel");
+        assert_eq!(module_name("kernel::smp"), "smp");
+        assert_eq!(module_name("kernel::smp::trampoline"), "smp::trampoline");
+    }
+
+    #[test]
+    fn display_record() {
+        let line = format!("{}", Record {
+            time: Duration::new(12, 345_678_901),
+            level: Level::Warn,
+            module: "smp",
+            args: format_args!("{} processors", 4),
+        });
+        assert_eq!(line, "[   12.345678] WARN  smp: 4 processors\n");
+    }
+
+    #[test]
+    fn truncate_long_lines() {
+        let mut line = Line::new();
+        line.write_str("short\n").unwrap();
+        assert_eq!(line.as_bytes(), b"short\n");
+        let mut line = Line::new();
+        for _ in 0..LINE_SIZE {
+            write!(line, "ab").unwrap();
+        }
+        let bytes = line.as_bytes();
+        assert_eq!(bytes.len(), LINE_SIZE);
+        assert!(bytes.starts_with(b"abab"));
+        assert!(bytes.ends_with(b"a\n"));
+    }
+}
@@ -19,28 +19,53 @@
 #![feature(alloc_error_handler)]
 #![feature(asm)]
 #![feature(doc_cfg)]
+#![feature(global_asm)]
 #![feature(lang_items)]
 #![feature(panic_info_message)]
 #![feature(stmt_expr_attributes)]
+#![cfg_attr(test, feature(test))]
 #![no_std]
 #![cfg_attr(all(not(test), not(rustdoc)), no_main)]
 
 // Lints that are allowed.
 #![allow(clippy::explicit_iter_loop)]
 
-mod collections;
-mod config;
+// The debug and log modules come first, so that the other modules can use
+// their macros.
 #[cfg(not(test))]
 #[macro_use]
 mod debug;
+#[macro_use]
+mod log;
+mod apic;
+mod backtrace;
+mod collections;
+mod config;
+mod frame;
+mod gdb;
+mod gdt;
 mod interrupt;
 mod kalloc;
+mod keyboard;
 mod layout;
 mod loader;
 mod paging;
+mod percpu;
+mod pit;
+mod port;
+mod process;
+mod serial;
+mod smp;
+mod sync;
 mod syscall;
+mod time;
+mod tty;
 mod util;
+mod vga;
+mod vm;
 
+#[cfg(not(test))]
+use config::{KERNEL_STACK_END, KERNEL_VIRT_BASE};
 #[cfg(not(test))]
 use core::alloc::Layout;
 #[cfg(not(test))]
@@ -52,6 +77,8 @@ extern crate siphasher;
 #[cfg(test)]
 #[macro_use]
 extern crate std;
+#[cfg(test)]
+extern crate test;
 
 /// Global allocator which will be used when there is a heap allocation.
 #[cfg(not(test))]
@@ -59,37 +86,54 @@ extern crate std;
 static ALLOCATOR: kalloc::Allocator = kalloc::Allocator;
 
 /// An entry function when the kernel is booted.
-#[allow(clippy::empty_loop)]
 #[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    println!("Booting...");
+    info!("Booting...");
     init();
-    println!("Hello World!");
-    println!("My name is Kelner.");
+    info!("Hello World!");
+    info!("My name is Kelner.");
     run();
-    loop {}
+    // The interrupts of the devices are sent to the boot processor, so it
+    // must enable the interrupts when it has nothing else to do.
+    smp::idle();
 }
 
 #[cfg(not(test))]
 fn run() {
-    println!("Before interrupt.");
+    info!("Before interrupt.");
     unsafe {
-      asm!("int $$0x80");
+      // The system call handler returns the result in rax.
+      asm!("int $$0x80" ::: "rax" : "volatile");
     }
-    println!("After interrupt.");
+    info!("After interrupt.");
 }
 
 #[cfg(not(test))]
 /// Initialize everything.
 fn init() {
+    // The console is ready after the serial port is initialized. The
+    // messages logged before that are printed when the log module is
+    // initialized.
+    serial::init();
+    time::init();
+    log::init();
     // Layout init must come before the kalloc init because kalloc uses
     // so much stack memory and layout init can check and abort if there is
     // not enough physical memory.
     layout::init();
     kalloc::init();
     paging::init();
+    frame::init();
+    kalloc::init_growth();
+    smp::init_bsp(KERNEL_VIRT_BASE + KERNEL_STACK_END);
+    apic::init();
     interrupt::init();
+    serial::init_interrupts();
+    keyboard::init();
+    smp::init();
+    // The other processors are started first, so that GDB sees them.
+    gdb::init();
 }
 
 /// A function that will be called when there is a panic.
@@ -98,6 +142,9 @@ fn init() {
 #[panic_handler]
 #[no_mangle]
 pub fn panic(info: &PanicInfo) -> ! {
+    unsafe {
+        debug::force_unlock();
+    }
     debug::set_color(debug::Color::LightRed);
     println!("Kelner paniked!");
     if let Some(message) = info.message() {
@@ -107,7 +154,9 @@ pub fn panic(info: &PanicInfo) -> ! {
         println!("panic occurred in file '{}' at line {}", location.file(),
             location.line());
     }
+    backtrace::print();
     debug::reset_color();
+    debug::flush();
     loop {}
 }
 
@@ -115,7 +164,15 @@ pub fn panic(info: &PanicInfo) -> ! {
 #[cfg(not(test))]
 #[alloc_error_handler]
 #[no_mangle]
-pub fn error_handler(_layout: Layout) -> ! {
+pub fn error_handler(layout: Layout) -> ! {
+    debug::set_color(debug::Color::LightRed);
+    println!("Kelner ran out of memory!");
+    println!("cannot allocate {} bytes aligned to {} bytes", layout.size(),
+             layout.align());
+    kalloc::dump_stats();
+    backtrace::print();
+    debug::reset_color();
+    debug::flush();
     loop {}
 }
 
@@ -26,6 +26,9 @@ macro_rules! cr3 {
                 "write_through" => result = set_bits(result, 3, 4, $value),
                 "cache_disable" => result = set_bits(result, 4, 5, $value),
                 "address" => result = set_bits(result, 12, MAXPHYADDR, $value),
+                // These are used only when CR4.PCIDE is set.
+                "pcid" => result = set_bits(result, 0, 12, $value),
+                "no_flush" => result = set_bits(result, 63, 64, $value),
                 _ => (),
             }
         );*
@@ -57,8 +60,9 @@ macro_rules! page_directory_entry {
 #[macro_export]
 macro_rules! page_table_entry {
     {$(.$field:ident = $value:expr),*} => {{
-        // Because this is a final page, we need to set bit 7 (PS) to be 1.
-        let mut result: u64 = (1 << 7);
+        // Bit 7 of a 4-KByte page table entry is PAT, not PS, so we leave it
+        // cleared to get the default write-back memory type.
+        let mut result: u64 = 0;
         $(
             match stringify!($field) {
                 "present"       => result = set_bits(result, 0, 1, $value),
@@ -68,6 +72,7 @@ macro_rules! page_table_entry {
                 "cache_disable" => result = set_bits(result, 4, 5, $value),
                 "accessed"      => result = set_bits(result, 5, 6, $value),
                 "dirty"         => result = set_bits(result, 6, 7, $value),
+                "global"        => result = set_bits(result, 8, 9, $value),
                 "address" => result = set_bits(result, 12, MAXPHYADDR, $value),
                 "exe_disable"   => result = set_bits(result, 63, 64, $value),
                 _ => (),
@@ -0,0 +1,116 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Permissions and attributes of a page mapping.
+
+use ::paging::MAXPHYADDR;
+use ::util::set_bits;
+
+/// Permissions and attributes that are applied to a mapped page. A mapping
+/// with the default flags is readable and executable by the kernel only.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MapFlags {
+    /// The page can be written.
+    pub write: bool,
+    /// The page can be accessed from user mode.
+    pub user: bool,
+    /// Instructions cannot be fetched from the page.
+    pub exe_disable: bool,
+    /// The page is not cached by the processor.
+    pub cache_disable: bool,
+    /// The translation is not flushed from the TLB when CR3 is reloaded.
+    pub global: bool,
+}
+
+impl MapFlags {
+    /// Return a page table entry mapping a frame at physical address
+    /// `phy_addr` with these flags.
+    pub fn to_entry(self, phy_addr: usize) -> u64 {
+        page_table_entry! {
+            .present = 1,
+            .write = u64::from(self.write),
+            .supervisor = u64::from(self.user),
+            .cache_disable = u64::from(self.cache_disable),
+            .global = u64::from(self.global),
+            .exe_disable = u64::from(self.exe_disable),
+            .address = (phy_addr >> 12) as u64
+        }
+    }
+
+    /// Return a page directory entry mapping a 2-MByte or 1-GByte page at
+    /// physical address `phy_addr` with these flags.
+    pub fn to_huge_entry(self, phy_addr: usize) -> u64 {
+        // Bit 7 is PS in page directory entries. The other flags are at the
+        // same positions as in page table entries.
+        self.to_entry(phy_addr) | (1 << 7)
+    }
+
+    /// Extract the flags from a page table entry or a huge page entry.
+    pub fn from_entry(entry: u64) -> MapFlags {
+        let bit = |position: u8| entry & (1 << position) != 0;
+        MapFlags {
+            write: bit(1),
+            user: bit(2),
+            cache_disable: bit(4),
+            global: bit(8),
+            exe_disable: bit(63),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_entry_is_present_only() {
+        assert_eq!(MapFlags::default().to_entry(0x5000), 0x5001);
+    }
+
+    #[test]
+    fn correct_entry_bits() {
+        let flags = MapFlags {
+            write: true,
+            user: true,
+            exe_disable: true,
+            cache_disable: true,
+            global: true,
+        };
+        let entry = flags.to_entry(0x5000);
+        assert_eq!(entry, (1 << 63) | 0x5000 | (1 << 8) | (1 << 4) | 0b111);
+    }
+
+    #[test]
+    fn correct_huge_entry_bits() {
+        let flags = MapFlags {
+            write: true,
+            ..MapFlags::default()
+        };
+        assert_eq!(flags.to_huge_entry(0x20_0000), 0x20_0000 | 0x83);
+        assert_eq!(MapFlags::from_entry(flags.to_huge_entry(0x20_0000)),
+                   flags);
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let flags = MapFlags {
+            write: true,
+            exe_disable: true,
+            ..MapFlags::default()
+        };
+        assert_eq!(MapFlags::from_entry(flags.to_entry(0x5000)), flags);
+    }
+}
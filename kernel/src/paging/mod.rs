@@ -20,14 +20,158 @@
 
 #[macro_use]
 mod macros;
+mod map_flags;
 mod paging_context;
+pub mod tlb;
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(test))]
+use ::collections::StaticIntvlist;
+#[cfg(not(test))]
+use ::layout::MemoryLayout;
+#[cfg(not(test))]
+use ::sync::{Once, OwnedSpinlock, OwnedSpinlockGuard};
 use config::PAGE_SIZE;
-use self::paging_context::PagingContext;
+#[cfg(not(test))]
+use config::{
+    KERNEL_VIRT_BASE,
+    LOW_MEMORY,
+    PHYSMAP_START,
+    USED_KERNEL_MEMORY,
+};
+pub use self::map_flags::MapFlags;
+pub use self::paging_context::PagingContext;
 
 // TODO: We should query this number from CPUID instead.
 const MAXPHYADDR: u8 = 52;
 
+/// True if the processor supports 1-GByte pages. The tests pretend that it
+/// does.
+static PAGES_1G: AtomicBool = AtomicBool::new(cfg!(test));
+
+/// The paging context used by the kernel after the paging module is
+/// initialized.
+#[cfg(not(test))]
+static KERNEL_CONTEXT: Once<OwnedSpinlock<PagingContext>> = Once::new();
+
+/// Lock and return the paging context of the kernel.
+#[cfg(not(test))]
+pub fn kernel_context() -> OwnedSpinlockGuard<'static, PagingContext> {
+    // We have to make sure that we already initialized the paging module
+    // before using the context.
+    KERNEL_CONTEXT.get().unwrap().lock()
+}
+
+/// Return true if the paging context of the kernel is locked by the
+/// processor that is running this code, which would wait forever if it
+/// locked the context again.
+#[cfg(not(test))]
+pub fn holds_kernel_context() -> bool {
+    KERNEL_CONTEXT.get().map_or(false, |context| context.is_held_here())
+}
+
+/// Map the page of device memory at physical address `phy_addr` to the
+/// physmap, if it's not mapped yet. The page is not cached.
+#[cfg(not(test))]
+pub fn map_device(phy_addr: usize) {
+    let mut context = kernel_context();
+    let virt_addr = phy_to_virt(phy_addr);
+    if context.find_page(virt_addr).is_some() {
+        return;
+    }
+    let flags = MapFlags {
+        write: true,
+        global: true,
+        exe_disable: true,
+        cache_disable: true,
+        ..MapFlags::default()
+    };
+    context.insert(virt_addr, phy_addr, flags).unwrap();
+}
+
+/// Map the page at virtual address `virt_addr` in the kernel half to the
+/// frame at physical address `phy_addr`. The page is writable by the kernel
+/// only.
+#[cfg(not(test))]
+pub fn map_kernel(virt_addr: usize, phy_addr: usize) -> Result<(), ()> {
+    // The heap doesn't grow while this processor holds the context, so
+    // this doesn't wait for itself.
+    let mut context = kernel_context();
+    let flags = MapFlags {
+        write: true,
+        global: true,
+        exe_disable: true,
+        ..MapFlags::default()
+    };
+    context.insert(virt_addr, phy_addr, flags)
+}
+
+/// Unmap the page at virtual address `virt_addr` in the kernel half and
+/// flush it from the TLBs of all the processors. Return the physical address
+/// of the frame that was mapped.
+#[cfg(not(test))]
+pub fn unmap_kernel(virt_addr: usize) -> Result<usize, ()> {
+    let phy_addr = kernel_context().remove(virt_addr)?;
+    // The page is global, so every processor may have it in the TLB no
+    // matter which context it uses.
+    tlb::shootdown(tlb::active_cpus(), virt_addr..virt_addr + PAGE_SIZE);
+    Ok(phy_addr)
+}
+
+/// The size of a page that can be mapped by a paging structure entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PageSize {
+    /// A 4-KByte page mapped by a page table entry.
+    Size4K,
+    /// A 2-MByte page mapped by a page directory entry.
+    Size2M,
+    /// A 1-GByte page mapped by a page directory pointer table entry.
+    Size1G,
+}
+
+impl PageSize {
+    /// Return the number of bytes in the page.
+    pub fn size(self) -> usize {
+        match self {
+            PageSize::Size4K => PAGE_SIZE,
+            PageSize::Size2M => 0x20_0000,
+            PageSize::Size1G => 0x4000_0000,
+        }
+    }
+
+    /// Return the level of the paging structure that maps the page, where
+    /// PML4 is level 0.
+    pub fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 3,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 1,
+        }
+    }
+
+    /// Return the size of the page mapped by an entry in the paging
+    /// structure at level `level`.
+    pub fn at_level(level: usize) -> PageSize {
+        match level {
+            3 => PageSize::Size4K,
+            2 => PageSize::Size2M,
+            1 => PageSize::Size1G,
+            _ => panic!("there is no page at level {}", level),
+        }
+    }
+}
+
+/// Return the page sizes larger than 4 KBytes that the processor supports,
+/// from the largest one.
+pub fn huge_page_sizes() -> &'static [PageSize] {
+    if PAGES_1G.load(Ordering::Relaxed) {
+        &[PageSize::Size1G, PageSize::Size2M]
+    } else {
+        &[PageSize::Size2M]
+    }
+}
+
 /// Make sure that the address is page aligned.
 pub fn assert_align(addr: usize) {
     if addr & (PAGE_SIZE-1) != 0 {
@@ -35,11 +179,51 @@ pub fn assert_align(addr: usize) {
     }
 }
 
+/// Return the physical address of the kernel memory at virtual address
+/// `virt_addr`. The address must be either in the kernel image, which is
+/// linked at `KERNEL_VIRT_BASE`, or in the physmap.
+#[cfg(not(test))]
+pub fn virt_to_phy(virt_addr: usize) -> usize {
+    if virt_addr >= KERNEL_VIRT_BASE {
+        virt_addr - KERNEL_VIRT_BASE
+    } else if virt_addr >= PHYSMAP_START {
+        virt_addr - PHYSMAP_START
+    } else {
+        panic!("the address is not in the kernel memory");
+    }
+}
+
+/// Return the virtual address in the physmap at which the kernel can access
+/// the physical address `phy_addr`.
+#[cfg(not(test))]
+pub const fn phy_to_virt(phy_addr: usize) -> usize {
+    PHYSMAP_START + phy_addr
+}
+
+// In test, the kernel memory is allocated by the host, so we pretend that
+// it is identity mapped.
+#[cfg(test)]
+pub fn virt_to_phy(virt_addr: usize) -> usize {
+    virt_addr
+}
+
+#[cfg(test)]
+pub const fn phy_to_virt(phy_addr: usize) -> usize {
+    phy_addr
+}
+
+/// Return the physical address stored in a paging structure entry.
+pub fn entry_address(entry: u64) -> usize {
+    (entry & (((1 << MAXPHYADDR) - 1) & !(PAGE_SIZE as u64 - 1))) as usize
+}
+
 /// Parse the virtual address to get indices of page directories and
 /// page tables.
 pub fn parse_addr(addr: usize) -> [usize; 4] {
     let mut result = [0; 4];
-    assert!(addr >> 48 == 0);
+    // The address must be canonical, that is bits 48 to 63 must be copies
+    // of bit 47.
+    assert!(addr >> 47 == 0 || addr >> 47 == (1 << 17) - 1);
     result[0] = (addr & ((1 << 48)-1)) >> 39;
     result[1] = (addr & ((1 << 39)-1)) >> 30;
     result[2] = (addr & ((1 << 30)-1)) >> 21;
@@ -47,7 +231,91 @@ pub fn parse_addr(addr: usize) -> [usize; 4] {
     result
 }
 
-/// Initialization function for paging module.
+/// Enable the no-execute bit and global pages, so that `exe_disable` and
+/// `global` in [MapFlags](MapFlags) take effect. PCIDs are enabled too if
+/// the processor supports them, and the support of 1-GByte pages is
+/// recorded for [huge_page_sizes](huge_page_sizes).
+#[cfg(not(test))]
+unsafe fn enable_paging_features() {
+    let edx: u32;
+    // CPUID clobbers rbx, which LLVM may use internally, so we save it to
+    // rsi first.
+    asm!("mov %rbx, %rsi
+          cpuid
+          mov %rsi, %rbx"
+          : "={edx}"(edx)
+          : "{eax}"(0x8000_0001_u32)
+          : "rsi", "ecx"
+          : "volatile");
+    if edx & (1 << 20) == 0 {
+        panic!("the processor doesn't support the no-execute bit");
+    }
+    // Qemu doesn't emulate 1-GByte pages unless they are asked for, like
+    // with `-cpu host`.
+    PAGES_1G.store(edx & (1 << 26) != 0, Ordering::Relaxed);
+
+    // Set NXE bit in IA32_EFER.
+    asm!("rdmsr
+          or $$0x800, %eax
+          wrmsr"
+          :: "{ecx}"(0xc000_0080_u32)
+          : "eax", "edx"
+          : "volatile");
+    // Set PGE bit in CR4.
+    asm!("mov %cr4, %rax
+          or $$0x80, %rax
+          mov %rax, %cr4"
+          ::: "rax"
+          : "volatile");
+    tlb::enable_pcid();
+}
+
+/// Initialization function for paging module. This replaces the page
+/// tables set up by the bootloader with our own page tables, which map the
+/// kernel and the physmap in the upper half only.
+#[cfg(not(test))]
 pub fn init() {
-    let _ = PagingContext::new();
+    // The supported page sizes must be known before anything is mapped.
+    unsafe {
+        enable_paging_features();
+    }
+    let mut context = PagingContext::new();
+
+    // Map all the memory to the physmap. This memory is used as data only.
+    let mut physmap = MemoryLayout::new().as_free_interval_list();
+    for interval in StaticIntvlist::from(LOW_MEMORY).unwrap().iter() {
+        physmap.push(*interval).unwrap();
+    }
+    let flags = MapFlags {
+        write: true,
+        global: true,
+        exe_disable: true,
+        ..MapFlags::default()
+    };
+    context.map_linear(&physmap, PHYSMAP_START, flags).unwrap();
+
+    // Map the kernel code, data, heap and stack to where they are linked.
+    let intervals = StaticIntvlist::from(USED_KERNEL_MEMORY).unwrap();
+    let flags = MapFlags {
+        write: true,
+        global: true,
+        ..MapFlags::default()
+    };
+    context.map_linear(&intervals, KERNEL_VIRT_BASE, flags).unwrap();
+
+    unsafe {
+        context.activate();
+    }
+    // The blobs are boxed, so moving the context doesn't move the page
+    // tables that CR3 is pointing to.
+    KERNEL_CONTEXT.call_once(|| OwnedSpinlock::new(context));
+}
+
+/// Initialization function for paging module on the processors other than
+/// the boot processor. The processor starts using the paging context of the
+/// kernel, so the running code and stack must be mapped there.
+#[cfg(not(test))]
+pub unsafe fn init_ap() {
+    enable_paging_features();
+    kernel_context().activate();
 }
@@ -20,11 +20,56 @@
 
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::AtomicUsize;
+#[cfg(not(test))]
+use core::sync::atomic::{self, Ordering};
 use ::collections::StaticIntvlist;
-use ::config::IDENTITY_MAP_MEMORY;
-use ::paging::{assert_align, parse_addr};
+use ::config::{PAGE_SIZE, USER_SPACE_END};
+#[cfg(not(test))]
+use ::apic;
+use ::paging::{
+    assert_align,
+    tlb,
+    entry_address,
+    huge_page_sizes,
+    parse_addr,
+    virt_to_phy,
+    MapFlags,
+    PageSize,
+    MAXPHYADDR,
+};
+use ::util::set_bits;
 
-const NUMBER_OF_ENTRIES: usize = 2^9;
+const NUMBER_OF_ENTRIES: usize = 1 << 9;
+
+/// An array of entries that the processor will read as a page directory or
+/// a page table. The processor requires it to be page aligned.
+#[repr(C, align(4096))]
+struct Blob([u64; NUMBER_OF_ENTRIES]);
+
+impl Blob {
+    /// Create a new [Blob](Blob) with no present entry.
+    fn new() -> Box<Blob> {
+        Box::new(Blob([0; NUMBER_OF_ENTRIES]))
+    }
+
+    /// Return the physical address of the blob.
+    fn phy_addr(&self) -> usize {
+        virt_to_phy(self as *const _ as usize)
+    }
+}
+
+impl fmt::Debug for Blob {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // Printing all 512 entries is too noisy, so we print only the
+        // non-empty ones.
+        fmt.debug_map()
+            .entries(self.0.iter().enumerate().filter(|(_, e)| **e != 0))
+            .finish()
+    }
+}
 
 #[derive(Debug)]
 enum PageDirTab {
@@ -34,22 +79,265 @@ enum PageDirTab {
 
 use self::PageDirTab::*;
 
+impl PageDirTab {
+    /// Return the physical address of the blob of this node.
+    fn phy_addr(&self) -> usize {
+        match self {
+            Directory(dir) => dir.blob.phy_addr(),
+            Table(tab) => tab.blob.phy_addr(),
+        }
+    }
+
+    /// Check if there is no present entry in this node.
+    fn is_empty(&self) -> bool {
+        match self {
+            Directory(dir) => dir.len == 0,
+            Table(tab) => tab.len == 0,
+        }
+    }
+}
+
 /// A structure that contains a list of pages.
 #[derive(Debug)]
 struct PageTable {
-    // A map of indices to the physical addresses.
-    map: BTreeMap<usize, usize>,
+    // The number of present entries in the blob.
+    len: usize,
     // A blob that the processor will read as a page table.
-    blob: Box<[u64; NUMBER_OF_ENTRIES]>,
+    blob: Box<Blob>,
 }
 
 /// A structure that contains a list of next page directories or page tables.
+/// Directories at the PDPT and PD levels may also map huge pages directly.
+/// Huge pages are stored only in the blob.
 #[derive(Debug)]
 struct PageDirectory {
+    // The number of present entries in the blob.
+    len: usize,
     // A map of indices to the next page directories or page tables.
     map: BTreeMap<usize, PageDirTab>,
     // A blob that the processor will read as a page directory.
-    blob: Box<[u64; NUMBER_OF_ENTRIES]>,
+    blob: Box<Blob>,
+}
+
+impl PageDirectory {
+    /// Create an empty [PageDirectory](PageDirectory).
+    fn new() -> PageDirectory {
+        PageDirectory {
+            len: 0,
+            map: BTreeMap::new(),
+            blob: Blob::new(),
+        }
+    }
+
+    /// Set the entry at index `index` in the blob and keep the number of
+    /// present entries up to date.
+    fn set(&mut self, index: usize, entry: u64) {
+        if self.blob.0[index] & 1 != 0 {
+            self.len -= 1;
+        }
+        if entry & 1 != 0 {
+            self.len += 1;
+        }
+        self.blob.0[index] = entry;
+    }
+
+    /// Insert the next level node at index `index` and point the
+    /// corresponding entry in the blob to it. The entry allows everything
+    /// because the permissions are controlled by the page table entries.
+    fn insert(&mut self, index: usize, dirtab: PageDirTab) {
+        self.set(index, page_directory_entry! {
+            .present = 1,
+            .write = 1,
+            .supervisor = 1,
+            .address = (dirtab.phy_addr() >> 12) as u64
+        });
+        self.map.insert(index, dirtab);
+    }
+
+    /// Remove the next level node at index `index` and clear the
+    /// corresponding entry in the blob.
+    fn remove(&mut self, index: usize) -> Option<PageDirTab> {
+        self.set(index, 0);
+        self.map.remove(&index)
+    }
+
+    /// Return the entry at index `index`, if it maps a huge page.
+    fn huge(&self, index: usize) -> Option<u64> {
+        let entry = self.blob.0[index];
+        if entry & 1 != 0 && entry & (1 << 7) != 0 {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    /// Split the huge page at index `index` into 512 pages of the next
+    /// smaller size with the same flags. `level` is the level of this
+    /// directory.
+    fn split(&mut self, index: usize, level: usize) {
+        let entry = self.huge(index).unwrap();
+        let flags = MapFlags::from_entry(entry);
+        let phy_addr = entry_address(entry);
+        let size = PageSize::at_level(level + 1).size();
+
+        let dirtab = if level + 1 == PageSize::Size4K.level() {
+            let mut table = PageTable::new();
+            for (i, entry) in table.blob.0.iter_mut().enumerate() {
+                *entry = flags.to_entry(phy_addr + i * size);
+            }
+            table.len = NUMBER_OF_ENTRIES;
+            Table(Box::new(table))
+        } else {
+            let mut directory = PageDirectory::new();
+            for i in 0..NUMBER_OF_ENTRIES {
+                directory.set(i, flags.to_huge_entry(phy_addr + i * size));
+            }
+            Directory(Box::new(directory))
+        };
+        self.insert(index, dirtab);
+    }
+
+    /// Merge the 512 pages under the node at index `index` into one huge
+    /// page. All of them must be mapped to contiguous frames with the same
+    /// flags. `level` is the level of this directory.
+    fn promote(&mut self, index: usize, level: usize) -> Result<(), ()> {
+        let phy_addr;
+        let flags;
+        // We need a block here because the borrow of `self.map` must end
+        // before we remove the node.
+        {
+            let entries = match self.map.get(&index).ok_or(())? {
+                // The smaller pages in a directory must be huge pages too.
+                Directory(dir) => if dir.map.is_empty() {
+                    &dir.blob.0
+                } else {
+                    return Err(());
+                },
+                Table(tab) => &tab.blob.0,
+            };
+            let size = PageSize::at_level(level + 1).size();
+            phy_addr = entry_address(entries[0]);
+            flags = MapFlags::from_entry(entries[0]);
+
+            if phy_addr & (PageSize::at_level(level).size() - 1) != 0 {
+                return Err(());
+            }
+            for (i, entry) in entries.iter().enumerate() {
+                if *entry & 1 == 0
+                    || entry_address(*entry) != phy_addr + i * size
+                    || MapFlags::from_entry(*entry) != flags {
+                    return Err(());
+                }
+            }
+        }
+
+        self.remove(index).unwrap();
+        self.set(index, flags.to_huge_entry(phy_addr));
+        Ok(())
+    }
+}
+
+impl PageTable {
+    /// Create an empty [PageTable](PageTable).
+    fn new() -> PageTable {
+        PageTable {
+            len: 0,
+            blob: Blob::new(),
+        }
+    }
+}
+
+/// Find the entry that maps the page indexed by `indices`, starting from
+/// `dirtab` at level `level`. Return the entry and the size of the page.
+fn lookup(dirtab: &PageDirTab, indices: &[usize; 4], level: usize)
+    -> Option<(u64, PageSize)>
+{
+    let index = indices[level];
+    match *dirtab {
+        Directory(ref dir) => {
+            if let Some(entry) = dir.huge(index) {
+                return Some((entry, PageSize::at_level(level)));
+            }
+            lookup(dir.map.get(&index)?, indices, level + 1)
+        },
+        Table(ref tab) => {
+            let entry = tab.blob.0[index];
+            if entry & 1 == 0 {
+                None
+            } else {
+                Some((entry, PageSize::Size4K))
+            }
+        },
+    }
+}
+
+/// Return the number of bytes mapped under `dirtab` at level `level`.
+fn mapped_size(dirtab: &PageDirTab, level: usize) -> usize {
+    match *dirtab {
+        Directory(ref dir) => {
+            let huge = (0..NUMBER_OF_ENTRIES)
+                .filter(|&index| dir.huge(index).is_some())
+                .count();
+            // The root directory can't map huge pages.
+            let huge_size = if level == 0 {
+                0
+            } else {
+                huge * PageSize::at_level(level).size()
+            };
+            dir.map.values().map(|next| mapped_size(next, level + 1))
+                .fold(huge_size, |sum, size| sum + size)
+        },
+        Table(ref tab) => tab.len * PAGE_SIZE,
+    }
+}
+
+/// Find the entry at level `leaf` that maps the page indexed by `indices`,
+/// starting from `dirtab` at level `level`. Bigger pages on the way are
+/// split, so that the page is mapped by an entry at level `leaf`.
+fn entry_mut<'a>(dirtab: &'a mut PageDirTab, indices: &[usize; 4],
+                 level: usize, leaf: usize) -> Option<&'a mut u64>
+{
+    let index = indices[level];
+    match *dirtab {
+        Directory(ref mut dir) => {
+            if level == leaf {
+                return dir.huge(index).map(move |_| &mut dir.blob.0[index]);
+            }
+            if dir.huge(index).is_some() {
+                dir.split(index, level);
+            }
+            entry_mut(dir.map.get_mut(&index)?, indices, level + 1, leaf)
+        },
+        Table(ref mut tab) => {
+            if tab.blob.0[index] & 1 == 0 {
+                None
+            } else {
+                Some(&mut tab.blob.0[index])
+            }
+        },
+    }
+}
+
+/// Return the page directory at level `target` on the path to the page
+/// indexed by `indices`, starting from `directory` at level `level`.
+/// Missing directories on the way are created.
+fn descend<'a>(directory: &'a mut PageDirectory, indices: &[usize; 4],
+               level: usize, target: usize) -> &'a mut PageDirectory
+{
+    if level == target {
+        return directory;
+    }
+    let index = indices[level];
+    if directory.map.get(&index).is_none() {
+        // If the page directory does not exist create a new one and
+        // insert it to the parent page directory.
+        let new_directory = Box::new(PageDirectory::new());
+        directory.insert(index, Directory(new_directory));
+    }
+    match directory.map.get_mut(&index).unwrap() {
+        Directory(next) => descend(next, indices, level + 1, target),
+        Table(_) => panic!("found page table, page directory expected"),
+    }
 }
 
 /// A structure that represents the whole paging context.
@@ -57,60 +345,134 @@ struct PageDirectory {
 pub struct PagingContext {
     // A value that will loaded to CR3 when this paging context is used.
     cr3: u64,
+    // The PCID that tags the TLB entries of this context, or 0 if there is
+    // no PCID left.
+    pcid: u16,
+    // A bitmap of the processors that may have stale TLB entries tagged
+    // with the PCID, so they must flush them when they load this context.
+    stale: AtomicUsize,
     // A root page directory. This is PML4 in x86.
     dirtab: PageDirTab,
 }
 
 impl PagingContext {
-    /// Find a physical address of the frame that is mapped by virtual address
-    /// `virt_addr`.
-    pub fn find(&self, virt_addr: usize) -> Option<usize> {
-        let page_table;
+    /// Return the root page directory.
+    fn root(&mut self) -> &mut PageDirectory {
+        match self.dirtab {
+            Directory(ref mut dir) => &mut **dir,
+            Table(_) => panic!("the first level shouldn't be the table"),
+        }
+    }
+
+    /// Find the page that contains virtual address `virt_addr`. Return the
+    /// physical address of the frame, the size and the flags of the page.
+    pub fn find_page(&self, virt_addr: usize)
+        -> Option<(usize, PageSize, MapFlags)>
+    {
+        let (entry, size) = lookup(&self.dirtab, &parse_addr(virt_addr), 0)?;
+        Some((entry_address(entry), size, MapFlags::from_entry(entry)))
+    }
 
+    /// Find a physical address of the frame that is mapped by virtual address
+    /// `virt_addr` and the flags of the mapping.
+    pub fn find(&self, virt_addr: usize) -> Option<(usize, MapFlags)> {
         // Assert that the address is page aligned.
         assert_align(virt_addr);
+
+        let (phy_addr, size, flags) = self.find_page(virt_addr)?;
+        Some((phy_addr + (virt_addr & (size.size() - 1)), flags))
+    }
+
+    /// Change the frame and the flags of a page that is already mapped at
+    /// virtual address `virt_addr`. If the page is a part of a huge page,
+    /// the huge page is split first. Return the previous physical address
+    /// and flags, if success.
+    pub fn update(&mut self, virt_addr: usize, phy_addr: usize,
+                  flags: MapFlags) -> Result<(usize, MapFlags), ()>
+    {
+        // Assert that both addresses are page aligned.
+        assert_align(virt_addr);
+        assert_align(phy_addr);
         let indices = parse_addr(virt_addr);
-        let mut i = 0;
 
-        match self.dirtab {
-            Directory(ref directory_) => {
-                // We move the variable here because we cannot make
-                // `directory_` mutable.
-                let mut directory = directory_;
-                loop {
-                    // Traverse through the paging tree until we find the
-                    // leave, that is a page table.
-                    match directory.map.get(&indices[i])? {
-                        Directory(ref dir) => directory = dir,
-                        Table(ref tab) => {
-                            page_table = tab;
-                            break;
-                        }
-                    }
-                    i += 1;
-                }
-                i += 1;
-            },
-            Table(ref table) => page_table = table,
+        let leaf = PageSize::Size4K.level();
+        let entry = entry_mut(&mut self.dirtab, &indices, 0, leaf)
+            .ok_or(())?;
+        let previous = (entry_address(*entry), MapFlags::from_entry(*entry));
+        *entry = flags.to_entry(phy_addr);
+        Ok(previous)
+    }
+
+    /// Change the flags of all pages in `range` to `flags`. Every page in
+    /// the range must be mapped, otherwise nothing is changed. Huge pages
+    /// that are only partly in the range are split. The caller must flush
+    /// the TLB if this context is active.
+    pub fn protect(&mut self, range: Range<usize>, flags: MapFlags)
+        -> Result<(), ()>
+    {
+        assert_align(range.start);
+        assert_align(range.end);
+
+        // Check that everything is mapped before changing anything.
+        let mut virt_addr = range.start;
+        while virt_addr < range.end {
+            let (_, size, _) = self.find_page(virt_addr).ok_or(())?;
+            virt_addr = (virt_addr & !(size.size() - 1)) + size.size();
         }
 
-        // Return the physical address mapped by virt_addr in the page table.
-        Some(*page_table.map.get(&indices[i])?)
+        let mut virt_addr = range.start;
+        while virt_addr < range.end {
+            let (phy_addr, size, _) = self.find_page(virt_addr).unwrap();
+            let offset = virt_addr & (size.size() - 1);
+            if offset == 0 && virt_addr + size.size() <= range.end {
+                // The whole page is in the range, so we don't need to
+                // split it.
+                let indices = parse_addr(virt_addr);
+                let entry = entry_mut(&mut self.dirtab, &indices, 0,
+                                      size.level()).unwrap();
+                *entry = match size {
+                    PageSize::Size4K => flags.to_entry(phy_addr),
+                    _ => flags.to_huge_entry(phy_addr),
+                };
+                virt_addr += size.size();
+            } else {
+                self.update(virt_addr, phy_addr + offset, flags).unwrap();
+                virt_addr += PAGE_SIZE;
+            }
+        }
+        Ok(())
     }
 
-    /// Unmap a page at virtual address `virt_addr`. Return the physical
+    /// Unmap the page of size `size` at virtual address `virt_addr`.
+    /// Bigger pages containing the page are split first. Return the physical
     /// address previously mapped by that `virt_addr`, if success.
-    pub fn remove(&mut self, virt_addr: usize) -> Result<usize, ()> {
-        // Assert that the address is page aligned.
-        assert_align(virt_addr);
+    pub fn remove_page(&mut self, virt_addr: usize, size: PageSize)
+        -> Result<usize, ()>
+    {
+        // Assert that the address is aligned to the page size.
+        assert!(virt_addr & (size.size() - 1) == 0);
         let indices = parse_addr(virt_addr);
 
         // This closure is used to traverse through the tree. It returns
-        // the physical address, if success.
-        fn traverse (dirtab: &mut PageDirTab, indices: &[usize])
-            -> Result<usize, ()> {
+        // the removed entry, if success.
+        fn traverse (dirtab: &mut PageDirTab, indices: &[usize; 4],
+                     level: usize, leaf: usize) -> Result<u64, ()> {
+            let index = indices[level];
             match dirtab {
                 Directory(directory) => {
+                    if level == leaf {
+                        // The page that we want to remove is a huge page in
+                        // this directory.
+                        let entry = directory.huge(index).ok_or(())?;
+                        directory.set(index, 0);
+                        return Ok(entry);
+                    }
+                    // If we want to remove a part of a huge page, we need
+                    // to split it first.
+                    if directory.huge(index).is_some() {
+                        directory.split(index, level);
+                    }
+
                     let is_next_node_empty;
                     let result;
                     // We need a block here because, otherwise, there will be
@@ -118,7 +480,7 @@ impl PagingContext {
                     {
                         // If the current node is a directory, check if we can
                         // go to the next level.
-                        let next_dirtab = directory.map.get_mut(&indices[0]);
+                        let next_dirtab = directory.map.get_mut(&index);
                         // If we cannot go, just return error.
                         if next_dirtab.is_none() {
                             return Err(());
@@ -126,41 +488,53 @@ impl PagingContext {
                         let next_dirtab = next_dirtab.unwrap();
 
                         // If there is another level, traverse through it.
-                        result = traverse(
-                            next_dirtab,
-                            &indices[1..],
-                        )?;
+                        result = traverse(next_dirtab, indices, level + 1,
+                                          leaf)?;
 
                         // After traversing through the next level, we need to
                         // check that the next level node is already empty or
                         // not. If it is, we should deallocate `next_dirtab`.
-                        is_next_node_empty = match next_dirtab {
-                            Directory(dir) => dir.map.is_empty(),
-                            Table(tab) => tab.map.is_empty(),
-                        };
+                        is_next_node_empty = next_dirtab.is_empty();
                     }
 
                     if is_next_node_empty {
-                        directory.map.remove(&indices[0]).unwrap();
+                        directory.remove(index).unwrap();
                     }
                     Ok(result)
                 },
                 Table(table) => {
-                    let phy_addr = table.map.remove(&indices[0]);
-                    match phy_addr {
-                        Some(phy_addr) => Ok(phy_addr),
-                        None => Err(()),
+                    let entry = table.blob.0[index];
+                    if entry & 1 == 0 {
+                        return Err(());
                     }
+                    table.blob.0[index] = 0;
+                    table.len -= 1;
+                    Ok(entry)
                 },
             }
         };
-        traverse(&mut self.dirtab, &indices[..])
+        let entry = traverse(&mut self.dirtab, &indices, 0, size.level())?;
+        Ok(entry_address(entry))
+    }
+
+    /// Return the number of bytes mapped in this context. The kernel part
+    /// shared by the user contexts is not counted.
+    pub fn mapped_size(&self) -> usize {
+        mapped_size(&self.dirtab, 0)
+    }
+
+    /// Unmap a page at virtual address `virt_addr`. Return the physical
+    /// address previously mapped by that `virt_addr`, if success.
+    pub fn remove(&mut self, virt_addr: usize) -> Result<usize, ()> {
+        // Assert that the address is page aligned.
+        assert_align(virt_addr);
+        self.remove_page(virt_addr, PageSize::Size4K)
     }
 
     /// Map a page at virtual address `virt_addr` to a frame at physical
-    /// address `phy_addr`.
-    pub fn insert(&mut self, virt_addr: usize, phy_addr: usize)
-        -> Result<(), ()>
+    /// address `phy_addr` with flags `flags`.
+    pub fn insert(&mut self, virt_addr: usize, phy_addr: usize,
+                  flags: MapFlags) -> Result<(), ()>
     {
         // Assert that the virtual address is page aligned.
         assert_align(virt_addr);
@@ -173,92 +547,243 @@ impl PagingContext {
 
         // Create two level page directory, that is PDPT and PD in x86.
         let indices = parse_addr(virt_addr);
-        let mut i = 0;
-        let mut page_directory = match self.dirtab {
-            Directory(ref mut dir) => &mut **dir,
-            Table(_) => panic!("the first level shouldn't be the table"),
-        };
+        let level = PageSize::Size4K.level() - 1;
+        let page_directory = descend(self.root(), &indices, 0, level);
 
-        for _ in 0..2 {
-            if page_directory.map.get(&indices[i]).is_none() {
-                // If the page directory does not exist create a new one.
-                let new_directory = Box::new(PageDirectory {
-                    map: BTreeMap::new(),
-                    blob: Box::new([0; NUMBER_OF_ENTRIES]),
-                });
-                // Insert the new directory to the map of the parent page
-                // directory.
-                page_directory.map.insert(
-                    indices[i],
-                    Directory(new_directory),
-                );
-            }
-
-            // We need a tmp variable here to avoid Rust borrow checker.
-            // https://goo.gl/BS92ft for more detail.
-            let page_directory_ = page_directory;
-            // Go deeper into the tree.
-            match page_directory_.map.get_mut(&indices[i]).unwrap() {
-                Directory(directory) => page_directory = &mut **directory,
-                Table(_) => {
-                    panic!("found page table, page directory expected");
-                },
-            }
-            i += 1;
+        if page_directory.map.get(&indices[level]).is_none() {
+            // If the page table does not exist create a new one and insert
+            // it to the parent page directory.
+            let new_table = Box::new(PageTable::new());
+            page_directory.insert(indices[level], Table(new_table));
         }
 
-        if page_directory.map.get(&indices[i]).is_none() {
-            // If the page table does not exist create a new one.
-            let new_table = Box::new(PageTable {
-                map: BTreeMap::new(),
-                blob: Box::new([0; NUMBER_OF_ENTRIES]),
-            });
-            // Insert the new table to the map of the parent page
-            // directory.
-            page_directory.map.insert(
-                indices[i],
-                Table(new_table),
-            );
-        }
-
-        match page_directory.map.get_mut(&indices[i]).unwrap() {
+        match page_directory.map.get_mut(&indices[level]).unwrap() {
             Directory(_) => {
                 panic!("found page directory, page table expected");
             },
             Table(table) => {
-                table.map.insert(indices[i+1], phy_addr);
+                table.blob.0[indices[level+1]] = flags.to_entry(phy_addr);
+                table.len += 1;
             },
         }
         Ok(())
     }
 
+    /// Map a page of size `size` at virtual address `virt_addr` to a frame
+    /// at physical address `phy_addr` with flags `flags`. Both addresses
+    /// must be aligned to the page size. Nothing in the page may be mapped
+    /// already.
+    pub fn insert_page(&mut self, virt_addr: usize, phy_addr: usize,
+                       size: PageSize, flags: MapFlags) -> Result<(), ()>
+    {
+        if size == PageSize::Size4K {
+            return self.insert(virt_addr, phy_addr, flags);
+        }
+        // Assert that both addresses are aligned to the page size.
+        assert!(virt_addr & (size.size() - 1) == 0);
+        assert!(phy_addr & (size.size() - 1) == 0);
+
+        let indices = parse_addr(virt_addr);
+        let level = size.level();
+        if lookup(&self.dirtab, &indices, 0).is_some() {
+            return Err(());
+        }
+
+        let page_directory = descend(self.root(), &indices, 0, level);
+        // If there is a smaller page inside, the entry points to the next
+        // level node.
+        if page_directory.blob.0[indices[level]] & 1 != 0 {
+            return Err(());
+        }
+        page_directory.set(indices[level], flags.to_huge_entry(phy_addr));
+        Ok(())
+    }
+
+    /// Merge the pages inside the page of size `size` containing virtual
+    /// address `virt_addr` into one huge page. The 512 smaller pages must
+    /// be mapped to contiguous frames with the same flags. The caller must
+    /// flush the TLB if this context is active.
+    pub fn promote(&mut self, virt_addr: usize, size: PageSize)
+        -> Result<(), ()>
+    {
+        assert!(size != PageSize::Size4K);
+        let indices = parse_addr(virt_addr);
+        let level = size.level();
+
+        // This closure is used to find the directory at level `level`
+        // without creating a new one.
+        fn find_directory<'a>(directory: &'a mut PageDirectory,
+                              indices: &[usize; 4], level: usize,
+                              target: usize)
+            -> Option<&'a mut PageDirectory>
+        {
+            if level == target {
+                return Some(directory);
+            }
+            match directory.map.get_mut(&indices[level])? {
+                Directory(next) => find_directory(next, indices, level + 1,
+                                                  target),
+                Table(_) => None,
+            }
+        }
+        find_directory(self.root(), &indices, 0, level)
+            .ok_or(())?
+            .promote(indices[level], level)
+    }
+
+    /// Map all pages covered by `intervals` to the frames at `offset` bytes
+    /// below, that is virtual address `offset + phy_addr` is mapped to
+    /// physical address `phy_addr`, with flags `flags`. Pages that are
+    /// already mapped are left untouched. We use the huge pages supported by
+    /// the processor whenever possible to save TLB entries.
+    pub fn map_linear(&mut self, intervals: &StaticIntvlist, offset: usize,
+                      flags: MapFlags) -> Result<(), ()>
+    {
+        for interval in intervals.iter() {
+            let end = interval.start() + interval.length();
+            let mut addr = interval.start() & !(PAGE_SIZE - 1);
+            'next_page: while addr < end {
+                let virt_addr = offset.wrapping_add(addr);
+                for size in huge_page_sizes().iter() {
+                    let mask = size.size() - 1;
+                    if (addr | virt_addr) & mask == 0
+                        && addr + size.size() <= end
+                        && self.insert_page(virt_addr, addr, *size, flags)
+                            .is_ok() {
+                        addr += size.size();
+                        continue 'next_page;
+                    }
+                }
+                if self.find(virt_addr).is_none() {
+                    self.insert(virt_addr, addr, flags)?;
+                }
+                addr += PAGE_SIZE;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the physical address of the root page directory.
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn phy_addr(&self) -> usize {
+        entry_address(self.cr3)
+    }
+
+    /// Load this paging context to CR3, so the processor starts using it.
+    /// The caller must make sure that the running code, stack and data are
+    /// mapped in this context.
+    #[cfg(not(test))]
+    pub unsafe fn activate(&self) {
+        let mut cr3 = self.cr3;
+        // Publish that this context is loaded before the stale bit is read,
+        // so a concurrent flush either shoots down this processor or leaves
+        // the stale bit for it to see.
+        tlb::set_loaded(self.cr3 as usize);
+        atomic::fence(Ordering::SeqCst);
+        if tlb::pcid_enabled() && self.pcid != 0 {
+            let bit = 1 << apic::id();
+            let stale = self.stale.fetch_and(!bit, Ordering::SeqCst) & bit;
+            // The entries tagged with the PCID are kept unless they are
+            // stale.
+            cr3 |= cr3! {
+                .pcid = u64::from(self.pcid),
+                .no_flush = if stale == 0 { 1 } else { 0 }
+            };
+        }
+        asm!("mov $0, %cr3" :: "r"(cr3) : "memory" : "volatile");
+    }
+
+    /// Flush the TLB entries of the pages in `range` on every processor
+    /// that uses this context. This must be called after mappings in
+    /// `range` are removed or their permissions are reduced.
+    #[cfg(not(test))]
+    pub fn flush(&self, range: Range<usize>) {
+        // The other processors may still have the entries tagged with the
+        // PCID, so they flush when they load this context again. The stale
+        // bits are set before the loaded contexts are read, so a processor
+        // that loads this context concurrently either sees its stale bit in
+        // `activate` or is shot down here.
+        self.stale.store(!0, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        let cpus = tlb::loaded_on(self.cr3 as usize);
+        tlb::shootdown(cpus, range);
+    }
+
+    /// There is no TLB in test.
+    #[cfg(test)]
+    pub fn flush(&self, _range: Range<usize>) {}
+
+    /// Create a new [PagingContext](PagingContext) for a user address space.
+    /// The upper half is shared with the kernel paging context `kernel`, so
+    /// the new context must only map pages in the lower half.
+    pub fn new_user(kernel: &PagingContext) -> PagingContext {
+        let mut context = PagingContext::new();
+        // The index of the first root entry in the upper half.
+        let half = USER_SPACE_END >> 39;
+        let kernel_blob = match kernel.dirtab {
+            Directory(ref dir) => &dir.blob,
+            Table(_) => panic!("the first level shouldn't be the table"),
+        };
+        // The root directory doesn't own the kernel nodes, so we copy only
+        // the entries and not the map.
+        context.root().blob.0[half..].copy_from_slice(&kernel_blob.0[half..]);
+        context
+    }
+
     /// Create a new [PagingContext](PagingContext).
     pub fn new() -> PagingContext {
-        let directory = PageDirectory {
-            map: BTreeMap::new(),
-            blob: Box::new([0; NUMBER_OF_ENTRIES]),
+        let directory = PageDirectory::new();
+        let cr3 = cr3! {
+            .address = (directory.blob.phy_addr() >> 12) as u64
         };
-        // We need to avoid setting .address in test because the address of
-        // the blod in test is beyond the size of configured physical
-        // address space.
-        let context = PagingContext {
-            cr3: 0,
+        PagingContext {
+            cr3,
+            pcid: tlb::alloc_pcid(),
+            // The PCID may be used by a context that is dropped, so every
+            // processor must flush the entries tagged with it first.
+            stale: AtomicUsize::new(!0),
             dirtab: Directory(Box::new(directory)),
-        };
-
-        // TODO: Initialize identity map memory sections.
-        let _intervals = StaticIntvlist::from(IDENTITY_MAP_MEMORY);
-
-        context
+        }
     }
 }
 
-#[cfg(test)]
-use config::PAGE_SIZE;
+impl Drop for PagingContext {
+    fn drop(&mut self) {
+        tlb::free_pcid(self.pcid);
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ::paging::phy_to_virt;
+
+    const RW: MapFlags = MapFlags {
+        write: true,
+        user: false,
+        exe_disable: false,
+        cache_disable: false,
+        global: false,
+    };
+
+    /// Walk through the blobs starting from CR3 the same way the processor
+    /// does and return the entry that maps `virt_addr`.
+    fn walk(context: &PagingContext, virt_addr: usize) -> Option<u64> {
+        let indices = parse_addr(virt_addr);
+        let mut addr = entry_address(context.cr3);
+        for (level, index) in indices.iter().enumerate() {
+            let blob = unsafe { &*(phy_to_virt(addr) as *const Blob) };
+            let entry = blob.0[*index];
+            if entry & 1 == 0 {
+                return None;
+            }
+            if level == indices.len() - 1 || entry & (1 << 7) != 0 {
+                return Some(entry);
+            }
+            addr = entry_address(entry);
+        }
+        None
+    }
 
     #[test]
     fn correctly_insert_page() {
@@ -268,12 +793,12 @@ mod tests {
         let vir_addr2 = 2 * PAGE_SIZE;
         let phy_addr2 = 6 * PAGE_SIZE;
         assert!(context.find(vir_addr1).is_none());
-        assert!(context.insert(vir_addr1, phy_addr1).is_ok());
-        assert_eq!(context.find(vir_addr1).unwrap(), phy_addr1);
+        assert!(context.insert(vir_addr1, phy_addr1, RW).is_ok());
+        assert_eq!(context.find(vir_addr1).unwrap().0, phy_addr1);
 
         assert!(context.find(vir_addr2).is_none());
-        assert!(context.insert(vir_addr2, phy_addr2).is_ok());
-        assert_eq!(context.find(vir_addr2).unwrap(), phy_addr2);
+        assert!(context.insert(vir_addr2, phy_addr2, RW).is_ok());
+        assert_eq!(context.find(vir_addr2).unwrap().0, phy_addr2);
     }
 
     #[test]
@@ -282,8 +807,8 @@ mod tests {
         let vir_addr = 4 * PAGE_SIZE;
         let phy_addr1 = 5 * PAGE_SIZE;
         let phy_addr2 = 6 * PAGE_SIZE;
-        assert!(context.insert(vir_addr, phy_addr1).is_ok());
-        assert!(context.insert(vir_addr, phy_addr2).is_err());
+        assert!(context.insert(vir_addr, phy_addr1, RW).is_ok());
+        assert!(context.insert(vir_addr, phy_addr2, RW).is_err());
     }
 
     #[test]
@@ -291,8 +816,8 @@ mod tests {
         let mut context = PagingContext::new();
         let vir_addr = 4 * PAGE_SIZE;
         let phy_addr = 5 * PAGE_SIZE;
-        assert!(context.insert(vir_addr, phy_addr).is_ok());
-        assert!(context.insert(vir_addr, phy_addr).is_err());
+        assert!(context.insert(vir_addr, phy_addr, RW).is_ok());
+        assert!(context.insert(vir_addr, phy_addr, RW).is_err());
     }
 
     #[test]
@@ -301,7 +826,7 @@ mod tests {
         let vir_addr1 = 4 * PAGE_SIZE;
         let vir_addr2 = 5 * PAGE_SIZE;
         let phy_addr = 6 * PAGE_SIZE;
-        assert!(context.insert(vir_addr1, phy_addr).is_ok());
+        assert!(context.insert(vir_addr1, phy_addr, RW).is_ok());
         assert!(context.find(vir_addr2).is_none());
     }
 
@@ -312,12 +837,12 @@ mod tests {
         let phy_addr1 = 5 * PAGE_SIZE;
         let vir_addr2 = 2 * PAGE_SIZE;
         let phy_addr2 = 6 * PAGE_SIZE;
-        assert!(context.insert(vir_addr1, phy_addr1).is_ok());
-        assert!(context.insert(vir_addr2, phy_addr2).is_ok());
+        assert!(context.insert(vir_addr1, phy_addr1, RW).is_ok());
+        assert!(context.insert(vir_addr2, phy_addr2, RW).is_ok());
         assert_eq!(context.remove(vir_addr1).unwrap(), phy_addr1);
         assert!(context.find(vir_addr1).is_none());
         assert!(context.find(vir_addr2).is_some());
-        assert!(context.insert(vir_addr1, phy_addr1).is_ok());
+        assert!(context.insert(vir_addr1, phy_addr1, RW).is_ok());
         assert!(context.find(vir_addr1).is_some());
         assert!(context.find(vir_addr2).is_some());
         assert_eq!(context.remove(vir_addr1).unwrap(), phy_addr1);
@@ -332,7 +857,332 @@ mod tests {
         let vir_addr1 = 4 * PAGE_SIZE;
         let vir_addr2 = 5 * PAGE_SIZE;
         let phy_addr = 6 * PAGE_SIZE;
-        assert!(context.insert(vir_addr1, phy_addr).is_ok());
+        assert!(context.insert(vir_addr1, phy_addr, RW).is_ok());
         assert!(context.remove(vir_addr2).is_err());
     }
+
+    #[test]
+    fn insert_writes_page_table_entries() {
+        let mut context = PagingContext::new();
+        let vir_addr = 0x12_3456_7000;
+        let phy_addr = 5 * PAGE_SIZE;
+        assert!(walk(&context, vir_addr).is_none());
+        assert!(context.insert(vir_addr, phy_addr, RW).is_ok());
+
+        let entry = walk(&context, vir_addr).unwrap();
+        assert_eq!(entry, RW.to_entry(phy_addr));
+        assert_eq!(entry_address(entry), phy_addr);
+    }
+
+    #[test]
+    fn remove_clears_page_table_entries() {
+        let mut context = PagingContext::new();
+        let vir_addr1 = 4 * PAGE_SIZE;
+        let vir_addr2 = 0x12_3456_7000;
+        let phy_addr = 5 * PAGE_SIZE;
+        assert!(context.insert(vir_addr1, phy_addr, RW).is_ok());
+        assert!(context.insert(vir_addr2, phy_addr, RW).is_ok());
+        assert!(context.remove(vir_addr2).is_ok());
+        assert!(walk(&context, vir_addr2).is_none());
+        assert!(walk(&context, vir_addr1).is_some());
+        assert!(context.remove(vir_addr1).is_ok());
+        assert!(walk(&context, vir_addr1).is_none());
+        // The root directory must not point to any freed directory.
+        assert!(context.dirtab.is_empty());
+    }
+
+    #[test]
+    fn blobs_are_page_aligned() {
+        let mut context = PagingContext::new();
+        assert!(context.insert(4 * PAGE_SIZE, 5 * PAGE_SIZE, RW).is_ok());
+        assert_eq!(entry_address(context.cr3) & (PAGE_SIZE - 1), 0);
+        assert_eq!(context.cr3 & (PAGE_SIZE as u64 - 1), 0);
+    }
+
+    #[test]
+    fn correctly_map_linear() {
+        let mut context = PagingContext::new();
+        let intervals = StaticIntvlist::from(b"0x0-0x3000,0x5800-0x6000")
+            .unwrap();
+        assert!(context.insert(PAGE_SIZE, PAGE_SIZE, RW).is_ok());
+        assert!(context.map_linear(&intervals, 0, RW).is_ok());
+        for addr in [0, PAGE_SIZE, 2 * PAGE_SIZE, 5 * PAGE_SIZE].iter() {
+            assert_eq!(context.find(*addr).unwrap().0, *addr);
+            assert_eq!(entry_address(walk(&context, *addr).unwrap()), *addr);
+        }
+        assert!(context.find(3 * PAGE_SIZE).is_none());
+        assert!(context.find(6 * PAGE_SIZE).is_none());
+    }
+
+    #[test]
+    fn insert_writes_flags_to_entries() {
+        let mut context = PagingContext::new();
+        let vir_addr = 0x40_0000;
+        let phy_addr = 5 * PAGE_SIZE;
+        let flags = MapFlags {
+            user: true,
+            exe_disable: true,
+            ..MapFlags::default()
+        };
+        assert!(context.insert(vir_addr, phy_addr, flags).is_ok());
+        assert_eq!(context.find(vir_addr).unwrap(), (phy_addr, flags));
+
+        let entry = walk(&context, vir_addr).unwrap();
+        // Present, user and no-execute. Not writable.
+        assert_eq!(entry, (1 << 63) | phy_addr as u64 | 0b101);
+    }
+
+    #[test]
+    fn correctly_update_page() {
+        let mut context = PagingContext::new();
+        let vir_addr = 4 * PAGE_SIZE;
+        let phy_addr1 = 5 * PAGE_SIZE;
+        let phy_addr2 = 6 * PAGE_SIZE;
+        let flags = MapFlags {
+            cache_disable: true,
+            ..MapFlags::default()
+        };
+        assert!(context.update(vir_addr, phy_addr2, flags).is_err());
+        assert!(context.insert(vir_addr, phy_addr1, RW).is_ok());
+        assert_eq!(context.update(vir_addr, phy_addr2, flags).unwrap(),
+                   (phy_addr1, RW));
+        assert_eq!(context.find(vir_addr).unwrap(), (phy_addr2, flags));
+        assert_eq!(walk(&context, vir_addr).unwrap(),
+                   phy_addr2 as u64 | (1 << 4) | 1);
+    }
+
+    #[test]
+    fn correctly_protect_range() {
+        let mut context = PagingContext::new();
+        let global = MapFlags {
+            global: true,
+            ..MapFlags::default()
+        };
+        for i in 0..3 {
+            let addr = (4 + i) * PAGE_SIZE;
+            assert!(context.insert(addr, addr, RW).is_ok());
+        }
+        assert!(context.protect(4 * PAGE_SIZE..6 * PAGE_SIZE, global)
+                .is_ok());
+        assert_eq!(walk(&context, 4 * PAGE_SIZE).unwrap(),
+                   (4 * PAGE_SIZE) as u64 | (1 << 8) | 1);
+        assert_eq!(walk(&context, 5 * PAGE_SIZE).unwrap(),
+                   (5 * PAGE_SIZE) as u64 | (1 << 8) | 1);
+        // The page after the range must be untouched.
+        assert_eq!(walk(&context, 6 * PAGE_SIZE).unwrap(),
+                   (6 * PAGE_SIZE) as u64 | 0b11);
+    }
+
+    #[test]
+    fn protect_range_with_hole() {
+        let mut context = PagingContext::new();
+        assert!(context.insert(4 * PAGE_SIZE, 4 * PAGE_SIZE, RW).is_ok());
+        assert!(context.insert(6 * PAGE_SIZE, 6 * PAGE_SIZE, RW).is_ok());
+        assert!(context.protect(4 * PAGE_SIZE..7 * PAGE_SIZE,
+                                MapFlags::default()).is_err());
+        // Nothing should be changed.
+        assert_eq!(context.find(4 * PAGE_SIZE).unwrap().1, RW);
+        assert_eq!(context.find(6 * PAGE_SIZE).unwrap().1, RW);
+    }
+
+    #[test]
+    fn directory_entries_allow_everything() {
+        let mut context = PagingContext::new();
+        let vir_addr = 4 * PAGE_SIZE;
+        assert!(context.insert(vir_addr, vir_addr, MapFlags::default())
+                .is_ok());
+        let mut addr = entry_address(context.cr3);
+        for index in parse_addr(vir_addr)[..3].iter() {
+            let blob = unsafe { &*(phy_to_virt(addr) as *const Blob) };
+            // Present, writable and user.
+            assert_eq!(blob.0[*index] & 0b111, 0b111);
+            assert_eq!(blob.0[*index] >> 63, 0);
+            addr = entry_address(blob.0[*index]);
+        }
+    }
+
+    const SIZE_2M: usize = 0x20_0000;
+    const SIZE_1G: usize = 0x4000_0000;
+
+    #[test]
+    fn correctly_insert_huge_pages() {
+        let mut context = PagingContext::new();
+        assert!(context.insert_page(SIZE_2M, 4 * SIZE_2M, PageSize::Size2M,
+                                    RW).is_ok());
+        assert!(context.insert_page(SIZE_1G, 2 * SIZE_1G, PageSize::Size1G,
+                                    RW).is_ok());
+
+        assert_eq!(walk(&context, SIZE_2M).unwrap(),
+                   RW.to_huge_entry(4 * SIZE_2M));
+        assert_eq!(walk(&context, SIZE_1G).unwrap(),
+                   RW.to_huge_entry(2 * SIZE_1G));
+        assert_eq!(context.find(SIZE_2M + 3 * PAGE_SIZE).unwrap(),
+                   (4 * SIZE_2M + 3 * PAGE_SIZE, RW));
+        assert_eq!(context.find_page(SIZE_1G + 0x1234_5678).unwrap(),
+                   (2 * SIZE_1G, PageSize::Size1G, RW));
+    }
+
+    #[test]
+    fn insert_overlapping_huge_pages() {
+        let mut context = PagingContext::new();
+        assert!(context.insert(SIZE_2M + PAGE_SIZE, PAGE_SIZE, RW).is_ok());
+        assert!(context.insert_page(SIZE_2M, 0, PageSize::Size2M, RW)
+                .is_err());
+        assert!(context.insert_page(0, 0, PageSize::Size1G, RW).is_err());
+        assert!(context.insert_page(2 * SIZE_2M, 0, PageSize::Size2M, RW)
+                .is_ok());
+        assert!(context.insert(2 * SIZE_2M + PAGE_SIZE, 0, RW).is_err());
+    }
+
+    #[test]
+    fn update_splits_huge_page() {
+        let mut context = PagingContext::new();
+        let vir_addr = SIZE_2M + 5 * PAGE_SIZE;
+        assert!(context.insert_page(SIZE_2M, 0, PageSize::Size2M, RW)
+                .is_ok());
+        assert_eq!(context.update(vir_addr, 0x7000, MapFlags::default())
+                   .unwrap(), (5 * PAGE_SIZE, RW));
+
+        assert_eq!(walk(&context, vir_addr).unwrap(), 0x7001);
+        // The other pages must still map the same frames.
+        for i in [0, 4, 6, 511].iter() {
+            assert_eq!(walk(&context, SIZE_2M + i * PAGE_SIZE).unwrap(),
+                       RW.to_entry(i * PAGE_SIZE));
+        }
+    }
+
+    #[test]
+    fn remove_splits_huge_page() {
+        let mut context = PagingContext::new();
+        assert!(context.insert_page(SIZE_1G, 0, PageSize::Size1G, RW)
+                .is_ok());
+        assert_eq!(context.remove(SIZE_1G + SIZE_2M + PAGE_SIZE).unwrap(),
+                   SIZE_2M + PAGE_SIZE);
+        assert!(context.find(SIZE_1G + SIZE_2M + PAGE_SIZE).is_none());
+        assert_eq!(context.find_page(SIZE_1G + SIZE_2M).unwrap(),
+                   (SIZE_2M, PageSize::Size4K, RW));
+        assert_eq!(context.find_page(SIZE_1G + 2 * SIZE_2M).unwrap(),
+                   (2 * SIZE_2M, PageSize::Size2M, RW));
+    }
+
+    #[test]
+    fn correctly_remove_huge_page() {
+        let mut context = PagingContext::new();
+        assert!(context.insert_page(SIZE_2M, 0, PageSize::Size2M, RW)
+                .is_ok());
+        assert!(context.remove_page(0, PageSize::Size1G).is_err());
+        assert_eq!(context.remove_page(SIZE_2M, PageSize::Size2M).unwrap(),
+                   0);
+        assert!(walk(&context, SIZE_2M).is_none());
+        assert!(context.dirtab.is_empty());
+    }
+
+    #[test]
+    fn protect_part_of_huge_page() {
+        let mut context = PagingContext::new();
+        assert!(context.insert_page(0, 0, PageSize::Size2M, RW).is_ok());
+        assert!(context.insert_page(SIZE_2M, SIZE_2M, PageSize::Size2M, RW)
+                .is_ok());
+        let range = SIZE_2M - PAGE_SIZE..2 * SIZE_2M;
+        assert!(context.protect(range, MapFlags::default()).is_ok());
+
+        assert_eq!(walk(&context, 0).unwrap(), RW.to_entry(0));
+        assert_eq!(walk(&context, SIZE_2M - PAGE_SIZE).unwrap(),
+                   MapFlags::default().to_entry(SIZE_2M - PAGE_SIZE));
+        // The second huge page is entirely in the range, so it's not split.
+        assert_eq!(walk(&context, SIZE_2M).unwrap(),
+                   MapFlags::default().to_huge_entry(SIZE_2M));
+    }
+
+    #[test]
+    fn correctly_promote_pages() {
+        let mut context = PagingContext::new();
+        for i in 0..NUMBER_OF_ENTRIES {
+            let addr = SIZE_2M + i * PAGE_SIZE;
+            assert!(context.insert(addr, addr, RW).is_ok());
+        }
+        assert!(context.promote(SIZE_2M, PageSize::Size1G).is_err());
+        assert!(context.promote(SIZE_2M, PageSize::Size2M).is_ok());
+        assert_eq!(walk(&context, SIZE_2M).unwrap(),
+                   RW.to_huge_entry(SIZE_2M));
+        assert_eq!(context.find_page(SIZE_2M).unwrap(),
+                   (SIZE_2M, PageSize::Size2M, RW));
+    }
+
+    #[test]
+    fn promote_non_contiguous_pages() {
+        let mut context = PagingContext::new();
+        for i in 0..NUMBER_OF_ENTRIES {
+            let addr = SIZE_2M + i * PAGE_SIZE;
+            assert!(context.insert(addr, addr, RW).is_ok());
+        }
+        assert!(context.update(SIZE_2M, 0, RW).is_ok());
+        assert!(context.promote(SIZE_2M, PageSize::Size2M).is_err());
+        assert!(context.update(SIZE_2M, SIZE_2M, MapFlags::default())
+                .is_ok());
+        assert!(context.promote(SIZE_2M, PageSize::Size2M).is_err());
+        assert_eq!(context.find_page(SIZE_2M).unwrap().1, PageSize::Size4K);
+    }
+
+    #[test]
+    fn map_linear_with_huge_pages() {
+        let mut context = PagingContext::new();
+        let intervals = StaticIntvlist::from(b"0x1ff000-0x600000").unwrap();
+        assert!(context.map_linear(&intervals, 0, RW).is_ok());
+        assert_eq!(context.find_page(0x1f_f000).unwrap(),
+                   (0x1f_f000, PageSize::Size4K, RW));
+        assert_eq!(context.find_page(SIZE_2M).unwrap(),
+                   (SIZE_2M, PageSize::Size2M, RW));
+        assert_eq!(context.find_page(0x5f_f000).unwrap(),
+                   (0x40_0000, PageSize::Size2M, RW));
+        assert!(context.find(0x60_0000).is_none());
+    }
+
+    #[test]
+    fn map_linear_to_upper_half() {
+        let mut context = PagingContext::new();
+        let offset = 0xffff_8000_0000_0000;
+        let intervals = StaticIntvlist::from(b"0x0-0x40200000").unwrap();
+        assert!(context.map_linear(&intervals, offset, RW).is_ok());
+        assert_eq!(context.find_page(offset + 0x1234_5000).unwrap(),
+                   (0, PageSize::Size1G, RW));
+        assert_eq!(context.find_page(offset + SIZE_1G).unwrap(),
+                   (SIZE_1G, PageSize::Size2M, RW));
+        assert_eq!(walk(&context, offset + SIZE_1G).unwrap(),
+                   RW.to_huge_entry(SIZE_1G));
+        assert!(context.find(offset + SIZE_1G + SIZE_2M).is_none());
+        assert!(context.find(0).is_none());
+    }
+
+    #[test]
+    fn user_context_shares_upper_half() {
+        let mut kernel = PagingContext::new();
+        let kernel_addr = 0xffff_ffff_8000_0000;
+        assert!(kernel.insert(kernel_addr, 0, RW).is_ok());
+
+        let mut user = PagingContext::new_user(&kernel);
+        assert!(user.insert(4 * PAGE_SIZE, 5 * PAGE_SIZE, RW).is_ok());
+        assert_eq!(walk(&user, kernel_addr).unwrap(), RW.to_entry(0));
+        assert_eq!(walk(&user, 4 * PAGE_SIZE).unwrap(),
+                   RW.to_entry(5 * PAGE_SIZE));
+        assert!(walk(&kernel, 4 * PAGE_SIZE).is_none());
+        // The kernel part is not counted in the user context.
+        assert_eq!(user.mapped_size(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn count_mapped_size() {
+        let mut context = PagingContext::new();
+        assert_eq!(context.mapped_size(), 0);
+        assert!(context.insert(PAGE_SIZE, 0, RW).is_ok());
+        assert!(context.insert_page(SIZE_2M, 0, PageSize::Size2M, RW)
+                .is_ok());
+        assert!(context.insert_page(SIZE_1G, 0, PageSize::Size1G, RW)
+                .is_ok());
+        assert_eq!(context.mapped_size(), PAGE_SIZE + SIZE_2M + SIZE_1G);
+
+        // Splitting a huge page doesn't change the size.
+        assert!(context.remove(SIZE_1G + PAGE_SIZE).is_ok());
+        assert_eq!(context.mapped_size(), SIZE_2M + SIZE_1G);
+    }
 }
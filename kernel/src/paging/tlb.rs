@@ -0,0 +1,307 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![allow(dead_code)]
+
+//! TLB invalidation. The processor caches the translations of the page
+//! tables in the TLB, so the TLB must be flushed whenever a mapping is
+//! removed or its permissions are reduced. Other processors that use the
+//! same page tables are asked to flush their TLBs with an interrupt.
+
+#[cfg(not(test))]
+use core::ops::Range;
+#[cfg(not(test))]
+use core::sync::atomic::spin_loop_hint;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use ::apic::{self, MAX_CPUS};
+#[cfg(not(test))]
+use ::config::PAGE_SIZE;
+#[cfg(not(test))]
+use ::interrupt::{self, InterruptStackFrame};
+use ::sync::Spinlock;
+
+/// The interrupt vector used to ask other processors to flush their TLBs.
+pub const SHOOTDOWN_INTERRUPT: u8 = 0xfd;
+
+/// If a range has more pages than this, the whole TLB is flushed instead of
+/// flushing the pages one by one.
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+/// The number of PCIDs. PCID 0 is never allocated, so the contexts without
+/// a PCID can use it.
+const NUMBER_OF_PCIDS: usize = 1 << 12;
+
+/// True if the processor supports PCIDs and CR4.PCIDE is set.
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A bitmap of the PCIDs that are allocated. PCID 0 is always allocated.
+static PCIDS: Spinlock<[u64; NUMBER_OF_PCIDS / 64]> =
+    Spinlock::new([0; NUMBER_OF_PCIDS / 64]);
+
+/// The physical address of the root page directory loaded on each
+/// processor, indexed by APIC ID. Each processor writes only its own
+/// entry.
+static LOADED: [AtomicUsize; MAX_CPUS] = [
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+    NOT_LOADED, NOT_LOADED, NOT_LOADED, NOT_LOADED,
+];
+/// An entry of `LOADED` on a processor that hasn't loaded any page tables.
+/// The atomics aren't `Copy`, so the entries above are written out.
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_LOADED: AtomicUsize = AtomicUsize::new(0);
+
+/// The shootdown that is in progress. Only one processor can request a
+/// shootdown at a time.
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_END: AtomicUsize = AtomicUsize::new(0);
+/// A bitmap of the processors that haven't flushed their TLBs yet.
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate a PCID. Return 0 if all the PCIDs are used.
+pub fn alloc_pcid() -> u16 {
+    for (index, word) in PCIDS.lock().iter_mut().enumerate() {
+        // PCID 0 is reserved.
+        let bits = if index == 0 { *word | 1 } else { *word };
+        if bits != !0 {
+            let bit = (!bits).trailing_zeros() as usize;
+            *word |= 1 << bit;
+            return (index * 64 + bit) as u16;
+        }
+    }
+    0
+}
+
+/// Free the PCID `pcid` allocated by [alloc_pcid](alloc_pcid).
+pub fn free_pcid(pcid: u16) {
+    let pcid = usize::from(pcid);
+    if pcid == 0 {
+        return;
+    }
+    PCIDS.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+/// Return true if the PCIDs are used to tag the TLB entries.
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Set CR4.PCIDE if the processor supports PCIDs. This must be called when
+/// the PCID in CR3 is 0.
+#[cfg(not(test))]
+pub unsafe fn enable_pcid() {
+    let ecx: u32;
+    asm!("mov %rbx, %rsi
+          cpuid
+          mov %rsi, %rbx"
+          : "={ecx}"(ecx)
+          : "{eax}"(1)
+          : "rsi", "edx"
+          : "volatile");
+    if ecx & (1 << 17) == 0 {
+        return;
+    }
+    asm!("mov %cr4, %rax
+          or $$0x20000, %rax
+          mov %rax, %cr4"
+          ::: "rax"
+          : "volatile");
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Record that the page tables whose root is at physical address `root` are
+/// loaded on this processor.
+pub fn set_loaded(root: usize) {
+    LOADED[apic::id()].store(root, Ordering::SeqCst);
+}
+
+/// Return a bitmap of the processors that have the page tables whose root
+/// is at physical address `root` loaded.
+pub fn loaded_on(root: usize) -> usize {
+    let mut cpus = 0;
+    for (id, loaded) in LOADED.iter().enumerate() {
+        if loaded.load(Ordering::SeqCst) == root {
+            cpus |= 1 << id;
+        }
+    }
+    cpus
+}
+
+/// Return a bitmap of the processors that have loaded any page tables.
+pub fn active_cpus() -> usize {
+    let mut cpus = 0;
+    for (id, loaded) in LOADED.iter().enumerate() {
+        if loaded.load(Ordering::SeqCst) != 0 {
+            cpus |= 1 << id;
+        }
+    }
+    cpus
+}
+
+/// Flush the TLB entry of the page that contains virtual address `addr`.
+#[cfg(not(test))]
+pub fn flush_page(addr: usize) {
+    unsafe {
+        asm!("invlpg ($0)" :: "r"(addr) : "memory" : "volatile");
+    }
+}
+
+/// Flush the TLB entries of the pages in `range`.
+#[cfg(not(test))]
+pub fn flush_range(range: Range<usize>) {
+    let start = range.start & !(PAGE_SIZE - 1);
+    if (range.end - start) / PAGE_SIZE > FLUSH_ALL_THRESHOLD {
+        flush_all();
+        return;
+    }
+    for page in (start..range.end).step_by(PAGE_SIZE) {
+        flush_page(page);
+    }
+}
+
+/// Flush all the TLB entries except the global ones by reloading CR3.
+#[cfg(not(test))]
+pub fn flush_all() {
+    unsafe {
+        asm!("mov %cr3, %rax
+              mov %rax, %cr3"
+              ::: "rax", "memory"
+              : "volatile");
+    }
+}
+
+/// Flush all the TLB entries including the global ones by toggling
+/// CR4.PGE. This flushes the entries of every PCID as well.
+#[cfg(not(test))]
+pub fn flush_global() {
+    unsafe {
+        asm!("mov %cr4, %rax
+              xor $$0x80, %rax
+              mov %rax, %cr4
+              xor $$0x80, %rax
+              mov %rax, %cr4"
+              ::: "rax", "memory"
+              : "volatile");
+    }
+}
+
+/// Flush the TLB entries requested by the shootdown in progress, if this
+/// processor is one of its targets and hasn't flushed them yet. The code
+/// that waits with the interrupts disabled for something held by another
+/// processor calls this, since the holder may be waiting for the flush.
+#[cfg(not(test))]
+pub fn handle_pending() {
+    let bit = 1 << apic::id();
+    if SHOOTDOWN_PENDING.load(Ordering::SeqCst) & bit == 0 {
+        return;
+    }
+    // The requester doesn't change the range until every target clears its
+    // bit.
+    let start = SHOOTDOWN_START.load(Ordering::Relaxed);
+    let end = SHOOTDOWN_END.load(Ordering::Relaxed);
+    flush_range(start..end);
+    SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::SeqCst);
+}
+
+/// Flush the TLB entries of the pages in `range` on the processors in the
+/// bitmap `cpus`, including this one if it's in `cpus`. Return after all
+/// the processors have flushed.
+///
+/// This may be called with the interrupts disabled, so the requests of the
+/// other processors are handled while waiting. Otherwise two processors
+/// that start a shootdown at the same time would wait for each other
+/// forever.
+#[cfg(not(test))]
+pub fn shootdown(cpus: usize, range: Range<usize>) {
+    let me = apic::id();
+    if cpus & (1 << me) != 0 {
+        flush_range(range.clone());
+    }
+    let others = cpus & !(1 << me);
+    if others == 0 {
+        return;
+    }
+
+    while SHOOTDOWN_LOCK.compare_and_swap(false, true, Ordering::Acquire) {
+        handle_pending();
+        spin_loop_hint();
+    }
+    SHOOTDOWN_START.store(range.start, Ordering::Relaxed);
+    SHOOTDOWN_END.store(range.end, Ordering::Relaxed);
+    SHOOTDOWN_PENDING.store(others, Ordering::SeqCst);
+    for id in 0..MAX_CPUS {
+        if others & (1 << id) != 0 {
+            apic::send_ipi(id, SHOOTDOWN_INTERRUPT);
+        }
+    }
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+        handle_pending();
+        spin_loop_hint();
+    }
+    SHOOTDOWN_LOCK.store(false, Ordering::Release);
+}
+
+/// The interrupt handler for the shootdown requests from other processors.
+#[cfg(not(test))]
+pub extern "x86-interrupt"
+fn shootdown_handler(_frame: &mut InterruptStackFrame) {
+    let _context = interrupt::enter();
+    // The request may already be handled while this processor was waiting
+    // for its own shootdown.
+    handle_pending();
+    apic::eoi();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_pcids() {
+        let pcid1 = alloc_pcid();
+        let pcid2 = alloc_pcid();
+        assert_ne!(pcid1, 0);
+        assert_ne!(pcid2, 0);
+        assert_ne!(pcid1, pcid2);
+        let is_allocated = |pcid: u16| {
+            PCIDS.lock()[usize::from(pcid) / 64] & (1 << (pcid % 64)) != 0
+        };
+        assert!(is_allocated(pcid1));
+        free_pcid(pcid1);
+        assert!(!is_allocated(pcid1));
+        free_pcid(pcid2);
+    }
+
+    #[test]
+    fn find_loaded_processors() {
+        // There is only one processor in test, so we use an address that
+        // no other test uses.
+        let root = 0xdead_0000;
+        assert_eq!(loaded_on(root), 0);
+        set_loaded(root);
+        assert_eq!(loaded_on(root), 1);
+        assert_eq!(active_cpus(), 1);
+        set_loaded(0);
+    }
+}
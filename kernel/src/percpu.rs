@@ -0,0 +1,110 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![cfg(not(test))]
+
+//! Per-CPU data. Every processor has its own [PerCpu](PerCpu) and the GS
+//! base of the processor points to it.
+
+use alloc::boxed::Box;
+use core::cell::{Cell, UnsafeCell};
+use core::ptr;
+
+use ::apic;
+use ::gdt::Gdt;
+use ::process::Process;
+use ::smp;
+
+/// The size of the stack that the double fault handler runs on. The handler
+/// prints a backtrace, so it needs more than a page.
+const DOUBLE_FAULT_STACK_SIZE: usize = 0x4000;
+
+/// The model-specific register that holds the GS base.
+const GS_BASE: u32 = 0xc000_0101;
+
+/// The data that each processor has its own copy of.
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerCpu {
+    // A pointer to the structure itself, so that it can be found by reading
+    // the first word at the GS base.
+    this: *const PerCpu,
+    /// The APIC ID of the processor.
+    pub id: usize,
+    /// The end of the kernel stack of the processor.
+    pub stack_end: usize,
+    /// The number of the interrupt handlers that are running.
+    pub interrupt_depth: Cell<usize>,
+    /// The process that is running on the processor. Only the processor
+    /// itself touches it, so it needs no lock.
+    pub process: UnsafeCell<Option<Process>>,
+    gdt: Gdt,
+}
+
+unsafe fn read_gs_base() -> usize {
+    let (low, high): (u32, u32);
+    asm!("rdmsr"
+         : "={eax}"(low), "={edx}"(high)
+         : "{ecx}"(GS_BASE)
+         :: "volatile");
+    (u64::from(high) << 32 | u64::from(low)) as usize
+}
+
+unsafe fn write_gs_base(addr: usize) {
+    asm!("wrmsr"
+         :: "{ecx}"(GS_BASE), "{eax}"(addr as u32),
+            "{edx}"((addr as u64 >> 32) as u32)
+         :: "volatile");
+}
+
+/// Return the per-CPU data of the processor that is running this code, or
+/// `None` if it's not initialized yet.
+pub fn try_current() -> Option<&'static PerCpu> {
+    unsafe {
+        if read_gs_base() == 0 {
+            return None;
+        }
+        Some(current())
+    }
+}
+
+/// Return the per-CPU data of the processor that is running this code. The
+/// caller must make sure that [init](init) is already called on this
+/// processor.
+pub unsafe fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    asm!("mov %gs:0, $0" : "=r"(this) ::: "volatile");
+    &*this
+}
+
+/// Initialization function for the processor that is running this code,
+/// whose kernel stack ends at `stack_end`. This loads a new GDT and TSS, so
+/// it must be called once on each processor.
+pub fn init(stack_end: usize) {
+    let percpu = Box::leak(Box::new(PerCpu {
+        this: ptr::null(),
+        id: apic::id(),
+        stack_end,
+        interrupt_depth: Cell::new(0),
+        process: UnsafeCell::new(None),
+        gdt: Gdt::new(stack_end, smp::alloc_stack(DOUBLE_FAULT_STACK_SIZE)),
+    }));
+    let this = percpu as *mut PerCpu;
+    percpu.this = this;
+    unsafe {
+        (*this).gdt.load();
+        write_gs_base(this as usize);
+    }
+}
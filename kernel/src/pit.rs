@@ -0,0 +1,77 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Programmable interval timer. Channel 2 of the PIT is used to wait for a
+//! precise amount of time without interrupts.
+
+#[cfg(not(test))]
+use ::port::{inb, outb};
+
+/// The frequency of the PIT in Hz.
+const FREQUENCY: u64 = 1_193_182;
+/// The maximum number of ticks that the PIT can count at a time.
+const MAX_TICKS: u64 = 0xffff;
+
+/// The I/O ports of the PIT.
+#[cfg(not(test))]
+const CHANNEL2: u16 = 0x42;
+#[cfg(not(test))]
+const COMMAND: u16 = 0x43;
+/// The port that controls the gate and shows the output of channel 2.
+#[cfg(not(test))]
+const GATE: u16 = 0x61;
+
+/// Return the number of ticks of the PIT in `micros` microseconds, rounded
+/// up.
+fn ticks(micros: u64) -> u64 {
+    (micros * FREQUENCY + 999_999) / 1_000_000
+}
+
+/// Wait for `micros` microseconds.
+#[cfg(not(test))]
+pub fn delay(micros: u64) {
+    let mut ticks = ticks(micros);
+    while ticks > 0 {
+        let count = if ticks > MAX_TICKS { MAX_TICKS } else { ticks };
+        ticks -= count;
+        unsafe {
+            // Disconnect the speaker and stop the counter.
+            let gate = inb(GATE) & !0x3;
+            outb(GATE, gate);
+            // Channel 2, the low byte then the high byte, mode 0. The
+            // output goes high when the count reaches zero.
+            outb(COMMAND, 0xb0);
+            outb(CHANNEL2, count as u8);
+            outb(CHANNEL2, (count >> 8) as u8);
+            // Start counting.
+            outb(GATE, gate | 0x1);
+            while inb(GATE) & 0x20 == 0 {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_to_ticks() {
+        assert_eq!(ticks(0), 0);
+        assert_eq!(ticks(1), 2);
+        assert_eq!(ticks(1_000_000), FREQUENCY);
+        assert!(ticks(10_000) < MAX_TICKS);
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![cfg(not(test))]
+
+//! I/O ports. Some legacy devices, like the PIT, are accessed through the
+//! I/O address space instead of memory.
+
+/// Read a byte from the I/O port `port`.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("inb %dx, %al" : "={al}"(value) : "{dx}"(port) :: "volatile");
+    value
+}
+
+/// Write the byte `value` to the I/O port `port`.
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("outb %al, %dx" :: "{dx}"(port), "{al}"(value) :: "volatile");
+}
@@ -0,0 +1,308 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![allow(dead_code)]
+
+//! Process module. This module keeps track of the processes.
+//!
+//! No process is created yet: there is no init program to load and no way
+//! to enter the user mode, so [set_current](set_current) is never called
+//! and the paths that handle a running process are only reached by the
+//! tests until they are added.
+
+use alloc::collections::btree_map::BTreeMap;
+use ::config::PAGE_SIZE;
+use ::vm::AddressSpace;
+
+/// The signals that can be sent to a process. The numbers follow Linux.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Signal {
+    /// The process executes an invalid instruction.
+    Ill = 4,
+    /// The process stops at a breakpoint or after a single step.
+    Trap = 5,
+    /// The process causes an arithmetic error, like a division by zero.
+    Fpe = 8,
+    /// Terminate the process immediately.
+    Kill = 9,
+    /// The process accesses memory that it is not allowed to.
+    Segv = 11,
+}
+
+/// A file opened by a process. There is no file system yet, so the content
+/// of a file is just some memory in the kernel.
+#[derive(Copy, Clone, Debug)]
+pub struct File {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+/// A structure that represents a process.
+#[derive(Debug)]
+pub struct Process {
+    pub pid: usize,
+    pub address_space: AddressSpace,
+    /// The maximum size of the heap in bytes, like RLIMIT_DATA in Linux.
+    pub data_limit: usize,
+    // The opened files indexed by their file descriptors.
+    files: BTreeMap<usize, File>,
+    // A bitmap of the signals that are sent, but not delivered yet.
+    pending_signals: u64,
+}
+
+impl Process {
+    /// Create a new [Process](Process) with the process id `pid`.
+    pub fn new(pid: usize, address_space: AddressSpace) -> Process {
+        Process {
+            pid,
+            address_space,
+            data_limit: usize::max_value(),
+            files: BTreeMap::new(),
+            pending_signals: 0,
+        }
+    }
+
+    /// Add `file` to the opened files and return its file descriptor. The
+    /// lowest unused file descriptor is used.
+    pub fn open(&mut self, file: File) -> usize {
+        let fd = (0..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, file);
+        fd
+    }
+
+    /// Return the file opened with file descriptor `fd`.
+    pub fn file(&self, fd: usize) -> Option<File> {
+        self.files.get(&fd).cloned()
+    }
+
+    /// Send the signal `signal` to the process. The signal is delivered
+    /// when [deliver_signals](Process::deliver_signals) is called.
+    pub fn send_signal(&mut self, signal: Signal) {
+        self.pending_signals |= 1 << signal as u64;
+    }
+
+    /// Return the number of pages that the process maps. When there is no
+    /// memory left, the process with the highest score is killed first.
+    pub fn oom_score(&self) -> usize {
+        self.address_space.paging().mapped_size() / PAGE_SIZE
+    }
+
+    /// Return true if the signal `signal` is sent, but not delivered yet.
+    pub fn is_pending(&self, signal: Signal) -> bool {
+        self.pending_signals & (1 << signal as u64) != 0
+    }
+
+    /// Unmap all the memory of the process and free the frames.
+    #[cfg(not(test))]
+    fn free_memory(&mut self) {
+        self.address_space.clear();
+        self.address_space.flush_tlb_and_free();
+    }
+
+    /// Deliver all the pending signals. There are no signal handlers yet,
+    /// so every signal terminates the process. Return true if the process
+    /// is terminated, in which case its memory is already freed.
+    #[cfg(not(test))]
+    fn deliver_signals(&mut self) -> bool {
+        let signals = [Signal::Ill, Signal::Trap, Signal::Fpe, Signal::Kill,
+                       Signal::Segv];
+        for signal in signals.iter() {
+            if self.is_pending(*signal) {
+                info!("Process {} is terminated by signal {}.",
+                      self.pid, *signal as u64);
+                self.free_memory();
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Return the process that is running on the processor. The process is in
+/// the per-CPU data, so the other processors never see it. The caller must
+/// not keep the reference after the process is replaced.
+#[cfg(not(test))]
+pub fn current() -> Option<&'static mut Process> {
+    let percpu = ::percpu::try_current()?;
+    unsafe { (*percpu.process.get()).as_mut() }
+}
+
+/// Make `process` the process that is running on the processor. Nothing
+/// calls this until the init process is started.
+#[cfg(not(test))]
+pub fn set_current(process: Process) {
+    unsafe {
+        *::percpu::current().process.get() = Some(process);
+    }
+}
+
+/// Deliver the pending signals of the process that is running on the
+/// processor. If the process is terminated, it's dropped and the processor
+/// goes to the idle loop, since there is no other process to switch to
+/// until we have a scheduler. The caller must not hold any reference to the
+/// process or any lock, because this may not return.
+#[cfg(not(test))]
+pub fn deliver_signals() {
+    let terminated = match current() {
+        Some(process) => process.deliver_signals(),
+        None => false,
+    };
+    if !terminated {
+        return;
+    }
+    unsafe {
+        // The page tables of the process are freed when it's dropped.
+        ::paging::kernel_context().activate();
+        *::percpu::current().process.get() = None;
+    }
+    ::smp::restart_idle();
+}
+
+/// Return the process with the highest OOM score in `processes`. If there
+/// is a tie, the last one is returned.
+fn select_victim<'a, I>(processes: I) -> Option<&'a mut Process>
+    where I: IntoIterator<Item = &'a mut Process>
+{
+    processes.into_iter().max_by_key(|process| process.oom_score())
+}
+
+/// Send SIGKILL to the process with the highest OOM score in `processes`
+/// and unmap all its memory. The frames are only freed after the TLBs are
+/// flushed, so they are left in the address space of the victim.
+fn kill_victim<'a, I>(processes: I) -> Option<&'a mut Process>
+    where I: IntoIterator<Item = &'a mut Process>
+{
+    let victim = select_victim(processes)?;
+    error!("Out of memory: kill process {} with score {}.", victim.pid,
+           victim.oom_score());
+    victim.send_signal(Signal::Kill);
+    victim.address_space.clear();
+    Some(victim)
+}
+
+/// Send SIGKILL to the process with the highest OOM score in `processes`
+/// because there is no memory left. Return the process id of the victim.
+/// There is no process table until we have a scheduler, so the caller
+/// passes the processes that can be killed, which may not include the one
+/// that runs out of memory.
+#[cfg(not(test))]
+pub fn oom_kill<'a, I>(processes: I) -> Option<usize>
+    where I: IntoIterator<Item = &'a mut Process>
+{
+    let victim = kill_victim(processes)?;
+    // Free the memory right away, so that the other processes can use it
+    // before the victim runs again to die. The frame allocator is not held
+    // while the TLBs are flushed.
+    victim.address_space.flush_tlb_and_free();
+    Some(victim.pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+    use ::frame::new_for_test;
+    use ::paging::PagingContext;
+    use ::vm::{Access, Protection, Vma};
+
+    #[test]
+    fn open_files() {
+        let mut process = Process::new(1, AddressSpace::new(
+            PagingContext::new()));
+        let file = File {
+            name: "/bin/init",
+            data: b"",
+        };
+        assert_eq!(process.open(file), 0);
+        assert_eq!(process.open(file), 1);
+        assert_eq!(process.file(1).unwrap().name, "/bin/init");
+        assert!(process.file(2).is_none());
+    }
+
+    #[test]
+    fn send_signals() {
+        let mut process = Process::new(1, AddressSpace::new(
+            PagingContext::new()));
+        assert!(!process.is_pending(Signal::Segv));
+        process.send_signal(Signal::Segv);
+        assert!(process.is_pending(Signal::Segv));
+        assert!(!process.is_pending(Signal::Kill));
+    }
+    #[test]
+    fn select_oom_victim() {
+        let rw = ::paging::MapFlags {
+            write: true,
+            ..::paging::MapFlags::default()
+        };
+        let mut processes: Vec<Process> = (1..4)
+            .map(|pid| {
+                let mut paging = PagingContext::new();
+                // Process 2 maps the most pages.
+                let pages = if pid == 2 { 3 } else { 1 };
+                for page in 0..pages {
+                    paging.insert(page * PAGE_SIZE, 0, rw).unwrap();
+                }
+                Process::new(pid, AddressSpace::new(paging))
+            })
+            .collect();
+        assert_eq!(processes[1].oom_score(), 3);
+        assert_eq!(select_victim(processes.iter_mut()).unwrap().pid, 2);
+        assert!(select_victim(processes[..0].iter_mut()).is_none());
+    }
+
+    #[test]
+    fn kill_other_process() {
+        let rw = Protection {
+            read: true,
+            write: true,
+            exec: false,
+        };
+        let write = Access {
+            write: true,
+            user: true,
+            ..Access::default()
+        };
+        let mut frames = new_for_test(3);
+        let mut processes: Vec<Process> = (1..3)
+            .map(|pid| {
+                let mut space = AddressSpace::new(PagingContext::new());
+                space.insert_vma(Vma::anonymous(0, 0x2000, rw)).unwrap();
+                // Process 2 uses more memory than process 1, which is the
+                // one that runs out of memory.
+                for page in 0..pid {
+                    space.handle_fault(&mut frames, page * PAGE_SIZE, write)
+                        .unwrap();
+                }
+                Process::new(pid, space)
+            })
+            .collect();
+        assert!(frames.alloc().is_err());
+
+        {
+            let victim = kill_victim(processes.iter_mut()).unwrap();
+            assert_eq!(victim.pid, 2);
+            victim.address_space.flush_tlb();
+            victim.address_space.free_released(&mut frames);
+        }
+        assert!(frames.alloc().is_ok());
+        assert!(frames.alloc().is_ok());
+
+        assert!(processes[1].is_pending(Signal::Kill));
+        assert_eq!(processes[1].oom_score(), 0);
+        assert!(!processes[0].is_pending(Signal::Kill));
+        assert_eq!(processes[0].oom_score(), 1);
+        assert!(processes[0].address_space.paging().find(0).is_some());
+    }
+}
@@ -0,0 +1,421 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Serial port module. This module drives the 16550 UART of COM1, so that
+//! the kernel output can be read from the other end of the cable, or from
+//! the terminal when Qemu runs with `-nographic`.
+//!
+//! The bytes to send are queued and the interrupt handler moves them to the
+//! FIFO of the UART whenever it becomes empty. Until the interrupts of the
+//! UART are routed to a processor, or when the queue is full, the bytes are
+//! sent by polling instead.
+//!
+//! The other serial ports, like COM2 used by the GDB stub, are driven by
+//! polling with [Uart](Uart).
+
+#[cfg(not(test))]
+use core::sync::atomic::spin_loop_hint;
+
+#[cfg(not(test))]
+use ::apic::{self, ioapic};
+#[cfg(not(test))]
+use ::collections::RingBuffer;
+#[cfg(not(test))]
+use ::interrupt::{self, InterruptStackFrame};
+#[cfg(not(test))]
+use ::port::{inb, outb};
+#[cfg(not(test))]
+use ::sync::IrqSpinlock;
+
+/// The I/O ports of the first registers of COM1 and COM2.
+#[cfg(not(test))]
+const COM1: u16 = 0x3f8;
+#[cfg(not(test))]
+pub const COM2: u16 = 0x2f8;
+/// The IRQ of COM1.
+#[cfg(not(test))]
+const COM1_IRQ: u8 = 4;
+
+/// The offsets of the registers of the UART. When the DLAB bit of LCR is
+/// set, the first two registers hold the divisor of the baud rate instead.
+#[cfg(not(test))]
+const DATA: u16 = 0;
+#[cfg(not(test))]
+const IER: u16 = 1;
+#[cfg(not(test))]
+const IIR: u16 = 2;
+#[cfg(not(test))]
+const FCR: u16 = 2;
+#[cfg(not(test))]
+const LCR: u16 = 3;
+#[cfg(not(test))]
+const MCR: u16 = 4;
+#[cfg(not(test))]
+const LSR: u16 = 5;
+#[cfg(not(test))]
+const MSR: u16 = 6;
+#[cfg(not(test))]
+const DIVISOR_LOW: u16 = 0;
+#[cfg(not(test))]
+const DIVISOR_HIGH: u16 = 1;
+
+/// The bits of the registers of the UART.
+#[cfg(not(test))]
+const IER_RX_AVAILABLE: u8 = 1;
+#[cfg(not(test))]
+const IER_TX_EMPTY: u8 = 1 << 1;
+#[cfg(not(test))]
+const IIR_NO_INTERRUPT: u8 = 1;
+#[cfg(not(test))]
+const LCR_8N1: u8 = 0b11;
+#[cfg(not(test))]
+const LCR_DLAB: u8 = 1 << 7;
+#[cfg(not(test))]
+const MCR_DTR: u8 = 1;
+#[cfg(not(test))]
+const MCR_RTS: u8 = 1 << 1;
+// The UART only sends its interrupts to the IRQ line when OUT2 is set.
+#[cfg(not(test))]
+const MCR_OUT2: u8 = 1 << 3;
+#[cfg(not(test))]
+const MCR_LOOPBACK: u8 = 1 << 4;
+#[cfg(not(test))]
+const LSR_DATA_READY: u8 = 1;
+#[cfg(not(test))]
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+/// Enable the FIFOs, clear them, and raise the receive interrupt when there
+/// are 14 bytes in the receive FIFO.
+#[cfg(not(test))]
+const FCR_INIT: u8 = 0xc7;
+
+/// The sources of the interrupts in bits 1 to 3 of IIR. The remaining
+/// source is a change of the modem status.
+#[cfg(not(test))]
+const SOURCE_TX_EMPTY: u8 = 0b001;
+#[cfg(not(test))]
+const SOURCE_RX_AVAILABLE: u8 = 0b010;
+#[cfg(not(test))]
+const SOURCE_LINE_STATUS: u8 = 0b011;
+#[cfg(not(test))]
+const SOURCE_RX_TIMEOUT: u8 = 0b110;
+
+/// The number of bytes that the transmit FIFO can hold.
+#[cfg(not(test))]
+const FIFO_SIZE: usize = 16;
+
+/// The clock of the UART divided by 16, which is the fastest baud rate.
+const MAX_BAUD: u32 = 115_200;
+/// The baud rate of the serial port.
+#[cfg(not(test))]
+const BAUD: u32 = 115_200;
+
+/// The interrupt vector of the serial port.
+#[cfg(not(test))]
+pub const SERIAL_INTERRUPT: u8 = 0x24;
+
+/// Return the divisor that makes the UART run at `baud` bits per second.
+/// Only the baud rates that divide [MAX_BAUD](MAX_BAUD) can be used.
+#[cfg_attr(test, allow(dead_code))]
+fn divisor(baud: u32) -> Result<u16, ()> {
+    if baud == 0 || MAX_BAUD % baud != 0 || MAX_BAUD / baud > 0xffff {
+        return Err(());
+    }
+    Ok((MAX_BAUD / baud) as u16)
+}
+
+/// A 16550 UART whose first register is at the I/O port `base`. It's
+/// driven by polling, without any interrupts.
+#[cfg(not(test))]
+pub struct Uart {
+    base: u16,
+}
+
+#[cfg(not(test))]
+impl Uart {
+    /// Create a [Uart](Uart) for the UART at the I/O port `base`.
+    pub const fn new(base: u16) -> Uart {
+        Uart { base }
+    }
+
+    /// Program the UART to run at [BAUD](BAUD) with 8 data bits, no parity
+    /// and 1 stop bit. Return false if there is no UART at the port.
+    pub fn init(&self) -> bool {
+        let divisor = divisor(BAUD).unwrap();
+        unsafe {
+            outb(self.base + IER, 0);
+            outb(self.base + LCR, LCR_DLAB);
+            outb(self.base + DIVISOR_LOW, divisor as u8);
+            outb(self.base + DIVISOR_HIGH, (divisor >> 8) as u8);
+            outb(self.base + LCR, LCR_8N1);
+            outb(self.base + FCR, FCR_INIT);
+
+            // The UART may not exist, in which case the reads return
+            // garbage. We check that a byte sent in loopback mode comes
+            // back.
+            outb(self.base + MCR, MCR_LOOPBACK | MCR_RTS | MCR_DTR);
+            outb(self.base + DATA, 0xae);
+            if inb(self.base + DATA) != 0xae {
+                return false;
+            }
+            outb(self.base + MCR, MCR_OUT2 | MCR_RTS | MCR_DTR);
+        }
+        true
+    }
+
+    /// Return the next received byte, if any.
+    pub fn try_read(&self) -> Option<u8> {
+        unsafe {
+            if inb(self.base + LSR) & LSR_DATA_READY == 0 {
+                return None;
+            }
+            Some(inb(self.base + DATA))
+        }
+    }
+
+    /// Wait for the next received byte.
+    pub fn read(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read() {
+                return byte;
+            }
+            spin_loop_hint();
+        }
+    }
+
+    /// Send `byte` after the transmit FIFO becomes empty.
+    pub fn write(&self, byte: u8) {
+        unsafe {
+            while inb(self.base + LSR) & LSR_TX_EMPTY == 0 {
+                spin_loop_hint();
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+}
+
+/// The state of the serial port, which is shared with its interrupt
+/// handler.
+#[cfg(not(test))]
+struct Serial {
+    // True if the UART is found and initialized.
+    present: bool,
+    // True if the interrupts of the UART are routed to a processor.
+    interrupts: bool,
+    // The value of IER.
+    ier: u8,
+    // The bytes that are waiting to be sent.
+    tx: RingBuffer,
+    // The bytes that are received but not read yet.
+    rx: RingBuffer,
+}
+
+/// The serial port is used by interrupt handlers too, so the interrupts are
+/// disabled while it's locked.
+#[cfg(not(test))]
+static SERIAL: IrqSpinlock<Serial> = IrqSpinlock::new(Serial {
+    present: false,
+    interrupts: false,
+    ier: 0,
+    tx: RingBuffer::new(),
+    rx: RingBuffer::new(),
+});
+
+#[cfg(not(test))]
+impl Serial {
+    fn set_ier(&mut self, ier: u8) {
+        self.ier = ier;
+        unsafe {
+            outb(COM1 + IER, ier);
+        }
+    }
+
+    /// Move the queued bytes to the transmit FIFO, if it's empty.
+    fn fill_fifo(&mut self) {
+        unsafe {
+            if inb(COM1 + LSR) & LSR_TX_EMPTY == 0 {
+                return;
+            }
+            for _ in 0..FIFO_SIZE {
+                match self.tx.pop() {
+                    Ok(byte) => outb(COM1 + DATA, byte),
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    /// Send all the queued bytes by polling the UART.
+    fn drain(&mut self) {
+        while !self.tx.is_empty() {
+            self.fill_fifo();
+            spin_loop_hint();
+        }
+    }
+
+    /// Move the received bytes from the UART to the queue. The bytes that
+    /// don't fit in the queue are dropped.
+    fn receive(&mut self) {
+        unsafe {
+            while inb(COM1 + LSR) & LSR_DATA_READY != 0 {
+                let _ = self.rx.push(inb(COM1 + DATA));
+            }
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if !self.present {
+            return;
+        }
+        for &byte in bytes {
+            if self.tx.is_full() {
+                self.drain();
+            }
+            self.tx.push(byte).unwrap();
+        }
+        if !self.interrupts {
+            self.drain();
+            return;
+        }
+        self.fill_fifo();
+        if !self.tx.is_empty() {
+            // The UART raises an interrupt when the FIFO becomes empty.
+            let ier = self.ier | IER_TX_EMPTY;
+            self.set_ier(ier);
+        }
+    }
+}
+
+/// The interrupt handler of the serial port.
+#[cfg(not(test))]
+pub extern "x86-interrupt" fn serial_handler(
+    _frame: &mut InterruptStackFrame)
+{
+    let _context = interrupt::enter();
+    {
+        let mut serial = SERIAL.lock();
+        // There may be more than one source at a time, so we handle them
+        // until IIR says that there is none left.
+        loop {
+            let iir = unsafe { inb(COM1 + IIR) };
+            if iir & IIR_NO_INTERRUPT != 0 {
+                break;
+            }
+            match iir >> 1 & 0b111 {
+                SOURCE_TX_EMPTY => {
+                    serial.fill_fifo();
+                    if serial.tx.is_empty() {
+                        let ier = serial.ier & !IER_TX_EMPTY;
+                        serial.set_ier(ier);
+                    }
+                },
+                SOURCE_RX_AVAILABLE | SOURCE_RX_TIMEOUT => serial.receive(),
+                // Reading the status registers clears the interrupts.
+                SOURCE_LINE_STATUS => unsafe {
+                    inb(COM1 + LSR);
+                },
+                _ => unsafe {
+                    inb(COM1 + MSR);
+                },
+            }
+        }
+    }
+    apic::eoi();
+}
+
+/// Send `bytes` to the serial port. Nothing is sent if there is no serial
+/// port.
+#[cfg(not(test))]
+pub fn write(bytes: &[u8]) {
+    SERIAL.lock().write(bytes);
+}
+
+/// Wait until all the queued bytes are sent. This is used when the
+/// interrupt handler may never run again, like when the kernel panics.
+#[cfg(not(test))]
+pub fn flush() {
+    let mut serial = SERIAL.lock();
+    if serial.present {
+        serial.drain();
+    }
+}
+
+/// Return the next received byte, if any.
+#[cfg(not(test))]
+#[allow(dead_code)]
+pub fn read() -> Option<u8> {
+    let mut serial = SERIAL.lock();
+    if !serial.present {
+        return None;
+    }
+    if !serial.interrupts {
+        serial.receive();
+    }
+    serial.rx.pop().ok()
+}
+
+/// Release the lock of the serial port, which may be held by the code that
+/// panics, so that the panic message can be sent.
+#[cfg(not(test))]
+pub unsafe fn force_unlock() {
+    SERIAL.force_unlock();
+}
+
+/// Initialization function for serial module. The UART is polled until
+/// [init_interrupts](init_interrupts) is called, so this can be called
+/// before anything else is initialized.
+#[cfg(not(test))]
+pub fn init() {
+    let mut serial = SERIAL.lock();
+    serial.present = Uart::new(COM1).init();
+}
+
+/// Route the interrupts of the serial port to the processor that is running
+/// this code. This must be called after the interrupt and local APIC
+/// modules are initialized, and the processor must enable the interrupts
+/// at some point, or the queued bytes are only sent when the queue is full.
+#[cfg(not(test))]
+pub fn init_interrupts() {
+    let mut serial = SERIAL.lock();
+    if !serial.present {
+        return;
+    }
+    ioapic::route(COM1_IRQ, SERIAL_INTERRUPT, apic::id());
+    serial.set_ier(IER_RX_AVAILABLE);
+    serial.interrupts = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_divisor() {
+        assert_eq!(divisor(115_200), Ok(1));
+        assert_eq!(divisor(57_600), Ok(2));
+        assert_eq!(divisor(9_600), Ok(12));
+        assert_eq!(divisor(50), Ok(2304));
+    }
+
+    #[test]
+    fn invalid_baud() {
+        assert!(divisor(0).is_err());
+        assert!(divisor(7).is_err());
+        assert!(divisor(200_000).is_err());
+        // The divisor doesn't fit in 16 bits.
+        assert!(divisor(1).is_err());
+    }
+}
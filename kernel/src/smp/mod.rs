@@ -0,0 +1,183 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![cfg(not(test))]
+
+//! Symmetric multiprocessing module. This module starts the application
+//! processors, which are all the processors other than the boot processor.
+
+mod trampoline;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::apic;
+use ::interrupt;
+use ::paging::{self, MapFlags, PagingContext};
+use ::percpu;
+use ::pit;
+use self::trampoline::TRAMPOLINE_ADDR;
+
+/// The size of the kernel stack of each application processor.
+const STACK_SIZE: usize = 0x4000;
+/// The size of the stack used by the trampoline.
+const TRAMPOLINE_STACK_SIZE: usize = 0x1000;
+
+/// The number of microseconds between two checks of the processors that
+/// are starting.
+const POLL_INTERVAL: u64 = 10_000;
+/// The processors are assumed to be all started when none of them comes
+/// online in this number of microseconds.
+const SETTLE_TIME: u64 = 100_000;
+/// The maximum number of microseconds to wait for the processors to start.
+const START_TIMEOUT: u64 = 1_000_000;
+
+/// A bitmap of the processors that are running.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Return a bitmap of the APIC IDs of the processors that are running.
+pub fn online() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Allocate a kernel stack of size `size` and return its end.
+pub fn alloc_stack(size: usize) -> usize {
+    let mut stack: Vec<u64> = Vec::new();
+    stack.resize(size / mem::size_of::<u64>(), 0);
+    let stack = Box::leak(stack.into_boxed_slice());
+    stack.as_ptr() as usize + size
+}
+
+/// The idle loop. The processor sleeps until there is an interrupt.
+pub fn idle() -> ! {
+    loop {
+        unsafe {
+            asm!("sti
+                  hlt"
+                  :::: "volatile");
+        }
+    }
+}
+
+/// Abandon the code that is running and go to the idle loop at the end of
+/// the kernel stack of the processor. This is used when there is nothing to
+/// return to, like after the running process is terminated.
+pub fn restart_idle() -> ! {
+    let stack_end = unsafe { percpu::current() }.stack_end;
+    unsafe {
+        asm!("mov $0, %rsp
+              xor %rbp, %rbp
+              call *$1"
+              :: "r"(stack_end), "r"(idle as usize)
+              :: "volatile");
+    }
+    unreachable!();
+}
+
+/// The function that the application processors call from the trampoline.
+/// It's still running on the stack of the trampoline.
+extern "C" fn ap_entry() -> ! {
+    unsafe {
+        paging::init_ap();
+    }
+    let stack_end = alloc_stack(STACK_SIZE);
+    unsafe {
+        asm!("mov $0, %rsp
+              call *$1"
+              :: "r"(stack_end), "r"(ap_main as usize), "{rdi}"(stack_end)
+              :: "volatile");
+    }
+    unreachable!();
+}
+
+/// The function that the application processors call after they switch to
+/// their own stack, which ends at `stack_end`.
+extern "C" fn ap_main(stack_end: usize) -> ! {
+    percpu::init(stack_end);
+    interrupt::load();
+    apic::enable();
+    ONLINE.fetch_or(1 << apic::id(), Ordering::SeqCst);
+    trampoline::unlock();
+    idle();
+}
+
+/// Initialization function for the boot processor. This must be called
+/// before anything else uses the GS base.
+pub fn init_bsp(stack_end: usize) {
+    percpu::init(stack_end);
+    ONLINE.fetch_or(1 << apic::id(), Ordering::SeqCst);
+}
+
+/// Wait for the application processors to come online. The number of the
+/// processors is not known, and they start one by one with a gap between
+/// them, so we wait until none of them is in the trampoline and no more of
+/// them comes online for a while.
+fn wait_for_aps() {
+    let mut last = online();
+    let mut waited = 0;
+    let mut quiet = 0;
+    while quiet < SETTLE_TIME || trampoline::is_locked() {
+        if waited >= START_TIMEOUT {
+            warn!("the processors are still starting after {} ms",
+                  START_TIMEOUT / 1000);
+            return;
+        }
+        pit::delay(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+        let online = online();
+        if online == last {
+            quiet += POLL_INTERVAL;
+        } else {
+            last = online;
+            quiet = 0;
+        }
+    }
+}
+
+/// Start all the application processors and wait for them to be parked in
+/// the idle loop. This must be called after the interrupt module is
+/// initialized.
+pub fn init() {
+    // The processors start with paging disabled, so the trampoline must be
+    // identity-mapped until they jump to the kernel.
+    let mut context = PagingContext::new_user(&paging::kernel_context());
+    let flags = MapFlags {
+        write: true,
+        ..MapFlags::default()
+    };
+    context.insert(TRAMPOLINE_ADDR, TRAMPOLINE_ADDR, flags).unwrap();
+    let stack_end = alloc_stack(TRAMPOLINE_STACK_SIZE);
+    unsafe {
+        trampoline::install(context.phy_addr(), stack_end,
+                            ap_entry as usize);
+    }
+
+    apic::broadcast_init();
+    pit::delay(10_000);
+    // The second startup interrupt is sent in case the first one is lost.
+    // The processors that already started ignore it.
+    apic::broadcast_startup(TRAMPOLINE_ADDR);
+    pit::delay(200);
+    apic::broadcast_startup(TRAMPOLINE_ADDR);
+
+    wait_for_aps();
+    info!("{} processors are online.", online().count_ones());
+
+    // A slow processor may still enter the trampoline later, so the page
+    // tables are never freed.
+    mem::forget(context);
+}
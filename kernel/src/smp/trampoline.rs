@@ -0,0 +1,150 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The code that the other processors run when they start. They start in
+//! real mode, so the code is copied to low memory and switches them
+//! directly to long mode.
+//!
+//! All the processors are started at the same time, but only one of them
+//! can use the stack of the trampoline at a time, so they take a lock
+//! first. The entry function must release the lock after it switches to
+//! its own stack.
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::paging::phy_to_virt;
+
+/// The physical address that the trampoline is copied to. This must be the
+/// same as the address used in the assembly.
+pub const TRAMPOLINE_ADDR: usize = 0x8000;
+
+global_asm!("
+    .pushsection .rodata
+    .global trampoline_start
+    .global trampoline_end
+    .global trampoline_cr3
+    .global trampoline_stack
+    .global trampoline_entry
+    .global trampoline_lock
+
+    .code16
+trampoline_start:
+    cli
+    cld
+    xor %ax, %ax
+    mov %ax, %ds
+    lgdtl 0x8000 + trampoline_gdtr - trampoline_start
+    # Enable PAE and global pages.
+    mov $0xa0, %eax
+    mov %eax, %cr4
+    mov 0x8000 + trampoline_cr3 - trampoline_start, %eax
+    mov %eax, %cr3
+    # Enable long mode and the no-execute bit in IA32_EFER.
+    mov $0xc0000080, %ecx
+    rdmsr
+    or $0x900, %eax
+    wrmsr
+    # Enable paging and protected mode at the same time, which activates
+    # long mode.
+    mov %cr0, %eax
+    or $0x80000001, %eax
+    mov %eax, %cr0
+    ljmpl $0x08, $0x8000 + trampoline_long - trampoline_start
+
+    .code64
+trampoline_long:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+1:
+    pause
+    lock btsq $0, 0x8000 + trampoline_lock - trampoline_start
+    jc 1b
+    mov 0x8000 + trampoline_stack - trampoline_start, %rsp
+    mov 0x8000 + trampoline_entry - trampoline_start, %rax
+    call *%rax
+
+    .align 8
+trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+trampoline_gdtr:
+    .word trampoline_gdtr - trampoline_gdt - 1
+    .long 0x8000 + trampoline_gdt - trampoline_start
+
+    .align 8
+trampoline_cr3:
+    .quad 0
+trampoline_stack:
+    .quad 0
+trampoline_entry:
+    .quad 0
+trampoline_lock:
+    .quad 0
+trampoline_end:
+    .popsection
+");
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_cr3: u8;
+    static trampoline_stack: u8;
+    static trampoline_entry: u8;
+    static trampoline_lock: u8;
+}
+
+/// Return the address of the copy of `symbol` in low memory.
+unsafe fn copied(symbol: *const u8) -> usize {
+    let offset = symbol as usize
+        - &trampoline_start as *const u8 as usize;
+    phy_to_virt(TRAMPOLINE_ADDR) + offset
+}
+
+/// Copy the trampoline to low memory. The processors will load the page
+/// tables at physical address `cr3`, which must be below 4GB, and call
+/// `entry` with the stack that ends at `stack_end`.
+pub unsafe fn install(cr3: usize, stack_end: usize, entry: usize) {
+    assert!(cr3 < 1 << 32);
+    let start = &trampoline_start as *const u8;
+    let len = &trampoline_end as *const u8 as usize - start as usize;
+    ptr::copy_nonoverlapping(start, phy_to_virt(TRAMPOLINE_ADDR) as *mut u8,
+                             len);
+    *(copied(&trampoline_cr3) as *mut u64) = cr3 as u64;
+    *(copied(&trampoline_stack) as *mut u64) = stack_end as u64;
+    *(copied(&trampoline_entry) as *mut u64) = entry as u64;
+    *(copied(&trampoline_lock) as *mut u64) = 0;
+}
+
+/// Return the lock that the processors take before using the stack of the
+/// trampoline.
+fn lock() -> &'static AtomicUsize {
+    unsafe { &*(copied(&trampoline_lock) as *const AtomicUsize) }
+}
+
+/// Return true if a processor is using the stack of the trampoline.
+pub fn is_locked() -> bool {
+    lock().load(Ordering::SeqCst) != 0
+}
+
+/// Let the next processor use the stack of the trampoline. This must be
+/// called by the entry function after it stops using the stack.
+pub fn unlock() {
+    lock().store(0, Ordering::SeqCst);
+}
@@ -0,0 +1,134 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use ::interrupt;
+use super::spinlock::{Spinlock, SpinlockGuard};
+
+/// A [Spinlock](Spinlock) that disables the interrupts while it's held, so
+/// the value can be used by interrupt handlers too. Otherwise, an interrupt
+/// handler that waits for the lock taken by the code it interrupts would
+/// wait forever.
+pub struct IrqSpinlock<T> {
+    inner: Spinlock<T>,
+}
+
+/// The guard of an [IrqSpinlock](IrqSpinlock). The lock is released and the
+/// interrupts are enabled again, if they were enabled before, when the
+/// guard is dropped.
+pub struct IrqSpinlockGuard<'a, T: 'a> {
+    guard: ManuallyDrop<SpinlockGuard<'a, T>>,
+    enabled: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    /// Create a new unlocked [IrqSpinlock](IrqSpinlock) that protects
+    /// `value`.
+    pub const fn new(value: T) -> IrqSpinlock<T> {
+        IrqSpinlock {
+            inner: Spinlock::new(value),
+        }
+    }
+
+    /// Disable the interrupts, wait until the lock is released, and take
+    /// it.
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+        let enabled = interrupt::disable();
+        IrqSpinlockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enabled,
+        }
+    }
+
+    /// Take the lock if it's not taken. Return None otherwise.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<T>> {
+        let enabled = interrupt::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard {
+                guard: ManuallyDrop::new(guard),
+                enabled,
+            }),
+            None => {
+                if enabled {
+                    interrupt::enable();
+                }
+                None
+            },
+        }
+    }
+
+    /// Release the lock even if somebody is holding it. This is only used
+    /// when the kernel panics and the holder will never release it.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T> Deref for IrqSpinlockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        // The lock must be released before the interrupts are enabled.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.enabled {
+            interrupt::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_and_unlock() {
+        let lock = IrqSpinlock::new(1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn force_unlock() {
+        let lock = IrqSpinlock::new(());
+        let guard = lock.lock();
+        unsafe {
+            lock.force_unlock();
+        }
+        assert!(lock.try_lock().is_some());
+        drop(guard);
+    }
+}
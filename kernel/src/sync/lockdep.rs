@@ -0,0 +1,536 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Lock dependency validator. When it's enabled, every lock that is taken
+//! is recorded together with the locks that are already held, so that two
+//! locks taken in different orders are reported even if they never
+//! deadlock in practice. The locks taken in interrupt handlers are checked
+//! too. The validator turns itself off after the first report.
+//!
+//! Every lock is its own class, identified by its address, so only the
+//! static locks should be used while the validator is enabled.
+
+use core::fmt::{self, Write};
+#[cfg(not(test))]
+use core::cell::UnsafeCell;
+#[cfg(not(test))]
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+use ::apic::MAX_CPUS;
+#[cfg(not(test))]
+use ::apic;
+use ::backtrace::Site;
+#[cfg(not(test))]
+use ::config::LOCKDEP;
+#[cfg(not(test))]
+use ::interrupt;
+
+/// The maximum number of lock classes. The dependencies of each class are
+/// kept in a 64-bit bitmap.
+const MAX_CLASSES: usize = 64;
+/// The maximum number of dependencies whose sites are recorded.
+const MAX_EDGES: usize = 256;
+/// The maximum number of locks that a processor can hold at a time.
+const MAX_HELD: usize = 16;
+
+#[derive(Copy, Clone)]
+struct Class {
+    addr: usize,
+    // The first place where the lock is taken in an interrupt handler.
+    irq_site: Option<Site>,
+    // The first place where the lock is taken with the interrupts enabled.
+    irqs_enabled_site: Option<Site>,
+}
+
+/// The lock `to` is taken at `site` while the lock `from` is held.
+#[derive(Copy, Clone)]
+struct Edge {
+    from: u8,
+    to: u8,
+    site: Option<Site>,
+}
+
+const NO_CLASS: Class = Class {
+    addr: 0,
+    irq_site: None,
+    irqs_enabled_site: None,
+};
+const NO_EDGE: Edge = Edge {
+    from: 0,
+    to: 0,
+    site: None,
+};
+
+/// The circumstances in which a lock is taken.
+#[derive(Copy, Clone)]
+pub struct Acquisition {
+    /// The APIC ID of the processor that takes the lock.
+    pub cpu: usize,
+    /// True if the processor is running an interrupt handler.
+    pub in_interrupt: bool,
+    /// True if the interrupts are enabled on the processor.
+    pub irqs_enabled: bool,
+    /// True if the lock is taken by `try_lock`, which never waits, so it
+    /// can't deadlock.
+    pub trylock: bool,
+    /// The place where the lock is taken.
+    pub site: Site,
+}
+
+/// The state of the validator.
+pub struct Lockdep {
+    enabled: bool,
+    classes: [Class; MAX_CLASSES],
+    num_classes: usize,
+    // A bitmap of the classes that are taken while each class is held.
+    after: [u64; MAX_CLASSES],
+    edges: [Edge; MAX_EDGES],
+    num_edges: usize,
+    // The stack of the classes held by each processor.
+    held: [[u8; MAX_HELD]; MAX_CPUS],
+    depth: [usize; MAX_CPUS],
+}
+
+impl Lockdep {
+    /// Create a new enabled [Lockdep](Lockdep) that knows no lock.
+    pub const fn new() -> Lockdep {
+        Lockdep {
+            enabled: true,
+            classes: [NO_CLASS; MAX_CLASSES],
+            num_classes: 0,
+            after: [0; MAX_CLASSES],
+            edges: [NO_EDGE; MAX_EDGES],
+            num_edges: 0,
+            held: [[0; MAX_HELD]; MAX_CPUS],
+            depth: [0; MAX_CPUS],
+        }
+    }
+
+    /// Return true if the validator is not turned off yet.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Return the class of the lock at address `addr`, or None if it's not
+    /// known yet.
+    fn find(&self, addr: usize) -> Option<usize> {
+        self.classes[..self.num_classes].iter()
+            .position(|class| class.addr == addr)
+    }
+
+    /// Return the class of the lock at address `addr`. A new class is
+    /// created if the lock is not known yet. Return None if there are too
+    /// many classes.
+    fn class(&mut self, addr: usize) -> Option<usize> {
+        if let Some(class) = self.find(addr) {
+            return Some(class);
+        }
+        if self.num_classes == MAX_CLASSES {
+            return None;
+        }
+        self.classes[self.num_classes] = Class { addr, ..NO_CLASS };
+        self.num_classes += 1;
+        Some(self.num_classes - 1)
+    }
+
+    /// Return the site where the class `to` is first taken while the class
+    /// `from` is held.
+    fn edge_site(&self, from: usize, to: usize) -> Option<Site> {
+        self.edges[..self.num_edges].iter()
+            .find(|edge| usize::from(edge.from) == from
+                         && usize::from(edge.to) == to)
+            .and_then(|edge| edge.site)
+    }
+
+    /// Search the dependencies from the class `from` to the class `to`.
+    /// Return the class that each class on the way is reached from, or None
+    /// if `to` doesn't depend on `from`.
+    fn find_path(&self, from: usize, to: usize)
+        -> Option<[usize; MAX_CLASSES]>
+    {
+        let mut parents = [MAX_CLASSES; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        parents[from] = from;
+        while head < tail && parents[to] == MAX_CLASSES {
+            let class = queue[head];
+            head += 1;
+            let classes = parents.iter_mut().enumerate()
+                .take(self.num_classes);
+            for (next, parent) in classes {
+                if self.after[class] & (1 << next) != 0
+                    && *parent == MAX_CLASSES
+                {
+                    *parent = class;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        if parents[to] == MAX_CLASSES {
+            None
+        } else {
+            Some(parents)
+        }
+    }
+
+    /// Turn the validator off after a report is written to `out`.
+    fn turn_off(&mut self, out: &mut Write) -> fmt::Result {
+        self.enabled = false;
+        writeln!(out, "turning off the lock validator")
+    }
+
+    /// Check the usage of the class `class` in interrupt handlers against
+    /// `how`. Return true if there is a problem, which is written to `out`.
+    fn check_irq_usage(&mut self, class: usize, how: &Acquisition,
+                       out: &mut Write) -> Result<bool, fmt::Error>
+    {
+        let addr = self.classes[class].addr;
+        if how.in_interrupt {
+            if let Some(site) = self.classes[class].irqs_enabled_site {
+                writeln!(out, "lock {:#x} is taken in an interrupt handler \
+                               at {}", addr, how.site)?;
+                writeln!(out, "but it's also taken with the interrupts \
+                               enabled at {}", site)?;
+                return Ok(true);
+            }
+            if self.classes[class].irq_site.is_none() {
+                self.classes[class].irq_site = Some(how.site);
+            }
+        }
+        if how.irqs_enabled {
+            if let Some(site) = self.classes[class].irq_site {
+                writeln!(out, "lock {:#x} is taken with the interrupts \
+                               enabled at {}", addr, how.site)?;
+                writeln!(out, "but it's also taken in an interrupt handler \
+                               at {}", site)?;
+                return Ok(true);
+            }
+            if self.classes[class].irqs_enabled_site.is_none() {
+                self.classes[class].irqs_enabled_site = Some(how.site);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Check the order of the class `class` against the classes that are
+    /// held by the processor, and record the new dependencies. Return true
+    /// if there is a problem, which is written to `out`.
+    fn check_order(&mut self, class: usize, how: &Acquisition,
+                   out: &mut Write) -> Result<bool, fmt::Error>
+    {
+        let addr = self.classes[class].addr;
+        for index in 0..self.depth[how.cpu] {
+            let held = usize::from(self.held[how.cpu][index]);
+            if held == class {
+                writeln!(out, "lock {:#x} is taken at {} while it's already \
+                               held", addr, how.site)?;
+                return Ok(true);
+            }
+            if self.after[held] & (1 << class) != 0 {
+                continue;
+            }
+            if let Some(parents) = self.find_path(class, held) {
+                writeln!(out, "possible deadlock: lock {:#x} is taken at {} \
+                               while lock {:#x} is held", addr, how.site,
+                         self.classes[held].addr)?;
+                writeln!(out, "but they are taken in the other order \
+                               before:")?;
+                // Walk back from the held lock, so the dependencies are
+                // printed from the last one.
+                let mut to = held;
+                while to != class {
+                    let from = parents[to];
+                    let site = self.edge_site(from, to).unwrap_or_default();
+                    writeln!(out, "  lock {:#x} then lock {:#x} at {}",
+                             self.classes[from].addr, self.classes[to].addr,
+                             site)?;
+                    to = from;
+                }
+                return Ok(true);
+            }
+            self.after[held] |= 1 << class;
+            if self.num_edges < MAX_EDGES {
+                self.edges[self.num_edges] = Edge {
+                    from: held as u8,
+                    to: class as u8,
+                    site: Some(how.site),
+                };
+                self.num_edges += 1;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Record that the lock at address `addr` is taken as described by
+    /// `how`, and write the problems found to `out`.
+    pub fn acquire(&mut self, addr: usize, how: &Acquisition,
+                   out: &mut Write) -> fmt::Result
+    {
+        if !self.enabled {
+            return Ok(());
+        }
+        let class = match self.class(addr) {
+            Some(class) => class,
+            None => {
+                writeln!(out, "too many lock classes")?;
+                return self.turn_off(out);
+            },
+        };
+        // The locks taken by try_lock never wait, so they are only pushed
+        // to the stack of the held locks.
+        if !how.trylock && (self.check_irq_usage(class, how, out)?
+                            || self.check_order(class, how, out)?)
+        {
+            return self.turn_off(out);
+        }
+        let depth = self.depth[how.cpu];
+        if depth == MAX_HELD {
+            writeln!(out, "too many locks are held at {}", how.site)?;
+            return self.turn_off(out);
+        }
+        self.held[how.cpu][depth] = class as u8;
+        self.depth[how.cpu] += 1;
+        Ok(())
+    }
+
+    /// Record that the lock at address `addr` is released by the processor
+    /// whose APIC ID is `cpu`.
+    pub fn release(&mut self, cpu: usize, addr: usize) {
+        if !self.enabled {
+            return;
+        }
+        let class = match self.find(addr) {
+            Some(class) => class as u8,
+            None => return,
+        };
+        let depth = self.depth[cpu];
+        // The locks are usually released in the reverse order.
+        if let Some(index) = self.held[cpu][..depth].iter()
+            .rposition(|&held| held == class)
+        {
+            for next in index + 1..depth {
+                self.held[cpu][next - 1] = self.held[cpu][next];
+            }
+            self.depth[cpu] -= 1;
+        }
+    }
+}
+
+/// The validator shared by all the processors. It has its own lock, which
+/// records the processor that holds it, so that the locks taken while a
+/// report is printed are not validated again.
+#[cfg(not(test))]
+struct Global {
+    owner: AtomicUsize,
+    lockdep: UnsafeCell<Lockdep>,
+}
+
+#[cfg(not(test))]
+unsafe impl Sync for Global {}
+
+#[cfg(not(test))]
+static GLOBAL: Global = Global {
+    owner: AtomicUsize::new(0),
+    lockdep: UnsafeCell::new(Lockdep::new()),
+};
+
+/// Writes the reports of the validator on the screen.
+#[cfg(not(test))]
+struct Console;
+
+#[cfg(not(test))]
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        ::debug::write(format_args!("{}", s))
+    }
+}
+
+/// Call `f` with the validator locked. Nothing is done if the processor is
+/// already in the validator.
+#[cfg(not(test))]
+fn with<F>(f: F) where F: FnOnce(&mut Lockdep, &mut Console) -> fmt::Result {
+    let owner = apic::id() + 1;
+    let enabled = interrupt::disable();
+    loop {
+        match GLOBAL.owner.compare_and_swap(0, owner, Ordering::Acquire) {
+            0 => break,
+            current if current == owner => {
+                if enabled {
+                    interrupt::enable();
+                }
+                return;
+            },
+            _ => spin_loop_hint(),
+        }
+    }
+    unsafe {
+        let _ = f(&mut *GLOBAL.lockdep.get(), &mut Console);
+    }
+    GLOBAL.owner.store(0, Ordering::Release);
+    if enabled {
+        interrupt::enable();
+    }
+}
+
+/// Validate the lock at address `addr` that is going to be taken, or is
+/// taken by `try_lock` if `trylock` is true.
+#[cfg(not(test))]
+#[inline(always)]
+pub fn acquire(addr: usize, trylock: bool) {
+    if !LOCKDEP {
+        return;
+    }
+    let how = Acquisition {
+        cpu: apic::id(),
+        in_interrupt: interrupt::in_interrupt(),
+        irqs_enabled: interrupt::are_enabled(),
+        trylock,
+        site: Site::here(),
+    };
+    with(|lockdep, console| lockdep.acquire(addr, &how, console));
+}
+
+/// Record that the lock at address `addr` is released.
+#[cfg(not(test))]
+pub fn release(addr: usize) {
+    if !LOCKDEP {
+        return;
+    }
+    let cpu = apic::id();
+    with(|lockdep, _| {
+        lockdep.release(cpu, addr);
+        Ok(())
+    });
+}
+
+/// The locks are not validated in test, because the tests run in many
+/// threads.
+#[cfg(test)]
+pub fn acquire(_addr: usize, _trylock: bool) {}
+
+#[cfg(test)]
+pub fn release(_addr: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    fn lock(lockdep: &mut Lockdep, addr: usize) -> String {
+        let how = Acquisition {
+            cpu: 0,
+            in_interrupt: false,
+            irqs_enabled: false,
+            trylock: false,
+            site: Site::default(),
+        };
+        let mut out = String::new();
+        lockdep.acquire(addr, &how, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn same_order() {
+        let mut lockdep = Lockdep::new();
+        for _ in 0..2 {
+            assert_eq!(lock(&mut lockdep, 0x10), "");
+            assert_eq!(lock(&mut lockdep, 0x20), "");
+            lockdep.release(0, 0x20);
+            lockdep.release(0, 0x10);
+        }
+        assert!(lockdep.is_enabled());
+    }
+
+    #[test]
+    fn report_abba() {
+        let mut lockdep = Lockdep::new();
+        lock(&mut lockdep, 0x10);
+        lock(&mut lockdep, 0x20);
+        lockdep.release(0, 0x10);
+        lockdep.release(0, 0x20);
+        lock(&mut lockdep, 0x20);
+        let out = lock(&mut lockdep, 0x10);
+        assert!(out.starts_with("possible deadlock: lock 0x10"));
+        assert!(out.contains("lock 0x10 then lock 0x20 at <unknown>"));
+        assert!(!lockdep.is_enabled());
+    }
+
+    #[test]
+    fn report_longer_cycle() {
+        let mut lockdep = Lockdep::new();
+        for &(first, second) in [(0x10, 0x20), (0x20, 0x30)].iter() {
+            lock(&mut lockdep, first);
+            lock(&mut lockdep, second);
+            lockdep.release(0, second);
+            lockdep.release(0, first);
+        }
+        lock(&mut lockdep, 0x30);
+        let out = lock(&mut lockdep, 0x10);
+        assert!(out.contains("lock 0x20 then lock 0x30"));
+        assert!(out.contains("lock 0x10 then lock 0x20"));
+    }
+
+    #[test]
+    fn report_recursion() {
+        let mut lockdep = Lockdep::new();
+        lock(&mut lockdep, 0x10);
+        assert!(lock(&mut lockdep, 0x10).contains("already held"));
+    }
+
+    #[test]
+    fn ignore_trylock() {
+        let mut lockdep = Lockdep::new();
+        lock(&mut lockdep, 0x10);
+        lock(&mut lockdep, 0x20);
+        lockdep.release(0, 0x20);
+        lockdep.release(0, 0x10);
+        lock(&mut lockdep, 0x20);
+        let how = Acquisition {
+            cpu: 0,
+            in_interrupt: false,
+            irqs_enabled: false,
+            trylock: true,
+            site: Site::default(),
+        };
+        let mut out = String::new();
+        lockdep.acquire(0x10, &how, &mut out).unwrap();
+        assert_eq!(out, "");
+        assert!(lockdep.is_enabled());
+    }
+
+    #[test]
+    fn report_irq_unsafe() {
+        let mut lockdep = Lockdep::new();
+        let mut how = Acquisition {
+            cpu: 0,
+            in_interrupt: true,
+            irqs_enabled: false,
+            trylock: false,
+            site: Site::default(),
+        };
+        let mut out = String::new();
+        lockdep.acquire(0x10, &how, &mut out).unwrap();
+        lockdep.release(0, 0x10);
+        assert_eq!(out, "");
+        how.in_interrupt = false;
+        how.irqs_enabled = true;
+        lockdep.acquire(0x10, &how, &mut out).unwrap();
+        assert!(out.contains("interrupts enabled"));
+        assert!(!lockdep.is_enabled());
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Synchronization module. The state shared by the processors and the
+//! interrupt handlers is protected by the locks in this module, and the
+//! state that is initialized only once is kept in a [Once](Once).
+
+mod irq_spinlock;
+mod lockdep;
+mod once;
+mod owned_spinlock;
+mod spinlock;
+
+pub use self::irq_spinlock::*;
+pub use self::once::*;
+pub use self::owned_spinlock::*;
+pub use self::spinlock::*;
@@ -0,0 +1,88 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+/// The states of a [Once](Once).
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+
+/// A cell that is initialized only once, even if many processors try to
+/// initialize it at the same time. The value can't be changed after that,
+/// so it can be read without any lock.
+pub struct Once<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    /// Create a new uninitialized [Once](Once).
+    pub const fn new() -> Once<T> {
+        Once {
+            state: AtomicUsize::new(INCOMPLETE),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Initialize the value with the result of `init` if it's not
+    /// initialized yet, and return it. If another processor is
+    /// initializing the value, wait until it's done.
+    pub fn call_once<F>(&self, init: F) -> &T
+        where F: FnOnce() -> T
+    {
+        let state = self.state.compare_and_swap(INCOMPLETE, RUNNING,
+                                                Ordering::Acquire);
+        if state == INCOMPLETE {
+            unsafe {
+                *self.value.get() = Some(init());
+            }
+            self.state.store(COMPLETE, Ordering::Release);
+        } else {
+            while self.state.load(Ordering::Acquire) != COMPLETE {
+                spin_loop_hint();
+            }
+        }
+        self.get().unwrap()
+    }
+
+    /// Return the value, or None if it's not initialized yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initialize_once() {
+        let once = Once::new();
+        assert_eq!(once.get(), None);
+        assert_eq!(*once.call_once(|| 1), 1);
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+}
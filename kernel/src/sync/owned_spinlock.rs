@@ -0,0 +1,141 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::apic;
+use ::interrupt;
+use super::spinlock::{Spinlock, SpinlockGuard};
+
+/// The owner of an [OwnedSpinlock](OwnedSpinlock) that is not held.
+const NO_OWNER: usize = !0;
+
+/// A [Spinlock](Spinlock) that knows which processor holds it. The code
+/// that may run again on the same processor while the lock is held, e.g.
+/// an interrupt handler or an allocation made while the heap is growing,
+/// can check it instead of waiting for the lock forever.
+pub struct OwnedSpinlock<T> {
+    inner: Spinlock<T>,
+    // The APIC ID of the processor that holds the lock, or NO_OWNER.
+    owner: AtomicUsize,
+}
+
+/// The guard of an [OwnedSpinlock](OwnedSpinlock). The lock is released
+/// when the guard is dropped.
+pub struct OwnedSpinlockGuard<'a, T: 'a> {
+    guard: Option<SpinlockGuard<'a, T>>,
+    owner: &'a AtomicUsize,
+}
+
+impl<T> OwnedSpinlock<T> {
+    /// Create a new unlocked [OwnedSpinlock](OwnedSpinlock) that protects
+    /// `value`.
+    pub const fn new(value: T) -> OwnedSpinlock<T> {
+        OwnedSpinlock {
+            inner: Spinlock::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    /// Wait until the lock is released, and take it. The interrupts are
+    /// disabled while waiting.
+    pub fn lock(&self) -> OwnedSpinlockGuard<T> {
+        // An interrupt handler must not see the lock held without the
+        // owner.
+        let enabled = interrupt::disable();
+        let guard = self.inner.lock();
+        self.owner.store(apic::id(), Ordering::Relaxed);
+        if enabled {
+            interrupt::enable();
+        }
+        OwnedSpinlockGuard {
+            guard: Some(guard),
+            owner: &self.owner,
+        }
+    }
+
+    /// Take the lock if it's not taken. Return None otherwise.
+    pub fn try_lock(&self) -> Option<OwnedSpinlockGuard<T>> {
+        let enabled = interrupt::disable();
+        let guard = self.inner.try_lock();
+        if guard.is_some() {
+            self.owner.store(apic::id(), Ordering::Relaxed);
+        }
+        if enabled {
+            interrupt::enable();
+        }
+        guard.map(|guard| OwnedSpinlockGuard {
+            guard: Some(guard),
+            owner: &self.owner,
+        })
+    }
+
+    /// Return true if the lock is held by the processor that is running
+    /// this code. Only the owner writes its own APIC ID, so the answer is
+    /// exact.
+    pub fn is_held_here(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == apic::id()
+    }
+}
+
+impl<'a, T> Deref for OwnedSpinlockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for OwnedSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for OwnedSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        // The owner is cleared and the lock is released without being
+        // interrupted in between, like in lock.
+        let enabled = interrupt::disable();
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.guard = None;
+        if enabled {
+            interrupt::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_and_unlock() {
+        let lock = OwnedSpinlock::new(1);
+        assert!(!lock.is_held_here());
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.is_held_here());
+            assert!(lock.try_lock().is_none());
+        }
+        assert!(!lock.is_held_here());
+        let guard = lock.try_lock().unwrap();
+        assert_eq!(*guard, 2);
+        assert!(lock.is_held_here());
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+use super::lockdep;
+
+/// A lock that busy-waits until the value is available. The interrupts are
+/// not disabled, so the value must not be used by interrupt handlers unless
+/// they only use [try_lock](Spinlock::try_lock).
+pub struct Spinlock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Spinlock<T> {}
+unsafe impl<T: Send> Sync for Spinlock<T> {}
+
+/// The guard of a [Spinlock](Spinlock). The lock is released when the guard
+/// is dropped.
+pub struct SpinlockGuard<'a, T: 'a> {
+    lock: &'a Spinlock<T>,
+}
+
+impl<T> Spinlock<T> {
+    /// Create a new unlocked [Spinlock](Spinlock) that protects `value`.
+    pub const fn new(value: T) -> Spinlock<T> {
+        Spinlock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Wait until the lock is released, and take it.
+    pub fn lock(&self) -> SpinlockGuard<T> {
+        lockdep::acquire(self.addr(), false);
+        while self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            // Wait without writing, so that the cache line is not bounced
+            // between the processors.
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop_hint();
+            }
+        }
+        SpinlockGuard { lock: self }
+    }
+
+    /// Take the lock if it's not taken. Return None otherwise.
+    pub fn try_lock(&self) -> Option<SpinlockGuard<T>> {
+        if self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            None
+        } else {
+            lockdep::acquire(self.addr(), true);
+            Some(SpinlockGuard { lock: self })
+        }
+    }
+
+    /// Return the address of the lock, which identifies it in the lock
+    /// validator.
+    fn addr(&self) -> usize {
+        self as *const Spinlock<T> as usize
+    }
+
+    /// Return true if the lock is taken.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Release the lock even if somebody is holding it. This is only used
+    /// when the kernel panics and the holder will never release it.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinlockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.addr());
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn lock_and_unlock() {
+        let lock = Spinlock::new(1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.is_locked());
+            assert!(lock.try_lock().is_none());
+        }
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn force_unlock() {
+        let lock = Spinlock::new(());
+        let guard = lock.lock();
+        unsafe {
+            lock.force_unlock();
+        }
+        assert!(lock.try_lock().is_some());
+        drop(guard);
+    }
+
+    #[test]
+    fn count_from_many_threads() {
+        let lock = Arc::new(Spinlock::new(0));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    *lock.lock() += 1;
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), 4000);
+    }
+}
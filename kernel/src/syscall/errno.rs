@@ -0,0 +1,37 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![allow(dead_code)]
+
+//! Error numbers returned by the system calls. These follow Linux.
+
+/// Operation not permitted.
+pub const EPERM: isize = 1;
+/// No such process.
+pub const ESRCH: isize = 3;
+/// Bad file descriptor.
+pub const EBADF: isize = 9;
+/// Out of memory.
+pub const ENOMEM: isize = 12;
+/// Permission denied.
+pub const EACCES: isize = 13;
+/// Bad address.
+pub const EFAULT: isize = 14;
+/// File exists.
+pub const EEXIST: isize = 17;
+/// Invalid argument.
+pub const EINVAL: isize = 22;
+/// Function not implemented.
+pub const ENOSYS: isize = 38;
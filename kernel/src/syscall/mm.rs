@@ -0,0 +1,374 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Memory management system calls.
+
+use ::config::{PAGE_SIZE, USER_SPACE_END};
+use ::process::Process;
+use ::vm::{Backing, Protection, SharedMemory, Vma, VmaFlags};
+use super::errno::*;
+
+const PROT_READ: usize = 0x1;
+const PROT_WRITE: usize = 0x2;
+const PROT_EXEC: usize = 0x4;
+
+const MAP_SHARED: usize = 0x1;
+const MAP_PRIVATE: usize = 0x2;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+const MAP_GROWSDOWN: usize = 0x100;
+const MAP_FIXED_NOREPLACE: usize = 0x10_0000;
+
+const MREMAP_MAYMOVE: usize = 0x1;
+
+/// Parse the protection bits `prot`.
+fn parse_prot(prot: usize) -> Result<Protection, isize> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EINVAL);
+    }
+    Ok(Protection {
+        read: prot & PROT_READ != 0,
+        write: prot & PROT_WRITE != 0,
+        exec: prot & PROT_EXEC != 0,
+    })
+}
+
+/// Round the length `len` up to the next page boundary. Return an error if
+/// the length is zero or too large.
+fn page_len(len: usize) -> Result<usize, isize> {
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    len.checked_add(PAGE_SIZE - 1)
+        .map(|len| len & !(PAGE_SIZE - 1))
+        .ok_or(ENOMEM)
+}
+
+/// Return the range of `len` bytes starting at `addr`, if it's in the user
+/// space.
+fn user_range(addr: usize, len: usize) -> Option<usize> {
+    addr.checked_add(len).filter(|end| *end <= USER_SPACE_END)
+}
+
+/// Map `len` bytes of memory and return the address of the memory. The
+/// memory is anonymous if MAP_ANONYMOUS is set. Otherwise, the memory is
+/// backed by the file `fd` starting at `offset`.
+pub fn mmap(process: &mut Process, addr: usize, len: usize, prot: usize,
+            flags: usize, fd: usize, offset: usize) -> Result<usize, isize>
+{
+    let fixed = flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0;
+    if fixed && addr & (PAGE_SIZE - 1) != 0 || offset & (PAGE_SIZE - 1) != 0 {
+        return Err(EINVAL);
+    }
+    let len = page_len(len)?;
+    let prot = parse_prot(prot)?;
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(EINVAL),
+    };
+
+    let backing = if flags & MAP_ANONYMOUS != 0 {
+        if shared {
+            Backing::Shared {
+                memory: SharedMemory::new(),
+                offset: 0,
+            }
+        } else {
+            Backing::Anonymous
+        }
+    } else {
+        let file = process.file(fd).ok_or(EBADF)?;
+        // The files are read-only, so the writes to a shared mapping can't
+        // be written back. A read-only shared mapping is the same as a
+        // private one, except that it can't be made writable later.
+        if shared && prot.write {
+            return Err(EACCES);
+        }
+        Backing::File {
+            name: file.name,
+            data: file.data,
+            offset,
+        }
+    };
+
+    let space = &mut process.address_space;
+    let start = if fixed {
+        let end = user_range(addr, len).ok_or(ENOMEM)?;
+        if flags & MAP_FIXED_NOREPLACE != 0 {
+            if !space.vmas().is_free(addr..end) {
+                return Err(EEXIST);
+            }
+        } else {
+            space.unmap(addr..end);
+        }
+        addr
+    } else {
+        space.find_free(addr & !(PAGE_SIZE - 1), len).ok_or(ENOMEM)?
+    };
+    space.insert_vma(Vma {
+        start,
+        end: start + len,
+        prot,
+        flags: VmaFlags {
+            grows_down: flags & MAP_GROWSDOWN != 0,
+            deny_write: shared && flags & MAP_ANONYMOUS == 0,
+            ..VmaFlags::default()
+        },
+        backing,
+    }).map_err(|_| ENOMEM)?;
+    Ok(start)
+}
+
+/// Unmap the memory in `len` bytes starting at `addr`. It's not an error if
+/// some of the memory is not mapped.
+pub fn munmap(process: &mut Process, addr: usize, len: usize)
+    -> Result<usize, isize>
+{
+    if addr & (PAGE_SIZE - 1) != 0 {
+        return Err(EINVAL);
+    }
+    let len = page_len(len)?;
+    let end = user_range(addr, len).ok_or(EINVAL)?;
+    process.address_space.unmap(addr..end);
+    Ok(0)
+}
+
+/// Change the protection of the memory in `len` bytes starting at `addr`.
+pub fn mprotect(process: &mut Process, addr: usize, len: usize,
+                prot: usize) -> Result<usize, isize>
+{
+    if addr & (PAGE_SIZE - 1) != 0 {
+        return Err(EINVAL);
+    }
+    let len = page_len(len)?;
+    let prot = parse_prot(prot)?;
+    let end = user_range(addr, len).ok_or(ENOMEM)?;
+    let space = &mut process.address_space;
+    let deny_write = space.vmas().iter()
+        .any(|vma| vma.start < end && addr < vma.end && vma.flags.deny_write);
+    if prot.write && deny_write {
+        return Err(EACCES);
+    }
+    space.protect(addr..end, prot).map_err(|_| ENOMEM)?;
+    Ok(0)
+}
+
+/// Resize the memory in `old_len` bytes starting at `old_addr` to `new_len`
+/// bytes. The memory may be moved if MREMAP_MAYMOVE is set. Return the new
+/// address of the memory.
+pub fn mremap(process: &mut Process, old_addr: usize, old_len: usize,
+              new_len: usize, flags: usize) -> Result<usize, isize>
+{
+    // MREMAP_FIXED is not supported yet.
+    if old_addr & (PAGE_SIZE - 1) != 0 || flags & !MREMAP_MAYMOVE != 0 {
+        return Err(EINVAL);
+    }
+    let old_len = page_len(old_len)?;
+    let new_len = page_len(new_len)?;
+    let old_end = user_range(old_addr, old_len).ok_or(EFAULT)?;
+    let space = &mut process.address_space;
+    match space.vmas().lookup(old_addr) {
+        Some(vma) if old_end <= vma.end => (),
+        _ => return Err(EFAULT),
+    }
+    space.remap(old_addr..old_end, new_len,
+                flags & MREMAP_MAYMOVE != 0).map_err(|_| ENOMEM)
+}
+
+/// Move the program break to `addr` and return the new program break. If
+/// the break can't be moved, the old one is returned. `brk(0)` just returns
+/// the program break. The sbrk function of libc is built on this.
+pub fn brk(process: &mut Process, addr: usize) -> Result<usize, isize> {
+    let limit = process.data_limit;
+    Ok(process.address_space.set_brk(addr, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::paging::PagingContext;
+    use ::process::File;
+    use ::vm::AddressSpace;
+
+    const ANONYMOUS: usize = MAP_PRIVATE | MAP_ANONYMOUS;
+    const RW: usize = PROT_READ | PROT_WRITE;
+
+    fn new_process() -> Process {
+        Process::new(1, AddressSpace::new(PagingContext::new()))
+    }
+
+    #[test]
+    fn map_anonymous_memory() {
+        let mut process = new_process();
+        let addr = mmap(&mut process, 0, 0x1800, RW, ANONYMOUS,
+                        !0, 0).unwrap();
+        {
+            let vma = process.address_space.vmas().lookup(addr).unwrap();
+            assert_eq!(vma.end - vma.start, 0x2000);
+            assert_eq!(vma.backing, Backing::Anonymous);
+            assert!(vma.prot.write && !vma.prot.exec);
+        }
+
+        let addr2 = mmap(&mut process, 0, 0x1000, PROT_READ,
+                         ANONYMOUS | MAP_GROWSDOWN, !0, 0).unwrap();
+        assert_eq!(addr2, addr - 0x1000);
+        let vma = process.address_space.vmas().lookup(addr2).unwrap();
+        assert!(vma.flags.grows_down);
+    }
+
+    #[test]
+    fn map_shared_memory() {
+        let mut process = new_process();
+        let addr = mmap(&mut process, 0, 0x1000, RW,
+                        MAP_SHARED | MAP_ANONYMOUS, !0, 0).unwrap();
+        let vma = process.address_space.vmas().lookup(addr).unwrap();
+        assert!(vma.backing.is_shared());
+    }
+
+    #[test]
+    fn map_file() {
+        let mut process = new_process();
+        let fd = process.open(File {
+            name: "/bin/init",
+            data: b"hello",
+        });
+        assert_eq!(mmap(&mut process, 0, 0x1000, PROT_READ,
+                        MAP_PRIVATE, fd + 1, 0), Err(EBADF));
+        assert_eq!(mmap(&mut process, 0, 0x1000, RW,
+                        MAP_SHARED, fd, 0), Err(EACCES));
+        assert_eq!(mmap(&mut process, 0, 0x1000, PROT_READ,
+                        MAP_PRIVATE, fd, 0x10), Err(EINVAL));
+
+        let addr = mmap(&mut process, 0, 0x1000, PROT_READ,
+                        MAP_PRIVATE, fd, 0x1000).unwrap();
+        let vma = process.address_space.vmas().lookup(addr).unwrap();
+        assert_eq!(format!("{}", vma).split(' ').nth(5), Some("/bin/init"));
+    }
+
+    #[test]
+    fn map_invalid_arguments() {
+        let mut process = new_process();
+        assert_eq!(mmap(&mut process, 0, 0, RW, ANONYMOUS, !0, 0),
+                   Err(EINVAL));
+        assert_eq!(mmap(&mut process, 0, 0x1000, 0x8, ANONYMOUS,
+                        !0, 0), Err(EINVAL));
+        assert_eq!(mmap(&mut process, 0, 0x1000, RW,
+                        MAP_ANONYMOUS, !0, 0), Err(EINVAL));
+        assert_eq!(mmap(&mut process, 0x1_0008, 0x1000, RW,
+                        ANONYMOUS | MAP_FIXED, !0, 0), Err(EINVAL));
+        assert_eq!(mmap(&mut process, USER_SPACE_END, 0x1000, RW,
+                        ANONYMOUS | MAP_FIXED, !0, 0), Err(ENOMEM));
+        assert_eq!(mmap(&mut process, 0, !0, RW, ANONYMOUS, !0, 0),
+                   Err(ENOMEM));
+    }
+
+    #[test]
+    fn map_fixed_address() {
+        let mut process = new_process();
+        let addr = 0x40_0000;
+        assert_eq!(mmap(&mut process, addr, 0x2000, RW,
+                        ANONYMOUS | MAP_FIXED_NOREPLACE, !0, 0), Ok(addr));
+        assert_eq!(mmap(&mut process, addr + 0x1000, 0x2000, RW,
+                        ANONYMOUS | MAP_FIXED_NOREPLACE, !0, 0), Err(EEXIST));
+
+        // MAP_FIXED replaces the existing memory.
+        process.address_space.protect(addr..addr + 0x2000,
+                                      parse_prot(PROT_READ).unwrap()).unwrap();
+        assert_eq!(mmap(&mut process, addr + 0x1000, 0x2000, RW,
+                        ANONYMOUS | MAP_FIXED, !0, 0), Ok(addr + 0x1000));
+        let vmas = process.address_space.vmas();
+        assert_eq!(vmas.protection(addr), parse_prot(PROT_READ).ok());
+        assert_eq!(vmas.protection(addr + 0x1000), parse_prot(RW).ok());
+        assert_eq!(vmas.lookup(addr + 0x1000).unwrap().end, addr + 0x3000);
+    }
+
+    #[test]
+    fn unmap_memory() {
+        let mut process = new_process();
+        let addr = mmap(&mut process, 0, 0x3000, RW, ANONYMOUS,
+                        !0, 0).unwrap();
+        assert_eq!(munmap(&mut process, addr + 8, 0x1000), Err(EINVAL));
+        assert_eq!(munmap(&mut process, addr, 0), Err(EINVAL));
+        assert_eq!(munmap(&mut process, addr + 0x1000, 0x1000), Ok(0));
+        let vmas = process.address_space.vmas();
+        assert!(vmas.lookup(addr).is_some());
+        assert!(vmas.lookup(addr + 0x1000).is_none());
+        assert!(vmas.lookup(addr + 0x2000).is_some());
+    }
+
+    #[test]
+    fn protect_memory() {
+        let mut process = new_process();
+        let addr = mmap(&mut process, 0, 0x2000, RW, ANONYMOUS,
+                        !0, 0).unwrap();
+        assert_eq!(mprotect(&mut process, addr, 0x1000, PROT_EXEC), Ok(0));
+        assert!(process.address_space.vmas().protection(addr).unwrap().exec);
+        assert_eq!(mprotect(&mut process, addr, 0x3000, PROT_READ),
+                   Err(ENOMEM));
+        assert_eq!(mprotect(&mut process, addr, 0x1000, 0x10), Err(EINVAL));
+    }
+
+    #[test]
+    fn protect_shared_file() {
+        let mut process = new_process();
+        let fd = process.open(File {
+            name: "/bin/init",
+            data: b"hello",
+        });
+        let addr = mmap(&mut process, 0, 0x1000, PROT_READ,
+                        MAP_SHARED, fd, 0).unwrap();
+        assert_eq!(mprotect(&mut process, addr, 0x1000, RW), Err(EACCES));
+        assert_eq!(mprotect(&mut process, addr, 0x1000, PROT_EXEC), Ok(0));
+
+        // A private mapping of the file can be written.
+        let addr = mmap(&mut process, 0, 0x1000, PROT_READ,
+                        MAP_PRIVATE, fd, 0).unwrap();
+        assert_eq!(mprotect(&mut process, addr, 0x1000, RW), Ok(0));
+    }
+
+    #[test]
+    fn move_program_break() {
+        let mut process = new_process();
+        process.address_space.init_brk(0x40_0000);
+        process.data_limit = 0x2000;
+        assert_eq!(brk(&mut process, 0), Ok(0x40_0000));
+        assert_eq!(brk(&mut process, 0x40_1000), Ok(0x40_1000));
+        assert_eq!(brk(&mut process, 0x40_3000), Ok(0x40_1000));
+        assert!(process.address_space.vmas().lookup(0x40_0000).is_some());
+        assert_eq!(brk(&mut process, 0x40_0000), Ok(0x40_0000));
+        assert!(process.address_space.vmas().lookup(0x40_0000).is_none());
+    }
+
+    #[test]
+    fn remap_memory() {
+        let mut process = new_process();
+        let addr = mmap(&mut process, 0, 0x1000, RW, ANONYMOUS,
+                        !0, 0).unwrap();
+        mmap(&mut process, addr + 0x2000, 0x1000, RW,
+             ANONYMOUS | MAP_FIXED_NOREPLACE, !0, 0).unwrap();
+        assert_eq!(mremap(&mut process, addr, 0x1000, 0x2000, 0), Ok(addr));
+        assert_eq!(mremap(&mut process, addr, 0x2000, 0x3000, 0), Err(ENOMEM));
+        let new_addr = mremap(&mut process, addr, 0x2000, 0x3000,
+                              MREMAP_MAYMOVE).unwrap();
+        assert_ne!(new_addr, addr);
+        assert!(process.address_space.vmas().lookup(addr).is_none());
+        assert_eq!(mremap(&mut process, addr, 0x1000, 0x2000,
+                          MREMAP_MAYMOVE), Err(EFAULT));
+        assert_eq!(mremap(&mut process, new_addr, 0x1000, 0x2000,
+                          0x2), Err(EINVAL));
+    }
+}
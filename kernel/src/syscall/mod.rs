@@ -16,11 +16,122 @@
 
 //! System call module. This module includes all system call routines.
 
-// The parameter that it needs here should be ExceptionStackFrame in crate
-// x86_64, but we don't want to add another depency crate. So we decided to
-// put a dummy parameter here.
+mod errno;
+mod mm;
+mod syslog;
+
+#[cfg(not(test))]
+use ::frame;
+#[cfg(not(test))]
+use ::process::{self, Process};
+#[cfg(not(test))]
+use self::errno::*;
+
+/// System call numbers. These follow x86-64 Linux.
+#[cfg(not(test))]
+const SYS_MMAP: u64 = 9;
+#[cfg(not(test))]
+const SYS_MPROTECT: u64 = 10;
+#[cfg(not(test))]
+const SYS_MUNMAP: u64 = 11;
+#[cfg(not(test))]
+const SYS_BRK: u64 = 12;
+#[cfg(not(test))]
+const SYS_MREMAP: u64 = 25;
+#[cfg(not(test))]
+const SYS_SYSLOG: u64 = 103;
+
+/// The registers saved by [syscall_entry](syscall_entry). The system call
+/// number is in rax and the arguments are in rdi, rsi, rdx, r10, r8 and r9
+/// like the syscall instruction in Linux. The result is returned in rax.
+#[cfg(not(test))]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rcx: u64,
+    pub r11: u64,
+}
+
+// The interrupt handler for system calls. The processor aligns the stack
+// to 16 bytes and pushes 5 quadwords before jumping here, so the stack is
+// aligned again after we push 9 quadwords.
+#[cfg(not(test))]
+global_asm!("
+    .global syscall_entry
+    syscall_entry:
+        push %r11
+        push %rcx
+        push %r9
+        push %r8
+        push %r10
+        push %rdx
+        push %rsi
+        push %rdi
+        push %rax
+        mov %rsp, %rdi
+        cld
+        call syscall_handler
+        pop %rax
+        pop %rdi
+        pop %rsi
+        pop %rdx
+        pop %r10
+        pop %r8
+        pop %r9
+        pop %rcx
+        pop %r11
+        iretq
+");
+
+#[cfg(not(test))]
+extern "C" {
+    pub fn syscall_entry();
+}
+
+/// Handle the system call whose registers are saved in `regs`.
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn syscall_handler(regs: &mut SyscallFrame) {
+    let result = match process::current() {
+        Some(process) => dispatch(process, regs),
+        None => Err(ESRCH),
+    };
+    regs.rax = match result {
+        Ok(value) => value as u64,
+        Err(errno) => (-errno) as u64,
+    };
+}
+
+/// Call the system call routine of the system call in `regs`.
 #[cfg(not(test))]
-pub extern "x86-interrupt"
-fn interrupt_handler(_: &mut ()) {
-    println!("Interrupted");
+fn dispatch(process: &mut Process, regs: &SyscallFrame)
+    -> Result<usize, isize>
+{
+    let args = [regs.rdi as usize, regs.rsi as usize, regs.rdx as usize,
+                regs.r10 as usize, regs.r8 as usize, regs.r9 as usize];
+    let result = match regs.rax {
+        SYS_MMAP => mm::mmap(process, args[0], args[1], args[2], args[3],
+                             args[4], args[5]),
+        SYS_MPROTECT => mm::mprotect(process, args[0], args[1], args[2]),
+        SYS_MUNMAP => mm::munmap(process, args[0], args[1]),
+        SYS_BRK => mm::brk(process, args[0]),
+        SYS_MREMAP => mm::mremap(process, args[0], args[1], args[2],
+                                 args[3]),
+        // The frames are only allocated when the user pages are faulted
+        // in, which can be done again, so the system call can be called
+        // again after it runs out of frames.
+        SYS_SYSLOG => frame::with_allocator(|frames| {
+            syslog::syslog(process, frames, args[0], args[1], args[2])
+        }),
+        _ => return Err(ENOSYS),
+    };
+    // The unmapped frames are freed after the flush.
+    process.address_space.flush_tlb_and_free();
+    result
 }
@@ -0,0 +1,140 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The system call that reads the kernel log and changes its filter.
+
+use alloc::vec::Vec;
+use core::{cmp, str};
+use ::frame::FrameAllocator;
+use ::log;
+use ::process::Process;
+use super::errno::*;
+
+/// Read the latest messages in the log buffer. The messages are not removed
+/// from the buffer.
+const SYSLOG_ACTION_READ_ALL: usize = 3;
+/// Return the size of the log buffer.
+const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+/// Replace the log filter with the one in the `len` bytes at `buf`, like
+/// `warn,smp=debug`. This action is only in Kelner.
+const SYSLOG_ACTION_SET_FILTER: usize = 0x100;
+
+/// The maximum length of a log filter.
+const MAX_FILTER_LEN: usize = 0x400;
+
+/// Do the action `action` on the kernel log. The messages are copied to
+/// `len` bytes of the memory at `buf`, if the action reads them. The other
+/// actions of Linux are not supported.
+pub fn syslog(process: &mut Process, frames: &mut FrameAllocator,
+              action: usize, buf: usize, len: usize) -> Result<usize, isize>
+{
+    match action {
+        SYSLOG_ACTION_READ_ALL => {
+            let mut data = Vec::new();
+            data.resize(cmp::min(len, log::BUFFER_SIZE), 0);
+            let count = log::read_last(&mut data);
+            process.address_space.write(frames, buf, &data[..count])
+                .map_err(|_| EFAULT)?;
+            Ok(count)
+        },
+        SYSLOG_ACTION_SIZE_BUFFER => Ok(log::BUFFER_SIZE),
+        SYSLOG_ACTION_SET_FILTER => {
+            if len > MAX_FILTER_LEN {
+                return Err(EINVAL);
+            }
+            let mut spec = Vec::new();
+            spec.resize(len, 0);
+            process.address_space.read(frames, buf, &mut spec)
+                .map_err(|_| EFAULT)?;
+            let spec = str::from_utf8(&spec).map_err(|_| EINVAL)?;
+            log::set_filter(spec).map_err(|_| EINVAL)?;
+            Ok(0)
+        },
+        _ => Err(EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::frame::new_for_test;
+    use ::log::Level;
+    use ::paging::PagingContext;
+    use ::vm::{AddressSpace, Protection, Vma};
+
+    #[test]
+    fn read_log() {
+        let mut frames = new_for_test(1);
+        let mut process = Process::new(1, AddressSpace::new(
+            PagingContext::new()));
+        let rw = Protection {
+            read: true,
+            write: true,
+            exec: false,
+        };
+        process.address_space.insert_vma(Vma::anonymous(0x2000, 0x3000, rw))
+            .unwrap();
+        log::log(Level::Error, "kernel::syscall", format_args!("hello"));
+
+        assert_eq!(syslog(&mut process, &mut frames,
+                          SYSLOG_ACTION_SIZE_BUFFER, 0, 0),
+                   Ok(log::BUFFER_SIZE));
+        let count = syslog(&mut process, &mut frames, SYSLOG_ACTION_READ_ALL,
+                           0x2000, 0x1000).unwrap();
+        assert!(count > 0 && count <= log::BUFFER_SIZE);
+        let (frame, _) = process.address_space.paging().find(0x2000)
+            .unwrap();
+        let data = unsafe {
+            ::core::slice::from_raw_parts(frame as *const u8, count)
+        };
+        assert!(data.ends_with(b"hello\n"));
+
+        assert_eq!(syslog(&mut process, &mut frames, SYSLOG_ACTION_READ_ALL,
+                          0x3000, 0x1000), Err(EFAULT));
+        assert_eq!(syslog(&mut process, &mut frames, 0, 0, 0), Err(EINVAL));
+    }
+
+    #[test]
+    fn set_filter() {
+        let mut frames = new_for_test(1);
+        let mut process = Process::new(1, AddressSpace::new(
+            PagingContext::new()));
+        let rw = Protection {
+            read: true,
+            write: true,
+            exec: false,
+        };
+        process.address_space.insert_vma(Vma::anonymous(0x2000, 0x3000, rw))
+            .unwrap();
+        // The other tests log errors, which this filter still allows.
+        let spec = b"info,syscall::syslog=debug";
+        process.address_space.write(&mut frames, 0x2000, spec).unwrap();
+        assert_eq!(syslog(&mut process, &mut frames, SYSLOG_ACTION_SET_FILTER,
+                          0x2000, spec.len()), Ok(0));
+
+        process.address_space.write(&mut frames, 0x2000, b"loud").unwrap();
+        assert_eq!(syslog(&mut process, &mut frames, SYSLOG_ACTION_SET_FILTER,
+                          0x2000, 4), Err(EINVAL));
+        assert_eq!(syslog(&mut process, &mut frames, SYSLOG_ACTION_SET_FILTER,
+                          0x2ffe, 4), Err(EFAULT));
+        assert_eq!(syslog(&mut process, &mut frames, SYSLOG_ACTION_SET_FILTER,
+                          0x2000, MAX_FILTER_LEN + 1), Err(EINVAL));
+
+        // The logger is shared by all the tests, so the filter that they
+        // start with is restored.
+        log::set_filter("info").unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Monotonic clock. The clock counts the time since it's initialized using
+//! the time stamp counter of the processor, whose frequency is measured
+//! with the PIT.
+
+use core::time::Duration;
+#[cfg(not(test))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(test))]
+use ::pit;
+
+/// The number of microseconds that the PIT counts while the frequency of
+/// the time stamp counter is measured.
+#[cfg(not(test))]
+const CALIBRATION_MICROS: u64 = 10_000;
+
+/// The value of the time stamp counter when the clock is initialized.
+#[cfg(not(test))]
+static START: AtomicUsize = AtomicUsize::new(0);
+/// The frequency of the time stamp counter in Hz. It's zero until the clock
+/// is initialized.
+#[cfg(not(test))]
+static FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+
+/// Return the value of the time stamp counter of the processor.
+#[cfg(not(test))]
+fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile");
+    }
+    u64::from(high) << 32 | u64::from(low)
+}
+
+/// Return the duration of `ticks` ticks of a counter whose frequency is
+/// `frequency` Hz.
+#[cfg_attr(test, allow(dead_code))]
+fn ticks_to_duration(ticks: u64, frequency: u64) -> Duration {
+    let secs = ticks / frequency;
+    let rest = u128::from(ticks % frequency);
+    let nanos = rest * 1_000_000_000 / u128::from(frequency);
+    Duration::new(secs, nanos as u32)
+}
+
+/// Return the time since the clock is initialized. It's zero before that.
+#[cfg(not(test))]
+pub fn now() -> Duration {
+    let frequency = FREQUENCY.load(Ordering::Relaxed) as u64;
+    if frequency == 0 {
+        return Duration::from_secs(0);
+    }
+    let start = START.load(Ordering::Relaxed) as u64;
+    ticks_to_duration(rdtsc().saturating_sub(start), frequency)
+}
+
+/// There is no clock in test.
+#[cfg(test)]
+pub fn now() -> Duration {
+    Duration::from_secs(0)
+}
+
+/// Initialization function for time module. This takes a few milliseconds
+/// to measure the frequency of the time stamp counter.
+#[cfg(not(test))]
+pub fn init() {
+    let start = rdtsc();
+    pit::delay(CALIBRATION_MICROS);
+    let ticks = rdtsc() - start;
+    let frequency = ticks * 1_000_000 / CALIBRATION_MICROS;
+    START.store(start as usize, Ordering::Relaxed);
+    FREQUENCY.store(frequency as usize, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_ticks() {
+        assert_eq!(ticks_to_duration(0, 1000), Duration::from_secs(0));
+        assert_eq!(ticks_to_duration(1500, 1000), Duration::from_millis(1500));
+        assert_eq!(ticks_to_duration(3, 3_000_000_000),
+                   Duration::from_nanos(1));
+        // The ticks of many years don't overflow.
+        let frequency = 4_000_000_000;
+        let ticks = frequency * 3600 * 24 * 365 * 100 + frequency / 4;
+        assert_eq!(ticks_to_duration(ticks, frequency),
+                   Duration::from_secs(3600 * 24 * 365 * 100) +
+                   Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Terminal module. There is only one terminal for now, the console, which
+//! shows its output on the screen and the serial port, and gets its input
+//! from the keyboard.
+//!
+//! The input is edited a line at a time, like in the canonical mode of the
+//! Unix terminals. The typed characters are echoed, Backspace erases the
+//! last one, Ctrl+U erases the whole line, and the line can only be read
+//! after Enter is pressed. The other control characters and the escape
+//! sequences, like the ones of the cursor keys, are dropped.
+
+use ::collections::RingBuffer;
+#[cfg(not(test))]
+use ::debug;
+#[cfg(not(test))]
+use ::sync::IrqSpinlock;
+
+/// The maximum length of a line, including the line feed.
+const LINE_SIZE: usize = 0x100;
+
+/// The control characters that edit the line.
+const ERASE: u8 = 0x7f;
+const BACKSPACE: u8 = 0x08;
+const KILL: u8 = 0x15;
+const ESCAPE: u8 = 0x1b;
+
+/// What is echoed to erase a character on the screen.
+const ERASE_ECHO: &[u8] = b"\x08 \x08";
+
+/// How far the terminal is in an escape sequence that it drops.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Escape {
+    None,
+    // After the escape, which is followed by one more byte.
+    Started,
+    // After the escape and the bracket, which are followed by the
+    // parameters and a final byte from @ to ~.
+    Csi,
+}
+
+/// A terminal with its line editor.
+struct Tty {
+    // The line that is being edited.
+    line: [u8; LINE_SIZE],
+    line_len: usize,
+    escape: Escape,
+    // The finished lines that are not read yet.
+    input: RingBuffer,
+}
+
+impl Tty {
+    /// Create a [Tty](Tty) with nothing typed.
+    const fn new() -> Tty {
+        Tty {
+            line: [0; LINE_SIZE],
+            line_len: 0,
+            escape: Escape::None,
+            input: RingBuffer::new(),
+        }
+    }
+
+    /// Erase the last character of the line. Return false if the line is
+    /// empty. The screen draws each character in one cell, so erasing it
+    /// always echoes one [ERASE_ECHO](ERASE_ECHO).
+    fn erase(&mut self) -> bool {
+        if self.line_len == 0 {
+            return false;
+        }
+        // A character may take more than one byte in UTF-8.
+        self.line_len -= 1;
+        while self.line_len > 0 && self.line[self.line_len] & 0xc0 == 0x80 {
+            self.line_len -= 1;
+        }
+        true
+    }
+
+    /// Handle the bytes typed on the keyboard, and pass the bytes to be
+    /// echoed to `echo`.
+    fn receive<F: FnMut(&[u8])>(&mut self, bytes: &[u8], mut echo: F) {
+        for &byte in bytes {
+            match self.escape {
+                Escape::None => {},
+                Escape::Started if byte == b'[' => {
+                    self.escape = Escape::Csi;
+                    continue;
+                },
+                Escape::Csi if byte < 0x40 || byte > 0x7e => continue,
+                _ => {
+                    self.escape = Escape::None;
+                    continue;
+                },
+            }
+            match byte {
+                ESCAPE => self.escape = Escape::Started,
+                ERASE | BACKSPACE => {
+                    if self.erase() {
+                        echo(ERASE_ECHO);
+                    }
+                },
+                KILL => {
+                    while self.erase() {
+                        echo(ERASE_ECHO);
+                    }
+                },
+                b'\n' => {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                    // The bytes that don't fit in the unread input are
+                    // dropped.
+                    for &byte in self.line[..self.line_len].iter() {
+                        if self.input.push(byte).is_err() {
+                            break;
+                        }
+                    }
+                    self.line_len = 0;
+                    echo(b"\n");
+                },
+                // There is always room for the line feed.
+                _ if byte < 0x20 || self.line_len == LINE_SIZE - 1 => {},
+                _ => {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                    echo(&[byte]);
+                },
+            }
+        }
+    }
+
+    /// Move the finished lines to `buf`, as many bytes as it can hold.
+    /// Return the number of the moved bytes.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < buf.len() {
+            match self.input.pop() {
+                Ok(byte) => buf[len] = byte,
+                Err(_) => break,
+            }
+            len += 1;
+        }
+        len
+    }
+}
+
+/// The console, which is the terminal that gets the input from the
+/// keyboard.
+#[cfg(not(test))]
+static CONSOLE: IrqSpinlock<Tty> = IrqSpinlock::new(Tty::new());
+
+/// Send the bytes typed on the keyboard to the console.
+#[cfg(not(test))]
+pub fn input(bytes: &[u8]) {
+    CONSOLE.lock().receive(bytes, debug::write_bytes);
+}
+
+/// Move the lines typed on the console to `buf`, as many bytes as it can
+/// hold. Return the number of the moved bytes.
+#[cfg(not(test))]
+#[allow(dead_code)]
+pub fn read(buf: &mut [u8]) -> usize {
+    CONSOLE.lock().read(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Type `bytes` on `tty` and return the echo.
+    fn type_bytes(tty: &mut Tty, bytes: &[u8]) -> Vec<u8> {
+        let mut echo = Vec::new();
+        tty.receive(bytes, |bytes| echo.extend_from_slice(bytes));
+        echo
+    }
+
+    #[test]
+    fn read_finished_lines() {
+        let mut tty = Tty::new();
+        let mut buf = [0; 4];
+        assert_eq!(type_bytes(&mut tty, b"ab"), b"ab");
+        assert_eq!(tty.read(&mut buf), 0);
+        assert_eq!(type_bytes(&mut tty, b"\x1b[3~\x1bx\x03c\n"), b"c\n");
+        assert_eq!(tty.read(&mut buf[..2]), 2);
+        assert_eq!(tty.read(&mut buf[2..]), 2);
+        assert_eq!(&buf, b"abc\n");
+    }
+
+    #[test]
+    fn edit_line() {
+        let mut tty = Tty::new();
+        let mut buf = [0; 6];
+        let echo = type_bytes(&mut tty, "x\x7fä\x7f\x7fab\x15d\n".as_bytes());
+        assert_eq!(echo, "x\x08 \x08ä\x08 \x08ab\x08 \x08\x08 \x08d\n"
+                   .as_bytes());
+        assert_eq!(tty.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"d\n");
+    }
+
+    #[test]
+    fn full_line() {
+        let mut tty = Tty::new();
+        let mut buf = [0; 6];
+        for _ in 0..LINE_SIZE {
+            type_bytes(&mut tty, b"a");
+        }
+        assert!(type_bytes(&mut tty, b"b").is_empty());
+        type_bytes(&mut tty, b"\n");
+        // The unread input only holds a few bytes in the tests.
+        assert_eq!(tty.read(&mut buf), 6);
+        assert_eq!(&buf, b"aaaaaa");
+    }
+}
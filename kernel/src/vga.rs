@@ -0,0 +1,675 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! VGA text mode module. This module writes characters to the text buffer
+//! of the VGA, which is what the screen shows.
+//!
+//! The screen understands the common ANSI escape sequences, so that the
+//! same output can be sent to a terminal too. The supported sequences are
+//! the ones that set the colors, move the cursor, and clear the screen.
+//!
+//! The text is decoded as UTF-8, and each character is drawn in one cell
+//! with its glyph in the code page 437, which is the font of the VGA. The
+//! characters without a glyph are drawn as '?'.
+
+use core::char;
+use core::cmp;
+use core::ptr;
+
+#[cfg(not(test))]
+use ::paging::phy_to_virt;
+#[cfg(not(test))]
+use ::port::outb;
+
+/// The physical address of the text buffer.
+#[cfg(not(test))]
+const BUFFER_ADDR: usize = 0xb8000;
+/// The maximum number of columns on the screen.
+const NUM_COLUMNS: usize = 80;
+/// The maximum number of lines on the screen.
+const NUM_LINES: usize = 25;
+/// The distance between two tab stops.
+const TAB_SIZE: usize = 8;
+/// The maximum number of parameters of an escape sequence. The others are
+/// ignored.
+const MAX_PARAMS: usize = 8;
+
+/// The I/O ports of the CRT controller, which draws the cursor. The index
+/// of a register is written to the first port, and then its value can be
+/// written to the second one.
+#[cfg(not(test))]
+const CRTC_INDEX: u16 = 0x3d4;
+#[cfg(not(test))]
+const CRTC_DATA: u16 = 0x3d5;
+/// The registers of the CRT controller that hold the cursor position.
+#[cfg(not(test))]
+const CURSOR_HIGH: u8 = 0x0e;
+#[cfg(not(test))]
+const CURSOR_LOW: u8 = 0x0f;
+
+/// The colors that the text can have. The values are the ones used by the
+/// VGA. Not all of them are used.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Color {
+    Black, Blue, Green, Cyan, Red, Purple, Brown, Gray,
+    DarkGray, LightBlue, LightGreen, LightCyan, LightRed, LightPurple,
+    Yellow, White
+}
+
+/// All the colors, ordered by their values.
+const COLORS: [Color; 16] = [
+    Color::Black, Color::Blue, Color::Green, Color::Cyan,
+    Color::Red, Color::Purple, Color::Brown, Color::Gray,
+    Color::DarkGray, Color::LightBlue, Color::LightGreen, Color::LightCyan,
+    Color::LightRed, Color::LightPurple, Color::Yellow, Color::White,
+];
+
+/// The bit of the value of a color that makes it bright.
+const BRIGHT: u8 = 0b1000;
+
+pub const DEFAULT_COLOR: Color = Color::LightCyan;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+
+/// The VGA puts blue in the lowest bit and red in the third bit, while ANSI
+/// puts them the other way around. This converts the three bits between
+/// them.
+fn swap_red_blue(bits: u8) -> u8 {
+    (bits & 0b010) | (bits & 0b001) << 2 | (bits & 0b100) >> 2
+}
+
+impl Color {
+    /// Return the color whose value is `value`, ignoring the bits that are
+    /// not used.
+    fn from_value(value: u8) -> Color {
+        COLORS[(value & 0xf) as usize]
+    }
+
+    /// Return the color whose number in the ANSI escape sequences is
+    /// `ansi`, from 0 to 7. The bright version is returned if `bright` is
+    /// true.
+    fn from_ansi(ansi: u8, bright: bool) -> Color {
+        let value = swap_red_blue(ansi & 0b111);
+        if bright {
+            Color::from_value(value | BRIGHT)
+        } else {
+            Color::from_value(value)
+        }
+    }
+
+    /// Return the parameter of the ANSI escape sequence that sets the
+    /// foreground color of a terminal to this color.
+    pub fn ansi_code(self) -> u8 {
+        let value = self as u8;
+        let ansi = swap_red_blue(value & 0b111);
+        if value & BRIGHT == 0 {
+            30 + ansi
+        } else {
+            90 + ansi
+        }
+    }
+}
+
+/// The glyphs of the code page 437 from 0x80 to 0xff.
+const CP437: &str = "\
+    ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒ\
+    áíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+    └┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// Return the glyph that draws `c`, or '?' if there is none.
+fn to_cp437(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        // The glyphs below 0x20 are not reachable, because the bytes are
+        // control characters, except these two.
+        '¶' => 0x14,
+        '§' => 0x15,
+        _ => CP437.chars().position(|glyph| glyph == c)
+            .map_or(b'?', |index| 0x80 + index as u8),
+    }
+}
+
+/// The states of the parser of the escape sequences.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    // The bytes are printed.
+    Normal,
+    // An escape character is received.
+    Escape,
+    // A control sequence introducer, which is an escape character followed
+    // by '[', is received. The parameters come next.
+    Csi,
+}
+
+/// The structure containing details of the screen.
+pub struct Screen {
+    // The address of the text buffer.
+    buffer: usize,
+    x: usize,
+    y: usize,
+    // The cursor position saved by the escape sequence.
+    saved: (usize, usize),
+    foreground: Color,
+    background: Color,
+    state: State,
+    // The parameters of the escape sequence being parsed.
+    params: [usize; MAX_PARAMS],
+    num_params: usize,
+    // The bits of the UTF-8 character being decoded, and the number of its
+    // bytes that are not received yet.
+    utf8: u32,
+    utf8_left: usize,
+}
+
+impl Screen {
+    /// Create a [Screen](Screen) whose cursor is at the top-left corner.
+    #[cfg(not(test))]
+    pub const fn new() -> Screen {
+        Screen::with_buffer(phy_to_virt(BUFFER_ADDR))
+    }
+
+    /// Create a [Screen](Screen) that writes to the text buffer at
+    /// `buffer`.
+    const fn with_buffer(buffer: usize) -> Screen {
+        Screen {
+            buffer,
+            x: 0,
+            y: 0,
+            saved: (0, 0),
+            foreground: DEFAULT_COLOR,
+            background: DEFAULT_BACKGROUND,
+            state: State::Normal,
+            params: [0; MAX_PARAMS],
+            num_params: 0,
+            utf8: 0,
+            utf8_left: 0,
+        }
+    }
+
+    /// Return the character with its attribute, which is what stored in the
+    /// text buffer.
+    fn cell(&self, byte: u8) -> u16 {
+        let attribute = (self.background as u8) << 4 | self.foreground as u8;
+        u16::from(attribute) << 8 | u16::from(byte)
+    }
+
+    fn read(&self, position: usize) -> u16 {
+        let buffer = self.buffer as *const u16;
+        unsafe { ptr::read_volatile(buffer.add(position)) }
+    }
+
+    fn write(&self, position: usize, cell: u16) {
+        let buffer = self.buffer as *mut u16;
+        unsafe { ptr::write_volatile(buffer.add(position), cell) }
+    }
+
+    /// Clear the characters from position `start` to position `end`,
+    /// excluding `end`. They take the current background color.
+    fn clear(&self, start: usize, end: usize) {
+        let blank = self.cell(b' ');
+        for position in start..end {
+            self.write(position, blank);
+        }
+    }
+
+    /// Move all the lines up by one. The first line is discarded.
+    fn scroll(&self) {
+        for position in NUM_COLUMNS..NUM_COLUMNS * NUM_LINES {
+            let cell = self.read(position);
+            self.write(position - NUM_COLUMNS, cell);
+        }
+        self.clear(NUM_COLUMNS * (NUM_LINES - 1), NUM_COLUMNS * NUM_LINES);
+    }
+
+    /// Move the cursor to the beginning of the next line, scrolling the
+    /// screen if it's at the last line.
+    fn newline(&mut self) {
+        self.x = 0;
+        if self.y == NUM_LINES - 1 {
+            self.scroll();
+        } else {
+            self.y += 1;
+        }
+    }
+
+    /// Print one character at the cursor.
+    fn print(&mut self, byte: u8) {
+        let cell = self.cell(byte);
+        self.write(self.y * NUM_COLUMNS + self.x, cell);
+        self.x += 1;
+        if self.x == NUM_COLUMNS {
+            self.newline();
+        }
+    }
+
+    /// Start decoding a UTF-8 character whose first byte has the bits
+    /// `bits`, and which has `left` more bytes.
+    fn start_utf8(&mut self, bits: u8, left: usize) {
+        self.utf8 = u32::from(bits);
+        self.utf8_left = left;
+    }
+
+    /// Decode the next byte of a UTF-8 character. Return false if the byte
+    /// is not a part of the character, which is then drawn as '?'.
+    fn continue_utf8(&mut self, byte: u8) -> bool {
+        self.utf8_left -= 1;
+        if byte & 0xc0 != 0x80 {
+            self.utf8_left = 0;
+            self.print(b'?');
+            return false;
+        }
+        self.utf8 = self.utf8 << 6 | u32::from(byte & 0x3f);
+        if self.utf8_left == 0 {
+            let glyph = char::from_u32(self.utf8)
+                .map_or(b'?', to_cp437);
+            self.print(glyph);
+        }
+        true
+    }
+
+    /// Return the parameter `index` of the escape sequence, or `default` if
+    /// it's not given or zero.
+    fn param(&self, index: usize, default: usize) -> usize {
+        if index < self.num_params && self.params[index] != 0 {
+            self.params[index]
+        } else {
+            default
+        }
+    }
+
+    /// Set the colors as told by the parameters of the escape sequence.
+    fn select_graphic_rendition(&mut self) {
+        // A sequence without parameters resets the colors.
+        for i in 0..cmp::max(self.num_params, 1) {
+            let param = if i < self.num_params { self.params[i] } else { 0 };
+            match param {
+                0 => {
+                    self.foreground = DEFAULT_COLOR;
+                    self.background = DEFAULT_BACKGROUND;
+                },
+                // Bold text is shown in the bright colors.
+                1 => {
+                    let value = self.foreground as u8 | BRIGHT;
+                    self.foreground = Color::from_value(value);
+                },
+                22 => {
+                    let value = self.foreground as u8 & !BRIGHT;
+                    self.foreground = Color::from_value(value);
+                },
+                30..=37 => {
+                    let ansi = (param - 30) as u8;
+                    self.foreground = Color::from_ansi(ansi, false);
+                },
+                39 => self.foreground = DEFAULT_COLOR,
+                90..=97 => {
+                    let ansi = (param - 90) as u8;
+                    self.foreground = Color::from_ansi(ansi, true);
+                },
+                // The highest bit of the attribute makes the character
+                // blink, so the bright colors can't be used as the
+                // background.
+                40..=47 | 100..=107 => {
+                    let ansi = (param % 10) as u8;
+                    self.background = Color::from_ansi(ansi, false);
+                },
+                49 => self.background = DEFAULT_BACKGROUND,
+                _ => (),
+            }
+        }
+    }
+
+    /// Run the control sequence that ends with `command`.
+    fn execute(&mut self, command: u8) {
+        let position = self.y * NUM_COLUMNS + self.x;
+        let line = self.y * NUM_COLUMNS;
+        match command {
+            b'A' => self.y = self.y.saturating_sub(self.param(0, 1)),
+            b'B' => {
+                self.y = cmp::min(self.y + self.param(0, 1), NUM_LINES - 1);
+            },
+            b'C' => {
+                self.x = cmp::min(self.x + self.param(0, 1), NUM_COLUMNS - 1);
+            },
+            b'D' => self.x = self.x.saturating_sub(self.param(0, 1)),
+            b'G' => self.x = cmp::min(self.param(0, 1), NUM_COLUMNS) - 1,
+            // The lines and columns are counted from one.
+            b'H' | b'f' => {
+                self.y = cmp::min(self.param(0, 1), NUM_LINES) - 1;
+                self.x = cmp::min(self.param(1, 1), NUM_COLUMNS) - 1;
+            },
+            b'J' => match self.param(0, 0) {
+                0 => self.clear(position, NUM_COLUMNS * NUM_LINES),
+                1 => self.clear(0, position + 1),
+                _ => self.clear(0, NUM_COLUMNS * NUM_LINES),
+            },
+            b'K' => match self.param(0, 0) {
+                0 => self.clear(position, line + NUM_COLUMNS),
+                1 => self.clear(line, position + 1),
+                _ => self.clear(line, line + NUM_COLUMNS),
+            },
+            b'm' => self.select_graphic_rendition(),
+            b's' => self.saved = (self.x, self.y),
+            b'u' => {
+                let (x, y) = self.saved;
+                self.x = x;
+                self.y = y;
+            },
+            _ => (),
+        }
+    }
+
+    /// Handle one byte, which is either a character to be printed, a
+    /// control character, or a part of an escape sequence.
+    fn putc(&mut self, byte: u8) {
+        if self.utf8_left > 0 && self.continue_utf8(byte) {
+            return;
+        }
+        match self.state {
+            State::Normal => match byte {
+                0x1b => self.state = State::Escape,
+                b'\n' => self.newline(),
+                b'\r' => self.x = 0,
+                b'\t' => {
+                    let x = (self.x / TAB_SIZE + 1) * TAB_SIZE;
+                    self.x = cmp::min(x, NUM_COLUMNS - 1);
+                },
+                // Backspace only moves the cursor. The character is erased
+                // when it's overwritten.
+                0x08 => self.x = self.x.saturating_sub(1),
+                // The other control characters are not printable.
+                _ if byte.is_ascii_control() => (),
+                0xc0..=0xdf => self.start_utf8(byte & 0x1f, 1),
+                0xe0..=0xef => self.start_utf8(byte & 0x0f, 2),
+                0xf0..=0xf7 => self.start_utf8(byte & 0x07, 3),
+                // A stray continuation byte, or an invalid one.
+                0x80..=0xbf | 0xf8..=0xff => self.print(b'?'),
+                _ => self.print(byte),
+            },
+            State::Escape => match byte {
+                b'[' => {
+                    self.params = [0; MAX_PARAMS];
+                    self.num_params = 0;
+                    self.state = State::Csi;
+                },
+                // Reset the terminal.
+                b'c' => {
+                    self.foreground = DEFAULT_COLOR;
+                    self.background = DEFAULT_BACKGROUND;
+                    self.clear(0, NUM_COLUMNS * NUM_LINES);
+                    self.x = 0;
+                    self.y = 0;
+                    self.state = State::Normal;
+                },
+                // The other escape sequences are not supported.
+                _ => self.state = State::Normal,
+            },
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    if self.num_params == 0 {
+                        self.num_params = 1;
+                    }
+                    if self.num_params <= MAX_PARAMS {
+                        let param = &mut self.params[self.num_params - 1];
+                        *param = param.saturating_mul(10)
+                            .saturating_add(usize::from(byte - b'0'));
+                    }
+                },
+                b';' => {
+                    // An empty parameter before ';' is a zero.
+                    self.num_params = cmp::max(self.num_params, 1) + 1;
+                },
+                // The bytes from 0x40 to 0x7e end the sequence.
+                0x40..=0x7e => {
+                    self.num_params = cmp::min(self.num_params, MAX_PARAMS);
+                    self.execute(byte);
+                    self.state = State::Normal;
+                },
+                // The other bytes, like the '?' of the private sequences,
+                // are ignored.
+                _ => (),
+            },
+        }
+    }
+
+    /// Move the hardware cursor to where the next character will be
+    /// printed.
+    #[cfg(not(test))]
+    fn update_cursor(&self) {
+        let position = (self.y * NUM_COLUMNS + self.x) as u16;
+        unsafe {
+            outb(CRTC_INDEX, CURSOR_HIGH);
+            outb(CRTC_DATA, (position >> 8) as u8);
+            outb(CRTC_INDEX, CURSOR_LOW);
+            outb(CRTC_DATA, position as u8);
+        }
+    }
+
+    #[cfg(test)]
+    fn update_cursor(&self) {}
+
+    /// Handle all the bytes in `bytes`. The hardware cursor is only moved
+    /// at the end, because it's slow.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.putc(byte);
+        }
+        self.update_cursor();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    /// A screen that writes to a buffer allocated by the host.
+    struct TestScreen {
+        buffer: [u16; NUM_COLUMNS * NUM_LINES],
+    }
+
+    impl TestScreen {
+        fn new() -> TestScreen {
+            TestScreen {
+                buffer: [0; NUM_COLUMNS * NUM_LINES],
+            }
+        }
+
+        fn screen(&mut self) -> Screen {
+            let buffer = self.buffer.as_mut_ptr() as usize;
+            let screen = Screen::with_buffer(buffer);
+            screen.clear(0, NUM_COLUMNS * NUM_LINES);
+            screen
+        }
+
+        /// Return the text on line `y` without the trailing spaces.
+        fn line(&self, y: usize) -> String {
+            let line = &self.buffer[y * NUM_COLUMNS..(y + 1) * NUM_COLUMNS];
+            let text: String = line.iter().map(|&cell| cell as u8 as char)
+                .collect();
+            String::from(text.trim_end())
+        }
+
+        /// Return the attribute of the character at `x` and `y`.
+        fn attribute(&self, x: usize, y: usize) -> u8 {
+            (self.buffer[y * NUM_COLUMNS + x] >> 8) as u8
+        }
+    }
+
+    #[test]
+    fn color_from_ansi() {
+        for &color in COLORS.iter() {
+            let code = color.ansi_code();
+            let color2 = if code >= 90 {
+                Color::from_ansi(code - 90, true)
+            } else {
+                Color::from_ansi(code - 30, false)
+            };
+            assert_eq!(color, color2);
+        }
+    }
+
+    #[test]
+    fn ansi_code() {
+        assert_eq!(Color::Black.ansi_code(), 30);
+        assert_eq!(Color::Red.ansi_code(), 31);
+        assert_eq!(Color::Brown.ansi_code(), 33);
+        assert_eq!(Color::Blue.ansi_code(), 34);
+        assert_eq!(Color::Gray.ansi_code(), 37);
+        assert_eq!(Color::DarkGray.ansi_code(), 90);
+        assert_eq!(Color::LightRed.ansi_code(), 91);
+        assert_eq!(Color::LightCyan.ansi_code(), 96);
+        assert_eq!(Color::White.ansi_code(), 97);
+    }
+
+    #[test]
+    fn print_lines() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        screen.write_bytes(b"Hello\nWorld");
+        assert_eq!((screen.x, screen.y), (5, 1));
+        assert_eq!(test.line(0), "Hello");
+        assert_eq!(test.line(1), "World");
+    }
+
+    #[test]
+    fn control_characters() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        screen.write_bytes(b"abc\rX\n");
+        screen.write_bytes(b"abc\x08\x08Y\n");
+        screen.write_bytes(b"a\tb\t\tc\n");
+        screen.write_bytes(b"\x07d\0");
+        assert_eq!(test.line(0), "Xbc");
+        assert_eq!(test.line(1), "aYc");
+        assert_eq!(test.line(2), "a       b               c");
+        assert_eq!(test.line(3), "d");
+    }
+
+    #[test]
+    fn wrap_long_line() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        for _ in 0..NUM_COLUMNS {
+            screen.write_bytes(b"a");
+        }
+        screen.write_bytes(b"b");
+        assert_eq!(test.line(0).len(), NUM_COLUMNS);
+        assert_eq!(test.line(1), "b");
+    }
+
+    #[test]
+    fn scroll() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        for i in 0..NUM_LINES + 2 {
+            screen.write_bytes(format!("line {}\n", i).as_bytes());
+        }
+        assert_eq!((screen.x, screen.y), (0, NUM_LINES - 1));
+        assert_eq!(test.line(0), "line 3");
+        let last = format!("line {}", NUM_LINES + 1);
+        assert_eq!(test.line(NUM_LINES - 2), last);
+        assert_eq!(test.line(NUM_LINES - 1), "");
+    }
+
+    #[test]
+    fn set_colors() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        screen.write_bytes(b"a\x1b[31;42mb\x1b[1mc\x1b[39md");
+        screen.write_bytes(b"\x1b[mE\x1b[94;101mf");
+        assert_eq!(test.attribute(0, 0), 0x0b);
+        assert_eq!(test.attribute(1, 0), 0x24);
+        assert_eq!(test.attribute(2, 0), 0x2c);
+        assert_eq!(test.attribute(3, 0), 0x2b);
+        assert_eq!(test.attribute(4, 0), 0x0b);
+        assert_eq!(test.attribute(5, 0), 0x49);
+        assert_eq!(test.line(0), "abcdEf");
+    }
+
+    #[test]
+    fn move_cursor() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        screen.write_bytes(b"\x1b[3;5Ha");
+        assert_eq!((screen.x, screen.y), (5, 2));
+        screen.write_bytes(b"\x1b[2A\x1b[3Db");
+        assert_eq!((screen.x, screen.y), (3, 0));
+        screen.write_bytes(b"\x1b[10B\x1b[20Cc");
+        screen.write_bytes(b"\x1b[sd\x1b[Ge\x1b[ufg");
+        screen.write_bytes(b"\x1b[Hh\x1b[99;99H");
+        assert_eq!((screen.x, screen.y), (NUM_COLUMNS - 1, NUM_LINES - 1));
+        assert_eq!(test.line(0), "h b");
+        assert_eq!(test.line(2), "    a");
+        assert_eq!(test.line(10), "e                      cfg");
+    }
+
+    #[test]
+    fn clear_screen() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        screen.write_bytes(b"abcdef\nghijkl\nmnopqr");
+        screen.write_bytes(b"\x1b[2;3H\x1b[K\x1b[1A\x1b[1K");
+        assert_eq!(test.line(0), "   def");
+        assert_eq!(test.line(1), "gh");
+        screen.write_bytes(b"\x1b[3;4H\x1b[J");
+        assert_eq!(test.line(2), "mno");
+        screen.write_bytes(b"\x1b[1J");
+        assert_eq!(test.line(0), "");
+        assert_eq!(test.line(2), "");
+        screen.write_bytes(b"\x1b[2J");
+        for y in 0..NUM_LINES {
+            assert_eq!(test.line(y), "");
+        }
+    }
+
+    #[test]
+    fn ignore_unsupported_sequences() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        screen.write_bytes(b"\x1b[?25la\x1b(Bb\x1b[1;2;3;4;5;6;7;8;9;10mc");
+        assert_eq!(test.line(0), "aBbc");
+    }
+
+    #[test]
+    fn cp437() {
+        assert_eq!(CP437.chars().count(), 0x80);
+        assert_eq!(to_cp437('a'), b'a');
+        assert_eq!(to_cp437('Ç'), 0x80);
+        assert_eq!(to_cp437('ß'), 0xe1);
+        assert_eq!(to_cp437('\u{a0}'), 0xff);
+        assert_eq!(to_cp437('§'), 0x15);
+        assert_eq!(to_cp437('€'), b'?');
+    }
+
+    #[test]
+    fn print_utf8() {
+        let mut test = TestScreen::new();
+        let mut screen = test.screen();
+        screen.write_bytes("äÖ£€".as_bytes());
+        screen.write_bytes(b"\xc3");
+        screen.write_bytes(b"\xbc");
+        assert_eq!(screen.x, 5);
+        let glyphs: Vec<u8> = test.buffer[..5].iter()
+            .map(|&cell| cell as u8).collect();
+        assert_eq!(glyphs, [0x84, 0x99, 0x9c, b'?', 0x81]);
+        // The broken characters are drawn as '?' without eating the next
+        // character.
+        screen.write_bytes(b"\n\xc3a\xbcb\xf0\x9f\x98\x80c");
+        assert_eq!(test.line(1), "?a?b?c");
+    }
+}
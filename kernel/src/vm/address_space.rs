@@ -0,0 +1,1004 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![allow(dead_code)]
+
+//! Address spaces of processes.
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::{cmp, ptr};
+use core::ops::Range;
+use ::config::{PAGE_SIZE, USER_SPACE_END};
+use ::frame::FrameAllocator;
+use ::paging::{assert_align, phy_to_virt, PagingContext};
+use super::{Access, Backing, Protection, Vma, VmaTree};
+
+/// The lowest address that can be mapped without a fixed address.
+const MMAP_MIN: usize = 0x1_0000;
+/// The highest address that can be mapped without a fixed address. We
+/// leave 1 GiB below the end of the user space for the stack.
+const MMAP_BASE: usize = USER_SPACE_END - (1 << 30);
+
+/// The reason why a page fault can't be resolved.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// The access is not permitted by any memory area.
+    Segv,
+    /// There is no free frame left to resolve the fault.
+    OutOfMemory,
+}
+
+/// A structure that represents the virtual memory of a process. The pages
+/// of the memory areas are not mapped until they are touched.
+#[derive(Debug)]
+pub struct AddressSpace {
+    paging: PagingContext,
+    vmas: VmaTree,
+    // The start of the heap, which is right after the executable.
+    brk_start: usize,
+    // The program break, which is the end of the heap.
+    brk: usize,
+    // The range of the pages whose mappings are removed or reduced, but
+    // their TLB entries are not flushed yet.
+    unflushed: Option<Range<usize>>,
+    // The frames that are unmapped, but may still be accessed through the
+    // TLBs until they are flushed.
+    released: Vec<usize>,
+}
+
+impl AddressSpace {
+    /// Create an empty [AddressSpace](AddressSpace) that uses the paging
+    /// context `paging`.
+    pub fn new(paging: PagingContext) -> AddressSpace {
+        AddressSpace {
+            paging,
+            vmas: VmaTree::new(),
+            brk_start: 0,
+            brk: 0,
+            unflushed: None,
+            released: Vec::new(),
+        }
+    }
+
+    /// Record that the TLB entries of the pages in `range` must be flushed.
+    fn invalidate(&mut self, range: Range<usize>) {
+        self.unflushed = Some(match self.unflushed.take() {
+            Some(old) => {
+                cmp::min(old.start, range.start)..cmp::max(old.end, range.end)
+            },
+            None => range,
+        });
+    }
+
+    /// Return the range of the pages whose TLB entries must be flushed and
+    /// forget it.
+    pub fn take_unflushed(&mut self) -> Option<Range<usize>> {
+        self.unflushed.take()
+    }
+
+    /// Flush the TLB entries of the pages whose mappings are removed or
+    /// reduced on every processor that uses the address space. This must be
+    /// called after the address space is changed and before returning to
+    /// the user mode. The other processors may need the frame allocator
+    /// before they can answer the shootdown, so it must not be locked here.
+    pub fn flush_tlb(&mut self) {
+        if let Some(range) = self.take_unflushed() {
+            self.paging.flush(range);
+        }
+    }
+
+    /// Free the frames that are unmapped before the last
+    /// [flush_tlb](AddressSpace::flush_tlb).
+    pub fn free_released(&mut self, frames: &mut FrameAllocator) {
+        assert!(self.unflushed.is_none(), "the TLBs must be flushed first");
+        for frame in self.released.drain(..) {
+            frames.free(frame);
+        }
+    }
+
+    /// Flush the TLBs, and then free the released frames with the frame
+    /// allocator of the system, which must not be locked.
+    #[cfg(not(test))]
+    pub fn flush_tlb_and_free(&mut self) {
+        self.flush_tlb();
+        self.free_released(&mut ::frame::allocator().lock());
+    }
+
+    /// Return the paging context of the address space.
+    pub fn paging(&self) -> &PagingContext {
+        &self.paging
+    }
+
+    /// Return the memory areas of the address space.
+    pub fn vmas(&self) -> &VmaTree {
+        &self.vmas
+    }
+
+    /// Return the program break.
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+
+    /// Make the heap start right after the executable that ends at virtual
+    /// address `end`. The heap is empty at first.
+    pub fn init_brk(&mut self, end: usize) {
+        let start = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        self.brk_start = start;
+        self.brk = start;
+    }
+
+    /// Move the program break to virtual address `new_brk`, so the heap
+    /// grows or shrinks. The heap can't be larger than `limit` bytes and
+    /// can't overlap other memory areas. The pages are mapped when they are
+    /// touched. Return the new program break, or the old one if the break
+    /// can't be moved.
+    pub fn set_brk(&mut self, new_brk: usize, limit: usize) -> usize {
+        if new_brk < self.brk_start || new_brk - self.brk_start > limit {
+            return self.brk;
+        }
+        let old_end = (self.brk + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let new_end = match new_brk.checked_add(PAGE_SIZE - 1) {
+            Some(end) => end & !(PAGE_SIZE - 1),
+            None => return self.brk,
+        };
+
+        if new_end > old_end {
+            let heap = Vma::anonymous(old_end, new_end, Protection {
+                read: true,
+                write: true,
+                exec: false,
+            });
+            if self.insert_vma(heap).is_err() {
+                return self.brk;
+            }
+        } else if new_end < old_end {
+            self.unmap(new_end..old_end);
+        }
+        self.brk = new_brk;
+        self.brk
+    }
+
+    /// Add the memory area `vma` to the address space. Return an error if
+    /// the area is empty, not in the user space, or overlaps another area.
+    pub fn insert_vma(&mut self, vma: Vma) -> Result<(), ()> {
+        // Assert that both ends are page aligned.
+        assert_align(vma.start);
+        assert_align(vma.end);
+
+        if vma.end > USER_SPACE_END {
+            return Err(());
+        }
+        self.vmas.insert(vma)
+    }
+
+    /// Resolve the page fault at virtual address `addr` caused by the memory
+    /// access `access`. The caller must invalidate the TLB entry of `addr`
+    /// after the fault is resolved.
+    pub fn handle_fault(&mut self, frames: &mut FrameAllocator, addr: usize,
+                        access: Access) -> Result<(), Fault>
+    {
+        let page = addr & !(PAGE_SIZE - 1);
+        let found = self.vmas.lookup(addr).cloned();
+        let vma = match found {
+            Some(vma) => vma,
+            None => self.grow_down(page)?,
+        };
+        if !vma.prot.allows(access) {
+            return Err(Fault::Segv);
+        }
+
+        match self.paging.find(page) {
+            None => self.fill(frames, page, &vma),
+            Some((frame, flags)) => {
+                if access.write && !flags.write && !vma.backing.is_shared() {
+                    self.copy_on_write(frames, page, frame, &vma)
+                } else {
+                    // The page is already mapped properly. This can happen
+                    // when the TLB entry is stale.
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    /// Fault in the pages of the `len` bytes at virtual address `addr` as
+    /// if the process accesses them with `access`. Then call `copy` for the
+    /// part of the bytes in each page with its address in the physmap and
+    /// its offset range in the bytes.
+    fn access_user<F>(&mut self, frames: &mut FrameAllocator, addr: usize,
+                      len: usize, access: Access, mut copy: F)
+        -> Result<(), Fault>
+        where F: FnMut(*mut u8, Range<usize>)
+    {
+        let end = addr.checked_add(len).ok_or(Fault::Segv)?;
+        let mut current = addr;
+        while current < end {
+            let page = current & !(PAGE_SIZE - 1);
+            let len = cmp::min(page + PAGE_SIZE, end) - current;
+            self.handle_fault(frames, current, access)?;
+            // The device memory may not be in the physmap.
+            if let Backing::Device { .. } = self.vmas.lookup(current)
+                .unwrap().backing
+            {
+                return Err(Fault::Segv);
+            }
+            let (frame, _) = self.paging.find(page).unwrap();
+            copy((phy_to_virt(frame) + current - page) as *mut u8,
+                 current - addr..current - addr + len);
+            current += len;
+        }
+        Ok(())
+    }
+
+    /// Copy `data` to the memory at virtual address `addr`, as if the
+    /// process writes it. The pages are faulted in first, so that the kernel
+    /// can write to their frames through the physmap.
+    pub fn write(&mut self, frames: &mut FrameAllocator, addr: usize,
+                 data: &[u8]) -> Result<(), Fault>
+    {
+        let access = Access {
+            write: true,
+            user: true,
+            ..Access::default()
+        };
+        self.access_user(frames, addr, data.len(), access, |ptr, range| {
+            unsafe {
+                ptr::copy_nonoverlapping(data[range.clone()].as_ptr(), ptr,
+                                         range.len());
+            }
+        })
+    }
+
+    /// Copy the memory at virtual address `addr` to `buf`, as if the
+    /// process reads it. The pages are faulted in first, like in
+    /// [write](AddressSpace::write).
+    pub fn read(&mut self, frames: &mut FrameAllocator, addr: usize,
+                buf: &mut [u8]) -> Result<(), Fault>
+    {
+        let access = Access {
+            user: true,
+            ..Access::default()
+        };
+        self.access_user(frames, addr, buf.len(), access, |ptr, range| {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, buf[range.clone()].as_mut_ptr(),
+                                         range.len());
+            }
+        })
+    }
+
+    /// Extend the stack above the page `page` down to `page` and return the
+    /// extended area. The stack must be anonymous and there must be at
+    /// least one free page between the stack and the area below it.
+    fn grow_down(&mut self, page: usize) -> Result<Vma, Fault> {
+        let mut vma = match self.vmas.next(page) {
+            Some(vma) if vma.flags.grows_down => vma.clone(),
+            _ => return Err(Fault::Segv),
+        };
+        if vma.backing != Backing::Anonymous || page < PAGE_SIZE ||
+            !self.vmas.is_free(page - PAGE_SIZE..vma.start)
+        {
+            return Err(Fault::Segv);
+        }
+        vma.end = vma.start;
+        vma.start = page;
+        // The new part is merged with the stack.
+        self.vmas.insert(vma).unwrap();
+        Ok(self.vmas.lookup(page).unwrap().clone())
+    }
+
+    /// Map the page `page` in `vma` to the frame that holds its content.
+    fn fill(&mut self, frames: &mut FrameAllocator, page: usize, vma: &Vma)
+        -> Result<(), Fault>
+    {
+        let delta = page - vma.start;
+        let mut flags = vma.prot.to_map_flags();
+        let frame = match vma.backing {
+            Backing::Anonymous => frames.alloc_zeroed(),
+            Backing::File { data, offset, .. } => {
+                frames.alloc_zeroed().map(|frame| {
+                    let start = cmp::min(offset + delta, data.len());
+                    let end = cmp::min(start + PAGE_SIZE, data.len());
+                    unsafe {
+                        ptr::copy_nonoverlapping(data[start..end].as_ptr(),
+                                                 phy_to_virt(frame) as *mut u8,
+                                                 end - start);
+                    }
+                    frame
+                })
+            },
+            Backing::Device { phy_addr } => {
+                flags.cache_disable = true;
+                Ok(phy_addr + delta)
+            },
+            Backing::Shared { ref memory, offset } => {
+                memory.frame(frames, offset + delta).map(|frame| {
+                    // The memory and the mapping hold separate references.
+                    frames.share(frame);
+                    frame
+                })
+            },
+        }.map_err(|_| Fault::OutOfMemory)?;
+
+        // The page is not mapped yet, so the insertion can't fail.
+        self.paging.insert(page, frame, flags).unwrap();
+        Ok(())
+    }
+
+    /// Give the page `page` in `vma` its own writable copy of the frame
+    /// `frame`, which is shared copy-on-write.
+    fn copy_on_write(&mut self, frames: &mut FrameAllocator, page: usize,
+                     frame: usize, vma: &Vma) -> Result<(), Fault>
+    {
+        let flags = vma.prot.to_map_flags();
+        if frames.refcount(frame) == 1 {
+            // No one else is sharing the frame anymore, so we can just make
+            // it writable.
+            self.paging.update(page, frame, flags).unwrap();
+            return Ok(());
+        }
+
+        let copy = frames.alloc().map_err(|_| Fault::OutOfMemory)?;
+        unsafe {
+            ptr::copy_nonoverlapping(phy_to_virt(frame) as *const u8,
+                                     phy_to_virt(copy) as *mut u8,
+                                     PAGE_SIZE);
+        }
+        self.paging.update(page, copy, flags).unwrap();
+        self.invalidate(page..page + PAGE_SIZE);
+        self.released.push(frame);
+        Ok(())
+    }
+
+    /// Create a copy of the address space that uses the paging context
+    /// `paging`. The frames of the private areas are shared copy-on-write,
+    /// so they become read-only in both address spaces.
+    pub fn fork(&mut self, frames: &mut FrameAllocator,
+                paging: PagingContext) -> AddressSpace
+    {
+        let mut child = AddressSpace::new(paging);
+        let mut protected: Option<Range<usize>> = None;
+        for vma in self.vmas.iter() {
+            if vma.flags.dont_fork {
+                continue;
+            }
+            child.vmas.insert(vma.clone()).unwrap();
+            for page in (vma.start..vma.end).step_by(PAGE_SIZE) {
+                if let Some((frame, mut flags)) = self.paging.find(page) {
+                    if !vma.backing.is_shared() && flags.write {
+                        flags.write = false;
+                        self.paging.update(page, frame, flags).unwrap();
+                        let start = match protected {
+                            Some(ref range) => range.start,
+                            None => page,
+                        };
+                        protected = Some(start..page + PAGE_SIZE);
+                    }
+                    child.paging.insert(page, frame, flags).unwrap();
+                    if vma.backing.uses_frames() {
+                        frames.share(frame);
+                    }
+                }
+            }
+        }
+        if let Some(range) = protected {
+            self.invalidate(range);
+        }
+        child
+    }
+
+    /// Find a free range of `len` bytes that can be mapped. The range at
+    /// `hint` is used if it's free. Otherwise, the highest free range below
+    /// [MMAP_BASE](MMAP_BASE) is used.
+    pub fn find_free(&self, hint: usize, len: usize) -> Option<usize> {
+        if hint >= MMAP_MIN && hint.checked_add(len)
+            .map_or(false, |end| end <= USER_SPACE_END &&
+                    self.vmas.is_free(hint..end))
+        {
+            return Some(hint);
+        }
+
+        // Return true if there are `len` bytes between `below` and `end`
+        // that are not below MMAP_MIN.
+        let fits = |end: usize, below: usize| {
+            end.checked_sub(len)
+                .map_or(false, |start| start >= cmp::max(below, MMAP_MIN))
+        };
+        // The end of the free range that we are looking at.
+        let mut end = MMAP_BASE;
+        for vma in self.vmas.iter().rev() {
+            if vma.start >= end {
+                continue;
+            }
+            if fits(end, vma.end) {
+                return Some(end - len);
+            }
+            end = vma.start;
+        }
+        if fits(end, 0) {
+            Some(end - len)
+        } else {
+            None
+        }
+    }
+
+    /// Remove the memory areas in `range` and free the mapped frames. The
+    /// areas that are partially covered are split first.
+    pub fn unmap(&mut self, range: Range<usize>) {
+        for vma in self.vmas.remove(range) {
+            self.unmap_vma(&vma);
+        }
+    }
+
+    /// Change the protection of the memory in `range` to `prot`. Return an
+    /// error if some part of `range` is not in any area.
+    pub fn protect(&mut self, range: Range<usize>, prot: Protection)
+        -> Result<(), ()>
+    {
+        if !self.vmas.is_covered(range.clone()) {
+            return Err(());
+        }
+        self.vmas.update(range.clone(), |vma| vma.prot = prot);
+
+        for page in range.step_by(PAGE_SIZE) {
+            if let Some((frame, old_flags)) = self.paging.find(page) {
+                let shared = self.vmas.lookup(page).unwrap()
+                    .backing.is_shared();
+                let mut flags = prot.to_map_flags();
+                // A read-only private page may be shared copy-on-write, so
+                // it only becomes writable when it's written.
+                flags.write &= old_flags.write || shared;
+                flags.cache_disable = old_flags.cache_disable;
+                self.paging.update(page, frame, flags).unwrap();
+                self.invalidate(page..page + PAGE_SIZE);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resize the memory in `range`, which must be in a single area, to
+    /// `new_len` bytes. If the memory can't grow in place and `may_move` is
+    /// true, the memory is moved to a new range. Return the new start of
+    /// the memory.
+    pub fn remap(&mut self, range: Range<usize>, new_len: usize,
+                 may_move: bool) -> Result<usize, ()>
+    {
+        let vma = match self.vmas.lookup(range.start) {
+            Some(vma) if range.end <= vma.end => vma.clone(),
+            _ => return Err(()),
+        };
+        let old_len = range.end - range.start;
+        if new_len <= old_len {
+            self.unmap(range.start + new_len..range.end);
+            return Ok(range.start);
+        }
+
+        // The part of the area that is extended from the memory.
+        let extension = |start: usize| Vma {
+            start: start + old_len,
+            end: start + new_len,
+            prot: vma.prot,
+            flags: vma.flags,
+            backing: vma.backing.advance(range.end - vma.start),
+        };
+        let fits = range.start.checked_add(new_len)
+            .map_or(false, |end| end <= USER_SPACE_END &&
+                    self.vmas.is_free(range.end..end));
+        if fits {
+            self.vmas.insert(extension(range.start)).unwrap();
+            return Ok(range.start);
+        }
+        if !may_move {
+            return Err(());
+        }
+
+        let start = self.find_free(0, new_len).ok_or(())?;
+        let mut moved = self.vmas.remove(range.clone()).pop().unwrap();
+        moved.end = start + old_len;
+        moved.start = start;
+        for offset in (0..old_len).step_by(PAGE_SIZE) {
+            let old = range.start + offset;
+            if let Some((frame, flags)) = self.paging.find(old) {
+                self.paging.remove(old).unwrap();
+                self.invalidate(old..old + PAGE_SIZE);
+                self.paging.insert(start + offset, frame, flags).unwrap();
+            }
+        }
+        self.vmas.insert(moved).unwrap();
+        self.vmas.insert(extension(start)).unwrap();
+        Ok(start)
+    }
+
+    /// Unmap all the pages of `vma`, which is already removed from the
+    /// address space. Other processors may still access the frames through
+    /// their TLBs, so the frames are only released, and they are freed
+    /// after the flush.
+    fn unmap_vma(&mut self, vma: &Vma) {
+        let mut unmapped = false;
+        for page in (vma.start..vma.end).step_by(PAGE_SIZE) {
+            if let Ok(frame) = self.paging.remove(page) {
+                if vma.backing.uses_frames() {
+                    self.released.push(frame);
+                }
+                unmapped = true;
+            }
+        }
+        if unmapped {
+            self.invalidate(vma.start..vma.end);
+        }
+        if let Backing::Shared { ref memory, .. } = vma.backing {
+            // This is the last area that uses the memory.
+            if Rc::strong_count(memory) == 1 {
+                memory.release(&mut self.released);
+            }
+        }
+    }
+
+    /// Remove all the memory areas and release all the mapped frames.
+    pub fn clear(&mut self) {
+        self.unmap(0..USER_SPACE_END);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use super::*;
+    use ::frame::new_for_test;
+    use ::vm::{Protection, SharedMemory};
+
+    const RW: Protection = Protection {
+        read: true,
+        write: true,
+        exec: false,
+    };
+    const READ: Access = Access {
+        present: false,
+        write: false,
+        user: true,
+        exec: false,
+    };
+    const WRITE: Access = Access {
+        present: false,
+        write: true,
+        user: true,
+        exec: false,
+    };
+    const PRESENT_WRITE: Access = Access {
+        present: true,
+        write: true,
+        user: true,
+        exec: false,
+    };
+
+    fn read_frame(frame: usize) -> &'static mut [u8] {
+        unsafe {
+            ::core::slice::from_raw_parts_mut(frame as *mut u8, PAGE_SIZE)
+        }
+    }
+
+    /// Flush the TLBs of `space` and free the frames it has released.
+    fn flush(space: &mut AddressSpace, frames: &mut FrameAllocator) {
+        space.flush_tlb();
+        space.free_released(frames);
+    }
+
+    #[test]
+    fn insert_vma_outside_user_space() {
+        let mut space = AddressSpace::new(PagingContext::new());
+        let vma = Vma::anonymous(USER_SPACE_END, USER_SPACE_END + 0x1000, RW);
+        assert!(space.insert_vma(vma).is_err());
+        let vma = Vma::anonymous(USER_SPACE_END - 0x1000, USER_SPACE_END, RW);
+        assert!(space.insert_vma(vma).is_ok());
+    }
+
+    #[test]
+    fn zero_fill_on_first_touch() {
+        let mut frames = new_for_test(2);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        assert!(space.paging().find(0x3000).is_none());
+
+        space.handle_fault(&mut frames, 0x3008, READ).unwrap();
+        let (frame, flags) = space.paging().find(0x3000).unwrap();
+        assert!(read_frame(frame).iter().all(|byte| *byte == 0));
+        assert!(flags.write && flags.user && flags.exe_disable);
+        // Only the touched page is mapped.
+        assert!(space.paging().find(0x2000).is_none());
+    }
+
+    #[test]
+    fn fill_from_file() {
+        let mut frames = new_for_test(2);
+        let mut space = AddressSpace::new(PagingContext::new());
+        let data: Vec<u8> = (0..PAGE_SIZE + 0x10).map(|i| i as u8).collect();
+        space.insert_vma(Vma {
+            backing: Backing::File {
+                name: "/bin/init",
+                data: Box::leak(data.into_boxed_slice()),
+                offset: 0x8,
+            },
+            ..Vma::anonymous(0x2000, 0x4000, RW)
+        }).unwrap();
+
+        space.handle_fault(&mut frames, 0x2000, READ).unwrap();
+        let (frame, _) = space.paging().find(0x2000).unwrap();
+        assert_eq!(read_frame(frame)[0], 0x8);
+        assert_eq!(read_frame(frame)[PAGE_SIZE - 1], 0x7);
+
+        // Only 8 bytes of the file are left for the second page.
+        space.handle_fault(&mut frames, 0x3000, READ).unwrap();
+        let (frame, _) = space.paging().find(0x3000).unwrap();
+        assert_eq!(read_frame(frame)[0x7], 0xf);
+        assert!(read_frame(frame)[0x8..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn map_device_memory() {
+        let mut frames = new_for_test(0);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma {
+            backing: Backing::Device { phy_addr: 0xb8000 },
+            ..Vma::anonymous(0x2000, 0x4000, RW)
+        }).unwrap();
+
+        space.handle_fault(&mut frames, 0x3000, WRITE).unwrap();
+        let (frame, flags) = space.paging().find(0x3000).unwrap();
+        assert_eq!(frame, 0xb9000);
+        assert!(flags.write && flags.cache_disable);
+        space.clear();
+        assert!(space.paging().find(0x3000).is_none());
+    }
+
+    #[test]
+    fn write_from_kernel() {
+        let mut frames = new_for_test(3);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        let data: Vec<u8> = (0..0x10).collect();
+        space.write(&mut frames, 0x2ff8, &data).unwrap();
+        let (frame, _) = space.paging().find(0x2000).unwrap();
+        assert_eq!(&read_frame(frame)[0xff8..], &data[..8]);
+        let (frame, _) = space.paging().find(0x3000).unwrap();
+        assert_eq!(&read_frame(frame)[..8], &data[8..]);
+
+        // The memory must be writable all the way.
+        assert_eq!(space.write(&mut frames, 0x3ff8, &data), Err(Fault::Segv));
+        assert_eq!(space.write(&mut frames, usize::max_value(), &data),
+                   Err(Fault::Segv));
+
+        let mut buf = [0; 0x10];
+        space.read(&mut frames, 0x2ff8, &mut buf).unwrap();
+        assert_eq!(&buf[..], &data[..]);
+        assert_eq!(space.read(&mut frames, 0x3ff8, &mut buf),
+                   Err(Fault::Segv));
+
+        // The copy is private after a fork.
+        let mut child = space.fork(&mut frames, PagingContext::new());
+        child.write(&mut frames, 0x2000, &[42]).unwrap();
+        let (copy, _) = child.paging().find(0x2000).unwrap();
+        let (frame, _) = space.paging().find(0x2000).unwrap();
+        assert_eq!(read_frame(copy)[0], 42);
+        assert_eq!(read_frame(frame)[0], 0);
+    }
+
+    #[test]
+    fn segv_on_violation() {
+        let mut frames = new_for_test(1);
+        let mut space = AddressSpace::new(PagingContext::new());
+        let read_only = Protection {
+            read: true,
+            ..Protection::default()
+        };
+        space.insert_vma(Vma::anonymous(0x2000, 0x3000, read_only)).unwrap();
+        let exec = Access {
+            exec: true,
+            ..READ
+        };
+
+        assert_eq!(space.handle_fault(&mut frames, 0x1000, READ),
+                   Err(Fault::Segv));
+        assert_eq!(space.handle_fault(&mut frames, 0x2000, WRITE),
+                   Err(Fault::Segv));
+        assert_eq!(space.handle_fault(&mut frames, 0x2000, exec),
+                   Err(Fault::Segv));
+        assert!(space.paging().find(0x2000).is_none());
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let mut frames = new_for_test(1);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        space.handle_fault(&mut frames, 0x2000, WRITE).unwrap();
+        assert_eq!(space.handle_fault(&mut frames, 0x3000, WRITE),
+                   Err(Fault::OutOfMemory));
+    }
+
+    #[test]
+    fn copy_on_write_after_fork() {
+        let mut frames = new_for_test(2);
+        let mut parent = AddressSpace::new(PagingContext::new());
+        parent.insert_vma(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        parent.handle_fault(&mut frames, 0x2000, WRITE).unwrap();
+        let (frame, _) = parent.paging().find(0x2000).unwrap();
+        read_frame(frame)[0] = 42;
+
+        let mut child = parent.fork(&mut frames, PagingContext::new());
+        assert_eq!(child.paging().find(0x2000), parent.paging().find(0x2000));
+        assert!(!parent.paging().find(0x2000).unwrap().1.write);
+        assert!(child.paging().find(0x3000).is_none());
+
+        // The child gets its own copy.
+        child.handle_fault(&mut frames, 0x2000, PRESENT_WRITE).unwrap();
+        let (copy, flags) = child.paging().find(0x2000).unwrap();
+        assert_ne!(copy, frame);
+        assert!(flags.write);
+        assert_eq!(read_frame(copy)[0], 42);
+        flush(&mut child, &mut frames);
+
+        // The parent is the only one left, so it takes the frame back.
+        parent.handle_fault(&mut frames, 0x2000, PRESENT_WRITE).unwrap();
+        let (same, flags) = parent.paging().find(0x2000).unwrap();
+        assert_eq!(same, frame);
+        assert!(flags.write);
+        assert!(frames.alloc().is_err());
+    }
+
+    #[test]
+    fn share_memory_after_fork() {
+        let mut frames = new_for_test(1);
+        let mut parent = AddressSpace::new(PagingContext::new());
+        parent.insert_vma(Vma {
+            backing: Backing::Shared {
+                memory: SharedMemory::new(),
+                offset: 0,
+            },
+            ..Vma::anonymous(0x2000, 0x3000, RW)
+        }).unwrap();
+        parent.handle_fault(&mut frames, 0x2000, WRITE).unwrap();
+
+        let mut child = parent.fork(&mut frames, PagingContext::new());
+        let (frame, flags) = child.paging().find(0x2000).unwrap();
+        assert!(flags.write);
+        assert_eq!(parent.paging().find(0x2000), Some((frame, flags)));
+
+        // The frame is freed only when no one uses the memory.
+        parent.clear();
+        flush(&mut parent, &mut frames);
+        assert!(frames.alloc().is_err());
+        child.clear();
+        flush(&mut child, &mut frames);
+        assert_eq!(frames.alloc(), Ok(frame));
+    }
+
+    #[test]
+    fn dont_fork_area() {
+        let mut frames = new_for_test(1);
+        let mut parent = AddressSpace::new(PagingContext::new());
+        let mut vma = Vma::anonymous(0x2000, 0x3000, RW);
+        vma.flags.dont_fork = true;
+        parent.insert_vma(vma).unwrap();
+        parent.handle_fault(&mut frames, 0x2000, WRITE).unwrap();
+
+        let child = parent.fork(&mut frames, PagingContext::new());
+        assert!(child.vmas().lookup(0x2000).is_none());
+        assert!(child.paging().find(0x2000).is_none());
+        assert!(parent.paging().find(0x2000).unwrap().1.write);
+    }
+
+    #[test]
+    fn track_unflushed_pages() {
+        let mut frames = new_for_test(3);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x6000, RW)).unwrap();
+        space.handle_fault(&mut frames, 0x3000, WRITE).unwrap();
+        space.handle_fault(&mut frames, 0x5000, WRITE).unwrap();
+        // Mapping new pages doesn't need any flush.
+        assert_eq!(space.take_unflushed(), None);
+
+        let mut child = space.fork(&mut frames, PagingContext::new());
+        assert_eq!(space.take_unflushed(), Some(0x3000..0x6000));
+        assert_eq!(child.take_unflushed(), None);
+        child.handle_fault(&mut frames, 0x3000, PRESENT_WRITE).unwrap();
+        assert_eq!(child.take_unflushed(), Some(0x3000..0x4000));
+
+        let read_only = Protection {
+            read: true,
+            ..Protection::default()
+        };
+        space.protect(0x2000..0x6000, read_only).unwrap();
+        assert_eq!(space.take_unflushed(), Some(0x3000..0x6000));
+    }
+
+    #[test]
+    fn grow_stack_down() {
+        let mut frames = new_for_test(2);
+        let mut space = AddressSpace::new(PagingContext::new());
+        let mut stack = Vma::anonymous(0x8000, 0x9000, RW);
+        stack.flags.grows_down = true;
+        space.insert_vma(stack).unwrap();
+        space.insert_vma(Vma::anonymous(0x2000, 0x3000, RW)).unwrap();
+
+        space.handle_fault(&mut frames, 0x6008, WRITE).unwrap();
+        assert_eq!(space.vmas().lookup(0x6000).unwrap().start, 0x6000);
+        assert_eq!(space.vmas().lookup(0x6000).unwrap().end, 0x9000);
+        assert!(space.paging().find(0x6000).is_some());
+        // There must be a free page between the stack and the area below.
+        assert_eq!(space.handle_fault(&mut frames, 0x3000, WRITE),
+                   Err(Fault::Segv));
+        assert_eq!(space.handle_fault(&mut frames, 0x9000, WRITE),
+                   Err(Fault::Segv));
+    }
+
+    #[test]
+    fn find_free_range() {
+        let mut space = AddressSpace::new(PagingContext::new());
+        assert_eq!(space.find_free(0x2000_0000, 0x2000), Some(0x2000_0000));
+        assert_eq!(space.find_free(0, 0x2000), Some(MMAP_BASE - 0x2000));
+        space.insert_vma(Vma::anonymous(MMAP_BASE - 0x2000, MMAP_BASE + 0x1000,
+                                        RW)).unwrap();
+        space.insert_vma(Vma::anonymous(MMAP_BASE - 0x5000, MMAP_BASE - 0x4000,
+                                        RW)).unwrap();
+        // The hint overlaps an area, so the highest free range is used.
+        assert_eq!(space.find_free(MMAP_BASE - 0x3000, 0x2000),
+                   Some(MMAP_BASE - 0x4000));
+        assert_eq!(space.find_free(0, 0x3000), Some(MMAP_BASE - 0x8000));
+        assert_eq!(space.find_free(0, MMAP_BASE), None);
+    }
+
+    #[test]
+    fn unmap_part_of_area() {
+        let mut frames = new_for_test(3);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x5000, RW)).unwrap();
+        for page in (0x2000..0x5000).step_by(PAGE_SIZE) {
+            space.handle_fault(&mut frames, page, WRITE).unwrap();
+        }
+
+        space.unmap(0x3000..0x4000);
+        assert!(space.vmas().lookup(0x3000).is_none());
+        assert!(space.paging().find(0x3000).is_none());
+        assert!(space.paging().find(0x2000).is_some());
+        assert!(space.paging().find(0x4000).is_some());
+        // The frame is freed only after the flush.
+        assert!(frames.alloc().is_err());
+        flush(&mut space, &mut frames);
+        assert!(frames.alloc().is_ok());
+        assert!(frames.alloc().is_err());
+    }
+
+    #[test]
+    fn protect_mapped_pages() {
+        let mut frames = new_for_test(3);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x5000, RW)).unwrap();
+        space.handle_fault(&mut frames, 0x3000, WRITE).unwrap();
+
+        let rx = Protection {
+            read: true,
+            exec: true,
+            ..Protection::default()
+        };
+        space.protect(0x3000..0x4000, rx).unwrap();
+        assert_eq!(space.vmas().protection(0x3000), Some(rx));
+        assert_eq!(space.vmas().protection(0x4000), Some(RW));
+        let (_, flags) = space.paging().find(0x3000).unwrap();
+        assert!(!flags.write && !flags.exe_disable);
+        assert_eq!(space.handle_fault(&mut frames, 0x3000, PRESENT_WRITE),
+                   Err(Fault::Segv));
+
+        // The page becomes writable again when it's written.
+        space.protect(0x3000..0x4000, RW).unwrap();
+        assert_eq!(space.vmas().iter().count(), 1);
+        assert!(!space.paging().find(0x3000).unwrap().1.write);
+        space.handle_fault(&mut frames, 0x3000, PRESENT_WRITE).unwrap();
+        assert!(space.paging().find(0x3000).unwrap().1.write);
+
+        assert!(space.protect(0x4000..0x6000, RW).is_err());
+    }
+
+    #[test]
+    fn remap_in_place() {
+        let mut frames = new_for_test(2);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        space.handle_fault(&mut frames, 0x3000, WRITE).unwrap();
+
+        assert_eq!(space.remap(0x2000..0x4000, 0x3000, false),
+                   Ok(0x2000));
+        assert_eq!(space.vmas().lookup(0x4000).unwrap().end, 0x5000);
+        assert_eq!(space.remap(0x2000..0x5000, 0x1000, false),
+                   Ok(0x2000));
+        assert_eq!(space.vmas().lookup(0x2000).unwrap().end, 0x3000);
+        assert!(space.paging().find(0x3000).is_none());
+        assert!(space.remap(0x2000..0x4000, 0x1000, false)
+                .is_err());
+    }
+
+    #[test]
+    fn remap_and_move() {
+        let mut frames = new_for_test(1);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2_0000, 0x2_2000, RW)).unwrap();
+        space.insert_vma(Vma::anonymous(0x2_2000, 0x2_3000,
+                                        Protection::default())).unwrap();
+        space.handle_fault(&mut frames, 0x2_1000, WRITE).unwrap();
+        let (frame, _) = space.paging().find(0x2_1000).unwrap();
+
+        assert!(space.remap(0x2_0000..0x2_2000, 0x4000, false)
+                .is_err());
+        let start = space.remap(0x2_0000..0x2_2000, 0x4000, true)
+            .unwrap();
+        assert_eq!(start, MMAP_BASE - 0x4000);
+        assert!(space.vmas().lookup(0x2_0000).is_none());
+        assert!(space.paging().find(0x2_1000).is_none());
+        assert_eq!(space.paging().find(start + 0x1000).unwrap().0, frame);
+        let vma = space.vmas().lookup(start).unwrap();
+        assert_eq!((vma.start, vma.end), (start, start + 0x4000));
+    }
+
+    #[test]
+    fn grow_and_shrink_heap() {
+        let mut frames = new_for_test(2);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.init_brk(0x4321);
+        assert_eq!(space.brk(), 0x5000);
+        assert_eq!(space.set_brk(0x4000, !0), 0x5000);
+
+        assert_eq!(space.set_brk(0x6800, !0), 0x6800);
+        assert!(space.paging().find(0x5000).is_none());
+        space.handle_fault(&mut frames, 0x6000, WRITE).unwrap();
+        let heap = space.vmas().lookup(0x5000).map(|vma| (vma.start, vma.end));
+        assert_eq!(heap, Some((0x5000, 0x7000)));
+
+        assert_eq!(space.set_brk(0x5800, !0), 0x5800);
+        assert!(space.vmas().lookup(0x6000).is_none());
+        assert!(space.paging().find(0x6000).is_none());
+        flush(&mut space, &mut frames);
+        assert!(frames.alloc().is_ok());
+        assert!(frames.alloc().is_ok());
+    }
+
+    #[test]
+    fn limit_heap() {
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.init_brk(0x5000);
+        space.insert_vma(Vma::anonymous(0x8000, 0x9000, RW)).unwrap();
+        assert_eq!(space.set_brk(0x7000, 0x1000), 0x5000);
+        assert_eq!(space.set_brk(0x7000, 0x2000), 0x7000);
+        // The heap can't overlap other areas.
+        assert_eq!(space.set_brk(0x8001, !0), 0x7000);
+        assert_eq!(space.set_brk(!0, !0), 0x7000);
+    }
+
+    #[test]
+    fn clear_frees_frames() {
+        let mut frames = new_for_test(2);
+        let mut space = AddressSpace::new(PagingContext::new());
+        space.insert_vma(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        space.handle_fault(&mut frames, 0x2000, WRITE).unwrap();
+        space.handle_fault(&mut frames, 0x3000, WRITE).unwrap();
+
+        space.clear();
+        assert!(space.vmas().lookup(0x2000).is_none());
+        assert!(space.paging().find(0x2000).is_none());
+        flush(&mut space, &mut frames);
+        assert!(frames.alloc().is_ok());
+        assert!(frames.alloc().is_ok());
+    }
+}
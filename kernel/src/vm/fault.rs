@@ -0,0 +1,135 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Page fault handling.
+
+#[cfg(not(test))]
+use ::config::USER_SPACE_END;
+#[cfg(not(test))]
+use ::frame;
+#[cfg(not(test))]
+use ::interrupt::{exception, InterruptStackFrame};
+#[cfg(not(test))]
+use ::paging::tlb;
+#[cfg(not(test))]
+use ::process::{self, Signal};
+#[cfg(not(test))]
+use super::Fault;
+
+/// The memory access that causes a page fault.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Access {
+    /// The page is present, so the fault is caused by a protection
+    /// violation.
+    pub present: bool,
+    pub write: bool,
+    /// The access is from the user mode.
+    pub user: bool,
+    /// The access is an instruction fetch.
+    pub exec: bool,
+}
+
+impl Access {
+    /// Create an [Access](Access) from the error code pushed by the
+    /// processor when there is a page fault.
+    pub fn from_error_code(error_code: u64) -> Access {
+        Access {
+            present: error_code & 1 != 0,
+            write: error_code & (1 << 1) != 0,
+            user: error_code & (1 << 2) != 0,
+            exec: error_code & (1 << 4) != 0,
+        }
+    }
+}
+
+/// The interrupt handler for page faults. The fault is resolved using the
+/// address space of the current process. If it can't be resolved, a signal
+/// is sent to the process.
+#[cfg(not(test))]
+pub extern "x86-interrupt"
+fn page_fault_handler(frame: &mut InterruptStackFrame, error_code: u64) {
+    let addr: usize;
+    unsafe {
+        asm!("mov %cr2, $0" : "=r"(addr) ::: "volatile");
+    }
+    let access = Access::from_error_code(error_code);
+    let fatal = |frame: &InterruptStackFrame| -> ! {
+        exception::fatal(frame, exception::interrupted_frame(),
+                         format_args!("Page fault at {:#x}, error code {:#x}",
+                                      addr, error_code))
+    };
+
+    // The kernel may touch the user memory of the current process, e.g. to
+    // copy the arguments of a system call, and the pages are faulted in as
+    // if the process touched them. Any other fault in the kernel mode is a
+    // bug.
+    if !access.user && addr >= USER_SPACE_END {
+        fatal(frame);
+    }
+
+    {
+        let process = match process::current() {
+            Some(process) => process,
+            None if !access.user => fatal(frame),
+            None => panic!("page fault in the user mode without any process"),
+        };
+        // If there is no frame left, the unused frames are taken back from
+        // the kernel heap and the fault is handled again.
+        let result = frame::with_allocator(|frames| {
+            process.address_space.handle_fault(frames, addr, access)
+        });
+        match result {
+            Ok(()) => {
+                process.address_space.flush_tlb_and_free();
+                // The TLB entry of the page may be stale even if the
+                // mapping isn't changed.
+                tlb::flush_page(addr);
+            },
+            // The kernel checks the user memory before it touches it.
+            Err(Fault::Segv) if !access.user => fatal(frame),
+            Err(Fault::Segv) => process.send_signal(Signal::Segv),
+            // Kill a process to free some memory. If the victim is another
+            // process, the access is tried again after we return.
+            Err(Fault::OutOfMemory) => {
+                process::oom_kill(Some(process));
+            },
+        }
+    }
+    // We can't return to the faulting instruction unless the fault is
+    // resolved.
+    process::deliver_signals();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_error_code() {
+        assert_eq!(Access::from_error_code(0x0), Access::default());
+        assert_eq!(Access::from_error_code(0x7), Access {
+            present: true,
+            write: true,
+            user: true,
+            exec: false,
+        });
+        assert_eq!(Access::from_error_code(0x14), Access {
+            user: true,
+            exec: true,
+            ..Access::default()
+        });
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Virtual memory module. This module keeps track of the memory areas of
+//! each address space and maps their pages lazily when they are touched.
+
+mod address_space;
+mod fault;
+mod vma;
+mod vma_tree;
+
+pub use self::address_space::{AddressSpace, Fault};
+pub use self::fault::Access;
+pub use self::vma::{Backing, Protection, SharedMemory, Vma, VmaFlags};
+pub use self::vma_tree::VmaTree;
+#[cfg(not(test))]
+pub use self::fault::page_fault_handler;
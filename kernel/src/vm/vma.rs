@@ -0,0 +1,370 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![allow(dead_code)]
+
+//! Virtual memory areas (VMAs) of an address space.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use ::frame::FrameAllocator;
+use ::paging::MapFlags;
+use super::Access;
+
+/// The access permissions of a memory area.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl Protection {
+    /// Return the flags used to map a user page with this protection.
+    pub fn to_map_flags(self) -> MapFlags {
+        // x86 can't map a page that is not readable, so a page that is not
+        // readable is just not mapped.
+        MapFlags {
+            write: self.write,
+            user: true,
+            exe_disable: !self.exec,
+            ..MapFlags::default()
+        }
+    }
+
+    /// Return true if the memory access `access` is permitted. A page that
+    /// is writable or executable is also readable in x86.
+    pub fn allows(self, access: Access) -> bool {
+        if access.write {
+            self.write
+        } else if access.exec {
+            self.exec
+        } else {
+            self.read || self.write || self.exec
+        }
+    }
+}
+
+/// The flags of a memory area that don't affect how the pages are mapped.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct VmaFlags {
+    /// The area is a stack that grows down.
+    pub grows_down: bool,
+    /// The area is not copied when the address space is forked.
+    pub dont_fork: bool,
+    /// The area can't be made writable, because the writes can't be kept.
+    /// This is the case of a shared mapping of a read-only file.
+    pub deny_write: bool,
+}
+
+/// Memory that can be shared between address spaces. The frames are
+/// allocated when they are first touched.
+#[derive(Debug, Default)]
+pub struct SharedMemory {
+    // The frames indexed by their offsets in the memory.
+    frames: RefCell<BTreeMap<usize, usize>>,
+}
+
+impl SharedMemory {
+    /// Create a new empty [SharedMemory](SharedMemory).
+    pub fn new() -> Rc<SharedMemory> {
+        Rc::new(SharedMemory::default())
+    }
+
+    /// Return the frame at offset `offset` of the memory. If there is no
+    /// such frame yet, a zeroed frame is allocated.
+    pub fn frame(&self, frames: &mut FrameAllocator, offset: usize)
+        -> Result<usize, ()>
+    {
+        let mut map = self.frames.borrow_mut();
+        if let Some(frame) = map.get(&offset) {
+            return Ok(*frame);
+        }
+        let frame = frames.alloc_zeroed()?;
+        map.insert(offset, frame);
+        Ok(frame)
+    }
+
+    /// Move the references to all the frames of the memory to `released`.
+    /// This must be called when no address space uses the memory anymore.
+    pub fn release(&self, released: &mut Vec<usize>) {
+        let mut map = self.frames.borrow_mut();
+        released.extend(map.values());
+        map.clear();
+    }
+}
+
+/// Where the content of the pages of a memory area comes from.
+#[derive(Clone, Debug)]
+pub enum Backing {
+    /// The pages are filled with zeroes when they are first touched.
+    Anonymous,
+    /// The pages are copied from `data`, which is the content of the file
+    /// `name`, starting at `offset` when they are first touched. The part
+    /// after the end of `data` is filled with zeroes.
+    File {
+        name: &'static str,
+        data: &'static [u8],
+        offset: usize,
+    },
+    /// The pages are mapped to the physical memory of a device starting at
+    /// `phy_addr`.
+    Device { phy_addr: usize },
+    /// The pages are mapped to `memory` starting at `offset`, so the writes
+    /// are seen by every address space that maps the same memory.
+    Shared {
+        memory: Rc<SharedMemory>,
+        offset: usize,
+    },
+}
+
+impl Backing {
+    /// Return the backing of the part of an area that starts `delta` bytes
+    /// after the area backed by this.
+    pub fn advance(&self, delta: usize) -> Backing {
+        match *self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { name, data, offset } => Backing::File {
+                name,
+                data,
+                offset: offset + delta,
+            },
+            Backing::Device { phy_addr } => Backing::Device {
+                phy_addr: phy_addr + delta,
+            },
+            Backing::Shared { ref memory, offset } => Backing::Shared {
+                memory: memory.clone(),
+                offset: offset + delta,
+            },
+        }
+    }
+
+    /// Return true if the writes are seen by the other address spaces that
+    /// map the same thing. Otherwise, the pages are private and copied on
+    /// write.
+    pub fn is_shared(&self) -> bool {
+        match *self {
+            Backing::Device { .. } | Backing::Shared { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Return true if the pages are mapped to frames from the frame
+    /// allocator.
+    pub fn uses_frames(&self) -> bool {
+        match *self {
+            Backing::Device { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Return the offset of the backing shown in the memory map.
+    fn offset(&self) -> usize {
+        match *self {
+            Backing::Anonymous => 0,
+            Backing::File { offset, .. } => offset,
+            Backing::Device { phy_addr } => phy_addr,
+            Backing::Shared { offset, .. } => offset,
+        }
+    }
+}
+
+impl PartialEq for Backing {
+    fn eq(&self, other: &Backing) -> bool {
+        match (self, other) {
+            (Backing::Anonymous, Backing::Anonymous) => true,
+            (Backing::File { name, data, offset },
+             Backing::File { name: name2, data: data2, offset: offset2 }) => {
+                // The content of the file isn't compared, only where it is.
+                name == name2 && data.as_ptr() == data2.as_ptr() &&
+                    data.len() == data2.len() && offset == offset2
+            },
+            (Backing::Device { phy_addr },
+             Backing::Device { phy_addr: phy_addr2 }) => phy_addr == phy_addr2,
+            (Backing::Shared { memory, offset },
+             Backing::Shared { memory: memory2, offset: offset2 }) => {
+                Rc::ptr_eq(memory, memory2) && offset == offset2
+            },
+            _ => false,
+        }
+    }
+}
+
+/// A virtual memory area `start..end` of an address space. Both ends are
+/// page aligned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: Protection,
+    pub flags: VmaFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    /// Create a private anonymous [Vma](Vma).
+    pub fn anonymous(start: usize, end: usize, prot: Protection) -> Vma {
+        Vma {
+            start,
+            end,
+            prot,
+            flags: VmaFlags::default(),
+            backing: Backing::Anonymous,
+        }
+    }
+
+    /// Return true if the area contains the virtual address `addr`.
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Split the area at virtual address `addr`. This area is shrunk to
+    /// end at `addr` and the rest is returned.
+    pub fn split_off(&mut self, addr: usize) -> Vma {
+        assert!(self.start < addr && addr < self.end);
+        let rest = Vma {
+            start: addr,
+            end: self.end,
+            prot: self.prot,
+            flags: self.flags,
+            backing: self.backing.advance(addr - self.start),
+        };
+        self.end = addr;
+        rest
+    }
+
+    /// Return true if the area `next` starts where this area ends and both
+    /// areas can be merged into one.
+    pub fn can_merge(&self, next: &Vma) -> bool {
+        self.end == next.start && self.prot == next.prot &&
+            self.flags == next.flags &&
+            self.backing.advance(self.end - self.start) == next.backing
+    }
+}
+
+/// The format of a line in `/proc/<pid>/maps`.
+impl fmt::Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}-{:08x} {}{}{}{} {:08x} 00:00 0",
+               self.start, self.end,
+               if self.prot.read { 'r' } else { '-' },
+               if self.prot.write { 'w' } else { '-' },
+               if self.prot.exec { 'x' } else { '-' },
+               if self.backing.is_shared() { 's' } else { 'p' },
+               self.backing.offset())?;
+        match self.backing {
+            Backing::File { name, .. } => write!(f, " {}", name),
+            _ if self.flags.grows_down => write!(f, " [stack]"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: Protection = Protection {
+        read: true,
+        write: true,
+        exec: false,
+    };
+
+    #[test]
+    fn split_file_backed_area() {
+        let mut vma = Vma {
+            backing: Backing::File {
+                name: "/bin/init",
+                data: b"",
+                offset: 0x1000,
+            },
+            ..Vma::anonymous(0x2000, 0x5000, RW)
+        };
+        let rest = vma.split_off(0x3000);
+        assert_eq!(vma.end, 0x3000);
+        assert_eq!((rest.start, rest.end), (0x3000, 0x5000));
+        assert_eq!(rest.backing.offset(), 0x2000);
+        assert!(vma.can_merge(&rest));
+    }
+
+    #[test]
+    fn cannot_merge_different_areas() {
+        let vma = Vma::anonymous(0x2000, 0x3000, RW);
+        assert!(vma.can_merge(&Vma::anonymous(0x3000, 0x4000, RW)));
+        assert!(!vma.can_merge(&Vma::anonymous(0x4000, 0x5000, RW)));
+        assert!(!vma.can_merge(&Vma::anonymous(0x3000, 0x4000,
+                                               Protection::default())));
+        let device = Vma {
+            backing: Backing::Device { phy_addr: 0xb8000 },
+            ..Vma::anonymous(0x3000, 0x4000, RW)
+        };
+        assert!(!vma.can_merge(&device));
+        let memory = SharedMemory::new();
+        let shared = Vma {
+            backing: Backing::Shared {
+                memory: memory.clone(),
+                offset: 0,
+            },
+            ..Vma::anonymous(0x2000, 0x3000, RW)
+        };
+        let other = Vma {
+            backing: Backing::Shared {
+                memory: SharedMemory::new(),
+                offset: 0x1000,
+            },
+            ..Vma::anonymous(0x3000, 0x4000, RW)
+        };
+        assert!(!shared.can_merge(&other));
+    }
+
+    #[test]
+    fn format_maps_line() {
+        let vma = Vma {
+            prot: Protection {
+                read: true,
+                exec: true,
+                ..Protection::default()
+            },
+            backing: Backing::File {
+                name: "/bin/init",
+                data: b"",
+                offset: 0x1000,
+            },
+            ..Vma::anonymous(0x40_0000, 0x45_2000, RW)
+        };
+        assert_eq!(format!("{}", vma),
+                   "00400000-00452000 r-xp 00001000 00:00 0 /bin/init");
+
+        let stack = Vma {
+            flags: VmaFlags {
+                grows_down: true,
+                ..VmaFlags::default()
+            },
+            ..Vma::anonymous(0x7fff_0000, 0x8000_0000, RW)
+        };
+        assert_eq!(format!("{}", stack),
+                   "7fff0000-80000000 rw-p 00000000 00:00 0 [stack]");
+
+        let device = Vma {
+            backing: Backing::Device { phy_addr: 0xb8000 },
+            ..Vma::anonymous(0x1000, 0x2000, RW)
+        };
+        assert_eq!(format!("{}", device),
+                   "00001000-00002000 rw-s 000b8000 00:00 0");
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright (c) 2019, Suphanat Chunhapanya
+// This file is part of Kelner.
+//
+// Kelner is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// Kelner is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kelner.  If not, see <https://www.gnu.org/licenses/>.
+#![allow(dead_code)]
+
+//! A tree of the virtual memory areas of an address space.
+
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use super::{Protection, Vma};
+
+/// A structure that keeps the virtual memory areas of an address space
+/// sorted by their addresses. The areas never overlap each other and the
+/// adjacent areas that can be merged are always merged.
+#[derive(Debug, Default)]
+pub struct VmaTree {
+    // The areas indexed by their start addresses.
+    map: BTreeMap<usize, Vma>,
+}
+
+impl VmaTree {
+    /// Create an empty [VmaTree](VmaTree).
+    pub fn new() -> VmaTree {
+        VmaTree::default()
+    }
+
+    /// Return an iterator over all the areas sorted by their addresses.
+    pub fn iter(&self) -> btree_map::Values<usize, Vma> {
+        self.map.values()
+    }
+
+    /// Find the area that contains virtual address `addr`.
+    pub fn lookup(&self, addr: usize) -> Option<&Vma> {
+        self.map.range(..=addr).next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Return the protection of the area that contains virtual address
+    /// `addr`, if `addr` is in any area.
+    pub fn protection(&self, addr: usize) -> Option<Protection> {
+        self.lookup(addr).map(|vma| vma.prot)
+    }
+
+    /// Find the first area that starts at or after virtual address `addr`.
+    pub fn next(&self, addr: usize) -> Option<&Vma> {
+        self.map.range(addr..).next().map(|(_, vma)| vma)
+    }
+
+    /// Return true if every address in `range` is in some area.
+    pub fn is_covered(&self, range: Range<usize>) -> bool {
+        let mut addr = range.start;
+        while addr < range.end {
+            match self.lookup(addr) {
+                Some(vma) => addr = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Return true if no area overlaps `range`.
+    pub fn is_free(&self, range: Range<usize>) -> bool {
+        match self.map.range(..range.end).next_back() {
+            Some((_, vma)) => vma.end <= range.start,
+            None => true,
+        }
+    }
+
+    /// Add the area `vma` to the tree and merge it with its neighbours if
+    /// possible. Return an error if the area is empty or overlaps another
+    /// area.
+    pub fn insert(&mut self, vma: Vma) -> Result<(), ()> {
+        if vma.start >= vma.end || !self.is_free(vma.start..vma.end) {
+            return Err(());
+        }
+        let start = vma.start;
+        self.map.insert(start, vma);
+        self.merge(start);
+        Ok(())
+    }
+
+    /// Make sure that there is an area boundary at virtual address `addr` by
+    /// splitting the area that contains `addr` into two.
+    pub fn split(&mut self, addr: usize) {
+        let start = match self.lookup(addr) {
+            Some(vma) if vma.start != addr => vma.start,
+            _ => return,
+        };
+        let rest = self.map.get_mut(&start).unwrap().split_off(addr);
+        self.map.insert(addr, rest);
+    }
+
+    /// Merge the area that starts at virtual address `addr` with its
+    /// neighbours if possible. Nothing happens if no area starts at `addr`.
+    pub fn merge(&mut self, addr: usize) {
+        if !self.map.contains_key(&addr) {
+            return;
+        }
+        let mut start = addr;
+        let prev = self.map.range(..addr).next_back().map(|(key, _)| *key);
+        if let Some(prev) = prev {
+            if self.map[&prev].can_merge(&self.map[&addr]) {
+                let vma = self.map.remove(&addr).unwrap();
+                self.map.get_mut(&prev).unwrap().end = vma.end;
+                start = prev;
+            }
+        }
+
+        let end = self.map[&start].end;
+        let mergeable = self.map.get(&end)
+            .map_or(false, |next| self.map[&start].can_merge(next));
+        if mergeable {
+            let next = self.map.remove(&end).unwrap();
+            self.map.get_mut(&start).unwrap().end = next.end;
+        }
+    }
+
+    /// Call `f` with each part of the areas that are in `range` and merge
+    /// the parts with their neighbours afterwards. The areas that are
+    /// partially covered are split first.
+    pub fn update<F>(&mut self, range: Range<usize>, mut f: F)
+        where F: FnMut(&mut Vma)
+    {
+        self.split(range.start);
+        self.split(range.end);
+        let end = range.end;
+        let starts: Vec<usize> = self.map.range_mut(range)
+            .map(|(start, vma)| {
+                f(vma);
+                *start
+            })
+            .collect();
+        for start in starts.iter() {
+            self.merge(*start);
+        }
+        self.merge(end);
+    }
+
+    /// Remove the parts of the areas that are in `range`. The areas that
+    /// are partially covered are split first. Return the removed parts.
+    pub fn remove(&mut self, range: Range<usize>) -> Vec<Vma> {
+        self.split(range.start);
+        self.split(range.end);
+        let starts: Vec<usize> = self.map.range(range)
+            .map(|(start, _)| *start)
+            .collect();
+        starts.iter().map(|start| self.map.remove(start).unwrap()).collect()
+    }
+}
+
+/// The format of `/proc/<pid>/maps`.
+impl fmt::Display for VmaTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for vma in self.iter() {
+            writeln!(f, "{}", vma)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::vm::Backing;
+
+    const RW: Protection = Protection {
+        read: true,
+        write: true,
+        exec: false,
+    };
+    const RO: Protection = Protection {
+        read: true,
+        write: false,
+        exec: false,
+    };
+
+    fn ranges(tree: &VmaTree) -> Vec<(usize, usize)> {
+        tree.iter().map(|vma| (vma.start, vma.end)).collect()
+    }
+
+    #[test]
+    fn insert_overlapping_area() {
+        let mut tree = VmaTree::new();
+        tree.insert(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        assert!(tree.insert(Vma::anonymous(0x3000, 0x5000, RO)).is_err());
+        assert!(tree.insert(Vma::anonymous(0x1000, 0x3000, RO)).is_err());
+        assert!(tree.insert(Vma::anonymous(0x1000, 0x1000, RO)).is_err());
+        assert!(tree.insert(Vma::anonymous(0x0, 0x2000, RO)).is_ok());
+        assert!(tree.insert(Vma::anonymous(0x4000, 0x5000, RO)).is_ok());
+        assert_eq!(ranges(&tree),
+                   vec![(0x0, 0x2000), (0x2000, 0x4000), (0x4000, 0x5000)]);
+    }
+
+    #[test]
+    fn lookup_area() {
+        let mut tree = VmaTree::new();
+        tree.insert(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        assert_eq!(tree.lookup(0x2000).unwrap().start, 0x2000);
+        assert_eq!(tree.lookup(0x3fff).unwrap().start, 0x2000);
+        assert!(tree.lookup(0x1fff).is_none());
+        assert!(tree.lookup(0x4000).is_none());
+        assert_eq!(tree.protection(0x3000), Some(RW));
+        assert_eq!(tree.protection(0x5000), None);
+    }
+
+    #[test]
+    fn merge_adjacent_areas() {
+        let mut tree = VmaTree::new();
+        tree.insert(Vma::anonymous(0x2000, 0x3000, RW)).unwrap();
+        tree.insert(Vma::anonymous(0x4000, 0x5000, RW)).unwrap();
+        tree.insert(Vma::anonymous(0x3000, 0x4000, RW)).unwrap();
+        assert_eq!(ranges(&tree), vec![(0x2000, 0x5000)]);
+    }
+
+    #[test]
+    fn merge_file_backed_areas() {
+        let file = |start, end, offset| Vma {
+            backing: Backing::File {
+                name: "/bin/init",
+                data: b"",
+                offset,
+            },
+            ..Vma::anonymous(start, end, RW)
+        };
+        let mut tree = VmaTree::new();
+        tree.insert(file(0x2000, 0x3000, 0x0)).unwrap();
+        tree.insert(file(0x3000, 0x4000, 0x1000)).unwrap();
+        tree.insert(file(0x4000, 0x5000, 0x0)).unwrap();
+        assert_eq!(ranges(&tree), vec![(0x2000, 0x4000), (0x4000, 0x5000)]);
+    }
+
+    #[test]
+    fn split_and_merge_back() {
+        let mut tree = VmaTree::new();
+        tree.insert(Vma::anonymous(0x2000, 0x5000, RW)).unwrap();
+        tree.split(0x3000);
+        tree.split(0x3000);
+        tree.split(0x2000);
+        tree.split(0x6000);
+        assert_eq!(ranges(&tree), vec![(0x2000, 0x3000), (0x3000, 0x5000)]);
+        tree.merge(0x3000);
+        assert_eq!(ranges(&tree), vec![(0x2000, 0x5000)]);
+    }
+
+    #[test]
+    fn remove_middle_of_area() {
+        let mut tree = VmaTree::new();
+        tree.insert(Vma::anonymous(0x2000, 0x6000, RW)).unwrap();
+        tree.insert(Vma::anonymous(0x7000, 0x8000, RW)).unwrap();
+        let removed = tree.remove(0x3000..0x7800);
+        assert_eq!(removed.iter().map(|vma| (vma.start, vma.end))
+                   .collect::<Vec<_>>(),
+                   vec![(0x3000, 0x6000), (0x7000, 0x7800)]);
+        assert_eq!(ranges(&tree), vec![(0x2000, 0x3000), (0x7800, 0x8000)]);
+        assert!(tree.is_free(0x3000..0x7800));
+        assert!(!tree.is_free(0x2fff..0x3000));
+    }
+
+    #[test]
+    fn update_part_of_areas() {
+        let mut tree = VmaTree::new();
+        tree.insert(Vma::anonymous(0x2000, 0x4000, RW)).unwrap();
+        tree.insert(Vma::anonymous(0x4000, 0x6000, RO)).unwrap();
+        assert!(tree.is_covered(0x3000..0x5000));
+        tree.update(0x3000..0x5000, |vma| vma.prot = RO);
+        assert_eq!(ranges(&tree), vec![(0x2000, 0x3000), (0x3000, 0x6000)]);
+        tree.update(0x2000..0x3000, |vma| vma.prot = RO);
+        assert_eq!(ranges(&tree), vec![(0x2000, 0x6000)]);
+        assert!(!tree.is_covered(0x5000..0x7000));
+        assert_eq!(tree.next(0x2001), None);
+        assert_eq!(tree.next(0x1000).unwrap().start, 0x2000);
+    }
+
+    #[test]
+    fn format_maps() {
+        let mut tree = VmaTree::new();
+        tree.insert(Vma::anonymous(0x2000, 0x3000, RW)).unwrap();
+        tree.insert(Vma::anonymous(0x3000, 0x4000, RO)).unwrap();
+        assert_eq!(format!("{}", tree),
+                   "00002000-00003000 rw-p 00000000 00:00 0\n\
+                    00003000-00004000 r--p 00000000 00:00 0\n");
+    }
+}